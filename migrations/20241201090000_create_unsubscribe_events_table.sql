@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE unsubscribe_events (
+    id uuid PRIMARY KEY,
+    subscriber_id uuid NOT NULL
+        REFERENCES subscriptions (id),
+    reason TEXT NULL,
+    unsubscribed_at timestamptz NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE unsubscribe_tokens
+(
+    unsubscribe_token TEXT NOT NULL,
+    subscriber_id     uuid NOT NULL UNIQUE
+        REFERENCES subscriptions (id),
+    PRIMARY KEY (unsubscribe_token)
+);
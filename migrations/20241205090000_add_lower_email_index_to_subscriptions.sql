@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE INDEX subscriptions_lower_email_idx ON subscriptions (lower(email));
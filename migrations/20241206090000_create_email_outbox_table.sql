@@ -0,0 +1,18 @@
+-- Add migration script here
+CREATE TABLE email_outbox (
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT,
+    text_body TEXT NOT NULL,
+    created_at timestamptz NOT NULL,
+    next_attempt_at timestamptz NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    delivered_at timestamptz,
+    failed_at timestamptz
+);
+
+CREATE INDEX email_outbox_pending_idx ON email_outbox (next_attempt_at)
+    WHERE delivered_at IS NULL AND failed_at IS NULL;
@@ -0,0 +1,7 @@
+-- Add migration script here
+-- Only a SHA-256 hash of each confirmation token is kept from now on.
+-- Existing tokens are hashed in place, so links already sent keep working.
+ALTER TABLE subscription_tokens
+    RENAME COLUMN subscription_token TO subscription_token_hash;
+UPDATE subscription_tokens
+    SET subscription_token_hash = encode(sha256(convert_to(subscription_token_hash, 'UTF8')), 'hex');
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Lets the admin listing page through subscribers without an OFFSET scan
+CREATE INDEX subscriptions_subscribed_at_id_idx ON subscriptions (subscribed_at, id);
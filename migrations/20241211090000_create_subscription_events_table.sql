@@ -0,0 +1,22 @@
+-- Add migration script here
+CREATE TABLE subscription_events (
+    -- Orders events that share a timestamp
+    id BIGSERIAL PRIMARY KEY,
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    event TEXT NOT NULL,
+    occurred_at timestamptz NOT NULL,
+    metadata jsonb NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX subscription_events_subscriber_id_idx ON subscription_events (subscriber_id, id);
+
+-- The audit trail is append-only
+CREATE FUNCTION reject_subscription_event_changes() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'subscription_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER subscription_events_append_only
+    BEFORE UPDATE OR DELETE ON subscription_events
+    FOR EACH ROW EXECUTE FUNCTION reject_subscription_event_changes();
@@ -0,0 +1,12 @@
+-- Add migration script here
+-- Erasing a subscriber for good is the one exception to the append-only
+-- audit trail, and only in a transaction that opts in to it with
+-- `SET LOCAL zero2prod.erasing_subscriber = 'on'`
+CREATE OR REPLACE FUNCTION reject_subscription_event_changes() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND current_setting('zero2prod.erasing_subscriber', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'subscription_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD COLUMN consent_source TEXT NULL;
+ALTER TABLE subscriptions ADD COLUMN consent_ip TEXT NULL;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Lets admins search subscribers by the start of their email or name
+CREATE INDEX subscriptions_lower_email_pattern_idx ON subscriptions (lower(email) text_pattern_ops);
+CREATE INDEX subscriptions_lower_name_pattern_idx ON subscriptions (lower(name) text_pattern_ops);
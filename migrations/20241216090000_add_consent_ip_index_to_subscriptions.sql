@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Counts recent signups per client IP for subscriptions.ip_cap
+CREATE INDEX subscriptions_consent_ip_subscribed_at_idx ON subscriptions (consent_ip, subscribed_at);
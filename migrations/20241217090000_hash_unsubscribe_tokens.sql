@@ -0,0 +1,12 @@
+-- Add migration script here
+-- Only a SHA-256 hash of each unsubscribe token is kept from now on.
+-- Existing tokens are hashed in place, so links already sent keep working.
+-- New tokens are derived from the subscriber id, so a subscriber with an
+-- old token gets a second row for the derived one.
+ALTER TABLE unsubscribe_tokens
+    RENAME COLUMN unsubscribe_token TO unsubscribe_token_hash;
+UPDATE unsubscribe_tokens
+    SET unsubscribe_token_hash = encode(sha256(convert_to(unsubscribe_token_hash, 'UTF8')), 'hex');
+ALTER TABLE unsubscribe_tokens
+    DROP CONSTRAINT unsubscribe_tokens_subscriber_id_key;
+CREATE INDEX unsubscribe_tokens_subscriber_id_idx ON unsubscribe_tokens (subscriber_id);
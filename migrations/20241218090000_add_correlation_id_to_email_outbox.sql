@@ -0,0 +1,3 @@
+-- Add migration script here
+-- The id of the request that queued the email, logged if sending fails
+ALTER TABLE email_outbox ADD COLUMN correlation_id TEXT NULL;
@@ -0,0 +1,7 @@
+-- Add migration script here
+-- Unsubscribe reasons are free text a subscriber wrote, kept only in
+-- `unsubscribe_events` where erasing them clears them. Copies of them in
+-- the append-only audit trail are dropped, the one change it allows.
+ALTER TABLE subscription_events DISABLE TRIGGER subscription_events_append_only;
+UPDATE subscription_events SET metadata = metadata - 'reason' WHERE metadata ? 'reason';
+ALTER TABLE subscription_events ENABLE TRIGGER subscription_events_append_only;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- What an email is for, so only confirmation emails are counted as such.
+-- Every email queued so far was a confirmation, but for password resets.
+ALTER TABLE email_outbox ADD COLUMN kind TEXT NOT NULL DEFAULT 'confirmation';
+UPDATE email_outbox SET kind = 'password_reset' WHERE subject = 'Reset your password';
+ALTER TABLE email_outbox ALTER COLUMN kind DROP DEFAULT;
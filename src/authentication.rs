@@ -0,0 +1,335 @@
+//! src/authentication.rs
+use crate::routes::error_chain_fmt;
+use crate::telemetry::spawn_blocking_with_traits;
+use actix_web::dev::Payload;
+use actix_web::http::header::{HeaderMap, HeaderValue};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use base64::Engine;
+use futures::future::LocalBoxFuture;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+const MIN_PASSWORD_LENGTH: usize = 12;
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum AuthError {
+    #[error("Invalid credentials.")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// The realm a 401 asks the client to authenticate against
+pub trait Realm {
+    const NAME: &'static str;
+}
+
+/// The admin API
+pub enum AdminRealm {}
+
+impl Realm for AdminRealm {
+    const NAME: &'static str = "admin";
+}
+
+/// Publishing newsletters, the realm clients of the original API know
+pub enum PublishRealm {}
+
+impl Realm for PublishRealm {
+    const NAME: &'static str = "publish";
+}
+
+/// An admin who authenticated with basic auth. Handlers that take it reject
+/// everyone else with a 401 for realm `R` before they run.
+pub struct AdminUser<R: Realm = AdminRealm> {
+    pub user_id: Uuid,
+    pub username: String,
+    realm: PhantomData<R>,
+}
+
+impl<R: Realm> AdminUser<R> {
+    /// Record who is asking on the handler's span, which declares empty
+    /// `username` and `user_id` fields
+    pub fn record_on_current_span(&self) {
+        let span = tracing::Span::current();
+        span.record("username", tracing::field::display(&self.username));
+        span.record("user_id", tracing::field::display(&self.user_id));
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum AdminAuthError {
+    #[error("Authentication failed")]
+    AuthError {
+        realm: &'static str,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AdminAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminAuthError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+            Self::AuthError { realm, .. } => {
+                let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
+                let header_value =
+                    HeaderValue::from_str(&format!(r#"Basic realm="{}""#, realm)).unwrap();
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, header_value);
+                response
+            }
+        }
+    }
+}
+
+impl<R: Realm + 'static> FromRequest for AdminUser<R> {
+    type Error = AdminAuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let rejected = |source| AdminAuthError::AuthError {
+            realm: R::NAME,
+            source,
+        };
+        let credentials = basic_authentification(req.headers());
+        let pool = req.app_data::<web::Data<PgPool>>().cloned();
+        Box::pin(async move {
+            let credentials = credentials.map_err(rejected)?;
+            let pool = pool.context("The database pool is not configured")?;
+            let username = credentials.username.clone();
+            let user_id = validate_credentials(credentials, &pool)
+                .await
+                .map_err(|e| match e {
+                    AuthError::InvalidCredentials(_) => rejected(e.into()),
+                    AuthError::UnexpectedError(_) => AdminAuthError::UnexpectedError(e.into()),
+                })?;
+            Ok(Self {
+                user_id,
+                username,
+                realm: PhantomData,
+            })
+        })
+    }
+}
+
+pub fn basic_authentification(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+    let header_value = headers
+        .get("Authorization")
+        .context("The 'Authorization' Header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF-8 string.")?;
+    let base64encodedu_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The Authorization schema was not 'Basic '")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64encodedu_segment)
+        .context("Failed to base64-decode 'Basis ' Credentials")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded Credentials string is not valid UTF-8.")?;
+
+    let mut credentials = decoded_credentials.splitn(2, ':');
+    let username = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basis ' auth."))?
+        .to_string();
+    let password = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basis ' auth."))?
+        .to_string();
+
+    Ok(Credentials {
+        username,
+        password: Secret::new(password),
+    })
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<uuid::Uuid, AuthError> {
+    let mut user_id = None;
+    let mut expected_password_hash = Secret::new(
+        "$argon2id$v=19$m=15000,t=2,p=1$\
+        gZiV/M1gPc22ElAH/Jh1Hw$\
+        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
+            .to_string(),
+    );
+
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool).await?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    spawn_blocking_with_traits(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .context("Failed to spawn blocking thread")??;
+
+    user_id.ok_or_else(|| AuthError::InvalidCredentials(anyhow::anyhow!("Unknown username")))
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(uuid::Uuid, Secret<String>)>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash
+        FROM users
+        WHERE username = $1
+        "#,
+        username
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve stored credentials.")?
+    .map(|row| (row.user_id, Secret::new(row.password_hash)));
+    Ok(row)
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password_candidate: Secret<String>,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .map_err(|e| AuthError::UnexpectedError(anyhow::anyhow!(e)))?;
+
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash,
+        )
+        .context("Invalid password")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+/// Check a new password and its confirmation before storing it
+pub fn validate_new_password(
+    new_password: &Secret<String>,
+    new_password_check: &Secret<String>,
+) -> Result<(), String> {
+    if new_password.expose_secret() != new_password_check.expose_secret() {
+        return Err("The two passwords don't match".into());
+    }
+    let length = new_password.expose_secret().chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(format!(
+            "The password must be between {} and {} characters long",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Change password", skip(password, pool))]
+pub async fn change_password(
+    user_id: Uuid,
+    password: Secret<String>,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    let password_hash = spawn_blocking_with_traits(move || compute_password_hash(password))
+        .await?
+        .context("Failed to hash password")?;
+    sqlx::query!(
+        r#"UPDATE users SET password_hash = $1 WHERE user_id = $2"#,
+        password_hash.expose_secret(),
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to change user's password in the database.")?;
+    Ok(())
+}
+
+fn compute_password_hash(password: Secret<String>) -> Result<Secret<String>, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).unwrap(),
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)?
+    .to_string();
+    Ok(Secret::new(password_hash))
+}
+
+#[tracing::instrument(name = "Get username", skip(pool))]
+pub async fn get_username(user_id: Uuid, pool: &PgPool) -> Result<String, anyhow::Error> {
+    let row = sqlx::query!(r#"SELECT username FROM users WHERE user_id = $1"#, user_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to perform a query to retrieve a username.")?;
+    Ok(row.username)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::authentication::validate_new_password;
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+
+    fn secret(s: &str) -> Secret<String> {
+        Secret::new(s.to_string())
+    }
+
+    #[test]
+    fn a_long_enough_matching_password_is_accepted() {
+        let password = secret("correct-horse-battery");
+        assert_ok!(validate_new_password(&password, &password));
+    }
+
+    #[test]
+    fn passwords_that_dont_match_are_rejected() {
+        assert_err!(validate_new_password(
+            &secret("correct-horse-battery"),
+            &secret("correct-horse-staple")
+        ));
+    }
+
+    #[test]
+    fn a_short_password_is_rejected() {
+        let password = secret("too-short");
+        assert_err!(validate_new_password(&password, &password));
+    }
+
+    #[test]
+    fn a_very_long_password_is_rejected() {
+        let password = secret(&"a".repeat(129));
+        assert_err!(validate_new_password(&password, &password));
+    }
+}
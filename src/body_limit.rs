@@ -0,0 +1,104 @@
+//! src/body_limit.rs
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, CONTENT_LENGTH, TRANSFER_ENCODING};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+
+/// The most bytes of body a route accepts. Resources with a larger limit
+/// register their own, overriding the application's.
+pub struct BodyLimit(pub usize);
+
+/// Turn a body that is too long away before any of it is read: 413 if it
+/// declares a length above the limit, 411 if it doesn't declare one at all.
+/// Only wraps routes, the resource's `BodyLimit` isn't visible outside them.
+pub async fn require_content_length(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let limit = request.app_data::<web::Data<BodyLimit>>().map(|l| l.0);
+    if let Some(limit) = limit {
+        if let Err(status) = check_content_length(request.headers(), limit) {
+            tracing::warn!(%status, limit, "Rejected a request body before reading it");
+            let response = HttpResponse::new(status);
+            return Ok(request.into_response(response).map_into_right_body());
+        }
+    }
+    Ok(next.call(request).await?.map_into_left_body())
+}
+
+/// Without either header there is no body to read
+fn check_content_length(headers: &HeaderMap, limit: usize) -> Result<(), StatusCode> {
+    let Some(content_length) = headers.get(CONTENT_LENGTH) else {
+        if headers.contains_key(TRANSFER_ENCODING) {
+            return Err(StatusCode::LENGTH_REQUIRED);
+        }
+        return Ok(());
+    };
+    let content_length: usize = content_length
+        .to_str()
+        .ok()
+        .and_then(|length| length.trim().parse().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if content_length > limit {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::body_limit::check_content_length;
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use actix_web::http::StatusCode;
+    use claims::{assert_err_eq, assert_ok};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn a_declared_length_within_the_limit_is_accepted() {
+        assert_ok!(check_content_length(
+            &headers(&[("content-length", "1024")]),
+            1024
+        ));
+    }
+
+    #[test]
+    fn a_declared_length_above_the_limit_is_too_large() {
+        assert_err_eq!(
+            check_content_length(&headers(&[("content-length", "1025")]), 1024),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn a_chunked_body_needs_a_declared_length() {
+        assert_err_eq!(
+            check_content_length(&headers(&[("transfer-encoding", "chunked")]), 1024),
+            StatusCode::LENGTH_REQUIRED
+        );
+    }
+
+    #[test]
+    fn no_body_at_all_is_accepted() {
+        assert_ok!(check_content_length(&HeaderMap::new(), 1024));
+    }
+
+    #[test]
+    fn an_unparsable_length_is_a_bad_request() {
+        assert_err_eq!(
+            check_content_length(&headers(&[("content-length", "lots")]), 1024),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
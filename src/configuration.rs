@@ -1,46 +1,723 @@
 //! src/configuration.rs
 
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    EmailProvider, HttpClientOptions, NullEmailProvider, PostmarkClient, RateLimitedProvider,
+    Sender, SmtpClient,
+};
+use crate::metrics::Metrics;
+use chrono::NaiveDate;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::postgres::PgSslMode;
 use sqlx::ConnectOptions;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
 
-#[derive(serde::Deserialize, Clone)]
+#[derive(serde::Deserialize, Clone, Debug)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub telemetry: TelemetrySettings,
+    pub subscriptions: SubscriptionSettings,
+    pub email_outbox: EmailOutboxSettings,
+    pub idempotency: IdempotencySettings,
+    pub session: SessionSettings,
+    pub confirmation_webhook: Option<ConfirmationWebhookSettings>,
+    #[serde(default)]
+    pub deprecations: Vec<DeprecatedEndpointSettings>,
+    #[serde(default)]
+    pub cors: CorsSettings,
+}
+
+impl Settings {
+    /// Catch settings that deserialize fine but would only fail once the
+    /// application is running. Every problem found is listed in the error.
+    pub fn validate(&self) -> Result<(), config::ConfigError> {
+        let mut problems = Vec::new();
+        if let Err(e) = check_http_url(&self.application.base_url) {
+            problems.push(format!("application.base_url {}", e));
+        }
+        if self.application.port == 0 {
+            problems.push("application.port must not be 0".to_string());
+        }
+        if self.application.workers == Some(0) {
+            problems.push("application.workers must be positive".to_string());
+        }
+        if self.application.max_body_bytes == 0 {
+            problems.push("application.max_body_bytes must be positive".to_string());
+        }
+        if self.application.max_newsletter_body_bytes < self.application.max_body_bytes {
+            problems.push(
+                "application.max_newsletter_body_bytes must be at least application.max_body_bytes"
+                    .to_string(),
+            );
+        }
+        if self.database.port == 0 {
+            problems.push("database.port must not be 0".to_string());
+        }
+        if self.database.keepalive_interval_seconds == Some(0) {
+            problems.push(
+                "database.keepalive_interval_seconds must be positive, or unset to disable it"
+                    .to_string(),
+            );
+        }
+        if let Some(smtp) = &self.email_client.smtp {
+            if smtp.port == 0 {
+                problems.push("email_client.smtp.port must not be 0".to_string());
+            }
+        }
+        // NaN is in no range, so it is rejected too
+        if !(0.0..=1.0).contains(&self.telemetry.trace_sample_rate) {
+            problems.push("telemetry.trace_sample_rate must be between 0.0 and 1.0".to_string());
+        }
+        if self.telemetry.pool_metrics_interval_milliseconds == 0 {
+            problems
+                .push("telemetry.pool_metrics_interval_milliseconds must be positive".to_string());
+        }
+        if let Err(e) = EnvFilter::try_new(&self.telemetry.level) {
+            problems.push(format!("telemetry.level is invalid: {}", e));
+        }
+        if let Err(e) = SubscriberEmail::parse(self.email_client.sender_email.clone()) {
+            problems.push(format!("email_client.sender_email is invalid: {}", e));
+        }
+        if let Some(reply_to) = &self.email_client.reply_to {
+            if let Err(e) = SubscriberEmail::parse(reply_to.clone()) {
+                problems.push(format!("email_client.reply_to is invalid: {}", e));
+            }
+        }
+        if self.email_client.confirmation_subject.trim().is_empty() {
+            problems.push("email_client.confirmation_subject must not be empty".to_string());
+        }
+        if self.email_client.max_concurrent_sends == 0 {
+            problems.push("email_client.max_concurrent_sends must be positive".to_string());
+        }
+        if self.subscriptions.request_timeout_milliseconds == Some(0) {
+            problems.push(
+                "subscriptions.request_timeout_milliseconds must be positive, or unset for no limit"
+                    .to_string(),
+            );
+        }
+        if let Some(ip_cap) = &self.subscriptions.ip_cap {
+            if ip_cap.max_subscriptions == 0 || ip_cap.window_hours == 0 {
+                problems.push(
+                    "subscriptions.ip_cap needs a positive max_subscriptions and window_hours"
+                        .to_string(),
+                );
+            }
+        }
+        if self.subscriptions.import_batch_size == 0 {
+            problems.push("subscriptions.import_batch_size must be positive".to_string());
+        }
+        for (name, rate_limit) in [
+            ("rate_limit", &self.subscriptions.rate_limit),
+            (
+                "validate_rate_limit",
+                &self.subscriptions.validate_rate_limit,
+            ),
+        ] {
+            if rate_limit.max_requests == 0 {
+                problems.push(format!(
+                    "subscriptions.{name}.max_requests must be positive"
+                ));
+            }
+            if rate_limit.window_seconds == 0 {
+                problems.push(format!(
+                    "subscriptions.{name}.window_seconds must be positive"
+                ));
+            }
+        }
+        if self.idempotency.ttl_hours == 0 {
+            problems.push("idempotency.ttl_hours must be positive".to_string());
+        }
+        if self.idempotency.cleanup_interval_seconds == 0 {
+            problems.push("idempotency.cleanup_interval_seconds must be positive".to_string());
+        }
+        if self.email_client.max_sends_per_second == Some(0) {
+            problems.push(
+                "email_client.max_sends_per_second must be positive, or unset for no limit"
+                    .to_string(),
+            );
+        }
+        if self.email_client.timeout_milliseconds == 0 {
+            problems.push("email_client.timeout_milliseconds must be positive".to_string());
+        }
+        if self.email_client.connect_timeout_milliseconds == 0 {
+            problems.push("email_client.connect_timeout_milliseconds must be positive".to_string());
+        }
+        if self.session.hmac_secret.expose_secret().len() < 64 {
+            problems.push("session.hmac_secret must be at least 64 bytes long".to_string());
+        }
+        if self.session.store == SessionStoreKind::Redis && self.session.redis_uri.is_none() {
+            problems.push("session.redis_uri must be set for the redis store".to_string());
+        }
+        if self.subscriptions.token_length < MIN_TOKEN_LENGTH {
+            problems.push(format!(
+                "subscriptions.token_length must be at least {}",
+                MIN_TOKEN_LENGTH
+            ));
+        }
+        if self.subscriptions.token_mode == TokenMode::Signed {
+            let key_length = self
+                .subscriptions
+                .token_signing_key
+                .as_ref()
+                .map_or(0, |key| key.expose_secret().len());
+            if key_length < MIN_SIGNING_KEY_BYTES {
+                problems.push(format!(
+                    "subscriptions.token_signing_key must be at least {} bytes long in signed mode",
+                    MIN_SIGNING_KEY_BYTES
+                ));
+            }
+        }
+        if self
+            .subscriptions
+            .unsubscribe_token_key
+            .expose_secret()
+            .len()
+            < MIN_SIGNING_KEY_BYTES
+        {
+            problems.push(format!(
+                "subscriptions.unsubscribe_token_key must be at least {} bytes long",
+                MIN_SIGNING_KEY_BYTES
+            ));
+        }
+        for origin in &self.cors.allowed_origins {
+            if origin == "*" {
+                if self.cors.allow_credentials {
+                    problems.push(
+                        "cors.allowed_origins must not contain * when cors.allow_credentials is set"
+                            .to_string(),
+                    );
+                }
+            } else if let Err(e) = check_http_url(origin) {
+                problems.push(format!("cors.allowed_origins {}", e));
+            }
+        }
+        if self.database.statement_timeout_milliseconds == Some(0) {
+            problems.push(
+                "database.statement_timeout_milliseconds must be positive, or unset for no limit"
+                    .to_string(),
+            );
+        }
+        if self.database.acquire_timeout_seconds == 0 {
+            problems.push("database.acquire_timeout_seconds must be positive".to_string());
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(config::ConfigError::Message(format!(
+                "Invalid configuration:\n- {}",
+                problems.join("\n- ")
+            )))
+        }
+    }
+}
+
+fn check_http_url(url: &str) -> Result<(), String> {
+    let parsed =
+        url::Url::parse(url).map_err(|e| format!("{:?} is not a valid URL: {}", url, e))?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(format!("{:?} must use http or https, not {}", url, scheme)),
+    }
+}
+
+/// Which browser frontends may call the public routes (subscribing,
+/// confirming, resending a confirmation). None may by default.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+pub struct CorsSettings {
+    /// Exact origins like `https://example.com`, or `*` for any
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Let browsers send cookies along, never together with `*`
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response
+    #[serde(default)]
+    pub max_age_seconds: Option<usize>,
+}
+
+/// An endpoint whose responses carry `Deprecation`, `Sunset` and `Warning`
+/// headers
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct DeprecatedEndpointSettings {
+    /// The route pattern, e.g. `/admin/subscribers/{id}`
+    pub path: String,
+    /// Only deprecate this method on the route; all methods if unset
+    pub method: Option<String>,
+    pub deprecated_on: NaiveDate,
+    pub sunset_on: NaiveDate,
+    /// Where integrators can read about the replacement
+    pub link: Option<String>,
 }
 
 #[derive(serde::Deserialize, Clone)]
+pub struct ConfirmationWebhookSettings {
+    pub url: String,
+    /// Tera template for the JSON payload, rendered with `subscriber_id`,
+    /// `email` and `name`
+    pub template: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// If set, the payload's HMAC-SHA256 is sent in `X-Webhook-Signature`
+    pub signing_key: Option<Secret<String>>,
+    pub timeout_milliseconds: u64,
+}
+
+// Header values are often credentials, only their names are shown
+impl std::fmt::Debug for ConfirmationWebhookSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConfirmationWebhookSettings")
+            .field("url", &self.url)
+            .field("template", &self.template)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("signing_key", &self.signing_key)
+            .field("timeout_milliseconds", &self.timeout_milliseconds)
+            .finish()
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct SubscriptionSettings {
+    /// Alias form field names to the canonical `email`/`name` fields,
+    /// e.g. `email_address: email`
+    #[serde(default)]
+    pub form_field_map: HashMap<String, String>,
+    /// How often the subscribe transaction is retried after a transient
+    /// database failure (e.g. a connection that couldn't be acquired, or a
+    /// concurrent signup with the same address)
+    pub transaction_retries: u32,
+    /// How long a confirmation link stays valid after it was sent
+    pub confirmation_token_ttl_hours: u32,
+    /// Per client IP; every signup triggers a real email
+    pub rate_limit: RateLimitSettings,
+    /// Per client IP, for checking confirmation tokens without confirming.
+    /// Kept apart from `rate_limit` so a confirmation page checking its
+    /// token doesn't use up the client's signups.
+    pub validate_rate_limit: RateLimitSettings,
+    /// Rows of an import written to the database per statement
+    #[serde(default = "default_import_batch_size")]
+    pub import_batch_size: usize,
+    /// New subscribers allowed per client IP over a longer window than the
+    /// rate limit, e.g. a day. No cap if unset.
+    pub ip_cap: Option<IpCapSettings>,
+    /// Answer a confirmation resend for an unknown email with 200 instead
+    /// of 404, so the endpoint can't be used to find out who subscribed
+    #[serde(default)]
+    pub conceal_unknown_emails: bool,
+    /// Characters in confirmation tokens, at least `MIN_TOKEN_LENGTH`
+    #[serde(default = "default_token_length")]
+    pub token_length: usize,
+    /// Where confirmation tokens are kept
+    #[serde(default)]
+    pub token_mode: TokenMode,
+    /// Signs confirmation tokens in `signed` mode, at least
+    /// `MIN_SIGNING_KEY_BYTES` long
+    pub token_signing_key: Option<Secret<String>>,
+    /// Derives the token in a subscriber's unsubscribe links from their id,
+    /// at least `MIN_SIGNING_KEY_BYTES` long. Only its hash is stored.
+    pub unsubscribe_token_key: Secret<String>,
+    /// How long a subscribe request may take before it is answered with
+    /// 504 and rolled back; no limit if unset
+    pub request_timeout_milliseconds: Option<u64>,
+    /// Only confirm on a POST. Opening the link shows a button that posts
+    /// the token, so mail clients prefetching links don't confirm anyone.
+    #[serde(default)]
+    pub confirm_by_post_only: bool,
+    /// Double opt-in. Without it new subscribers are confirmed right away
+    /// and aren't sent a confirmation email, e.g. for internal tools.
+    pub require_confirmation: bool,
+}
+
+/// How a confirmation token is tied to its subscriber
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenMode {
+    /// A random token, its hash stored in `subscription_tokens`
+    #[default]
+    Db,
+    /// The subscriber id and an expiry, signed with
+    /// `subscriptions.token_signing_key`. Nothing is stored.
+    Signed,
+}
+
+/// A shorter key would make signatures easier to forge
+pub const MIN_SIGNING_KEY_BYTES: usize = 32;
+
+/// Shorter tokens could be guessed
+pub const MIN_TOKEN_LENGTH: usize = 20;
+
+/// What tokens were before the length became configurable
+fn default_token_length() -> usize {
+    25
+}
+
+fn default_import_batch_size() -> usize {
+    500
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct RateLimitSettings {
+    pub max_requests: u32,
+    pub window_seconds: u64,
+}
+
+impl RateLimitSettings {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct IpCapSettings {
+    pub max_subscriptions: u32,
+    pub window_hours: u32,
+}
+
+impl IpCapSettings {
+    pub fn window(&self) -> chrono::Duration {
+        chrono::Duration::hours(i64::from(self.window_hours))
+    }
+}
+
+impl SubscriptionSettings {
+    pub fn confirmation_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(i64::from(self.confirmation_token_ttl_hours))
+    }
+
+    /// The key confirmation tokens are signed with, `None` while they are
+    /// stored in the database
+    pub fn token_signing_key(&self) -> Option<&Secret<String>> {
+        match self.token_mode {
+            TokenMode::Db => None,
+            TokenMode::Signed => self.token_signing_key.as_ref(),
+        }
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_milliseconds.map(Duration::from_millis)
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct EmailOutboxSettings {
+    /// Run the worker delivering queued emails inside the application
+    pub worker_enabled: bool,
+    /// How long the worker sleeps once the outbox is empty
+    pub poll_interval_milliseconds: u64,
+    /// Give up on an email after this many failed sends
+    pub max_attempts: u32,
+    /// The delay before an email is tried again, doubled for each failure
+    pub base_retry_delay_seconds: u64,
+}
+
+impl EmailOutboxSettings {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_milliseconds)
+    }
+
+    pub fn base_retry_delay(&self) -> Duration {
+        Duration::from_secs(self.base_retry_delay_seconds)
+    }
+}
+
+/// Saved responses to requests with an idempotency key
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct IdempotencySettings {
+    /// Periodically delete keys older than `ttl_hours` inside the application
+    pub cleanup_enabled: bool,
+    /// How long a key is remembered, retries after that are processed again
+    pub ttl_hours: u32,
+    pub cleanup_interval_seconds: u64,
+}
+
+impl IdempotencySettings {
+    pub fn ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(i64::from(self.ttl_hours))
+    }
+
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_secs(self.cleanup_interval_seconds)
+    }
+}
+
+/// The admin login session
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct SessionSettings {
+    #[serde(default)]
+    pub store: SessionStoreKind,
+    /// Signs and encrypts the session cookie, at least 64 bytes
+    pub hmac_secret: Secret<String>,
+    /// Only used by the `redis` store
+    pub redis_uri: Option<Secret<String>>,
+    /// Only send the session cookie over HTTPS
+    pub cookie_secure: bool,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStoreKind {
+    /// The whole session lives in the cookie
+    #[default]
+    Cookie,
+    /// The cookie only holds a key, sessions are shared between instances
+    Redis,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct TelemetrySettings {
+    pub trace_sample_rate: f64,
+    pub log_subscriber_identifier: SubscriberIdentifier,
+    /// Where spans are exported to over OTLP/HTTP; not exported if unset
+    pub otlp_endpoint: Option<String>,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// The default log filter, e.g. `info` or `zero2prod=debug,info`.
+    /// `RUST_LOG` takes precedence if set.
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// How often the connection pool's saturation is sampled into the metrics
+    #[serde(default = "default_pool_metrics_interval_milliseconds")]
+    pub pool_metrics_interval_milliseconds: u64,
+}
+
+fn default_log_level() -> String {
+    "info".into()
+}
+
+fn default_pool_metrics_interval_milliseconds() -> u64 {
+    10_000
+}
+
+impl TelemetrySettings {
+    pub fn pool_metrics_interval(&self) -> Duration {
+        Duration::from_millis(self.pool_metrics_interval_milliseconds)
+    }
+}
+
+/// How logs are written to stdout
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Bunyan-style JSON, one object per line, for log aggregators
+    #[default]
+    Json,
+    /// Human-readable lines, for local development
+    Pretty,
+}
+
+/// How subscribers are identified in logs and spans
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriberIdentifier {
+    /// Only the subscriber id, no PII at all
+    Id,
+    /// The id and a masked email, e.g. `u***@gmail.com`
+    EmailObfuscated,
+    /// The id, the full email and the name
+    EmailFull,
+}
+
+impl SubscriberIdentifier {
+    /// The subscriber's email as it may appear in logs, if at all
+    pub fn loggable_email(&self, email: &SubscriberEmail) -> Option<String> {
+        match self {
+            Self::Id => None,
+            Self::EmailObfuscated => Some(email.obfuscated()),
+            Self::EmailFull => Some(email.as_ref().to_owned()),
+        }
+    }
+
+    pub fn includes_name(&self) -> bool {
+        *self == Self::EmailFull
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
 pub struct EmailClientSettings {
+    #[serde(default)]
+    pub provider: EmailProviderKind,
+    /// Only used by the `smtp` provider
+    pub smtp: Option<SmtpSettings>,
     pub base_url: String,
     pub sender_email: String,
+    /// Display name in the `From` of every email, the bare address if unset
+    #[serde(default)]
+    pub sender_name: Option<String>,
+    /// Where replies to our emails go, `sender_email` if unset
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    pub confirmation_subject: String,
     pub authorization_token: Secret<String>,
     pub timeout_milliseconds: u64,
+    /// How long connecting to the provider may take, within the timeout
+    pub connect_timeout_milliseconds: u64,
+    /// Pooled connections unused for this long are closed
+    pub pool_idle_timeout_seconds: u64,
+    /// Idle connections kept open to the provider between sends. Around
+    /// `max_concurrent_sends` lets a newsletter reuse them.
+    pub pool_max_idle_per_host: usize,
+    /// How often a send is retried after a connection error or a 5xx
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled for each one after
+    pub base_retry_delay_milliseconds: u64,
+    /// Send confirmation emails as plain text only if disabled, e.g. where
+    /// the templates directory isn't deployed
+    pub html_enabled: bool,
+    /// How many newsletter emails are in flight at once. Higher is faster,
+    /// but the provider may start throttling us.
+    pub max_concurrent_sends: usize,
+    /// The provider's cap on sends per second, shared by every email we
+    /// send. Unlimited if unset.
+    #[serde(default)]
+    pub max_sends_per_second: Option<u32>,
+    /// Whether `/health/ready` also checks that the provider can be reached.
+    /// Off where there is no provider to reach, e.g. offline development.
+    #[serde(default)]
+    pub readiness_check_enabled: bool,
+    /// Whether new subscribers are turned away if their email domain has no
+    /// host to deliver to, catching typos like `gmailc.om` before they bounce
+    #[serde(default)]
+    pub verify_mx: bool,
 }
 
 impl EmailClientSettings {
-    pub fn sender(&self) -> Result<SubscriberEmail, String> {
-        SubscriberEmail::parse(self.sender_email.clone())
+    pub fn sender(&self) -> Result<Sender, String> {
+        Ok(Sender {
+            email: SubscriberEmail::parse(self.sender_email.clone())?,
+            name: self.sender_name.clone(),
+            reply_to: self
+                .reply_to
+                .clone()
+                .map(SubscriberEmail::parse)
+                .transpose()?,
+        })
     }
 
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
+
+    pub fn base_retry_delay(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.base_retry_delay_milliseconds)
+    }
+
+    pub fn http_client_options(&self) -> HttpClientOptions {
+        HttpClientOptions {
+            timeout: self.timeout(),
+            connect_timeout: std::time::Duration::from_millis(self.connect_timeout_milliseconds),
+            pool_idle_timeout: std::time::Duration::from_secs(self.pool_idle_timeout_seconds),
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+        }
+    }
+
+    pub fn provider(&self, metrics: Arc<Metrics>) -> Result<Arc<dyn EmailProvider>, String> {
+        let sender = self.sender()?;
+        let provider: Arc<dyn EmailProvider> = match self.provider {
+            EmailProviderKind::Postmark => Arc::new(PostmarkClient::new(
+                self.base_url.clone(),
+                sender,
+                self.authorization_token.clone(),
+                self.http_client_options(),
+                self.max_retries,
+                self.base_retry_delay(),
+                metrics,
+            )),
+            EmailProviderKind::Smtp => {
+                let settings = self
+                    .smtp
+                    .as_ref()
+                    .ok_or("The smtp email provider needs `email_client.smtp` settings")?;
+                let client = SmtpClient::new(settings, sender, self.timeout(), metrics)
+                    .map_err(|e| format!("Invalid SMTP settings: {}", e))?;
+                Arc::new(client)
+            }
+            EmailProviderKind::Null => Arc::new(NullEmailProvider::default()),
+        };
+        Ok(match self.max_sends_per_second {
+            Some(max_sends_per_second) => {
+                Arc::new(RateLimitedProvider::new(provider, max_sends_per_second))
+            }
+            None => provider,
+        })
+    }
 }
 
-#[derive(serde::Deserialize, Clone)]
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailProviderKind {
+    #[default]
+    Postmark,
+    Smtp,
+    /// Log emails instead of sending them
+    Null,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    /// Upgrade the connection with STARTTLS. Only disable this for a local
+    /// test server.
+    pub starttls: bool,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
 pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    /// Where the email templates are loaded from at startup
+    pub templates_directory: String,
+    /// How long in-flight requests get to finish after a shutdown signal
+    pub shutdown_timeout_seconds: u64,
+    /// How long the link in a password reset email works
+    pub password_reset_token_ttl_minutes: u32,
+    /// The largest form or JSON body accepted, larger ones get a 413
+    pub max_body_bytes: usize,
+    /// The same for newsletter issues, which are a lot longer than anything
+    /// else we are sent
+    pub max_newsletter_body_bytes: usize,
+    /// Build links in emails from `Forwarded` or `X-Forwarded-Proto` and
+    /// `X-Forwarded-Host` rather than `base_url`, and tell clients apart by
+    /// `Forwarded` or `X-Forwarded-For` rather than the peer address. Only
+    /// enable this behind a proxy that sets them, clients could point the
+    /// links anywhere and dodge rate limits otherwise.
+    #[serde(default)]
+    pub trust_proxy_headers: bool,
+    /// Further proxies requests are forwarded through before the one we are
+    /// connected to, e.g. a CDN in front of a load balancer. Their hops are
+    /// skipped when looking for the client.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Apply pending migrations while starting up, before serving traffic.
+    /// Otherwise they are run out of band with `sqlx migrate run`.
+    #[serde(default)]
+    pub run_migrations_on_start: bool,
+    /// How many worker threads serve requests, one per CPU core if unset.
+    /// Fewer suit containers limited to a fraction of the host's cores.
+    #[serde(default)]
+    pub workers: Option<usize>,
 }
 
-#[derive(serde::Deserialize, Clone)]
+impl ApplicationSettings {
+    pub fn password_reset_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::minutes(i64::from(self.password_reset_token_ttl_minutes))
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
 pub struct DatabaseSettings {
     pub username: String,
     pub password: Secret<String>,
@@ -49,6 +726,59 @@ pub struct DatabaseSettings {
     pub host: String,
     pub database_name: String,
     pub require_ssl: bool,
+    /// Ping connections before handing them out, so ones that went stale
+    /// (e.g. after a Postgres restart) are replaced instead of failing a query
+    pub test_before_acquire: bool,
+    /// How often idle connections are pinged to keep them warm; disabled if
+    /// unset
+    pub keepalive_interval_seconds: Option<u64>,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_connections: u32,
+    /// How long a request waits for a free connection before giving up
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub acquire_timeout_seconds: u64,
+    /// Idle connections are closed after this long; kept forever if unset
+    pub idle_timeout_seconds: Option<u64>,
+    /// Postgres cancels any query running longer, so a stuck one can't hold
+    /// a connection forever; no limit if unset
+    pub statement_timeout_milliseconds: Option<u64>,
+    /// How often connecting at startup is retried, e.g. while Postgres is
+    /// still starting next to us
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub startup_max_retries: u32,
+    /// How long startup waits for Postgres at most. If unset it doesn't wait,
+    /// the first request to need a connection makes one.
+    pub startup_max_wait_seconds: Option<u64>,
+}
+
+/// Supplies the password used when building Postgres connection options,
+/// e.g. a short-lived IAM auth token instead of a static secret.
+///
+/// `password` is called each time options are built, so implementations
+/// that fetch tokens remotely should cache them.
+pub trait PasswordProvider: Send + Sync {
+    fn password(&self) -> Secret<String>;
+
+    /// How often the pool's connect options should be rebuilt to pick up a
+    /// rotated password. `None` means the password never changes.
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The password from the configuration file, used as is
+pub struct StaticPassword(Secret<String>);
+
+impl StaticPassword {
+    pub fn new(password: Secret<String>) -> Self {
+        Self(password)
+    }
+}
+
+impl PasswordProvider for StaticPassword {
+    fn password(&self) -> Secret<String> {
+        self.0.clone()
+    }
 }
 
 pub enum Environment {
@@ -81,6 +811,9 @@ impl TryFrom<String> for Environment {
     }
 }
 
+/// Layer `base.yaml`, the file of the current `APP_ENVIRONMENT` and `APP_`
+/// prefixed environment variables, so secrets can come from the environment
+/// alone, e.g. `APP_DATABASE__PASSWORD`.
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
     let base_path = std::env::current_dir().expect("Failed to determine the current directory");
     let configuration_directory = base_path.join("configuration");
@@ -89,6 +822,15 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         .unwrap_or_else(|_| "local".into())
         .try_into()
         .expect("Failed to parse APP_ENVIRONMENT.");
+    load_configuration(&configuration_directory, environment, None)
+}
+
+/// Reads `environment_variables` instead of the process environment if set
+fn load_configuration(
+    configuration_directory: &Path,
+    environment: Environment,
+    environment_variables: Option<config::Map<String, String>>,
+) -> Result<Settings, config::ConfigError> {
     let environment_filename = format!("{}.yaml", environment.as_str());
 
     // Initialise our configuration reader
@@ -103,7 +845,8 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         .add_source(
             config::Environment::with_prefix("APP")
                 .prefix_separator("_")
-                .separator("__"),
+                .separator("__")
+                .source(environment_variables),
         )
         .build()?;
     settings.try_deserialize::<Settings>()
@@ -111,11 +854,46 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
 
 impl DatabaseSettings {
     pub fn with_db(&self) -> PgConnectOptions {
-        let options = self.without_db().database(&self.database_name);
-        options.log_statements(tracing_log::log::LevelFilter::Trace)
+        self.with_db_using(&self.static_password())
     }
 
     pub fn without_db(&self) -> PgConnectOptions {
+        self.without_db_using(&self.static_password())
+    }
+
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval_seconds.map(Duration::from_secs)
+    }
+
+    pub fn acquire_timeout(&self) -> Duration {
+        Duration::from_secs(self.acquire_timeout_seconds)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_seconds.map(Duration::from_secs)
+    }
+
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.statement_timeout_milliseconds
+            .map(Duration::from_millis)
+    }
+
+    pub fn startup_max_wait(&self) -> Option<Duration> {
+        self.startup_max_wait_seconds.map(Duration::from_secs)
+    }
+
+    pub fn static_password(&self) -> StaticPassword {
+        StaticPassword::new(self.password.clone())
+    }
+
+    pub fn with_db_using(&self, password_provider: &dyn PasswordProvider) -> PgConnectOptions {
+        let options = self
+            .without_db_using(password_provider)
+            .database(&self.database_name);
+        options.log_statements(tracing_log::log::LevelFilter::Trace)
+    }
+
+    pub fn without_db_using(&self, password_provider: &dyn PasswordProvider) -> PgConnectOptions {
         let ssl_mode = if self.require_ssl {
             PgSslMode::Require
         } else {
@@ -124,8 +902,467 @@ impl DatabaseSettings {
         PgConnectOptions::new()
             .host(&self.host)
             .username(&self.username)
-            .password(self.password.expose_secret())
+            .password(password_provider.password().expose_secret())
             .port(self.port)
             .ssl_mode(ssl_mode)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{
+        load_configuration, ConfirmationWebhookSettings, DatabaseSettings, EmailClientSettings,
+        Environment, IpCapSettings, LogFormat, SessionStoreKind, Settings, SmtpSettings, TokenMode,
+    };
+    use claims::{assert_err, assert_ok};
+    use config::{Config, File, FileFormat};
+    use secrecy::{ExposeSecret, Secret};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn parse(yaml: &str) -> DatabaseSettings {
+        Config::builder()
+            .add_source(File::from_str(yaml, FileFormat::Yaml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    const DATABASE: &str = r#"
+host: "127.0.0.1"
+port: 5432
+username: "postgres"
+password: "password"
+database_name: "newsletter"
+require_ssl: false
+test_before_acquire: true
+startup_max_retries: 5
+"#;
+
+    #[test]
+    fn pool_settings_are_parsed_from_yaml() {
+        let settings = parse(&format!(
+            "{}max_connections: 25\nacquire_timeout_seconds: 3\nidle_timeout_seconds: 300\n",
+            DATABASE
+        ));
+        assert_eq!(settings.max_connections, 25);
+        assert_eq!(settings.acquire_timeout(), Duration::from_secs(3));
+        assert_eq!(settings.idle_timeout(), Some(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn the_idle_timeout_is_optional() {
+        let settings = parse(&format!(
+            "{}max_connections: 25\nacquire_timeout_seconds: 3\n",
+            DATABASE
+        ));
+        assert_eq!(settings.idle_timeout(), None);
+    }
+
+    #[test]
+    fn startup_does_not_wait_for_postgres_by_default() {
+        let settings = parse(&format!(
+            "{}max_connections: 25\nacquire_timeout_seconds: 3\n",
+            DATABASE
+        ));
+        assert_eq!(settings.startup_max_wait(), None);
+    }
+
+    fn local_settings() -> Settings {
+        Config::builder()
+            .add_source(File::from_str(
+                include_str!("../configuration/base.yaml"),
+                FileFormat::Yaml,
+            ))
+            .add_source(File::from_str(
+                include_str!("../configuration/local.yaml"),
+                FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn problems(settings: Settings) -> String {
+        assert_err!(settings.validate()).to_string()
+    }
+
+    fn load_with_environment(variables: &[(&str, &str)]) -> Settings {
+        let variables = variables
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        load_configuration(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("configuration"),
+            Environment::Local,
+            Some(variables),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn environment_variables_override_the_configuration_files() {
+        let settings = load_with_environment(&[
+            ("APP_DATABASE__PASSWORD", "from-the-environment"),
+            (
+                "APP_EMAIL_CLIENT__AUTHORIZATION_TOKEN",
+                "token-from-the-environment",
+            ),
+        ]);
+        assert_eq!(
+            settings.database.password.expose_secret(),
+            "from-the-environment"
+        );
+        assert_eq!(
+            settings.email_client.authorization_token.expose_secret(),
+            "token-from-the-environment"
+        );
+        // Everything else still comes from the files
+        assert_eq!(settings.database.username, "postgres");
+    }
+
+    #[test]
+    fn secrets_are_redacted_from_the_debug_output() {
+        let mut settings = load_with_environment(&[
+            ("APP_DATABASE__PASSWORD", "database-password-0451"),
+            ("APP_SESSION__HMAC_SECRET", &"session-secret-0451".repeat(4)),
+        ]);
+        settings.confirmation_webhook = Some(ConfirmationWebhookSettings {
+            url: "https://hooks.example.com".into(),
+            template: "{}".into(),
+            headers: HashMap::from([("Authorization".into(), "Bearer webhook-0451".into())]),
+            signing_key: Some(Secret::new("signing-key-0451".into())),
+            timeout_milliseconds: 1000,
+        });
+        let debug = format!("{:?}", settings);
+        assert!(debug.contains("Authorization"));
+        assert!(!debug.contains("0451"), "{}", debug);
+        assert!(!debug.contains("my-secret-token"));
+    }
+
+    #[test]
+    fn the_local_configuration_is_valid() {
+        assert_ok!(local_settings().validate());
+    }
+
+    #[test]
+    fn an_empty_base_url_is_rejected() {
+        let mut settings = local_settings();
+        settings.application.base_url = "".into();
+        assert!(problems(settings).contains("application.base_url"));
+    }
+
+    #[test]
+    fn a_base_url_that_is_not_http_is_rejected() {
+        let mut settings = local_settings();
+        settings.application.base_url = "ftp://127.0.0.1".into();
+        assert!(problems(settings).contains("must use http or https, not ftp"));
+    }
+
+    #[test]
+    fn a_zero_application_port_is_rejected() {
+        let mut settings = local_settings();
+        settings.application.port = 0;
+        assert!(problems(settings).contains("application.port"));
+    }
+
+    #[test]
+    fn a_zero_database_port_is_rejected() {
+        let mut settings = local_settings();
+        settings.database.port = 0;
+        assert!(problems(settings).contains("database.port"));
+    }
+
+    #[test]
+    fn a_zero_smtp_port_is_rejected() {
+        let mut settings = local_settings();
+        settings.email_client.smtp = Some(SmtpSettings {
+            host: "localhost".into(),
+            port: 0,
+            username: None,
+            password: None,
+            starttls: false,
+        });
+        assert!(problems(settings).contains("email_client.smtp.port"));
+    }
+
+    #[test]
+    fn an_invalid_reply_to_address_is_rejected() {
+        let mut settings = local_settings();
+        settings.email_client.reply_to = Some("not-an-email".into());
+        let problems = problems(settings);
+        assert!(problems.contains("email_client.reply_to"));
+        assert!(!problems.contains("email_client.sender_email"));
+    }
+
+    #[test]
+    fn an_invalid_sender_email_is_rejected() {
+        let mut settings = local_settings();
+        settings.email_client.sender_email = "not-an-email".into();
+        assert!(problems(settings).contains("email_client.sender_email"));
+    }
+
+    #[test]
+    fn an_empty_confirmation_subject_is_rejected() {
+        let mut settings = local_settings();
+        settings.email_client.confirmation_subject = " ".into();
+        assert!(problems(settings).contains("email_client.confirmation_subject"));
+    }
+
+    #[test]
+    fn a_zero_email_client_timeout_is_rejected() {
+        let mut settings = local_settings();
+        settings.email_client.timeout_milliseconds = 0;
+        assert!(problems(settings).contains("email_client.timeout_milliseconds"));
+    }
+
+    #[test]
+    fn a_zero_connect_timeout_is_rejected() {
+        let mut settings = local_settings();
+        settings.email_client.connect_timeout_milliseconds = 0;
+        assert!(problems(settings).contains("email_client.connect_timeout_milliseconds"));
+    }
+
+    #[test]
+    fn email_client_pool_settings_are_parsed_from_yaml() {
+        let settings: EmailClientSettings = Config::builder()
+            .add_source(File::from_str(
+                r#"
+base_url: "localhost"
+sender_email: "test@gmail.com"
+confirmation_subject: "Welcome!"
+authorization_token: "my-secret-token"
+timeout_milliseconds: 10000
+connect_timeout_milliseconds: 1500
+pool_idle_timeout_seconds: 30
+pool_max_idle_per_host: 4
+max_retries: 3
+base_retry_delay_milliseconds: 100
+html_enabled: true
+max_concurrent_sends: 4
+"#,
+                FileFormat::Yaml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let options = settings.http_client_options();
+        assert_eq!(options.timeout, Duration::from_secs(10));
+        assert_eq!(options.connect_timeout, Duration::from_millis(1500));
+        assert_eq!(options.pool_idle_timeout, Duration::from_secs(30));
+        assert_eq!(options.pool_max_idle_per_host, 4);
+    }
+
+    #[test]
+    fn the_worker_count_defaults_to_one_per_core() {
+        assert_eq!(local_settings().application.workers, None);
+        let settings = load_with_environment(&[("APP_APPLICATION__WORKERS", "2")]);
+        assert_eq!(settings.application.workers, Some(2));
+    }
+
+    #[test]
+    fn zero_workers_are_rejected() {
+        let mut settings = local_settings();
+        settings.application.workers = Some(0);
+        assert!(problems(settings).contains("application.workers"));
+    }
+
+    #[test]
+    fn an_invalid_log_level_is_rejected() {
+        let mut settings = local_settings();
+        settings.telemetry.level = "zero2prod=loud".into();
+        assert!(problems(settings).contains("telemetry.level"));
+    }
+
+    #[test]
+    fn a_zero_pool_metrics_interval_is_rejected() {
+        let mut settings = local_settings();
+        settings.telemetry.pool_metrics_interval_milliseconds = 0;
+        assert!(problems(settings).contains("telemetry.pool_metrics_interval_milliseconds"));
+    }
+
+    #[test]
+    fn logs_are_pretty_printed_locally() {
+        let settings = local_settings();
+        assert_eq!(settings.telemetry.format, LogFormat::Pretty);
+        assert_eq!(settings.telemetry.level, "info");
+    }
+
+    #[test]
+    fn a_zero_body_limit_is_rejected() {
+        let mut settings = local_settings();
+        settings.application.max_body_bytes = 0;
+        assert!(problems(settings).contains("application.max_body_bytes must be positive"));
+    }
+
+    #[test]
+    fn a_newsletter_body_limit_below_the_default_is_rejected() {
+        let mut settings = local_settings();
+        settings.application.max_body_bytes = 1024;
+        settings.application.max_newsletter_body_bytes = 512;
+        assert!(problems(settings).contains("application.max_newsletter_body_bytes"));
+    }
+
+    #[test]
+    fn zero_concurrent_sends_are_rejected() {
+        let mut settings = local_settings();
+        settings.email_client.max_concurrent_sends = 0;
+        assert!(problems(settings).contains("email_client.max_concurrent_sends"));
+    }
+
+    #[test]
+    fn a_zero_subscribe_timeout_is_rejected() {
+        let mut settings = local_settings();
+        settings.subscriptions.request_timeout_milliseconds = Some(0);
+        assert!(problems(settings).contains("subscriptions.request_timeout_milliseconds"));
+    }
+
+    #[test]
+    fn an_ip_cap_of_zero_is_rejected() {
+        let mut settings = local_settings();
+        settings.subscriptions.ip_cap = Some(IpCapSettings {
+            max_subscriptions: 0,
+            window_hours: 24,
+        });
+        assert!(problems(settings).contains("subscriptions.ip_cap"));
+    }
+
+    #[test]
+    fn a_trace_sample_rate_outside_of_0_to_1_is_rejected() {
+        for rate in [f64::NAN, f64::INFINITY, -0.1, 1.5] {
+            let mut settings = local_settings();
+            settings.telemetry.trace_sample_rate = rate;
+            assert!(
+                problems(settings).contains("telemetry.trace_sample_rate"),
+                "{}",
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn a_zero_keepalive_interval_is_rejected() {
+        let mut settings = local_settings();
+        settings.database.keepalive_interval_seconds = Some(0);
+        assert!(problems(settings).contains("database.keepalive_interval_seconds"));
+    }
+
+    #[test]
+    fn a_zero_idempotency_ttl_is_rejected() {
+        let mut settings = local_settings();
+        settings.idempotency.ttl_hours = 0;
+        assert!(problems(settings).contains("idempotency.ttl_hours"));
+    }
+
+    #[test]
+    fn a_zero_rate_limit_is_rejected() {
+        let mut settings = local_settings();
+        settings.subscriptions.rate_limit.max_requests = 0;
+        settings.subscriptions.rate_limit.window_seconds = 0;
+        settings.subscriptions.validate_rate_limit.max_requests = 0;
+        settings.subscriptions.validate_rate_limit.window_seconds = 0;
+        let problems = problems(settings);
+        assert!(problems.contains("subscriptions.rate_limit.max_requests"));
+        assert!(problems.contains("subscriptions.rate_limit.window_seconds"));
+        assert!(problems.contains("subscriptions.validate_rate_limit.max_requests"));
+        assert!(problems.contains("subscriptions.validate_rate_limit.window_seconds"));
+    }
+
+    #[test]
+    fn a_zero_import_batch_size_is_rejected() {
+        let mut settings = local_settings();
+        settings.subscriptions.import_batch_size = 0;
+        assert!(problems(settings).contains("subscriptions.import_batch_size must be positive"));
+    }
+
+    #[test]
+    fn a_zero_send_rate_is_rejected() {
+        let mut settings = local_settings();
+        settings.email_client.max_sends_per_second = Some(0);
+        assert!(problems(settings).contains("email_client.max_sends_per_second"));
+    }
+
+    #[test]
+    fn a_zero_acquire_timeout_is_rejected() {
+        let mut settings = local_settings();
+        settings.database.acquire_timeout_seconds = 0;
+        assert!(problems(settings).contains("database.acquire_timeout_seconds"));
+    }
+
+    #[test]
+    fn a_short_token_length_is_rejected() {
+        let mut settings = local_settings();
+        settings.subscriptions.token_length = 8;
+        assert!(problems(settings).contains("subscriptions.token_length"));
+    }
+
+    #[test]
+    fn signed_tokens_need_a_long_enough_signing_key() {
+        let mut settings = local_settings();
+        settings.subscriptions.token_mode = TokenMode::Signed;
+        assert!(problems(settings.clone()).contains("subscriptions.token_signing_key"));
+
+        settings.subscriptions.token_signing_key = Some(Secret::new("too-short".into()));
+        assert!(problems(settings.clone()).contains("subscriptions.token_signing_key"));
+
+        settings.subscriptions.token_signing_key = Some(Secret::new("a".repeat(32)));
+        assert_ok!(settings.validate());
+    }
+
+    #[test]
+    fn a_zero_statement_timeout_is_rejected() {
+        let mut settings = local_settings();
+        settings.database.statement_timeout_milliseconds = Some(0);
+        assert!(problems(settings).contains("database.statement_timeout_milliseconds"));
+    }
+
+    #[test]
+    fn a_cors_wildcard_with_credentials_is_rejected() {
+        let mut settings = local_settings();
+        settings.cors.allowed_origins = vec!["*".into()];
+        settings.cors.allow_credentials = true;
+        assert!(problems(settings).contains("cors.allowed_origins must not contain *"));
+    }
+
+    #[test]
+    fn a_cors_wildcard_without_credentials_is_accepted() {
+        let mut settings = local_settings();
+        settings.cors.allowed_origins = vec!["*".into(), "https://example.com".into()];
+        assert_ok!(settings.validate());
+    }
+
+    #[test]
+    fn an_invalid_cors_origin_is_rejected() {
+        let mut settings = local_settings();
+        settings.cors.allowed_origins = vec!["example.com".into()];
+        assert!(problems(settings).contains("cors.allowed_origins"));
+    }
+
+    #[test]
+    fn a_short_session_secret_is_rejected() {
+        let mut settings = local_settings();
+        settings.session.hmac_secret = Secret::new("too-short".into());
+        assert!(problems(settings).contains("session.hmac_secret"));
+    }
+
+    #[test]
+    fn the_redis_session_store_needs_a_uri() {
+        let mut settings = local_settings();
+        settings.session.store = SessionStoreKind::Redis;
+        assert!(problems(settings).contains("session.redis_uri"));
+    }
+
+    #[test]
+    fn every_problem_is_listed() {
+        let mut settings = local_settings();
+        settings.application.base_url = "".into();
+        settings.email_client.timeout_milliseconds = 0;
+        let problems = problems(settings);
+        assert!(problems.contains("application.base_url"));
+        assert!(problems.contains("email_client.timeout_milliseconds"));
+    }
+}
@@ -0,0 +1,83 @@
+//! src/confirmation_webhook.rs
+use crate::configuration::ConfirmationWebhookSettings;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use std::collections::HashMap;
+use tera::Tera;
+use uuid::Uuid;
+
+const TEMPLATE_NAME: &str = "confirmation_webhook";
+
+/// Notifies an integration once a subscriber confirmed their subscription.
+///
+/// The payload is rendered from a Tera template with `subscriber_id`,
+/// `email` and `name` in its context, so each deployment can send the shape
+/// its integration expects.
+pub struct ConfirmationWebhook {
+    http_client: Client,
+    url: String,
+    template: Tera,
+    headers: HashMap<String, String>,
+    signing_key: Option<Secret<String>>,
+}
+
+pub struct ConfirmedSubscriber {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+}
+
+impl ConfirmationWebhook {
+    pub fn new(settings: ConfirmationWebhookSettings) -> Result<Self, tera::Error> {
+        let mut template = Tera::default();
+        template.add_raw_template(TEMPLATE_NAME, &settings.template)?;
+        let http_client = Client::builder()
+            .timeout(std::time::Duration::from_millis(
+                settings.timeout_milliseconds,
+            ))
+            .build()
+            .unwrap();
+        Ok(Self {
+            http_client,
+            url: settings.url,
+            template,
+            headers: settings.headers,
+            signing_key: settings.signing_key,
+        })
+    }
+
+    pub fn render(&self, subscriber: &ConfirmedSubscriber) -> Result<String, tera::Error> {
+        let mut context = tera::Context::new();
+        context.insert("subscriber_id", &subscriber.id.to_string());
+        context.insert("email", &subscriber.email);
+        context.insert("name", &subscriber.name);
+        self.template.render(TEMPLATE_NAME, &context)
+    }
+
+    #[tracing::instrument(name = "Send confirmation webhook", skip(self, subscriber))]
+    pub async fn send(&self, subscriber: &ConfirmedSubscriber) -> Result<(), anyhow::Error> {
+        let payload = self.render(subscriber)?;
+        let mut request = self
+            .http_client
+            .post(&self.url)
+            .header("Content-Type", "application/json");
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        if let Some(signing_key) = &self.signing_key {
+            request = request.header("X-Webhook-Signature", sign(signing_key, &payload));
+        }
+        request.body(payload).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// HMAC-SHA256 of the payload, hex-encoded and prefixed with `sha256=`
+pub fn sign(signing_key: &Secret<String>, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
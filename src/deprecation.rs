@@ -0,0 +1,117 @@
+//! src/deprecation.rs
+use crate::configuration::DeprecatedEndpointSettings;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web;
+use chrono::NaiveDate;
+
+/// Response headers announcing the deprecation of configured endpoints
+pub struct Deprecations(Vec<DeprecatedEndpoint>);
+
+struct DeprecatedEndpoint {
+    path: String,
+    method: Option<Method>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Deprecations {
+    pub fn new(settings: &[DeprecatedEndpointSettings]) -> Result<Self, String> {
+        let endpoints = settings
+            .iter()
+            .map(DeprecatedEndpoint::new)
+            .collect::<Result<_, _>>()?;
+        Ok(Self(endpoints))
+    }
+
+    fn headers_for(&self, method: &Method, path: &str) -> Option<&[(HeaderName, HeaderValue)]> {
+        self.0
+            .iter()
+            .find(|e| e.path == path && e.method.as_ref().is_none_or(|m| m == method))
+            .map(|e| e.headers.as_slice())
+    }
+}
+
+impl DeprecatedEndpoint {
+    fn new(settings: &DeprecatedEndpointSettings) -> Result<Self, String> {
+        let method = settings
+            .method
+            .as_deref()
+            .map(|m| Method::from_bytes(m.to_uppercase().as_bytes()))
+            .transpose()
+            .map_err(|e| format!("Invalid method for deprecated {}: {}", settings.path, e))?;
+
+        // RFC 9745 wants a unix timestamp, RFC 8594 an HTTP date
+        let mut headers = vec![
+            (
+                HeaderName::from_static("deprecation"),
+                format!("@{}", midnight(settings.deprecated_on).timestamp()),
+            ),
+            (
+                HeaderName::from_static("sunset"),
+                http_date(settings.sunset_on),
+            ),
+            (
+                HeaderName::from_static("warning"),
+                format!(
+                    r#"299 - "Deprecated API: {} will be removed on {}""#,
+                    settings.path, settings.sunset_on
+                ),
+            ),
+        ];
+        if let Some(link) = &settings.link {
+            headers.push((
+                HeaderName::from_static("link"),
+                format!(r#"<{}>; rel="deprecation""#, link),
+            ));
+        }
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| {
+                HeaderValue::from_str(&value)
+                    .map(|value| (name, value))
+                    .map_err(|e| format!("Invalid deprecation header for {}: {}", settings.path, e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            path: settings.path.clone(),
+            method,
+            headers,
+        })
+    }
+}
+
+fn midnight(date: NaiveDate) -> chrono::DateTime<chrono::Utc> {
+    date.and_hms_opt(0, 0, 0).unwrap().and_utc()
+}
+
+fn http_date(date: NaiveDate) -> String {
+    midnight(date)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Middleware adding the deprecation headers to responses of deprecated routes
+pub async fn add_deprecation_headers(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut response = next.call(request).await?;
+    let deprecations = response
+        .request()
+        .app_data::<web::Data<Deprecations>>()
+        .cloned();
+    let pattern = response.request().match_pattern();
+    if let (Some(deprecations), Some(pattern)) = (deprecations, pattern) {
+        let method = response.request().method().clone();
+        if let Some(headers) = deprecations.headers_for(&method, &pattern) {
+            for (name, value) in headers {
+                response.headers_mut().insert(name.clone(), value.clone());
+            }
+        }
+    }
+    Ok(response)
+}
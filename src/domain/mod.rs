@@ -1,8 +1,16 @@
 //! src/domain/mod.rs
+mod new_newsletter_issue;
 mod new_subscriber;
+mod newsletter_body;
 mod subscriber_email;
 mod subscriber_name;
+mod subscription_status;
+mod subscription_token;
 
-pub use new_subscriber::NewSubscriber;
+pub use new_newsletter_issue::{NewNewsletterIssue, NewsletterValidationError};
+pub use new_subscriber::{NewSubscriber, SubscriberValidationError};
+pub use newsletter_body::NewsletterBody;
 pub use subscriber_email::SubscriberEmail;
-pub use subscriber_name::SubscriberName;
+pub use subscriber_name::{SubscriberName, SubscriberNameError};
+pub use subscription_status::SubscriptionStatus;
+pub use subscription_token::SubscriptionToken;
@@ -0,0 +1,133 @@
+use crate::domain::NewsletterBody;
+use unicode_segmentation::UnicodeSegmentation;
+
+const MAX_TITLE_GRAPHEMES: usize = 256;
+/// Both parts together, well under what email providers accept per message
+const MAX_CONTENT_BYTES: usize = 512 * 1024;
+
+/// An issue that is fit to be sent to every confirmed subscriber
+#[derive(Debug)]
+pub struct NewNewsletterIssue {
+    pub title: String,
+    pub body: NewsletterBody,
+}
+
+/// Which rule a rejected newsletter issue broke
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum NewsletterValidationError {
+    #[error("The title must not be empty")]
+    EmptyTitle,
+    #[error(
+        "The title must be at most {} characters long, got {0}",
+        MAX_TITLE_GRAPHEMES
+    )]
+    TitleTooLong(usize),
+    #[error("The content must not be empty")]
+    EmptyContent,
+    #[error(
+        "The content must be at most {} bytes long, got {0}",
+        MAX_CONTENT_BYTES
+    )]
+    ContentTooLong(usize),
+}
+
+impl NewNewsletterIssue {
+    /// Both the HTML and the plain text part must have content, subscribers
+    /// get one or the other depending on their mail client.
+    pub fn parse(
+        title: String,
+        body: NewsletterBody,
+    ) -> Result<NewNewsletterIssue, NewsletterValidationError> {
+        if title.trim().is_empty() {
+            return Err(NewsletterValidationError::EmptyTitle);
+        }
+        let graphemes = title.graphemes(true).count();
+        if graphemes > MAX_TITLE_GRAPHEMES {
+            return Err(NewsletterValidationError::TitleTooLong(graphemes));
+        }
+        if body.html.trim().is_empty() || body.text.trim().is_empty() {
+            return Err(NewsletterValidationError::EmptyContent);
+        }
+        let bytes = body.html.len() + body.text.len();
+        if bytes > MAX_CONTENT_BYTES {
+            return Err(NewsletterValidationError::ContentTooLong(bytes));
+        }
+        Ok(Self { title, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{NewNewsletterIssue, NewsletterBody, NewsletterValidationError};
+    use claims::assert_ok;
+
+    fn body(html: &str, text: &str) -> NewsletterBody {
+        NewsletterBody {
+            html: html.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn a_titled_issue_with_content_is_valid() {
+        assert_ok!(NewNewsletterIssue::parse(
+            "Issue 1".to_string(),
+            body("<p>Hello</p>", "Hello")
+        ));
+    }
+
+    #[test]
+    fn an_empty_or_whitespace_only_title_is_rejected() {
+        for title in ["", " \t\n"] {
+            assert_eq!(
+                NewNewsletterIssue::parse(title.to_string(), body("<p>Hello</p>", "Hello"))
+                    .unwrap_err(),
+                NewsletterValidationError::EmptyTitle
+            );
+        }
+    }
+
+    #[test]
+    fn a_title_longer_than_256_graphemes_is_rejected() {
+        assert_eq!(
+            NewNewsletterIssue::parse("á".repeat(257), body("<p>Hello</p>", "Hello")).unwrap_err(),
+            NewsletterValidationError::TitleTooLong(257)
+        );
+    }
+
+    #[test]
+    fn an_empty_html_or_text_part_is_rejected() {
+        for body in [body("", "Hello"), body("<p>Hello</p>", "  "), body("", "")] {
+            assert_eq!(
+                NewNewsletterIssue::parse("Issue 1".to_string(), body).unwrap_err(),
+                NewsletterValidationError::EmptyContent
+            );
+        }
+    }
+
+    #[test]
+    fn blank_markdown_is_rejected() {
+        assert_eq!(
+            NewNewsletterIssue::parse(
+                "Issue 1".to_string(),
+                NewsletterBody::from_markdown("\n\n   \n")
+            )
+            .unwrap_err(),
+            NewsletterValidationError::EmptyContent
+        );
+    }
+
+    #[test]
+    fn content_up_to_the_limit_is_valid_and_longer_is_rejected() {
+        let html = "a".repeat(256 * 1024);
+        assert_ok!(NewNewsletterIssue::parse(
+            "Issue 1".to_string(),
+            body(&html, &html)
+        ));
+        assert_eq!(
+            NewNewsletterIssue::parse("Issue 1".to_string(), body(&html, &format!("{html}a")))
+                .unwrap_err(),
+            NewsletterValidationError::ContentTooLong(512 * 1024 + 1)
+        );
+    }
+}
@@ -1,7 +1,21 @@
 use crate::domain::SubscriberEmail;
-use crate::domain::SubscriberName;
+use crate::domain::{SubscriberName, SubscriberNameError};
+use std::net::IpAddr;
 
 pub struct NewSubscriber {
     pub email: SubscriberEmail,
     pub name: SubscriberName,
+    /// The page they subscribed on, kept as a record of their consent
+    pub consent_source: Option<String>,
+    /// The address they subscribed from, kept as a record of their consent
+    pub consent_ip: Option<IpAddr>,
+}
+
+/// Which field of a new subscriber was rejected, and why
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SubscriberValidationError {
+    #[error("Invalid name: {0}")]
+    InvalidName(#[source] SubscriberNameError),
+    #[error("Invalid email: {0}")]
+    InvalidEmail(String),
 }
@@ -0,0 +1,112 @@
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+
+/// The HTML and plain text parts of a newsletter issue
+#[derive(Debug)]
+pub struct NewsletterBody {
+    pub html: String,
+    pub text: String,
+}
+
+impl NewsletterBody {
+    /// Render `markdown` to HTML, and to plain text for clients that don't
+    /// show HTML. Raw HTML in the Markdown is sanitized, so an author can't
+    /// sneak scripts or event handlers into subscribers' inboxes.
+    pub fn from_markdown(markdown: &str) -> Self {
+        let mut unsafe_html = String::new();
+        html::push_html(
+            &mut unsafe_html,
+            Parser::new_ext(markdown, Options::empty()),
+        );
+        Self {
+            html: ammonia::clean(&unsafe_html),
+            text: to_plain_text(markdown),
+        }
+    }
+}
+
+fn to_plain_text(markdown: &str) -> String {
+    let mut text = String::new();
+    let mut links = Vec::new();
+    // Inline `<script>alert(1)</script>` arrives as text between two HTML
+    // events, that text must not end up in the email either
+    let mut in_script = false;
+    for event in Parser::new_ext(markdown, Options::empty()) {
+        match event {
+            Event::InlineHtml(tag) => {
+                let tag = tag.to_ascii_lowercase();
+                if tag.starts_with("<script") || tag.starts_with("<style") {
+                    in_script = true;
+                } else if tag.starts_with("</script") || tag.starts_with("</style") {
+                    in_script = false;
+                }
+            }
+            Event::Text(_) | Event::Code(_) if in_script => {}
+            Event::Text(s) | Event::Code(s) => text.push_str(&s),
+            Event::SoftBreak => text.push(' '),
+            Event::HardBreak => text.push('\n'),
+            Event::Start(Tag::Item) => text.push_str("- "),
+            Event::Start(Tag::Link { dest_url, .. }) => links.push(dest_url),
+            Event::End(TagEnd::Link) => {
+                if let Some(url) = links.pop() {
+                    text.push_str(&format!(" ({})", url));
+                }
+            }
+            Event::End(TagEnd::Item) => text.push('\n'),
+            Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::List(_)) => {
+                text.push_str("\n\n")
+            }
+            // Raw HTML, rules, images, ... have no plain text equivalent
+            _ => {}
+        }
+    }
+    // The last item of a list already ended its line
+    text.replace("\n\n\n", "\n\n").trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::NewsletterBody;
+
+    #[test]
+    fn headings_and_paragraphs_are_rendered() {
+        let body = NewsletterBody::from_markdown("# Issue 1\n\nHello *there*.");
+        assert_eq!(
+            body.html,
+            "<h1>Issue 1</h1>\n<p>Hello <em>there</em>.</p>\n"
+        );
+        assert_eq!(body.text, "Issue 1\n\nHello there.");
+    }
+
+    #[test]
+    fn links_keep_their_url_in_the_plain_text() {
+        let body = NewsletterBody::from_markdown("Read [the blog](https://example.com).");
+        assert!(body.html.contains(r#"<a href="https://example.com""#));
+        assert_eq!(body.text, "Read the blog (https://example.com).");
+    }
+
+    #[test]
+    fn lists_become_dashed_lines() {
+        let body = NewsletterBody::from_markdown("Topics:\n\n- Rust\n- Actix\n");
+        assert!(body
+            .html
+            .contains("<ul>\n<li>Rust</li>\n<li>Actix</li>\n</ul>"));
+        assert_eq!(body.text, "Topics:\n\n- Rust\n- Actix");
+    }
+
+    #[test]
+    fn scripts_are_removed() {
+        let body = NewsletterBody::from_markdown("Hi<script>alert('pwned')</script>");
+        assert!(!body.html.contains("<script"));
+        assert!(!body.html.contains("alert"));
+        assert_eq!(body.text, "Hi");
+    }
+
+    #[test]
+    fn event_handlers_and_javascript_links_are_removed() {
+        let body = NewsletterBody::from_markdown(
+            "<img src=\"x.png\" onerror=\"alert(1)\">\n\n[click](javascript:alert(1))",
+        );
+        assert!(!body.html.contains("onerror"));
+        assert!(!body.html.contains("javascript:"));
+    }
+}
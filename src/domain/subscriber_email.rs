@@ -4,11 +4,29 @@ use validator::validate_email;
 pub struct SubscriberEmail(String);
 
 impl SubscriberEmail {
+    /// Domains are case-insensitive, so they are lowercased to give a stable
+    /// canonical form. The local part may be case-sensitive and is kept as is.
     pub fn parse(s: String) -> Result<SubscriberEmail, String> {
-        if validate_email(&s) {
-            Ok(Self(s))
-        } else {
-            Err(format!("{} is not a valid subscriber email", s))
+        let s = s.trim();
+        if !validate_email(s) {
+            return Err(format!("{} is not a valid subscriber email", s));
+        }
+        match s.rsplit_once('@') {
+            Some((local_part, domain)) => {
+                Ok(Self(format!("{}@{}", local_part, domain.to_lowercase())))
+            }
+            None => Err(format!("{} is not a valid subscriber email", s)),
+        }
+    }
+
+    /// A masked form of the address that is safe to log, e.g. `u***@gmail.com`
+    pub fn obfuscated(&self) -> String {
+        match self.0.split_once('@') {
+            Some((local_part, domain)) => {
+                let first = local_part.chars().next().unwrap_or('*');
+                format!("{}***@{}", first, domain)
+            }
+            None => "***".to_string(),
         }
     }
 }
@@ -64,4 +82,28 @@ mod tests {
         let email = "@domain.com".to_string();
         assert_err!(SubscriberEmail::parse(email));
     }
+
+    #[test]
+    fn the_domain_is_lowercased() {
+        let email = SubscriberEmail::parse("ursula@GMail.COM".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@gmail.com");
+    }
+
+    #[test]
+    fn the_local_part_keeps_its_casing() {
+        let email = SubscriberEmail::parse("Ursula.LeGuin@Gmail.com".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "Ursula.LeGuin@gmail.com");
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_trimmed() {
+        let email = SubscriberEmail::parse("  ursula@gmail.com \n".to_string()).unwrap();
+        assert_eq!(email.as_ref(), "ursula@gmail.com");
+    }
+
+    #[test]
+    fn obfuscated_email_masks_the_local_part() {
+        let email = SubscriberEmail::parse("ursula@gmail.com".to_string()).unwrap();
+        assert_eq!(email.obfuscated(), "u***@gmail.com");
+    }
 }
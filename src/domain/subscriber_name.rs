@@ -1,20 +1,42 @@
+use unicode_normalization::UnicodeNormalization;
 use unicode_segmentation::UnicodeSegmentation;
 
+const MAX_GRAPHEMES: usize = 256;
+const FORBIDDEN_CHARACTERS: [char; 9] = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+
 #[derive(Debug)]
 pub struct SubscriberName(String);
 
-impl SubscriberName {
-    pub fn parse(s: String) -> Result<SubscriberName, String> {
-        let is_empty_or_whitespace = s.trim().is_empty();
-        let is_too_long = s.graphemes(true).count() > 256;
-        let forbidden_characters = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-        let contains_forbidden_characters = s.chars().any(|g| forbidden_characters.contains(&g));
+/// Which rule a rejected subscriber name broke
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SubscriberNameError {
+    #[error("The subscriber name must not be empty or whitespace only")]
+    Empty,
+    #[error(
+        "The subscriber name must be at most {} characters long, got {0}",
+        MAX_GRAPHEMES
+    )]
+    TooLong(usize),
+    #[error("The subscriber name must not contain {0:?}")]
+    ForbiddenCharacter(char),
+}
 
-        if is_empty_or_whitespace || is_too_long || contains_forbidden_characters {
-            Err(format!("{} is not a valid subscriber name", s))
-        } else {
-            Ok(Self(s))
+impl SubscriberName {
+    /// The name is NFC-normalized first, so visually identical names are
+    /// stored identically. Its length is counted in graphemes.
+    pub fn parse(s: String) -> Result<SubscriberName, SubscriberNameError> {
+        let s: String = s.nfc().collect();
+        if s.trim().is_empty() {
+            return Err(SubscriberNameError::Empty);
+        }
+        let graphemes = s.graphemes(true).count();
+        if graphemes > MAX_GRAPHEMES {
+            return Err(SubscriberNameError::TooLong(graphemes));
         }
+        if let Some(c) = s.chars().find(|c| FORBIDDEN_CHARACTERS.contains(c)) {
+            return Err(SubscriberNameError::ForbiddenCharacter(c));
+        }
+        Ok(Self(s))
     }
 }
 
@@ -26,7 +48,7 @@ impl AsRef<str> for SubscriberName {
 
 #[cfg(test)]
 mod tests {
-    use crate::domain::SubscriberName;
+    use crate::domain::{SubscriberName, SubscriberNameError};
     use claims::{assert_err, assert_ok};
 
     #[test]
@@ -60,6 +82,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn the_error_names_the_broken_rule() {
+        assert_eq!(
+            SubscriberName::parse("  ".to_string()).unwrap_err(),
+            SubscriberNameError::Empty
+        );
+        assert_eq!(
+            SubscriberName::parse("a".repeat(300)).unwrap_err(),
+            SubscriberNameError::TooLong(300)
+        );
+        assert_eq!(
+            SubscriberName::parse("Ursula <Le Guin>".to_string()).unwrap_err(),
+            SubscriberNameError::ForbiddenCharacter('<')
+        );
+    }
+
+    #[test]
+    fn names_are_nfc_normalized() {
+        // "e" followed by a combining acute accent
+        let decomposed = SubscriberName::parse("Rene\u{301}e".to_string()).unwrap();
+        let precomposed = SubscriberName::parse("Ren\u{e9}e".to_string()).unwrap();
+        assert_eq!(decomposed.as_ref(), precomposed.as_ref());
+        assert_eq!(decomposed.as_ref(), "Renée");
+    }
+
+    #[test]
+    fn combining_characters_count_as_part_of_their_grapheme() {
+        // Each grapheme is a base character plus two combining marks
+        let name = "a\u{301}\u{316}".repeat(256);
+        assert_ok!(SubscriberName::parse(name));
+    }
+
+    #[test]
+    fn an_oversized_multibyte_name_is_rejected_by_grapheme_count() {
+        let name = "名".repeat(257);
+        assert_eq!(
+            SubscriberName::parse(name).unwrap_err(),
+            SubscriberNameError::TooLong(257)
+        );
+        // 256 three-byte characters are still fine
+        assert_ok!(SubscriberName::parse("名".repeat(256)));
+    }
+
     #[test]
     fn a_valid_name_is_parsed_successfully() {
         let name = "Ursula Le Guin".to_string();
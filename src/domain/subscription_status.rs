@@ -0,0 +1,81 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+}
+
+impl SubscriptionStatus {
+    pub fn parse(s: String) -> Result<SubscriptionStatus, String> {
+        match s.as_str() {
+            "pending_confirmation" => Ok(Self::PendingConfirmation),
+            "confirmed" => Ok(Self::Confirmed),
+            "unsubscribed" => Ok(Self::Unsubscribed),
+            other => Err(format!("{} is not a valid subscription status", other)),
+        }
+    }
+
+    /// Whether an admin may move a subscriber from this status to `target`.
+    /// Unsubscribed subscribers can only be sent back to pending, so they
+    /// have to opt in again.
+    pub fn can_transition_to(&self, target: SubscriptionStatus) -> bool {
+        use SubscriptionStatus::*;
+        matches!(
+            (self, target),
+            (PendingConfirmation, Confirmed)
+                | (PendingConfirmation, Unsubscribed)
+                | (Confirmed, PendingConfirmation)
+                | (Confirmed, Unsubscribed)
+                | (Unsubscribed, PendingConfirmation)
+        )
+    }
+}
+
+impl AsRef<str> for SubscriptionStatus {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::PendingConfirmation => "pending_confirmation",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriptionStatus;
+    use claims::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn known_statuses_are_parsed_successfully() {
+        assert_ok_eq!(
+            SubscriptionStatus::parse("pending_confirmation".to_string()),
+            SubscriptionStatus::PendingConfirmation
+        );
+        assert_ok_eq!(
+            SubscriptionStatus::parse("confirmed".to_string()),
+            SubscriptionStatus::Confirmed
+        );
+        assert_ok_eq!(
+            SubscriptionStatus::parse("unsubscribed".to_string()),
+            SubscriptionStatus::Unsubscribed
+        );
+    }
+
+    #[test]
+    fn unknown_status_is_rejected() {
+        assert_err!(SubscriptionStatus::parse("deleted".to_string()));
+    }
+
+    #[test]
+    fn unsubscribed_subscribers_cannot_be_confirmed_directly() {
+        assert!(!SubscriptionStatus::Unsubscribed.can_transition_to(SubscriptionStatus::Confirmed));
+        assert!(SubscriptionStatus::Unsubscribed
+            .can_transition_to(SubscriptionStatus::PendingConfirmation));
+    }
+
+    #[test]
+    fn a_status_cannot_transition_to_itself() {
+        assert!(!SubscriptionStatus::Confirmed.can_transition_to(SubscriptionStatus::Confirmed));
+    }
+}
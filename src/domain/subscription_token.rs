@@ -0,0 +1,99 @@
+use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
+use rand::Rng;
+
+/// The token in a confirmation link: ASCII alphanumeric, of the configured
+/// `subscriptions.token_length`
+#[derive(Debug)]
+pub struct SubscriptionToken(String);
+
+impl SubscriptionToken {
+    /// Drawn straight from the operating system's CSPRNG
+    pub fn generate(length: usize) -> SubscriptionToken {
+        let token = std::iter::repeat_with(|| OsRng.sample(Alphanumeric))
+            .map(char::from)
+            .take(length)
+            .collect();
+        Self(token)
+    }
+
+    pub fn parse(s: String, length: usize) -> Result<SubscriptionToken, String> {
+        if s.len() != length {
+            return Err(format!(
+                "A subscription token must be {} characters long, not {}",
+                length,
+                s.len()
+            ));
+        }
+        if !s.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err("A subscription token must only contain letters and digits".into());
+        }
+        Ok(Self(s))
+    }
+}
+
+impl AsRef<str> for SubscriptionToken {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriptionToken;
+    use claims::{assert_err, assert_ok};
+    use std::collections::HashSet;
+
+    #[test]
+    fn a_25_character_alphanumeric_token_is_accepted() {
+        assert_ok!(SubscriptionToken::parse("aB3".repeat(9)[..25].into(), 25));
+    }
+
+    #[test]
+    fn a_short_alphanumeric_token_is_rejected() {
+        assert_err!(SubscriptionToken::parse("a".repeat(10), 25));
+    }
+
+    #[test]
+    fn a_long_alphanumeric_token_is_rejected() {
+        assert_err!(SubscriptionToken::parse("a".repeat(26), 25));
+    }
+
+    #[test]
+    fn a_token_with_symbols_is_rejected() {
+        assert_err!(SubscriptionToken::parse(format!("{}-", "a".repeat(24)), 25));
+    }
+
+    #[test]
+    fn an_empty_token_is_rejected() {
+        assert_err!(SubscriptionToken::parse("".into(), 25));
+    }
+
+    #[test]
+    fn a_token_with_multibyte_characters_is_rejected() {
+        // 25 bytes, but not ASCII
+        assert_err!(SubscriptionToken::parse(format!("{}é", "a".repeat(23)), 25));
+    }
+
+    #[test]
+    fn the_configured_length_is_enforced() {
+        assert_ok!(SubscriptionToken::parse("a".repeat(40), 40));
+        assert_err!(SubscriptionToken::parse("a".repeat(25), 40));
+    }
+
+    #[test]
+    fn generated_tokens_can_be_parsed() {
+        for length in [20, 25, 64] {
+            let tokens: Vec<_> = (0..1000)
+                .map(|_| SubscriptionToken::generate(length).as_ref().to_owned())
+                .collect();
+            for token in &tokens {
+                assert_ok!(SubscriptionToken::parse(token.clone(), length));
+            }
+            // Collisions or a skewed alphabet would point at a broken RNG
+            assert_eq!(tokens.iter().collect::<HashSet<_>>().len(), tokens.len());
+            let characters: HashSet<char> = tokens.iter().flat_map(|t| t.chars()).collect();
+            assert_eq!(characters.len(), 62);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! src/email_client/mod.rs
+mod null;
+mod postmark;
+mod rate_limited;
+mod smtp;
+
+pub use null::{MemoryEmailClient, NullEmailProvider, SentEmail};
+pub use postmark::{HttpClientOptions, PostmarkClient};
+pub use rate_limited::RateLimitedProvider;
+pub use smtp::SmtpClient;
+
+use crate::domain::SubscriberEmail;
+use lettre::message::Mailbox;
+use reqwest::StatusCode;
+use std::time::Duration;
+
+/// Who emails are sent from
+pub struct Sender {
+    pub email: SubscriberEmail,
+    /// Shown instead of the bare address by most email clients
+    pub name: Option<String>,
+    /// Where replies go, the sender's address if unset
+    pub reply_to: Option<SubscriberEmail>,
+}
+
+impl Sender {
+    /// `Name <email>`, quoted where needed, or just the address without a name
+    pub fn mailbox(&self) -> Result<Mailbox, EmailClientError> {
+        let address = self
+            .email
+            .as_ref()
+            .parse()
+            .map_err(|e: lettre::address::AddressError| {
+                EmailClientError::InvalidMessage(e.to_string())
+            })?;
+        Ok(Mailbox::new(self.name.clone(), address))
+    }
+}
+
+/// Sent with `List-Unsubscribe`, tells mail clients they may unsubscribe
+/// the recipient with a POST to the link, without opening it (RFC 8058)
+pub const LIST_UNSUBSCRIBE_POST: &str = "List-Unsubscribe=One-Click";
+
+/// A backend that delivers emails
+#[async_trait::async_trait]
+pub trait EmailProvider: Send + Sync {
+    /// Without `html_content` the email is sent as plain text only. With
+    /// `list_unsubscribe`, a link that unsubscribes the recipient in one
+    /// click, mail clients offer an unsubscribe button.
+    async fn send(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        list_unsubscribe: Option<&str>,
+    ) -> Result<(), EmailClientError>;
+
+    /// A cheap check that the backend can be reached, without sending
+    /// anything. Backends with nothing to reach are always up.
+    async fn probe(&self) -> Result<(), EmailClientError> {
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EmailClientError {
+    #[error("The email API did not respond in time")]
+    Timeout,
+    #[error("Failed to reach the email API")]
+    Transport(#[source] reqwest::Error),
+    #[error("The email API rejected the request with {status}: {body}")]
+    Api { status: StatusCode, body: String },
+    /// A 429, maybe with how long the API wants us to wait before trying
+    /// again
+    #[error("The email API is rate limiting us: {body}")]
+    RateLimited {
+        retry_after: Option<Duration>,
+        body: String,
+    },
+    #[error("The SMTP server failed to accept the email")]
+    Smtp(#[source] lettre::transport::smtp::Error),
+    #[error("Failed to build the email: {0}")]
+    InvalidMessage(String),
+    #[error("The SMTP server did not accept a connection")]
+    SmtpUnreachable,
+}
+
+impl EmailClientError {
+    /// Whether sending the same request again could succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Timeout => true,
+            Self::Transport(e) => e.is_connect() || e.is_request(),
+            Self::Api { status, .. } => status.is_server_error(),
+            Self::RateLimited { .. } => true,
+            // 4xx replies, timeouts and dropped connections
+            Self::Smtp(e) => !(e.is_permanent() || e.is_client() || e.is_tls()),
+            Self::SmtpUnreachable => true,
+            Self::InvalidMessage(_) => false,
+        }
+    }
+
+    /// How long the API asked us to wait before sending again, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for EmailClientError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Transport(e)
+        }
+    }
+}
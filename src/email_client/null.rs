@@ -0,0 +1,145 @@
+use super::{EmailClientError, EmailProvider};
+use crate::domain::SubscriberEmail;
+use std::sync::Mutex;
+
+/// Logs emails instead of sending them, for local development. Every email
+/// is also kept in memory, in the order they were sent.
+#[derive(Default)]
+pub struct NullEmailProvider {
+    sent: Mutex<Vec<SentEmail>>,
+}
+
+/// What tests call it when they assert on the emails sent, without an HTTP
+/// mock
+pub type MemoryEmailClient = NullEmailProvider;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentEmail {
+    pub recipient: String,
+    pub subject: String,
+    pub html_content: Option<String>,
+    pub text_content: String,
+    pub list_unsubscribe: Option<String>,
+}
+
+impl NullEmailProvider {
+    pub fn sent_emails(&self) -> Vec<SentEmail> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn sent_to(&self, recipient: &str) -> Vec<SentEmail> {
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|email| email.recipient == recipient)
+            .cloned()
+            .collect()
+    }
+
+    pub fn last_sent(&self) -> Option<SentEmail> {
+        self.sent.lock().unwrap().last().cloned()
+    }
+
+    /// Forget everything sent so far
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailProvider for NullEmailProvider {
+    async fn send(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        list_unsubscribe: Option<&str>,
+    ) -> Result<(), EmailClientError> {
+        tracing::info!(
+            subject,
+            text = text_content,
+            "Not sending an email, the null email provider is configured"
+        );
+        self.sent.lock().unwrap().push(SentEmail {
+            recipient: recipient.as_ref().to_string(),
+            subject: subject.to_string(),
+            html_content: html_content.map(str::to_string),
+            text_content: text_content.to_string(),
+            list_unsubscribe: list_unsubscribe.map(str::to_string),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailProvider, NullEmailProvider, SentEmail};
+    use claims::assert_ok;
+
+    #[tokio::test]
+    async fn sends_are_recorded_in_memory() {
+        let provider = NullEmailProvider::default();
+        let recipient = SubscriberEmail::parse("ursula@gmail.com".to_string()).unwrap();
+
+        assert_ok!(
+            provider
+                .send(&recipient, "Welcome!", Some("<p>Hi</p>"), "Hi", None)
+                .await
+        );
+        assert_ok!(
+            provider
+                .send(
+                    &recipient,
+                    "Issue #1",
+                    None,
+                    "News",
+                    Some("https://example.com/unsubscribe")
+                )
+                .await
+        );
+
+        assert_eq!(
+            provider.sent_emails(),
+            vec![
+                SentEmail {
+                    recipient: "ursula@gmail.com".into(),
+                    subject: "Welcome!".into(),
+                    html_content: Some("<p>Hi</p>".into()),
+                    text_content: "Hi".into(),
+                    list_unsubscribe: None,
+                },
+                SentEmail {
+                    recipient: "ursula@gmail.com".into(),
+                    subject: "Issue #1".into(),
+                    html_content: None,
+                    text_content: "News".into(),
+                    list_unsubscribe: Some("https://example.com/unsubscribe".into()),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_are_kept_per_recipient_in_order() {
+        let provider = NullEmailProvider::default();
+        let ursula = SubscriberEmail::parse("ursula@gmail.com".to_string()).unwrap();
+        let octavia = SubscriberEmail::parse("octavia@gmail.com".to_string()).unwrap();
+
+        assert_ok!(provider.send(&ursula, "First", None, "1", None).await);
+        assert_ok!(provider.send(&octavia, "Second", None, "2", None).await);
+        assert_ok!(provider.send(&ursula, "Third", None, "3", None).await);
+
+        let subjects: Vec<_> = provider
+            .sent_to("ursula@gmail.com")
+            .into_iter()
+            .map(|email| email.subject)
+            .collect();
+        assert_eq!(subjects, vec!["First", "Third"]);
+        assert_eq!(provider.last_sent().unwrap().subject, "Third");
+        provider.clear();
+        assert!(provider.sent_emails().is_empty());
+    }
+}
@@ -0,0 +1,674 @@
+use super::{EmailClientError, EmailProvider, Sender, LIST_UNSUBSCRIBE_POST};
+use crate::domain::SubscriberEmail;
+use crate::metrics::Metrics;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, StatusCode};
+use secrecy::{ExposeSecret, Secret};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Enough of a rejection to tell what went wrong
+const MAX_ERROR_BODY_CHARS: usize = 512;
+
+/// The longest `Retry-After` we wait out while sending. The outbox tries a
+/// failed email again later anyway, without holding anything up meanwhile.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// How the HTTP client connects to the API and keeps connections around
+#[derive(Clone, Copy, Debug)]
+pub struct HttpClientOptions {
+    /// For a whole request, from connecting to reading the response
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub pool_idle_timeout: Duration,
+    pub pool_max_idle_per_host: usize,
+}
+
+/// Sends emails through a Postmark-style JSON API
+pub struct PostmarkClient {
+    http_client: Client,
+    base_url: String,
+    sender: Sender,
+    authorization_token: Secret<String>,
+    max_retries: u32,
+    base_retry_delay: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl PostmarkClient {
+    pub fn new(
+        base_url: String,
+        sender: Sender,
+        authorization_token: Secret<String>,
+        http: HttpClientOptions,
+        max_retries: u32,
+        base_retry_delay: Duration,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let http_client = Client::builder()
+            .timeout(http.timeout)
+            .connect_timeout(http.connect_timeout)
+            .pool_idle_timeout(http.pool_idle_timeout)
+            .pool_max_idle_per_host(http.pool_max_idle_per_host)
+            .build()
+            .unwrap();
+        Self {
+            http_client,
+            base_url,
+            sender,
+            authorization_token,
+            max_retries,
+            base_retry_delay,
+            metrics,
+        }
+    }
+
+    /// `base_retry_delay * 2^(retry - 1)`, plus up to 50% jitter so retries
+    /// from concurrent requests don't line up
+    fn retry_delay(&self, retry: u32) -> Duration {
+        let backoff = self.base_retry_delay * 2u32.saturating_pow(retry - 1);
+        let jitter = rand::thread_rng().gen_range(0.0..=0.5);
+        backoff.mul_f64(1.0 + jitter)
+    }
+
+    async fn try_send_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        list_unsubscribe: Option<&str>,
+    ) -> Result<(), EmailClientError> {
+        let url = format!("{}/email", self.base_url);
+        let from = self.sender.mailbox()?.to_string();
+        let list_unsubscribe = list_unsubscribe.map(|link| format!("<{}>", link));
+        let headers = match &list_unsubscribe {
+            Some(link) => vec![
+                EmailHeader {
+                    name: "List-Unsubscribe",
+                    value: link,
+                },
+                EmailHeader {
+                    name: "List-Unsubscribe-Post",
+                    value: LIST_UNSUBSCRIBE_POST,
+                },
+            ],
+            None => Vec::new(),
+        };
+        let request_body = SendEmailRequest {
+            from: &from,
+            to: recipient.as_ref(),
+            reply_to: self.sender.reply_to.as_ref().map(|email| email.as_ref()),
+            subject,
+            html_body: html_content,
+            text_body: text_content,
+            headers,
+        };
+        let response = self
+            .http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(&request_body)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|value| parse_retry_after(value, Utc::now()));
+            // Keep the body, the provider explains what it rejected there
+            let body = redact_error_body(&response.text().await?);
+            tracing::Span::current().record("email_provider.status", status.as_u16());
+            tracing::warn!(
+                email_provider.body = %body,
+                recipient = %recipient.obfuscated(),
+                "The email provider rejected the email"
+            );
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                return Err(EmailClientError::RateLimited { retry_after, body });
+            }
+            return Err(EmailClientError::Api { status, body });
+        }
+        Ok(())
+    }
+}
+
+/// How long a `Retry-After` of either delay seconds or an HTTP-date asks us
+/// to wait. A date that has passed means right away.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// `body` cut short, with every email address in it masked. Providers like
+/// to quote the address they rejected.
+fn redact_error_body(body: &str) -> String {
+    let is_address_char = |c: char| c.is_alphanumeric() || "._%+-@".contains(c);
+    let mut redacted = String::with_capacity(body.len());
+    let mut rest = body;
+    while !rest.is_empty() {
+        let word_end = rest.find(|c| !is_address_char(c)).unwrap_or(rest.len());
+        let (word, tail) = rest.split_at(word_end);
+        match SubscriberEmail::parse(word.to_string()) {
+            Ok(email) if word.contains('@') => redacted.push_str(&email.obfuscated()),
+            _ => redacted.push_str(word),
+        }
+        let separator_end = tail.find(is_address_char).unwrap_or(tail.len());
+        redacted.push_str(&tail[..separator_end]);
+        rest = &tail[separator_end..];
+    }
+    if redacted.chars().count() > MAX_ERROR_BODY_CHARS {
+        redacted = redacted.chars().take(MAX_ERROR_BODY_CHARS).collect();
+        redacted.push('…');
+    }
+    redacted
+}
+
+#[async_trait::async_trait]
+impl EmailProvider for PostmarkClient {
+    /// Send an email, retrying connection errors, 5xx and 429 responses up
+    /// to `max_retries` times with exponential backoff. A 429's
+    /// `Retry-After` is waited for instead, unless it is longer than
+    /// `MAX_RETRY_AFTER`. Other 4xx responses are returned right away,
+    /// sending the same request again wouldn't help.
+    #[tracing::instrument(
+        name = "Send an email through the API",
+        skip_all,
+        fields(email_provider.status = tracing::field::Empty)
+    )]
+    async fn send(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        list_unsubscribe: Option<&str>,
+    ) -> Result<(), EmailClientError> {
+        let mut retries = 0;
+        loop {
+            let outcome = self
+                .try_send_email(
+                    recipient,
+                    subject,
+                    html_content,
+                    text_content,
+                    list_unsubscribe,
+                )
+                .await;
+            match outcome {
+                Err(e)
+                    if e.is_transient()
+                        && retries < self.max_retries
+                        && e.retry_after().is_none_or(|wait| wait <= MAX_RETRY_AFTER) =>
+                {
+                    retries += 1;
+                    tracing::warn!(
+                        error.cause_chain = ?e,
+                        retry = retries,
+                        "Transient failure sending an email, retrying"
+                    );
+                    let delay = e.retry_after().unwrap_or_else(|| self.retry_delay(retries));
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => {
+                    self.metrics.email_send_failures_total.inc();
+                    return Err(e);
+                }
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+
+    /// Any answer short of a 5xx means the API is there, even a 404 or 405
+    /// for a `HEAD` it doesn't route
+    async fn probe(&self) -> Result<(), EmailClientError> {
+        let response = self.http_client.head(&self.base_url).send().await?;
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(EmailClientError::Api {
+                status,
+                body: String::new(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    subject: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html_body: Option<&'a str>,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<EmailHeader<'a>>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EmailHeader<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::postmark::{
+        parse_retry_after, redact_error_body, MAX_ERROR_BODY_CHARS,
+    };
+    use crate::email_client::{
+        EmailClientError, EmailProvider, HttpClientOptions, PostmarkClient, Sender,
+    };
+    use claims::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use secrecy::Secret;
+    use wiremock::matchers::{any, body_partial_json, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+            if let Ok(body) = result {
+                dbg!(&body);
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    fn email_client(base_url: String) -> PostmarkClient {
+        email_client_with_retries(base_url, 0)
+    }
+
+    fn email_client_with_retries(base_url: String, max_retries: u32) -> PostmarkClient {
+        email_client_from(
+            base_url,
+            Sender {
+                email: email(),
+                name: None,
+                reply_to: None,
+            },
+            max_retries,
+        )
+    }
+
+    fn http_options() -> HttpClientOptions {
+        HttpClientOptions {
+            timeout: std::time::Duration::from_millis(200),
+            connect_timeout: std::time::Duration::from_millis(100),
+            pool_idle_timeout: std::time::Duration::from_secs(90),
+            pool_max_idle_per_host: 10,
+        }
+    }
+
+    fn email_client_from(base_url: String, sender: Sender, max_retries: u32) -> PostmarkClient {
+        PostmarkClient::new(
+            base_url,
+            sender,
+            Secret::new(Faker.fake()),
+            http_options(),
+            max_retries,
+            std::time::Duration::from_millis(10),
+            Default::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_mail_sends_the_expected_request() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let _ = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn send_mail_succeeds_if_the_server_returns_200() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let outcome = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_mail_fails_if_the_server_returns_500() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let outcome = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let outcome = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+
+        assert!(matches!(outcome, Err(EmailClientError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn a_client_with_a_custom_connection_pool_sends_emails() {
+        let mock_server = MockServer::start().await;
+        let email_client = PostmarkClient::new(
+            mock_server.uri(),
+            Sender {
+                email: email(),
+                name: None,
+                reply_to: None,
+            },
+            Secret::new(Faker.fake()),
+            HttpClientOptions {
+                timeout: std::time::Duration::from_secs(1),
+                connect_timeout: std::time::Duration::from_millis(50),
+                pool_idle_timeout: std::time::Duration::from_secs(1),
+                pool_max_idle_per_host: 1,
+            },
+            0,
+            std::time::Duration::from_millis(10),
+            Default::default(),
+        );
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        for _ in 0..2 {
+            let outcome = email_client
+                .send(&email(), &subject(), Some(&content()), &content(), None)
+                .await;
+            assert_ok!(outcome);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_5xx_responses_until_it_succeeds() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), 3);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let outcome = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_4xx_responses() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), 3);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let outcome = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn a_429_is_retried_once_its_retry_after_has_passed() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), 3);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "2"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let started = std::time::Instant::now();
+        let outcome = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+
+        assert_ok!(outcome);
+        let waited = started.elapsed();
+        assert!(
+            waited >= std::time::Duration::from_secs(2)
+                && waited < std::time::Duration::from_secs(4),
+            "waited {:?}",
+            waited
+        );
+    }
+
+    #[tokio::test]
+    async fn a_429_asking_for_too_long_a_wait_is_not_retried() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), 3);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let outcome = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+
+        match outcome {
+            Err(EmailClientError::RateLimited { retry_after, .. }) => {
+                assert_eq!(retry_after, Some(std::time::Duration::from_secs(3600)));
+            }
+            other => panic!("Expected a RateLimited error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_an_http_date() {
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(std::time::Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:05 GMT", now),
+            Some(std::time::Duration::from_secs(5))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(std::time::Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    #[tokio::test]
+    async fn a_4xx_response_yields_an_api_error_with_the_response_body() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422).set_body_string("Invalid 'To' address"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let outcome = email_client
+            .send(&email(), &subject(), Some(&content()), &content(), None)
+            .await;
+
+        match outcome {
+            Err(EmailClientError::Api { status, body }) => {
+                assert_eq!(status.as_u16(), 422);
+                assert_eq!(body, "Invalid 'To' address");
+            }
+            other => panic!("Expected an Api error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn email_addresses_in_an_error_body_are_masked() {
+        assert_eq!(
+            redact_error_body(r#"{"Message": "Invalid 'To' address: 'ursula@gmail.com'."}"#),
+            r#"{"Message": "Invalid 'To' address: 'u***@gmail.com'."}"#
+        );
+    }
+
+    #[test]
+    fn a_long_error_body_is_truncated() {
+        let redacted = redact_error_body(&"a".repeat(2048));
+        assert_eq!(redacted.chars().count(), MAX_ERROR_BODY_CHARS + 1);
+        assert!(redacted.ends_with('…'));
+    }
+
+    #[tokio::test]
+    async fn a_list_unsubscribe_link_is_sent_as_one_click_headers() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(body_partial_json(serde_json::json!({
+            "Headers": [
+                {
+                    "Name": "List-Unsubscribe",
+                    "Value": "<https://example.com/unsubscribe?unsubscribe_token=abc>"
+                },
+                {
+                    "Name": "List-Unsubscribe-Post",
+                    "Value": "List-Unsubscribe=One-Click"
+                }
+            ]
+        })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+        let outcome = email_client
+            .send(
+                &email(),
+                &subject(),
+                None,
+                &content(),
+                Some("https://example.com/unsubscribe?unsubscribe_token=abc"),
+            )
+            .await;
+
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn the_reply_to_address_is_sent_if_configured() {
+        let mock_server = MockServer::start().await;
+        let sender = Sender {
+            email: email(),
+            name: None,
+            reply_to: Some(SubscriberEmail::parse("replies@example.com".into()).unwrap()),
+        };
+        let email_client = email_client_from(mock_server.uri(), sender, 0);
+        Mock::given(body_partial_json(
+            serde_json::json!({ "ReplyTo": "replies@example.com" }),
+        ))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+        let outcome = email_client
+            .send(&email(), &subject(), None, &content(), None)
+            .await;
+
+        assert_ok!(outcome);
+    }
+}
@@ -0,0 +1,105 @@
+use super::{EmailClientError, EmailProvider};
+use crate::domain::SubscriberEmail;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Spaces out the sends of another provider, so that together confirmation
+/// emails, the outbox and newsletter deliveries stay under the provider's
+/// per-second cap. A token bucket holding a single token: sends beyond the
+/// rate wait for their turn instead of being rejected.
+pub struct RateLimitedProvider {
+    inner: Arc<dyn EmailProvider>,
+    interval: Duration,
+    /// When the next send may start
+    next_slot: Mutex<Instant>,
+}
+
+impl RateLimitedProvider {
+    pub fn new(inner: Arc<dyn EmailProvider>, max_sends_per_second: u32) -> Self {
+        Self {
+            inner,
+            interval: Duration::from_secs(1) / max_sends_per_second,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserve the earliest free slot, then wait for it
+    async fn wait_for_slot(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailProvider for RateLimitedProvider {
+    async fn send(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        list_unsubscribe: Option<&str>,
+    ) -> Result<(), EmailClientError> {
+        self.wait_for_slot().await;
+        self.inner
+            .send(
+                recipient,
+                subject,
+                html_content,
+                text_content,
+                list_unsubscribe,
+            )
+            .await
+    }
+
+    async fn probe(&self) -> Result<(), EmailClientError> {
+        self.inner.probe().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailProvider, NullEmailProvider, RateLimitedProvider};
+    use claims::assert_ok;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn a_burst_of_sends_is_spread_over_the_rate() {
+        let inner = Arc::new(NullEmailProvider::default());
+        let provider = Arc::new(RateLimitedProvider::new(inner.clone(), 20));
+        let recipient = SubscriberEmail::parse("ursula@gmail.com".to_string()).unwrap();
+        let started = Instant::now();
+
+        let sends = (0..5).map(|_| {
+            let provider = provider.clone();
+            let recipient = &recipient;
+            async move { provider.send(recipient, "Hi", None, "Hi", None).await }
+        });
+        for outcome in futures::future::join_all(sends).await {
+            assert_ok!(outcome);
+        }
+
+        // The first goes out right away, each of the others 50ms after the last
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(inner.sent_emails().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn sends_within_the_rate_are_not_delayed() {
+        let provider = RateLimitedProvider::new(Arc::new(NullEmailProvider::default()), 1);
+        let recipient = SubscriberEmail::parse("ursula@gmail.com".to_string()).unwrap();
+        let started = Instant::now();
+
+        assert_ok!(provider.send(&recipient, "Hi", None, "Hi", None).await);
+
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
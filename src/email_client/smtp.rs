@@ -0,0 +1,152 @@
+use super::{EmailClientError, EmailProvider, Sender, LIST_UNSUBSCRIBE_POST};
+use crate::configuration::SmtpSettings;
+use crate::domain::SubscriberEmail;
+use crate::metrics::Metrics;
+use lettre::message::header::{ContentType, Header, HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use secrecy::ExposeSecret;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Sends emails through an SMTP relay
+pub struct SmtpClient {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    sender: Sender,
+    metrics: Arc<Metrics>,
+}
+
+impl SmtpClient {
+    pub fn new(
+        settings: &SmtpSettings,
+        sender: Sender,
+        timeout: Duration,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, lettre::transport::smtp::Error> {
+        let mut builder = if settings.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.host)?
+        } else {
+            // Plaintext, only meant for a local test server
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&settings.host)
+        };
+        builder = builder.port(settings.port).timeout(Some(timeout));
+        if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                password.expose_secret().clone(),
+            ));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            sender,
+            metrics,
+        })
+    }
+
+    fn message(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        list_unsubscribe: Option<&str>,
+    ) -> Result<Message, EmailClientError> {
+        let parse_mailbox = |email: &SubscriberEmail| {
+            email
+                .as_ref()
+                .parse::<Mailbox>()
+                .map_err(|e| EmailClientError::InvalidMessage(e.to_string()))
+        };
+        let mut builder = Message::builder()
+            .from(self.sender.mailbox()?)
+            .to(parse_mailbox(recipient)?)
+            .subject(subject);
+        if let Some(reply_to) = &self.sender.reply_to {
+            builder = builder.reply_to(parse_mailbox(reply_to)?);
+        }
+        if let Some(link) = list_unsubscribe {
+            builder = builder
+                .header(ListUnsubscribe(format!("<{}>", link)))
+                .header(ListUnsubscribePost);
+        }
+        let message = match html_content {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(
+                text_content.to_string(),
+                html.to_string(),
+            )),
+            None => builder
+                .header(ContentType::TEXT_PLAIN)
+                .body(text_content.to_string()),
+        };
+        message.map_err(|e| EmailClientError::InvalidMessage(e.to_string()))
+    }
+}
+
+#[derive(Clone)]
+struct ListUnsubscribe(String);
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.into()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+#[derive(Clone)]
+struct ListUnsubscribePost;
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    }
+
+    fn parse(_: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self)
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), LIST_UNSUBSCRIBE_POST.to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailProvider for SmtpClient {
+    async fn send(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: Option<&str>,
+        text_content: &str,
+        list_unsubscribe: Option<&str>,
+    ) -> Result<(), EmailClientError> {
+        let message = self.message(
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            list_unsubscribe,
+        )?;
+        if let Err(e) = self.transport.send(message).await {
+            self.metrics.email_send_failures_total.inc();
+            return Err(EmailClientError::Smtp(e));
+        }
+        Ok(())
+    }
+
+    /// Connects and says hello, then hangs up
+    async fn probe(&self) -> Result<(), EmailClientError> {
+        match self.transport.test_connection().await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(EmailClientError::SmtpUnreachable),
+            Err(e) => Err(EmailClientError::Smtp(e)),
+        }
+    }
+}
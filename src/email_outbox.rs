@@ -0,0 +1,329 @@
+//! src/email_outbox.rs
+use crate::configuration::EmailOutboxSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailProvider;
+use crate::metrics::Metrics;
+use crate::telemetry::RequestId;
+use chrono::Utc;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// What an email in the outbox is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailKind {
+    /// Asks a subscriber to confirm, counted in the metrics
+    Confirmation,
+    PasswordReset,
+}
+
+impl AsRef<str> for EmailKind {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Confirmation => "confirmation",
+            Self::PasswordReset => "password_reset",
+        }
+    }
+}
+
+/// An email waiting in the outbox
+pub struct EmailContent {
+    pub kind: EmailKind,
+    pub subject: String,
+    /// Plain text only if unset
+    pub html_body: Option<String>,
+    pub text_body: String,
+    /// Sent as `List-Unsubscribe`, for emails to subscribers
+    pub list_unsubscribe: Option<String>,
+}
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// Queue an email for the outbox worker. It is only sent once `transaction`
+/// commits, and is never lost if the email API is down at that moment.
+/// `correlation_id` is the request that queued it, so a failed send can be
+/// traced back to it.
+#[tracing::instrument(name = "Enqueue an email", skip_all)]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    content: &EmailContent,
+    correlation_id: Option<&RequestId>,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO email_outbox
+            (id, kind, recipient, subject, html_body, text_body, list_unsubscribe,
+            correlation_id, created_at, next_attempt_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9)
+        "#,
+        Uuid::new_v4(),
+        content.kind.as_ref(),
+        recipient.as_ref(),
+        content.subject,
+        content.html_body,
+        content.text_body,
+        content.list_unsubscribe,
+        correlation_id.map(|id| id.to_string()),
+        now
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// `enqueue_email` for many emails in one statement
+#[tracing::instrument(name = "Enqueue emails", skip_all, fields(emails = emails.len()))]
+pub async fn enqueue_emails(
+    transaction: &mut Transaction<'_, Postgres>,
+    emails: &[(&SubscriberEmail, EmailContent)],
+    correlation_id: Option<&RequestId>,
+) -> Result<(), sqlx::Error> {
+    let ids: Vec<Uuid> = emails.iter().map(|_| Uuid::new_v4()).collect();
+    let kinds: Vec<&str> = emails.iter().map(|(_, c)| c.kind.as_ref()).collect();
+    let recipients: Vec<&str> = emails.iter().map(|(r, _)| r.as_ref()).collect();
+    let subjects: Vec<&str> = emails.iter().map(|(_, c)| c.subject.as_str()).collect();
+    let html_bodies: Vec<Option<&str>> =
+        emails.iter().map(|(_, c)| c.html_body.as_deref()).collect();
+    let text_bodies: Vec<&str> = emails.iter().map(|(_, c)| c.text_body.as_str()).collect();
+    let list_unsubscribes: Vec<Option<&str>> = emails
+        .iter()
+        .map(|(_, c)| c.list_unsubscribe.as_deref())
+        .collect();
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO email_outbox
+            (id, kind, recipient, subject, html_body, text_body, list_unsubscribe,
+            correlation_id, created_at, next_attempt_at)
+        SELECT *, $8, $9, $9
+        FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[])
+        "#,
+        &ids,
+        &kinds as &[&str],
+        &recipients as &[&str],
+        &subjects as &[&str],
+        &html_bodies as &[Option<&str>],
+        &text_bodies as &[&str],
+        &list_unsubscribes as &[Option<&str>],
+        correlation_id.map(|id| id.to_string()),
+        Utc::now()
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// Deliver pending emails until `stop` turns true or its sender is dropped.
+/// An email that is being sent at that point is still finished.
+pub async fn run_worker_until_stopped(
+    pool: PgPool,
+    email_client: Arc<dyn EmailProvider>,
+    metrics: Arc<Metrics>,
+    settings: EmailOutboxSettings,
+    mut stop: watch::Receiver<bool>,
+) {
+    while !*stop.borrow() {
+        let idle = match try_execute_task(&pool, &*email_client, &metrics, &settings).await {
+            Ok(ExecutionOutcome::TaskCompleted) => false,
+            Ok(ExecutionOutcome::EmptyQueue) => true,
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, "Failed to process the email outbox");
+                true
+            }
+        };
+        if idle {
+            tokio::select! {
+                _ = tokio::time::sleep(settings.poll_interval()) => {}
+                changed = stop.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Try to deliver the oldest email that is due. A failed send is tried again
+/// later with exponential backoff, until `max_attempts` is reached or the
+/// email API rejects it for good.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        email_id = tracing::field::Empty,
+        correlation_id = tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailProvider,
+    metrics: &Metrics,
+    settings: &EmailOutboxSettings,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let Some((mut transaction, email)) = dequeue_email(pool).await? else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    let span = tracing::Span::current();
+    span.record("email_id", tracing::field::display(email.id));
+    if let Some(correlation_id) = &email.correlation_id {
+        span.record("correlation_id", tracing::field::display(correlation_id));
+    }
+
+    let recipient = SubscriberEmail::parse(email.recipient.clone());
+    let outcome = match &recipient {
+        Ok(recipient) => email_client
+            .send(
+                recipient,
+                &email.subject,
+                email.html_body.as_deref(),
+                &email.text_body,
+                email.list_unsubscribe.as_deref(),
+            )
+            .await
+            .map_err(|e| (e.is_transient(), e.to_string())),
+        Err(e) => Err((false, e.clone())),
+    };
+
+    match outcome {
+        Ok(()) => {
+            mark_as_delivered(&mut transaction, email.id).await?;
+            metrics.emails_sent_total.inc();
+            if email.kind == EmailKind::Confirmation.as_ref() {
+                metrics.confirmation_emails_sent_total.inc();
+            }
+        }
+        Err((transient, error)) => {
+            let attempts = email.attempts + 1;
+            let give_up = !transient || attempts >= i32::try_from(settings.max_attempts)?;
+            // Only logged when a send fails, like the email client does
+            let recipient = recipient
+                .as_ref()
+                .map_or_else(|_| "(invalid)".to_string(), SubscriberEmail::obfuscated);
+            if give_up {
+                tracing::error!(attempts, %error, %recipient, "Giving up delivering an email");
+            } else {
+                tracing::warn!(
+                    attempts,
+                    %error,
+                    %recipient,
+                    "Failed to deliver an email, retrying later"
+                );
+            }
+            let next_attempt_in = retry_delay(settings.base_retry_delay(), attempts);
+            record_failed_attempt(
+                &mut transaction,
+                email.id,
+                &error,
+                chrono::Duration::from_std(next_attempt_in)?,
+                give_up,
+            )
+            .await?;
+        }
+    }
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// `base_retry_delay * 2^(attempts - 1)`
+fn retry_delay(base_retry_delay: Duration, attempts: i32) -> Duration {
+    let exponent = u32::try_from(attempts.saturating_sub(1)).unwrap_or(0);
+    base_retry_delay.saturating_mul(2u32.saturating_pow(exponent))
+}
+
+struct OutboxEmail {
+    id: Uuid,
+    kind: String,
+    recipient: String,
+    subject: String,
+    html_body: Option<String>,
+    text_body: String,
+    list_unsubscribe: Option<String>,
+    correlation_id: Option<String>,
+    attempts: i32,
+}
+
+/// Lock the oldest due email. Concurrent workers skip locked rows, so each
+/// email is only picked up once.
+async fn dequeue_email(
+    pool: &PgPool,
+) -> Result<Option<(Transaction<'static, Postgres>, OutboxEmail)>, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let email = sqlx::query_as!(
+        OutboxEmail,
+        r#"
+        SELECT id, kind, recipient, subject, html_body, text_body, list_unsubscribe, correlation_id,
+            attempts
+        FROM email_outbox
+        WHERE delivered_at IS NULL AND failed_at IS NULL AND next_attempt_at <= now()
+        ORDER BY next_attempt_at
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    Ok(email.map(|email| (transaction, email)))
+}
+
+async fn mark_as_delivered(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET attempts = attempts + 1, delivered_at = $2
+        WHERE id = $1
+        "#,
+        email_id,
+        Utc::now()
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+async fn record_failed_attempt(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_id: Uuid,
+    error: &str,
+    next_attempt_in: chrono::Duration,
+    give_up: bool,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let query = sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET attempts = attempts + 1,
+            last_error = $2,
+            next_attempt_at = $3,
+            failed_at = $4
+        WHERE id = $1
+        "#,
+        email_id,
+        error,
+        now + next_attempt_in,
+        give_up.then_some(now)
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::email_outbox::retry_delay;
+    use std::time::Duration;
+
+    #[test]
+    fn the_retry_delay_doubles_with_every_attempt() {
+        let base = Duration::from_secs(30);
+        assert_eq!(retry_delay(base, 1), Duration::from_secs(30));
+        assert_eq!(retry_delay(base, 2), Duration::from_secs(60));
+        assert_eq!(retry_delay(base, 4), Duration::from_secs(240));
+    }
+}
@@ -0,0 +1,254 @@
+use actix_web::http::header::HeaderMap;
+use std::net::IpAddr;
+
+/// The external base URL a reverse proxy says it was reached on, taken from
+/// the first hop of `Forwarded` or else from `X-Forwarded-Proto` and
+/// `X-Forwarded-Host`. `None` without a usable scheme and host, or if they
+/// don't make a plain `scheme://host[:port]` URL.
+pub fn forwarded_base_url(headers: &HeaderMap) -> Option<String> {
+    let (proto, host) = match header(headers, "Forwarded") {
+        Some(forwarded) => from_forwarded(forwarded),
+        None => (
+            header(headers, "X-Forwarded-Proto").and_then(first_value),
+            header(headers, "X-Forwarded-Host").and_then(first_value),
+        ),
+    };
+    let base_url = format!("{}://{}", proto?, host?);
+    is_plain_base_url(&base_url).then_some(base_url)
+}
+
+/// The client a reverse proxy says it forwarded the request for: going
+/// through the hops of `Forwarded`, or else of `X-Forwarded-For`, from the
+/// right, the first one that isn't one of `trusted_proxies`. Hops further
+/// left were sent by the client itself and can be anything. `None` unless
+/// that hop is an IP address, e.g. for an obfuscated `for=_hidden`.
+pub fn forwarded_client_ip(headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let hops: Vec<Option<String>> = match all_values(headers, "Forwarded") {
+        Some(forwarded) => forwarded
+            .split(',')
+            .map(|element| element_param(element, "for"))
+            .collect(),
+        None => all_values(headers, "X-Forwarded-For")?
+            .split(',')
+            .map(|hop| Some(hop.trim().to_string()))
+            .collect(),
+    };
+    for hop in hops.iter().rev() {
+        let ip = parse_node(hop.as_deref()?)?;
+        if !trusted_proxies.contains(&ip) {
+            return Some(ip);
+        }
+    }
+    None
+}
+
+/// A `for=` node: an IPv4 address, maybe with a port, or an IPv6 address
+/// in brackets when it has one, e.g. `[2001:db8::17]:4711`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok();
+    }
+    let (ip, _port) = node.split_once(':')?;
+    ip.parse().ok()
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|h| h.to_str().ok())
+}
+
+/// A proxy may add its own header line rather than append to the last one
+fn all_values(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .filter_map(|h| h.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(","))
+}
+
+/// Proxies append themselves, the client-facing one comes first
+fn first_value(value: &str) -> Option<String> {
+    let first = value.split(',').next()?.trim();
+    (!first.is_empty()).then(|| first.to_string())
+}
+
+/// `proto` and `host` of the first element, e.g. of
+/// `for=192.0.2.60;proto=https;host="example.com", for=198.51.100.17`
+fn from_forwarded(forwarded: &str) -> (Option<String>, Option<String>) {
+    (
+        first_element_param(forwarded, "proto"),
+        first_element_param(forwarded, "host"),
+    )
+}
+
+fn first_element_param(forwarded: &str, param: &str) -> Option<String> {
+    element_param(forwarded.split(',').next().unwrap_or_default(), param)
+}
+
+fn element_param(element: &str, param: &str) -> Option<String> {
+    element.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case(param)
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Nothing but an http(s) scheme, a host and maybe a port, so a header
+/// can't sneak a path, credentials or a query into our links
+fn is_plain_base_url(base_url: &str) -> bool {
+    let Ok(url) = url::Url::parse(base_url) else {
+        return false;
+    };
+    matches!(url.scheme(), "http" | "https")
+        && url.host_str().is_some_and(|host| !host.is_empty())
+        && url.username().is_empty()
+        && url.password().is_none()
+        && url.path() == "/"
+        && url.query().is_none()
+        && url.fragment().is_none()
+        && !base_url.ends_with('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::forwarded::{forwarded_base_url, forwarded_client_ip};
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use std::net::IpAddr;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn the_forwarded_header_is_used_first() {
+        let headers = headers(&[
+            (
+                "forwarded",
+                r#"for=192.0.2.60;proto=https;host="news.example.com", for=10.0.0.1;proto=http"#,
+            ),
+            ("x-forwarded-host", "other.example.com"),
+        ]);
+        assert_eq!(
+            forwarded_base_url(&headers).as_deref(),
+            Some("https://news.example.com")
+        );
+    }
+
+    #[test]
+    fn x_forwarded_headers_are_used_without_forwarded() {
+        let headers = headers(&[
+            ("x-forwarded-proto", "https, http"),
+            ("x-forwarded-host", "news.example.com:8443, 10.0.0.1"),
+        ]);
+        assert_eq!(
+            forwarded_base_url(&headers).as_deref(),
+            Some("https://news.example.com:8443")
+        );
+    }
+
+    #[test]
+    fn a_scheme_and_a_host_are_both_needed() {
+        assert_eq!(
+            forwarded_base_url(&headers(&[("x-forwarded-host", "news.example.com")])),
+            None
+        );
+        assert_eq!(
+            forwarded_base_url(&headers(&[("forwarded", "proto=https")])),
+            None
+        );
+        assert_eq!(forwarded_base_url(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn anything_but_a_plain_base_url_is_rejected() {
+        for (proto, host) in [
+            ("javascript", "news.example.com"),
+            ("https", "news.example.com/phishing"),
+            ("https", "user@news.example.com"),
+            ("https", "news.example.com?next=evil"),
+            ("https", "news example.com"),
+        ] {
+            let headers = headers(&[("x-forwarded-proto", proto), ("x-forwarded-host", host)]);
+            assert_eq!(forwarded_base_url(&headers), None, "{}://{}", proto, host);
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn the_client_ip_is_the_right_most_hop_that_isnt_a_trusted_proxy() {
+        let trusted = [ip("10.0.0.1")];
+        let cases = [
+            (
+                "forwarded",
+                r#"for=192.0.2.60;proto=https, for=10.0.0.1"#,
+                "192.0.2.60",
+            ),
+            ("forwarded", r#"for="192.0.2.60:4711""#, "192.0.2.60"),
+            (
+                "forwarded",
+                r#"For="[2001:db8:cafe::17]:4711""#,
+                "2001:db8:cafe::17",
+            ),
+            ("x-forwarded-for", "203.0.113.7, 10.0.0.1", "203.0.113.7"),
+            ("x-forwarded-for", "2001:db8::1", "2001:db8::1"),
+            (
+                "x-forwarded-for",
+                "198.51.100.99, 203.0.113.7, 10.0.0.1",
+                "203.0.113.7",
+            ),
+        ];
+        for (name, value, client) in cases {
+            assert_eq!(
+                forwarded_client_ip(&headers(&[(name, value)]), &trusted),
+                Some(ip(client)),
+                "{}: {}",
+                name,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn hops_the_client_sent_itself_are_ignored() {
+        let spoofed = headers(&[("x-forwarded-for", "198.51.100.99, 203.0.113.7")]);
+        assert_eq!(forwarded_client_ip(&spoofed, &[]), Some(ip("203.0.113.7")));
+
+        let split = headers(&[
+            ("x-forwarded-for", "198.51.100.99"),
+            ("x-forwarded-for", "203.0.113.7"),
+        ]);
+        assert_eq!(forwarded_client_ip(&split, &[]), Some(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn a_client_that_isnt_an_ip_address_is_ignored() {
+        for (name, value) in [
+            ("forwarded", "for=_hidden;proto=https"),
+            ("x-forwarded-for", "unknown"),
+            ("x-forwarded-for", "203.0.113.7, unknown"),
+            ("x-forwarded-for", "10.0.0.1"),
+        ] {
+            assert_eq!(
+                forwarded_client_ip(&headers(&[(name, value)]), &[ip("10.0.0.1")]),
+                None,
+                "{}: {}",
+                name,
+                value
+            );
+        }
+        assert_eq!(forwarded_client_ip(&HeaderMap::new(), &[]), None);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::configuration::IdempotencySettings;
+use chrono::Utc;
+use sqlx::PgPool;
+
+/// Every `cleanup_interval`, delete the keys older than the TTL, until the
+/// pool is closed
+pub async fn clean_up_expired_keys(pool: PgPool, settings: IdempotencySettings) {
+    let mut ticker = tokio::time::interval(settings.cleanup_interval());
+    let mut closed = pool.close_event();
+    while closed.do_until(ticker.tick()).await.is_ok() {
+        if let Err(e) = delete_expired_keys(&pool, settings.ttl()).await {
+            tracing::error!(error.cause_chain = ?e, "Failed to delete expired idempotency keys");
+        }
+    }
+}
+
+/// Delete the keys created more than `ttl` ago, returning how many
+#[tracing::instrument(name = "Delete expired idempotency keys", skip(pool))]
+pub async fn delete_expired_keys(pool: &PgPool, ttl: chrono::Duration) -> Result<u64, sqlx::Error> {
+    let purged = sqlx::query!(
+        r#"DELETE FROM idempotency WHERE created_at < $1"#,
+        Utc::now() - ttl
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    tracing::info!(purged, "Deleted expired idempotency keys");
+    Ok(purged)
+}
@@ -0,0 +1,8 @@
+//! src/idempotency/mod.rs
+mod cleanup;
+mod key;
+mod persistence;
+
+pub use cleanup::{clean_up_expired_keys, delete_expired_keys};
+pub use key::IdempotencyKey;
+pub use persistence::{save_response, try_processing, NextAction};
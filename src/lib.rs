@@ -1,10 +1,28 @@
 //! src/lib.rs
+pub mod authentication;
+pub mod body_limit;
 pub mod configuration;
+pub mod confirmation_webhook;
+pub mod deprecation;
+pub mod forwarded;
+pub mod idempotency;
+pub mod locale;
+pub mod mail_domain;
+pub mod metrics;
+pub mod problem_details;
+pub mod rate_limit;
+pub mod request_timeout;
+pub mod retry;
 pub mod routes;
+pub mod session_state;
+pub mod shutdown;
+pub mod signed_token;
 pub mod startup;
+pub mod subscription_events;
 
 pub mod domain;
 
 pub mod telemetry;
 
 pub mod email_client;
+pub mod email_outbox;
@@ -0,0 +1,91 @@
+use actix_web::http::header::HeaderMap;
+
+/// The locale a subscriber asked for, from the form if it has one and else
+/// the most preferred language of `Accept-Language`. Always lowercase.
+pub fn requested_locale(form_locale: Option<&str>, headers: &HeaderMap) -> Option<String> {
+    if let Some(locale) = form_locale.and_then(parse_locale) {
+        return Some(locale);
+    }
+    let accept_language = headers.get("Accept-Language")?.to_str().ok()?;
+    preferred_language(accept_language)
+}
+
+/// The tag with the highest weight, e.g. `fr-ch` of `de;q=0.7, fr-CH, en;q=0.8`.
+/// Ties go to the one listed first.
+fn preferred_language(accept_language: &str) -> Option<String> {
+    let mut preferred: Option<(String, f32)> = None;
+    for range in accept_language.split(',') {
+        let mut parts = range.split(';');
+        let Some(locale) = parts.next().and_then(parse_locale) else {
+            continue;
+        };
+        let weight = parts
+            .find_map(|part| part.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+        let Some(weight) = weight.filter(|weight| *weight > 0.0) else {
+            continue;
+        };
+        if preferred.as_ref().is_none_or(|(_, best)| weight > *best) {
+            preferred = Some((locale, weight));
+        }
+    }
+    preferred.map(|(locale, _)| locale)
+}
+
+/// Letters, digits and dashes only, as a locale ends up in a template name
+fn parse_locale(locale: &str) -> Option<String> {
+    let locale = locale.trim();
+    let is_valid = !locale.is_empty()
+        && locale.len() <= 35
+        && locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !locale.starts_with('-');
+    is_valid.then(|| locale.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::locale::requested_locale;
+    use actix_web::http::header::{HeaderMap, HeaderValue};
+
+    fn accept_language(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::ACCEPT_LANGUAGE,
+            HeaderValue::from_static(value),
+        );
+        headers
+    }
+
+    #[test]
+    fn the_form_locale_is_used_first() {
+        let headers = accept_language("de");
+        assert_eq!(
+            requested_locale(Some("fr-CA"), &headers).as_deref(),
+            Some("fr-ca")
+        );
+    }
+
+    #[test]
+    fn the_most_preferred_language_is_used_without_a_form_locale() {
+        let headers = accept_language("de;q=0.7, fr-CH, en;q=0.8, *;q=0.5");
+        assert_eq!(requested_locale(None, &headers).as_deref(), Some("fr-ch"));
+        let headers = accept_language("de;q=0.7, en;q=0.8");
+        assert_eq!(requested_locale(None, &headers).as_deref(), Some("en"));
+    }
+
+    #[test]
+    fn anything_that_isnt_a_plain_locale_is_ignored() {
+        for locale in ["", "../secret", "fr.html", "*", "-fr"] {
+            assert_eq!(
+                requested_locale(Some(locale), &HeaderMap::new()),
+                None,
+                "{}",
+                locale
+            );
+        }
+        let headers = accept_language("fr;q=0, ../x, de;q=abc");
+        assert_eq!(requested_locale(None, &headers), None);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::domain::SubscriberEmail;
+use std::time::Duration;
+
+/// Don't let a slow resolver hold up a subscription
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Looks up whether a domain could receive mail at all
+#[async_trait::async_trait]
+pub trait MailDomainResolver: Send + Sync {
+    /// Whether `domain` has a host mail can be delivered to
+    async fn has_mail_host(&self, domain: &str) -> bool;
+}
+
+/// Asks the system resolver. That only knows A and AAAA records, which
+/// mail falls back to for domains without an MX record, so a domain with
+/// nothing but an MX record is turned away as well.
+pub struct SystemResolver;
+
+#[async_trait::async_trait]
+impl MailDomainResolver for SystemResolver {
+    async fn has_mail_host(&self, domain: &str) -> bool {
+        match tokio::net::lookup_host((domain, 25)).await {
+            Ok(mut addresses) => addresses.next().is_some(),
+            Err(e) => {
+                tracing::info!(error.cause_chain = ?e, domain, "The email domain doesn't resolve");
+                false
+            }
+        }
+    }
+}
+
+/// Reject `email` if its domain has no host to deliver to. A lookup that
+/// times out lets it through, a resolver outage shouldn't stop subscriptions.
+pub async fn check_mail_domain(
+    resolver: &dyn MailDomainResolver,
+    email: &SubscriberEmail,
+) -> Result<(), String> {
+    let domain = email
+        .as_ref()
+        .rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .unwrap_or_default();
+    match tokio::time::timeout(LOOKUP_TIMEOUT, resolver.has_mail_host(domain)).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("The email domain {} can't receive email", domain)),
+        Err(_) => {
+            tracing::warn!(domain, "Timed out looking up the email domain");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::mail_domain::{check_mail_domain, MailDomainResolver};
+    use claims::{assert_err, assert_ok};
+
+    /// Knows one domain with a mail host, and hangs for `hang.example.com`
+    struct StubResolver;
+
+    #[async_trait::async_trait]
+    impl MailDomainResolver for StubResolver {
+        async fn has_mail_host(&self, domain: &str) -> bool {
+            if domain == "hang.example.com" {
+                std::future::pending::<()>().await;
+            }
+            domain == "gmail.com"
+        }
+    }
+
+    fn email(email: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(email.into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_domain_with_a_mail_host_is_accepted() {
+        assert_ok!(check_mail_domain(&StubResolver, &email("ursula@gmail.com")).await);
+    }
+
+    #[tokio::test]
+    async fn a_domain_without_a_mail_host_is_rejected() {
+        let error = assert_err!(check_mail_domain(&StubResolver, &email("ursula@gmailc.om")).await);
+        assert_eq!(error, "The email domain gmailc.om can't receive email");
+    }
+
+    #[tokio::test]
+    async fn a_lookup_that_times_out_lets_the_address_through() {
+        assert_ok!(check_mail_domain(&StubResolver, &email("ursula@hang.example.com")).await);
+    }
+}
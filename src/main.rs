@@ -4,12 +4,25 @@ use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let subscriber = get_subscriber("zero2prod".into(), "info".into(), std::io::stdout);
+    let configuration = get_configuration().expect("Failed to read configuration.");
+
+    let subscriber = get_subscriber(
+        "zero2prod".into(),
+        configuration.telemetry.level.clone(),
+        std::io::stdout,
+        configuration.telemetry.otlp_endpoint.clone(),
+        configuration.telemetry.format,
+    );
     init_subscriber(subscriber);
 
-    let configuration = get_configuration().expect("Failed to read configuration.");
+    if let Err(e) = configuration.validate() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
     let application = Application::build(configuration).await?;
     application.run_until_stopped().await?;
+    // Flush the spans that haven't been exported yet
+    opentelemetry::global::shutdown_tracer_provider();
     Ok(())
 }
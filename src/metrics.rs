@@ -0,0 +1,167 @@
+//! src/metrics.rs
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The path the metrics are scraped from, left out of its own metrics
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Prometheus metrics for one application instance
+pub struct Metrics {
+    registry: Registry,
+    http_requests_total: IntCounterVec,
+    http_request_duration_seconds: HistogramVec,
+    pub confirmation_emails_sent_total: IntCounter,
+    pub emails_sent_total: IntCounter,
+    pub email_send_failures_total: IntCounter,
+    db_pool_connections: IntGauge,
+    db_pool_idle_connections: IntGauge,
+    db_pool_active_connections: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let http_requests_total = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+            &["method", "route"],
+        )
+        .unwrap();
+        let confirmation_emails_sent_total = IntCounter::new(
+            "confirmation_emails_sent_total",
+            "Confirmation emails sent to subscribers",
+        )
+        .unwrap();
+        let emails_sent_total =
+            IntCounter::new("emails_sent_total", "Emails delivered from the outbox").unwrap();
+        let email_send_failures_total = IntCounter::new(
+            "email_send_failures_total",
+            "Emails that could not be sent, retries included",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(http_requests_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(http_request_duration_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(confirmation_emails_sent_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(emails_sent_total.clone()))
+            .unwrap();
+        let db_pool_connections =
+            IntGauge::new("db_pool_connections", "Open Postgres connections").unwrap();
+        let db_pool_idle_connections = IntGauge::new(
+            "db_pool_idle_connections",
+            "Open Postgres connections not in use",
+        )
+        .unwrap();
+        let db_pool_active_connections = IntGauge::new(
+            "db_pool_active_connections",
+            "Postgres connections checked out of the pool",
+        )
+        .unwrap();
+        registry
+            .register(Box::new(email_send_failures_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_idle_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_active_connections.clone()))
+            .unwrap();
+        Self {
+            registry,
+            http_requests_total,
+            http_request_duration_seconds,
+            confirmation_emails_sent_total,
+            emails_sent_total,
+            email_send_failures_total,
+            db_pool_connections,
+            db_pool_idle_connections,
+            db_pool_active_connections,
+        }
+    }
+
+    /// How saturated the connection pool is right now
+    pub fn record_pool(&self, pool: &PgPool) {
+        let size = i64::from(pool.size());
+        let idle = pool.num_idle() as i64;
+        self.db_pool_connections.set(size);
+        self.db_pool_idle_connections.set(idle);
+        // Connections are counted separately, so the two can disagree briefly
+        self.db_pool_active_connections.set((size - idle).max(0));
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Failed to encode the metrics");
+        String::from_utf8(buffer).expect("The metrics are not valid UTF-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Record the pool's saturation every `interval` until it is closed
+pub async fn sample_pool_metrics(pool: PgPool, metrics: Arc<Metrics>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut closed = pool.close_event();
+    while closed.do_until(ticker.tick()).await.is_ok() {
+        metrics.record_pool(&pool);
+    }
+}
+
+/// Middleware counting and timing each request by method, route and status
+pub async fn record_request_metrics(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    let response = next.call(request).await?;
+    let request = response.request();
+    let Some(metrics) = request.app_data::<web::Data<Metrics>>() else {
+        return Ok(response);
+    };
+    // Label by route pattern so ids in paths don't blow up the cardinality
+    let route = request
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".into());
+    if route == METRICS_PATH {
+        return Ok(response);
+    }
+    let method = request.method().as_str();
+    metrics
+        .http_requests_total
+        .with_label_values(&[method, &route, response.status().as_str()])
+        .inc();
+    metrics
+        .http_request_duration_seconds
+        .with_label_values(&[method, &route])
+        .observe(started.elapsed().as_secs_f64());
+    Ok(response)
+}
@@ -0,0 +1,107 @@
+//! src/problem_details.rs
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// An error body as described by RFC 7807
+#[derive(serde::Serialize)]
+struct ProblemDetails {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl ProblemDetails {
+    /// Server errors only get a generic title, their messages may contain
+    /// internals (queries, hostnames, ...)
+    fn new(status: StatusCode, error: Option<&actix_web::Error>) -> Self {
+        let detail = error
+            .filter(|_| status.is_client_error())
+            .map(|e| e.to_string());
+        Self {
+            // No error has documentation of its own yet
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Unknown Error"),
+            status: status.as_u16(),
+            detail,
+        }
+    }
+}
+
+/// Whether the client asked for JSON. Browsers don't, so they keep getting
+/// the usual bodies.
+fn accepts_problem_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_range| {
+            media_range
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .any(|media_type| media_type == PROBLEM_JSON || media_type == "application/json")
+}
+
+/// Replace the body of error responses with problem details for clients
+/// accepting them. Status and headers (e.g. `WWW-Authenticate`) are kept.
+pub async fn render_problem_details(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let wants_problem_json = accepts_problem_json(request.headers());
+    let response = next.call(request).await?;
+    let status = response.status();
+    if !wants_problem_json || !(status.is_client_error() || status.is_server_error()) {
+        return Ok(response.map_into_boxed_body());
+    }
+    let problem = ProblemDetails::new(status, response.response().error());
+    let body =
+        serde_json::to_string(&problem).map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(response.map_body(|head, _| {
+        head.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        BoxBody::new(body)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::problem_details::accepts_problem_json;
+    use actix_web::http::header::{HeaderMap, HeaderValue, ACCEPT};
+
+    fn accepts(accept: &'static str) -> bool {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(accept));
+        accepts_problem_json(&headers)
+    }
+
+    #[test]
+    fn problem_json_is_accepted() {
+        assert!(accepts("application/problem+json"));
+        assert!(accepts("text/plain, application/problem+json;q=0.9"));
+    }
+
+    #[test]
+    fn plain_json_is_accepted() {
+        assert!(accepts("Application/JSON"));
+    }
+
+    #[test]
+    fn browsers_do_not_get_problem_json() {
+        assert!(!accepts(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        ));
+        assert!(!accepts_problem_json(&HeaderMap::new()));
+    }
+}
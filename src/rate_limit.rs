@@ -0,0 +1,175 @@
+//! src/rate_limit.rs
+use crate::configuration::RateLimitSettings;
+use crate::startup::ClientIp;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use hashlink::linked_hash_map::Entry;
+use hashlink::LinkedHashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Past this many tracked clients, the one seen least recently is forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// A token bucket per client IP. Each bucket holds up to `max_requests`
+/// tokens and refills at `max_requests` per `window`.
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_second: f64,
+    /// The client seen least recently comes first
+    buckets: Mutex<LinkedHashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        let capacity = f64::from(settings.max_requests);
+        Self {
+            capacity,
+            refill_per_second: capacity / settings.window().as_secs_f64(),
+            buckets: Mutex::new(LinkedHashMap::new()),
+        }
+    }
+
+    /// Take a token for `client`, or return how long until one is available
+    fn try_acquire(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        // A bucket that has refilled completely behaves exactly like a client
+        // we have never seen. Clients are kept in the order they were last
+        // seen, so idle ones are dropped from the front without a scan.
+        while let Some((_, bucket)) = buckets.front() {
+            let full = buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client);
+            if !full && self.refilled(bucket, now) < self.capacity {
+                break;
+            }
+            buckets.pop_front();
+        }
+        let bucket = match buckets.entry(client) {
+            Entry::Occupied(mut entry) => {
+                entry.to_back();
+                entry.into_mut()
+            }
+            Entry::Vacant(entry) => entry.insert(Bucket {
+                tokens: self.capacity,
+                updated_at: now,
+            }),
+        };
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.refill_per_second,
+            ))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.refill_per_second).min(self.capacity)
+    }
+}
+
+/// Reject requests beyond the client's limit with `429 Too Many Requests`
+pub async fn limit_requests(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let limiter = request.app_data::<web::Data<RateLimiter>>().cloned();
+    if let Some(limiter) = limiter {
+        if let ClientIp(Some(client)) = ClientIp::of(request.request()) {
+            if let Err(retry_after) = limiter.try_acquire(client, Instant::now()) {
+                tracing::warn!(client = %client, "Rate limit exceeded");
+                let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                let response = HttpResponse::TooManyRequests()
+                    .insert_header((RETRY_AFTER, retry_after.to_string()))
+                    .finish();
+                return Ok(request.into_response(response).map_into_right_body());
+            }
+        }
+    }
+    Ok(next.call(request).await?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::RateLimitSettings;
+    use crate::rate_limit::{RateLimiter, MAX_TRACKED_CLIENTS};
+    use claims::{assert_err, assert_ok};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    fn limiter(max_requests: u32, window_seconds: u64) -> RateLimiter {
+        RateLimiter::new(&RateLimitSettings {
+            max_requests,
+            window_seconds,
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn requests_beyond_the_limit_are_rejected() {
+        let limiter = limiter(2, 60);
+        let now = Instant::now();
+        assert_ok!(limiter.try_acquire(ip("10.0.0.1"), now));
+        assert_ok!(limiter.try_acquire(ip("10.0.0.1"), now));
+        let retry_after = assert_err!(limiter.try_acquire(ip("10.0.0.1"), now));
+        assert_eq!(retry_after, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn clients_are_limited_separately() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        assert_ok!(limiter.try_acquire(ip("10.0.0.1"), now));
+        assert_ok!(limiter.try_acquire(ip("10.0.0.2"), now));
+    }
+
+    #[test]
+    fn tokens_refill_over_the_window() {
+        let limiter = limiter(2, 60);
+        let now = Instant::now();
+        assert_ok!(limiter.try_acquire(ip("10.0.0.1"), now));
+        assert_ok!(limiter.try_acquire(ip("10.0.0.1"), now));
+        assert_ok!(limiter.try_acquire(ip("10.0.0.1"), now + Duration::from_secs(30)));
+        assert_err!(limiter.try_acquire(ip("10.0.0.1"), now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn refilled_clients_are_forgotten() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        assert_ok!(limiter.try_acquire(ip("10.0.0.1"), now));
+        assert_ok!(limiter.try_acquire(ip("10.0.0.2"), now + Duration::from_secs(60)));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn past_the_cap_the_least_recently_seen_client_is_forgotten() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        let clients: Vec<IpAddr> = (0..=MAX_TRACKED_CLIENTS as u32)
+            .map(|i| IpAddr::V4(Ipv4Addr::from(i)))
+            .collect();
+        for client in &clients {
+            assert_ok!(limiter.try_acquire(*client, now));
+        }
+
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_TRACKED_CLIENTS);
+        assert_ok!(limiter.try_acquire(clients[0], now));
+        assert_err!(limiter.try_acquire(clients[MAX_TRACKED_CLIENTS], now));
+    }
+}
@@ -0,0 +1,37 @@
+//! src/request_timeout.rs
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use std::time::Duration;
+
+/// How long a route may take to respond, no limit if `None`. Registered by
+/// the resources that have one.
+pub struct RequestTimeout(pub Option<Duration>);
+
+/// Answer 504 once a request takes longer than its `RequestTimeout`. The
+/// handler is dropped halfway, so a transaction it hasn't committed yet is
+/// rolled back. Only wraps routes, like `require_content_length`.
+pub async fn time_out_requests(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let timeout = request
+        .app_data::<web::Data<RequestTimeout>>()
+        .and_then(|timeout| timeout.0);
+    let Some(timeout) = timeout else {
+        return Ok(next.call(request).await?.map_into_left_body());
+    };
+    let http_request = request.request().clone();
+    match tokio::time::timeout(timeout, next.call(request)).await {
+        Ok(response) => Ok(response?.map_into_left_body()),
+        Err(_) => {
+            tracing::warn!(
+                timeout_milliseconds = timeout.as_millis() as u64,
+                "Gave up on a request that took too long"
+            );
+            let response = HttpResponse::GatewayTimeout().finish();
+            Ok(ServiceResponse::new(http_request, response).map_into_right_body())
+        }
+    }
+}
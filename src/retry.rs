@@ -0,0 +1,142 @@
+//! src/retry.rs
+use std::future::Future;
+
+/// A failed attempt at a unit of database work
+pub enum AttemptError {
+    /// Nothing was committed and the failure is likely to clear up on its
+    /// own, so the work can safely be tried again
+    Transient(anyhow::Error),
+    Permanent(anyhow::Error),
+}
+
+impl AttemptError {
+    pub fn from_sqlx(e: sqlx::Error, context: &'static str) -> Self {
+        if is_transient_error(&e) {
+            Self::Transient(anyhow::Error::new(e).context(context))
+        } else {
+            Self::Permanent(anyhow::Error::new(e).context(context))
+        }
+    }
+
+    /// Like `from_sqlx`, for work that reads before it writes. A unique
+    /// violation then means a concurrent attempt wrote the same row first,
+    /// and trying again finds it instead.
+    pub fn from_racing_sqlx(e: sqlx::Error, context: &'static str) -> Self {
+        if is_unique_violation(&e) {
+            Self::Transient(anyhow::Error::new(e).context(context))
+        } else {
+            Self::from_sqlx(e, context)
+        }
+    }
+}
+
+/// Only connection failures and serialization failures are worth retrying,
+/// anything else (constraint violations, bad queries, ...) would fail again.
+pub fn is_transient_error(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) => true,
+        sqlx::Error::Database(e) => e.code().as_deref() == Some("40001"),
+        _ => false,
+    }
+}
+
+pub fn is_unique_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|e| e.is_unique_violation())
+}
+
+/// Run `attempt`, retrying up to `max_retries` times while it fails with
+/// an `AttemptError::Transient`.
+pub async fn retry_transient<T, F, Fut>(
+    max_retries: u32,
+    mut attempt: F,
+) -> Result<T, anyhow::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AttemptError>>,
+{
+    let mut retries = 0;
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(AttemptError::Transient(e)) if retries < max_retries => {
+                retries += 1;
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    retry = retries,
+                    "Transient database failure, retrying"
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(50 * u64::from(retries))).await;
+            }
+            Err(AttemptError::Transient(e)) | Err(AttemptError::Permanent(e)) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retry::{is_transient_error, retry_transient, AttemptError};
+    use claims::{assert_err, assert_ok};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn pool_timed_out() -> AttemptError {
+        AttemptError::from_sqlx(sqlx::Error::PoolTimedOut, "Failed to acquire a connection")
+    }
+
+    #[test]
+    fn acquire_timeouts_are_transient() {
+        assert!(is_transient_error(&sqlx::Error::PoolTimedOut));
+    }
+
+    #[test]
+    fn missing_rows_are_not_transient() {
+        assert!(!is_transient_error(&sqlx::Error::RowNotFound));
+    }
+
+    #[tokio::test]
+    async fn a_transient_failure_is_retried_until_it_succeeds() {
+        let attempts = AtomicU32::new(0);
+
+        let outcome = retry_transient(2, || async {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(pool_timed_out())
+            } else {
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_ok!(outcome);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn a_permanent_failure_is_not_retried() {
+        let attempts = AtomicU32::new(0);
+
+        let outcome: Result<(), _> = retry_transient(2, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(AttemptError::Permanent(anyhow::anyhow!(
+                "Business logic failed"
+            )))
+        })
+        .await;
+
+        assert_err!(outcome);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_the_configured_count() {
+        let attempts = AtomicU32::new(0);
+
+        let outcome: Result<(), _> = retry_transient(2, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(pool_timed_out())
+        })
+        .await;
+
+        assert_err!(outcome);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::authentication::AdminUser;
+use crate::domain::SubscriptionStatus;
+use crate::routes::error_chain_fmt;
+use crate::subscription_events::{record_subscription_event, SubscriptionEvent};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::{Executor, PgPool, Postgres, Row, Transaction};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Every subscriber in a batch stays locked until the whole batch is done
+const MAX_BULK_IDS: usize = 1000;
+
+#[derive(serde::Deserialize)]
+pub struct BulkStatusBody {
+    ids: Vec<Uuid>,
+    status: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BulkStatusOutcome {
+    Updated,
+    Unchanged,
+    NotFound,
+    InvalidTransition,
+}
+
+#[derive(serde::Serialize)]
+struct BulkStatusResult {
+    id: Uuid,
+    outcome: BulkStatusOutcome,
+    previous_status: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct BulkStatusResponse {
+    results: Vec<BulkStatusResult>,
+}
+
+#[derive(thiserror::Error)]
+pub enum BulkStatusError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for BulkStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for BulkStatusError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::ValidationError(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            Self::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Bulk update subscription statuses",
+    skip(admin, body, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn bulk_update_status(
+    admin: AdminUser,
+    body: web::Json<BulkStatusBody>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, BulkStatusError> {
+    admin.record_on_current_span();
+    let BulkStatusBody { ids, status } = body.0;
+    let target = SubscriptionStatus::parse(status).map_err(BulkStatusError::ValidationError)?;
+    if ids.len() > MAX_BULK_IDS {
+        return Err(BulkStatusError::ValidationError(format!(
+            "At most {} subscribers can be updated at once",
+            MAX_BULK_IDS
+        )));
+    }
+
+    // Locked in id order, so batches that overlap can't deadlock
+    let mut locking_order = ids.clone();
+    locking_order.sort();
+    locking_order.dedup();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let mut outcomes = HashMap::with_capacity(locking_order.len());
+    for id in locking_order {
+        let result = update_status(&mut transaction, id, target)
+            .await
+            .context("Failed to update the status of a subscriber")?;
+        outcomes.insert(id, result);
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to update subscriber statuses")?;
+
+    // Reported in the order they were asked for, once each
+    let results = ids
+        .into_iter()
+        .filter_map(|id| outcomes.remove(&id))
+        .collect();
+    Ok(HttpResponse::Ok().json(BulkStatusResponse { results }))
+}
+
+async fn update_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+    target: SubscriptionStatus,
+) -> Result<BulkStatusResult, anyhow::Error> {
+    let query = sqlx::query!(
+        r#"SELECT status FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        id
+    );
+    let Some(row) = transaction.fetch_optional(query).await? else {
+        return Ok(BulkStatusResult {
+            id,
+            outcome: BulkStatusOutcome::NotFound,
+            previous_status: None,
+        });
+    };
+    let previous_status: String = row.get("status");
+    let current =
+        SubscriptionStatus::parse(previous_status.clone()).map_err(|e| anyhow::anyhow!(e))?;
+
+    let outcome = if current == target {
+        BulkStatusOutcome::Unchanged
+    } else if current.can_transition_to(target) {
+        let query = sqlx::query!(
+            r#"UPDATE subscriptions SET status = $1 WHERE id = $2"#,
+            target.as_ref(),
+            id
+        );
+        transaction.execute(query).await?;
+        record_subscription_event(
+            transaction,
+            id,
+            SubscriptionEvent::StatusChanged,
+            serde_json::json!({ "from": previous_status, "to": target.as_ref() }),
+        )
+        .await?;
+        BulkStatusOutcome::Updated
+    } else {
+        BulkStatusOutcome::InvalidTransition
+    };
+    Ok(BulkStatusResult {
+        id,
+        outcome,
+        previous_status: Some(previous_status),
+    })
+}
@@ -0,0 +1,283 @@
+use crate::authentication::AdminUser;
+use crate::configuration::SubscriptionSettings;
+use crate::domain::{NewSubscriber, SubscriberValidationError};
+use crate::email_outbox::enqueue_emails;
+use crate::routes::{
+    confirmation_email, error_chain_fmt, issue_confirmation_tokens, store_unsubscribe_tokens,
+    FormData,
+};
+use crate::startup::{ConfirmationEmailOptions, RequestBaseUrl};
+use crate::subscription_events::{record_subscription_events, SubscriptionEvent};
+use crate::telemetry::RequestId;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct ImportParameters {
+    /// Import as confirmed, e.g. people who already confirmed with the
+    /// previous service. Otherwise they are sent a confirmation email.
+    #[serde(default)]
+    confirmed: bool,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ImportStatus {
+    Imported,
+    AlreadySubscribed,
+    Invalid,
+}
+
+#[derive(serde::Serialize)]
+struct ImportResult {
+    /// Counted from 1
+    row: usize,
+    status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct ImportResponse {
+    results: Vec<ImportResult>,
+}
+
+#[derive(thiserror::Error)]
+pub enum ImportError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ImportError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+/// Import a JSON array of `{name, email}` rows in one transaction, with field
+/// names mapped like those of a subscription form. Every row is reported on,
+/// invalid ones and addresses we already have are skipped without holding up
+/// the rest. Valid rows are written `import_batch_size` at a time, a few
+/// statements per batch. 200 if every row was imported, 207 if not.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "Import subscribers",
+    skip(admin, rows, parameters, pool, base_url, email_options, settings, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn import_subscribers(
+    admin: AdminUser,
+    rows: web::Json<Vec<HashMap<String, String>>>,
+    parameters: web::Query<ImportParameters>,
+    pool: web::Data<PgPool>,
+    base_url: RequestBaseUrl,
+    email_options: web::Data<ConfirmationEmailOptions>,
+    settings: web::Data<SubscriptionSettings>,
+    request: HttpRequest,
+) -> Result<HttpResponse, ImportError> {
+    admin.record_on_current_span();
+    let started_at = Instant::now();
+
+    let mut results = Vec::with_capacity(rows.len());
+    let mut valid_rows = Vec::with_capacity(rows.len());
+    for (index, row) in rows.0.into_iter().enumerate() {
+        match parse_row(row, &settings.form_field_map) {
+            Ok(new_subscriber) => valid_rows.push((index + 1, new_subscriber)),
+            Err(error) => results.push(ImportResult {
+                row: index + 1,
+                status: ImportStatus::Invalid,
+                error: Some(error),
+            }),
+        }
+    }
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let mut known_emails = HashSet::new();
+    let mut batches = 0;
+    for batch in valid_rows.chunks(settings.import_batch_size) {
+        batches += 1;
+        let subscriber_ids = insert_imported_subscribers(
+            &mut transaction,
+            batch,
+            &mut known_emails,
+            parameters.confirmed,
+        )
+        .await
+        .context("Failed to insert imported subscribers")?;
+        let mut imported = Vec::with_capacity(batch.len());
+        for ((row, new_subscriber), subscriber_id) in batch.iter().zip(subscriber_ids) {
+            let status = match subscriber_id {
+                Some(subscriber_id) => {
+                    imported.push((subscriber_id, new_subscriber));
+                    ImportStatus::Imported
+                }
+                None => ImportStatus::AlreadySubscribed,
+            };
+            results.push(ImportResult {
+                row: *row,
+                status,
+                error: None,
+            });
+        }
+        let imported_ids: Vec<Uuid> = imported.iter().map(|(id, _)| *id).collect();
+        let unsubscribe_tokens = store_unsubscribe_tokens(
+            &mut transaction,
+            &imported_ids,
+            &settings.unsubscribe_token_key,
+        )
+        .await
+        .context("Failed to store the unsubscribe tokens")?;
+        if !parameters.confirmed {
+            let subscription_tokens =
+                issue_confirmation_tokens(&mut transaction, &imported_ids, &settings)
+                    .await
+                    .context("Failed to store the confirmation tokens")?;
+            let emails: Vec<_> = imported
+                .iter()
+                .zip(subscription_tokens)
+                .zip(&unsubscribe_tokens)
+                .map(
+                    |(((_, new_subscriber), subscription_token), unsubscribe_token)| {
+                        let content = confirmation_email(
+                            new_subscriber.name.as_ref(),
+                            &base_url.0,
+                            &subscription_token,
+                            unsubscribe_token,
+                            &email_options.subject,
+                            email_options.templates.as_ref(),
+                            None,
+                        );
+                        (&new_subscriber.email, content)
+                    },
+                )
+                .collect();
+            enqueue_emails(&mut transaction, &emails, RequestId::of(&request).as_ref())
+                .await
+                .context("Failed to enqueue the confirmation emails")?;
+        }
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to import subscribers")?;
+    results.sort_by_key(|result| result.row);
+    tracing::info!(
+        rows = results.len(),
+        batches,
+        elapsed_ms = started_at.elapsed().as_millis() as u64,
+        "Imported subscribers"
+    );
+
+    let all_imported = results
+        .iter()
+        .all(|result| matches!(result.status, ImportStatus::Imported));
+    let status = if all_imported {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+    Ok(HttpResponse::build(status).json(ImportResponse { results }))
+}
+
+fn parse_row(
+    row: HashMap<String, String>,
+    field_map: &HashMap<String, String>,
+) -> Result<NewSubscriber, String> {
+    let form = FormData::from_fields(row.into_iter().collect(), field_map)?;
+    form.try_into()
+        .map_err(|e: SubscriberValidationError| e.to_string())
+}
+
+/// For each row, the new subscriber's id, or `None` if someone already
+/// subscribed with this address, whatever their status, or it came up earlier
+/// in the import. Imports never resubscribe people who left.
+#[tracing::instrument(
+    name = "Insert a batch of imported subscribers",
+    skip_all,
+    fields(rows = batch.len())
+)]
+async fn insert_imported_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    batch: &[(usize, NewSubscriber)],
+    known_emails: &mut HashSet<String>,
+    confirmed: bool,
+) -> Result<Vec<Option<Uuid>>, sqlx::Error> {
+    let emails: Vec<&str> = batch
+        .iter()
+        .map(|(_, new_subscriber)| new_subscriber.email.as_ref())
+        .collect();
+    let existing = sqlx::query!(
+        r#"
+        SELECT
+            lower(email) AS "email!",
+            EXISTS (
+                SELECT 1 FROM subscriptions WHERE lower(subscriptions.email) = lower(t.email)
+            ) AS "taken!"
+        FROM UNNEST($1::text[]) WITH ORDINALITY AS t(email, position)
+        ORDER BY position
+        "#,
+        &emails as &[&str]
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    let mut subscriber_ids = Vec::with_capacity(batch.len());
+    let mut new_ids = Vec::new();
+    let mut new_emails = Vec::new();
+    let mut new_names = Vec::new();
+    for ((_, new_subscriber), existing) in batch.iter().zip(existing) {
+        if existing.taken || !known_emails.insert(existing.email) {
+            subscriber_ids.push(None);
+            continue;
+        }
+        let subscriber_id = Uuid::new_v4();
+        subscriber_ids.push(Some(subscriber_id));
+        new_ids.push(subscriber_id);
+        new_emails.push(new_subscriber.email.as_ref());
+        new_names.push(new_subscriber.name.as_ref());
+    }
+
+    let status = if confirmed {
+        "confirmed"
+    } else {
+        "pending_confirmation"
+    };
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        SELECT id, email, name, $4, $5
+        FROM UNNEST($1::uuid[], $2::text[], $3::text[]) AS t(id, email, name)
+        "#,
+        &new_ids,
+        &new_emails as &[&str],
+        &new_names as &[&str],
+        Utc::now(),
+        status
+    );
+    transaction.execute(query).await?;
+    record_subscription_events(
+        transaction,
+        &new_ids,
+        SubscriptionEvent::Subscribed,
+        serde_json::json!({ "imported": true, "status": status }),
+    )
+    .await?;
+    Ok(subscriber_ids)
+}
@@ -0,0 +1,18 @@
+//! src/routes/admin/mod.rs
+mod bulk_status;
+mod import;
+mod newsletters;
+mod password;
+mod stats;
+mod subscriber;
+mod subscriptions;
+mod unsubscribe;
+
+pub use bulk_status::*;
+pub use import::*;
+pub use newsletters::*;
+pub use password::*;
+pub use stats::*;
+pub use subscriber::*;
+pub use subscriptions::*;
+pub use unsubscribe::*;
@@ -0,0 +1,151 @@
+use crate::configuration::SubscriptionSettings;
+use crate::email_client::EmailProvider;
+use crate::routes::{
+    deliver_issue, error_chain_fmt, insert_newsletter_issue, parse_issue, see_other, Content,
+};
+use crate::session_state::TypedSession;
+use crate::startup::{NewsletterSendConcurrency, RequestBaseUrl};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct NewsletterForm {
+    title: String,
+    text_content: String,
+    html_content: String,
+    csrf_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum NewsletterFormError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("The CSRF token is missing or invalid")]
+    InvalidCsrfToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for NewsletterFormError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for NewsletterFormError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::ValidationError(_) | Self::InvalidCsrfToken => StatusCode::BAD_REQUEST,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The logged in admin, if any
+fn logged_in_user(session: &TypedSession) -> Result<Option<Uuid>, NewsletterFormError> {
+    Ok(session
+        .get_user_id()
+        .context("Failed to read the user id from the session")?)
+}
+
+pub async fn newsletter_form(session: TypedSession) -> Result<HttpResponse, NewsletterFormError> {
+    if logged_in_user(&session)?.is_none() {
+        return Ok(see_other("/login"));
+    }
+    let csrf_token = session.csrf_token()?;
+    // Flash messages are set by us, never taken from the request
+    let flash = session
+        .take_flash()?
+        .map(|message| format!("<p><i>{}</i></p>", message))
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Publish a newsletter issue</title>
+</head>
+<body>
+    {flash}
+    <form action="/admin/newsletters" method="post">
+        <input type="hidden" name="csrf_token" value="{csrf_token}">
+        <label>Title
+            <input type="text" placeholder="Enter the issue title" name="title">
+        </label>
+        <label>Plain text content
+            <textarea placeholder="Enter the plain text content" name="text_content" rows="20" cols="50"></textarea>
+        </label>
+        <label>HTML content
+            <textarea placeholder="Enter the HTML content" name="html_content" rows="20" cols="50"></textarea>
+        </label>
+        <button type="submit">Publish</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+/// Publish an issue from the admin form, for admins logged in with a session
+#[tracing::instrument(
+    name = "Publish a newsletter issue from the admin form",
+    skip(form, pool, email_client, base_url, concurrency, settings, session),
+    fields(user_id=tracing::field::Empty)
+)]
+pub async fn publish_newsletter_form(
+    form: web::Form<NewsletterForm>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<Arc<dyn EmailProvider>>,
+    base_url: RequestBaseUrl,
+    concurrency: web::Data<NewsletterSendConcurrency>,
+    settings: web::Data<SubscriptionSettings>,
+    session: TypedSession,
+) -> Result<HttpResponse, NewsletterFormError> {
+    let Some(user_id) = logged_in_user(&session)? else {
+        return Ok(see_other("/login"));
+    };
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+    let form = form.0;
+    let csrf_token_is_valid = session
+        .is_valid_csrf_token(&form.csrf_token)
+        .context("Failed to read the CSRF token from the session")?;
+    if !csrf_token_is_valid {
+        return Err(NewsletterFormError::InvalidCsrfToken);
+    }
+    let content = Content::Html {
+        html: form.html_content,
+        text: form.text_content,
+    };
+    let new_issue =
+        parse_issue(form.title, content).map_err(NewsletterFormError::ValidationError)?;
+    let issue = insert_newsletter_issue(&pool, new_issue).await?;
+    let summary = deliver_issue(
+        &issue,
+        &pool,
+        email_client.get_ref().as_ref(),
+        &base_url.0,
+        &settings.unsubscribe_token_key,
+        concurrency.0,
+    )
+    .await
+    .context("Failed to deliver the newsletter issue")?;
+    let flash = if summary.failed == 0 {
+        "The newsletter issue has been published!".to_string()
+    } else {
+        format!(
+            "The newsletter issue has been published, but {} of {} emails failed to send",
+            summary.failed,
+            summary.sent + summary.failed
+        )
+    };
+    session
+        .set_flash(&flash)
+        .context("Failed to store the flash message in the session")?;
+    Ok(see_other("/admin/newsletters"))
+}
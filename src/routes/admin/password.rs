@@ -0,0 +1,143 @@
+use crate::authentication::{
+    change_password, get_username, validate_credentials, validate_new_password, AuthError,
+    Credentials,
+};
+use crate::routes::{error_chain_fmt, see_other};
+use crate::session_state::TypedSession;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use secrecy::Secret;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct ChangePasswordForm {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+    csrf_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ChangePasswordError {
+    #[error("The CSRF token is missing or invalid")]
+    InvalidCsrfToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ChangePasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ChangePasswordError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::InvalidCsrfToken => StatusCode::BAD_REQUEST,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+pub async fn change_password_form(
+    session: TypedSession,
+) -> Result<HttpResponse, ChangePasswordError> {
+    let user_id = session
+        .get_user_id()
+        .context("Failed to read the user id from the session")?;
+    if user_id.is_none() {
+        return Ok(see_other("/login"));
+    }
+    let csrf_token = session.csrf_token()?;
+    // Flash messages are set by us, never taken from the request
+    let flash = session
+        .take_flash()?
+        .map(|message| format!("<p><i>{}</i></p>", message))
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Change Password</title>
+</head>
+<body>
+    {flash}
+    <form action="/admin/password" method="post">
+        <input type="hidden" name="csrf_token" value="{csrf_token}">
+        <label>Current password
+            <input type="password" placeholder="Enter current password" name="current_password">
+        </label>
+        <label>New password
+            <input type="password" placeholder="Enter new password" name="new_password">
+        </label>
+        <label>Confirm new password
+            <input type="password" placeholder="Type the new password again" name="new_password_check">
+        </label>
+        <button type="submit">Change password</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+/// Change the password of the logged in admin, who has to know the current one
+#[tracing::instrument(
+    name = "Change an admin's password",
+    skip(form, pool, session),
+    fields(user_id=tracing::field::Empty)
+)]
+pub async fn change_admin_password(
+    form: web::Form<ChangePasswordForm>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+) -> Result<HttpResponse, ChangePasswordError> {
+    let Some(user_id) = session
+        .get_user_id()
+        .context("Failed to read the user id from the session")?
+    else {
+        return Ok(see_other("/login"));
+    };
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+    let form = form.0;
+    let csrf_token_is_valid = session
+        .is_valid_csrf_token(&form.csrf_token)
+        .context("Failed to read the CSRF token from the session")?;
+    if !csrf_token_is_valid {
+        return Err(ChangePasswordError::InvalidCsrfToken);
+    }
+
+    if let Err(e) = validate_new_password(&form.new_password, &form.new_password_check) {
+        session
+            .set_flash(&e)
+            .context("Failed to store the flash message in the session")?;
+        return Ok(see_other("/admin/password"));
+    }
+    let credentials = Credentials {
+        username: get_username(user_id, &pool).await?,
+        password: form.current_password,
+    };
+    match validate_credentials(credentials, &pool).await {
+        Ok(_) => {}
+        Err(AuthError::InvalidCredentials(_)) => {
+            session
+                .set_flash("The current password is incorrect")
+                .context("Failed to store the flash message in the session")?;
+            return Ok(see_other("/admin/password"));
+        }
+        Err(e @ AuthError::UnexpectedError(_)) => {
+            return Err(ChangePasswordError::UnexpectedError(e.into()))
+        }
+    }
+
+    change_password(user_id, form.new_password, &pool).await?;
+    session
+        .set_flash("Your password has been changed")
+        .context("Failed to store the flash message in the session")?;
+    Ok(see_other("/admin/password"))
+}
@@ -0,0 +1,124 @@
+use crate::authentication::AdminUser;
+use crate::domain::SubscriptionStatus;
+use crate::routes::error_chain_fmt;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+
+#[derive(serde::Serialize)]
+struct ReasonCount {
+    /// `None` groups the unsubscribes that came without a reason
+    reason: Option<String>,
+    count: i64,
+}
+
+#[derive(serde::Serialize)]
+struct UnsubscribeReasonsResponse {
+    reasons: Vec<ReasonCount>,
+}
+
+/// Every status is listed, with 0 if nobody has it
+#[derive(serde::Serialize, Default)]
+struct SubscriptionCounts {
+    pending_confirmation: i64,
+    confirmed: i64,
+    unsubscribed: i64,
+}
+
+#[derive(thiserror::Error)]
+pub enum AdminStatsError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AdminStatsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminStatsError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Break down unsubscribes by reason",
+    skip(admin, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn unsubscribe_reasons(
+    admin: AdminUser,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminStatsError> {
+    admin.record_on_current_span();
+    let reasons = count_unsubscribe_reasons(&pool)
+        .await
+        .context("Failed to count unsubscribe reasons")?;
+    Ok(HttpResponse::Ok().json(UnsubscribeReasonsResponse { reasons }))
+}
+
+/// How many subscribers there are in each status, a cheap snapshot for
+/// dashboards
+#[tracing::instrument(
+    name = "Count subscriptions by status",
+    skip(admin, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn subscription_counts(
+    admin: AdminUser,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminStatsError> {
+    admin.record_on_current_span();
+    let counts = count_subscriptions_by_status(&pool)
+        .await
+        .context("Failed to count subscriptions by status")?;
+    Ok(HttpResponse::Ok().json(counts))
+}
+
+#[tracing::instrument(name = "Count unsubscribe reasons", skip(pool))]
+async fn count_unsubscribe_reasons(pool: &PgPool) -> Result<Vec<ReasonCount>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT reason, COUNT(*) AS "count!"
+        FROM unsubscribe_events
+        GROUP BY reason
+        ORDER BY 2 DESC, reason
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| ReasonCount {
+            reason: r.reason,
+            count: r.count,
+        })
+        .collect())
+}
+
+#[tracing::instrument(name = "Count subscriptions by status", skip(pool))]
+async fn count_subscriptions_by_status(pool: &PgPool) -> Result<SubscriptionCounts, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT status, COUNT(*) AS "count!"
+        FROM subscriptions
+        GROUP BY status
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut counts = SubscriptionCounts::default();
+    for row in rows {
+        match SubscriptionStatus::parse(row.status).map_err(anyhow::Error::msg)? {
+            SubscriptionStatus::PendingConfirmation => counts.pending_confirmation = row.count,
+            SubscriptionStatus::Confirmed => counts.confirmed = row.count,
+            SubscriptionStatus::Unsubscribed => counts.unsubscribed = row.count,
+        }
+    }
+    Ok(counts)
+}
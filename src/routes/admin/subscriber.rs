@@ -0,0 +1,598 @@
+use crate::authentication::AdminUser;
+use crate::configuration::SubscriptionSettings;
+use crate::domain::{SubscriberEmail, SubscriberName};
+use crate::email_outbox::enqueue_email;
+use crate::routes::{
+    confirmation_email, error_chain_fmt, issue_confirmation_token, store_unsubscribe_token,
+};
+use crate::startup::{ConfirmationEmailOptions, RequestBaseUrl};
+use crate::subscription_events::{
+    get_subscription_events, record_subscription_event, RecordedSubscriptionEvent,
+    SubscriptionEvent,
+};
+use crate::telemetry::RequestId;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Serialize)]
+struct SubscriberDetail {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    has_confirmation_token: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EditSubscriberBody {
+    name: Option<String>,
+}
+
+/// Everything stored about a subscriber. Tokens are only described, their
+/// values would let whoever reads the export act on the subscriber's behalf.
+#[derive(serde::Serialize)]
+struct SubscriberExport {
+    subscription: SubscriptionRecord,
+    /// When the pending confirmation token was issued, if there is one
+    confirmation_token_created_at: Option<DateTime<Utc>>,
+    has_unsubscribe_token: bool,
+    events: Vec<RecordedSubscriptionEvent>,
+    unsubscribes: Vec<UnsubscribeRecord>,
+    newsletter_deliveries: Vec<DeliveryRecord>,
+    emails: Vec<EmailRecord>,
+}
+
+#[derive(serde::Serialize)]
+struct SubscriptionRecord {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    consent_source: Option<String>,
+    consent_ip: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct UnsubscribeRecord {
+    reason: Option<String>,
+    unsubscribed_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct DeliveryRecord {
+    newsletter_issue_id: Uuid,
+    title: String,
+    delivered_at: DateTime<Utc>,
+}
+
+/// An email in the outbox, without its body
+#[derive(serde::Serialize)]
+struct EmailRecord {
+    subject: String,
+    created_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+    failed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EraseParameters {
+    /// Delete every row about the subscriber, the audit trail included.
+    /// Otherwise they are anonymized and keep their history.
+    #[serde(default)]
+    hard: bool,
+}
+
+/// Anonymized subscribers get an address here, which can't receive mail
+const ERASED_EMAIL_DOMAIN: &str = "erased.invalid";
+
+#[derive(thiserror::Error)]
+pub enum AdminSubscriberError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There is no subscriber with the provided id")]
+    UnknownSubscriber,
+    #[error("The subscriber has already confirmed")]
+    AlreadyConfirmed,
+    #[error("The subscriber has unsubscribed")]
+    Unsubscribed,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AdminSubscriberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminSubscriberError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::ValidationError(_) => HttpResponse::new(StatusCode::UNPROCESSABLE_ENTITY),
+            Self::UnknownSubscriber => HttpResponse::new(StatusCode::NOT_FOUND),
+            Self::AlreadyConfirmed | Self::Unsubscribed => HttpResponse::new(StatusCode::CONFLICT),
+            Self::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Get subscriber detail on behalf of an admin",
+    skip(admin, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn get_subscriber_detail(
+    admin: AdminUser,
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminSubscriberError> {
+    admin.record_on_current_span();
+    let detail = fetch_subscriber_detail(&pool, *subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber detail")?
+        .ok_or(AdminSubscriberError::UnknownSubscriber)?;
+    Ok(HttpResponse::Ok().json(detail))
+}
+
+#[tracing::instrument(
+    name = "Edit a subscriber on behalf of an admin",
+    skip(admin, body, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn edit_subscriber(
+    admin: AdminUser,
+    subscriber_id: web::Path<Uuid>,
+    body: web::Json<EditSubscriberBody>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminSubscriberError> {
+    admin.record_on_current_span();
+    let user_id = admin.user_id;
+    let subscriber_id = subscriber_id.into_inner();
+    let name = body
+        .0
+        .name
+        .map(SubscriberName::parse)
+        .transpose()
+        .map_err(|e| AdminSubscriberError::ValidationError(e.to_string()))?;
+
+    if let Some(name) = name {
+        let updated = update_subscriber_name(&pool, subscriber_id, &name)
+            .await
+            .context("Failed to update the subscriber name")?;
+        if !updated {
+            return Err(AdminSubscriberError::UnknownSubscriber);
+        }
+        tracing::info!(
+            target: "audit",
+            event = "subscriber_edited",
+            %subscriber_id,
+            %user_id,
+            "An admin edited a subscriber"
+        );
+    }
+
+    let detail = fetch_subscriber_detail(&pool, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber detail")?
+        .ok_or(AdminSubscriberError::UnknownSubscriber)?;
+    Ok(HttpResponse::Ok().json(detail))
+}
+
+/// Send a pending subscriber a fresh confirmation email on their behalf,
+/// e.g. when support is told the first one never arrived
+#[tracing::instrument(
+    name = "Resend a confirmation email on behalf of an admin",
+    skip(admin, pool, base_url, email_options, settings, request),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn reconfirm_subscriber(
+    admin: AdminUser,
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    base_url: RequestBaseUrl,
+    email_options: web::Data<ConfirmationEmailOptions>,
+    settings: web::Data<SubscriptionSettings>,
+    request: HttpRequest,
+) -> Result<HttpResponse, AdminSubscriberError> {
+    admin.record_on_current_span();
+    let user_id = admin.user_id;
+    let subscriber_id = subscriber_id.into_inner();
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber = lock_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to look up the subscriber")?
+        .ok_or(AdminSubscriberError::UnknownSubscriber)?;
+    match subscriber.status.as_str() {
+        "confirmed" => return Err(AdminSubscriberError::AlreadyConfirmed),
+        "unsubscribed" => return Err(AdminSubscriberError::Unsubscribed),
+        _ => {}
+    }
+    let email = SubscriberEmail::parse(subscriber.email)
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("The stored subscriber email is invalid")?;
+
+    let subscription_token = issue_confirmation_token(&mut transaction, subscriber_id, &settings)
+        .await
+        .context("Failed to store the new confirmation token")?;
+    let unsubscribe_token = store_unsubscribe_token(
+        &mut transaction,
+        subscriber_id,
+        &settings.unsubscribe_token_key,
+    )
+    .await
+    .context("Failed to store the unsubscribe token")?;
+    let content = confirmation_email(
+        &subscriber.name,
+        &base_url.0,
+        &subscription_token,
+        &unsubscribe_token,
+        &email_options.subject,
+        email_options.templates.as_ref(),
+        None,
+    );
+    enqueue_email(
+        &mut transaction,
+        &email,
+        &content,
+        RequestId::of(&request).as_ref(),
+    )
+    .await
+    .context("Failed to enqueue the confirmation email")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to resend a confirmation email")?;
+
+    tracing::info!(
+        target: "audit",
+        event = "confirmation_resent",
+        %subscriber_id,
+        %user_id,
+        "An admin resent a confirmation email"
+    );
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Everything stored about a subscriber, for a data access request. Served as
+/// a JSON file to download.
+#[tracing::instrument(
+    name = "Export a subscriber's data on behalf of an admin",
+    skip(admin, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn export_subscriber(
+    admin: AdminUser,
+    subscriber_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminSubscriberError> {
+    admin.record_on_current_span();
+    let user_id = admin.user_id;
+    let subscriber_id = subscriber_id.into_inner();
+    let export = fetch_subscriber_export(&pool, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber's data")?
+        .ok_or(AdminSubscriberError::UnknownSubscriber)?;
+
+    tracing::info!(
+        target: "audit",
+        event = "subscriber_exported",
+        %subscriber_id,
+        %user_id,
+        "An admin exported a subscriber's data"
+    );
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!(
+            "subscriber-{}.json",
+            subscriber_id
+        ))],
+    };
+    Ok(HttpResponse::Ok().insert_header(disposition).json(export))
+}
+
+/// Erase a subscriber's personal data on request. By default their email is
+/// replaced by a random placeholder and their name blanked, so the audit
+/// trail and delivery history survive without saying who they were.
+#[tracing::instrument(
+    name = "Erase a subscriber on behalf of an admin",
+    skip(admin, parameters, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn erase_subscriber(
+    admin: AdminUser,
+    subscriber_id: web::Path<Uuid>,
+    parameters: web::Query<EraseParameters>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminSubscriberError> {
+    admin.record_on_current_span();
+    let user_id = admin.user_id;
+    let subscriber_id = subscriber_id.into_inner();
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let subscriber = lock_subscriber(&mut transaction, subscriber_id)
+        .await
+        .context("Failed to look up the subscriber")?
+        .ok_or(AdminSubscriberError::UnknownSubscriber)?;
+    remove_personal_data(&mut transaction, subscriber_id, &subscriber.email)
+        .await
+        .context("Failed to remove the subscriber's personal data")?;
+    if parameters.hard {
+        delete_subscriber(&mut transaction, subscriber_id)
+            .await
+            .context("Failed to delete the subscriber")?;
+    } else if !subscriber.erased {
+        anonymize_subscriber(&mut transaction, subscriber_id, &subscriber)
+            .await
+            .context("Failed to anonymize the subscriber")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to erase a subscriber")?;
+
+    // The audit log is all that is left of a hard delete
+    tracing::info!(
+        target: "audit",
+        event = "subscriber_erased",
+        %subscriber_id,
+        %user_id,
+        hard = parameters.hard,
+        "An admin erased a subscriber"
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument(name = "Fetch subscriber detail", skip(pool))]
+async fn fetch_subscriber_detail(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriberDetail>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT id, email, name, status, subscribed_at,
+            EXISTS (
+                SELECT 1 FROM subscription_tokens WHERE subscriber_id = subscriptions.id
+            ) AS "has_confirmation_token!"
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(result.map(|r| SubscriberDetail {
+        id: r.id,
+        email: r.email,
+        name: r.name,
+        status: r.status,
+        subscribed_at: r.subscribed_at,
+        has_confirmation_token: r.has_confirmation_token,
+    }))
+}
+
+/// Returns whether a subscriber with the given id existed
+#[tracing::instrument(name = "Update subscriber name", skip(pool, name))]
+async fn update_subscriber_name(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    name: &SubscriberName,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE subscriptions SET name = $1 WHERE id = $2"#,
+        name.as_ref(),
+        subscriber_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+struct LockedSubscriber {
+    email: String,
+    name: String,
+    status: String,
+    /// Anonymized by an earlier erasure
+    erased: bool,
+}
+
+/// Locked, so the subscriber can't change while we act on them
+async fn lock_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Option<LockedSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        LockedSubscriber,
+        r#"SELECT email, name, status,
+            EXISTS (
+                SELECT 1 FROM subscription_events
+                WHERE subscriber_id = s.id AND event = $2
+            ) AS "erased!"
+        FROM subscriptions s
+        WHERE id = $1
+        FOR UPDATE OF s"#,
+        subscriber_id,
+        SubscriptionEvent::Erased.as_ref()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
+
+/// What is dropped either way: the tokens in the links we sent, free-text
+/// unsubscribe reasons and every email to them still in the outbox
+async fn remove_personal_data(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    );
+    transaction.execute(query).await?;
+    let query = sqlx::query!(
+        r#"DELETE FROM unsubscribe_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    );
+    transaction.execute(query).await?;
+    let query = sqlx::query!(
+        r#"UPDATE unsubscribe_events SET reason = NULL WHERE subscriber_id = $1"#,
+        subscriber_id
+    );
+    transaction.execute(query).await?;
+    let query = sqlx::query!(
+        r#"DELETE FROM email_outbox WHERE lower(recipient) = lower($1)"#,
+        email
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// Unsubscribed, so nothing is sent to them, with a random placeholder for
+/// their email: a hash of it could be reversed by guessing addresses. Should
+/// they sign up again, they do so as someone new.
+async fn anonymize_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    subscriber: &LockedSubscriber,
+) -> Result<(), sqlx::Error> {
+    let anonymized_email = format!("{}@{}", Uuid::new_v4().simple(), ERASED_EMAIL_DOMAIN);
+    let query = sqlx::query!(
+        r#"UPDATE subscriptions
+        SET email = $1, name = '', status = 'unsubscribed',
+            consent_source = NULL, consent_ip = NULL
+        WHERE id = $2"#,
+        anonymized_email,
+        subscriber_id
+    );
+    transaction.execute(query).await?;
+    record_subscription_event(
+        transaction,
+        subscriber_id,
+        SubscriptionEvent::Erased,
+        serde_json::json!({ "from": subscriber.status }),
+    )
+    .await
+}
+
+async fn delete_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    // The audit trail is append-only unless a transaction opts in
+    transaction
+        .execute(sqlx::query!(
+            "SELECT set_config('zero2prod.erasing_subscriber', 'on', true)"
+        ))
+        .await?;
+    let query = sqlx::query!(
+        r#"DELETE FROM subscription_events WHERE subscriber_id = $1"#,
+        subscriber_id
+    );
+    transaction.execute(query).await?;
+    let query = sqlx::query!(
+        r#"DELETE FROM unsubscribe_events WHERE subscriber_id = $1"#,
+        subscriber_id
+    );
+    transaction.execute(query).await?;
+    let query = sqlx::query!(
+        r#"DELETE FROM newsletter_delivery WHERE subscriber_id = $1"#,
+        subscriber_id
+    );
+    transaction.execute(query).await?;
+    let query = sqlx::query!(r#"DELETE FROM subscriptions WHERE id = $1"#, subscriber_id);
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Fetch everything stored about a subscriber", skip(pool))]
+async fn fetch_subscriber_export(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriberExport>, sqlx::Error> {
+    let subscription = sqlx::query_as!(
+        SubscriptionRecord,
+        r#"SELECT id, email, name, status, subscribed_at, consent_source, consent_ip
+        FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(subscription) = subscription else {
+        return Ok(None);
+    };
+    let confirmation_token_created_at = sqlx::query!(
+        r#"SELECT created_at FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|r| r.created_at);
+    let has_unsubscribe_token = sqlx::query!(
+        r#"SELECT EXISTS (SELECT 1 FROM unsubscribe_tokens WHERE subscriber_id = $1) AS "exists!""#,
+        subscriber_id
+    )
+    .fetch_one(pool)
+    .await?
+    .exists;
+    let events = get_subscription_events(pool, subscriber_id).await?;
+    let unsubscribes = sqlx::query_as!(
+        UnsubscribeRecord,
+        r#"
+        SELECT reason, unsubscribed_at
+        FROM unsubscribe_events
+        WHERE subscriber_id = $1
+        ORDER BY unsubscribed_at
+        "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let newsletter_deliveries = sqlx::query_as!(
+        DeliveryRecord,
+        r#"
+        SELECT newsletter_delivery.newsletter_issue_id, title, delivered_at
+        FROM newsletter_delivery
+        JOIN newsletter_issues USING (newsletter_issue_id)
+        WHERE subscriber_id = $1
+        ORDER BY delivered_at
+        "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let emails = sqlx::query_as!(
+        EmailRecord,
+        r#"
+        SELECT subject, created_at, delivered_at, failed_at
+        FROM email_outbox
+        WHERE lower(recipient) = lower($1)
+        ORDER BY created_at
+        "#,
+        subscription.email
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(Some(SubscriberExport {
+        subscription,
+        confirmation_token_created_at,
+        has_unsubscribe_token,
+        events,
+        unsubscribes,
+        newsletter_deliveries,
+        emails,
+    }))
+}
@@ -0,0 +1,301 @@
+use crate::authentication::AdminUser;
+use crate::domain::SubscriptionStatus;
+use crate::routes::error_chain_fmt;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+/// Longer search terms can't match anything worth finding
+const MAX_SEARCH_LENGTH: usize = 256;
+
+#[derive(serde::Deserialize)]
+pub struct Pagination {
+    /// At most `MAX_PAGE_SIZE`
+    limit: Option<i64>,
+    /// The `next_cursor` of the previous page, the first page without one
+    cursor: Option<String>,
+    status: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct SearchQuery {
+    /// Matched case-insensitively against the start of the email or name
+    q: String,
+    /// At most `MAX_PAGE_SIZE`
+    limit: Option<i64>,
+    /// The `next_cursor` of the previous page, the first page without one
+    cursor: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct SubscriptionItem {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct SubscriptionPage {
+    items: Vec<SubscriptionItem>,
+    /// `None` on the last page
+    next_cursor: Option<String>,
+}
+
+/// Where a page starts: right after the subscriber with this
+/// `(subscribed_at, id)`. Subscribers share timestamps, so the id breaks ties.
+#[derive(Debug)]
+struct Cursor {
+    subscribed_at: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl Cursor {
+    /// Opaque to clients, they only pass it back
+    fn encode(&self) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(format!(
+            "{}:{}",
+            self.subscribed_at.timestamp_micros(),
+            self.id
+        ))
+    }
+
+    fn decode(cursor: &str) -> Result<Self, String> {
+        let invalid = || format!("{:?} is not a valid cursor", cursor);
+        let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (micros, id) = decoded.split_once(':').ok_or_else(invalid)?;
+        let subscribed_at = micros
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or_else(invalid)?;
+        let id = id.parse().map_err(|_| invalid())?;
+        Ok(Self { subscribed_at, id })
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ListSubscriptionsError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ListSubscriptionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ListSubscriptionsError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::ValidationError(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            Self::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+/// A page of subscribers, oldest first. Pages are keyed on the last
+/// subscriber of the previous one rather than an offset, so they stay fast
+/// deep into the list and nobody is skipped or repeated when subscribers
+/// are added in between.
+#[tracing::instrument(
+    name = "List subscriptions on behalf of an admin",
+    skip(admin, query, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn list_subscriptions(
+    admin: AdminUser,
+    query: web::Query<Pagination>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ListSubscriptionsError> {
+    admin.record_on_current_span();
+    let query = query.into_inner();
+    let (limit, cursor) = parse_page(query.limit, query.cursor.as_deref())?;
+    let status = query
+        .status
+        .map(SubscriptionStatus::parse)
+        .transpose()
+        .map_err(ListSubscriptionsError::ValidationError)?;
+
+    let items = fetch_subscriptions(&pool, cursor, status, limit)
+        .await
+        .context("Failed to retrieve a page of subscriptions")?;
+    Ok(HttpResponse::Ok().json(SubscriptionPage::from_fetched(items, limit)))
+}
+
+/// Subscribers whose email or name starts with `q`, paged like
+/// `list_subscriptions`. `q` is taken literally, `%` and `_` included.
+#[tracing::instrument(
+    name = "Search subscriptions on behalf of an admin",
+    skip(admin, query, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn search_subscriptions(
+    admin: AdminUser,
+    query: web::Query<SearchQuery>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, ListSubscriptionsError> {
+    admin.record_on_current_span();
+    let query = query.into_inner();
+    let (limit, cursor) = parse_page(query.limit, query.cursor.as_deref())?;
+    let term = query.q.trim();
+    if term.is_empty() || term.chars().count() > MAX_SEARCH_LENGTH {
+        return Err(ListSubscriptionsError::ValidationError(format!(
+            "The search term must be between 1 and {} characters",
+            MAX_SEARCH_LENGTH
+        )));
+    }
+
+    let items = fetch_matching_subscriptions(&pool, &like_prefix(term), cursor, limit)
+        .await
+        .context("Failed to search subscriptions")?;
+    Ok(HttpResponse::Ok().json(SubscriptionPage::from_fetched(items, limit)))
+}
+
+impl SubscriptionPage {
+    /// One more than `limit` is fetched to know whether another page follows
+    fn from_fetched(mut items: Vec<SubscriptionItem>, limit: i64) -> Self {
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(|last| {
+                Cursor {
+                    subscribed_at: last.subscribed_at,
+                    id: last.id,
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        Self { items, next_cursor }
+    }
+}
+
+fn parse_page(
+    limit: Option<i64>,
+    cursor: Option<&str>,
+) -> Result<(i64, Option<Cursor>), ListSubscriptionsError> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ListSubscriptionsError::ValidationError(format!(
+            "The limit must be between 1 and {}",
+            MAX_PAGE_SIZE
+        )));
+    }
+    let cursor = cursor
+        .map(Cursor::decode)
+        .transpose()
+        .map_err(ListSubscriptionsError::ValidationError)?;
+    Ok((limit, cursor))
+}
+
+/// A `LIKE` pattern for whatever starts with `term`, lowercased, with the
+/// wildcards and the escape character in it matching only themselves
+fn like_prefix(term: &str) -> String {
+    let mut pattern = String::with_capacity(term.len() + 1);
+    for c in term.to_lowercase().chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+#[tracing::instrument(name = "Fetch a page of subscriptions", skip(pool, cursor))]
+async fn fetch_subscriptions(
+    pool: &PgPool,
+    cursor: Option<Cursor>,
+    status: Option<SubscriptionStatus>,
+    limit: i64,
+) -> Result<Vec<SubscriptionItem>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriptionItem,
+        r#"
+        SELECT id, email, name, status, subscribed_at
+        FROM subscriptions
+        WHERE ($1::text IS NULL OR status = $1)
+        AND ($2::timestamptz IS NULL OR (subscribed_at, id) > ($2, $3::uuid))
+        ORDER BY subscribed_at, id
+        LIMIT $4
+        "#,
+        status.as_ref().map(|status| status.as_ref()),
+        cursor.as_ref().map(|cursor| cursor.subscribed_at),
+        cursor.as_ref().map(|cursor| cursor.id),
+        limit + 1
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[tracing::instrument(name = "Fetch a page of matching subscriptions", skip(pool, cursor))]
+async fn fetch_matching_subscriptions(
+    pool: &PgPool,
+    pattern: &str,
+    cursor: Option<Cursor>,
+    limit: i64,
+) -> Result<Vec<SubscriptionItem>, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriptionItem,
+        r#"
+        SELECT id, email, name, status, subscribed_at
+        FROM subscriptions
+        WHERE (lower(email) LIKE $1 ESCAPE '\' OR lower(name) LIKE $1 ESCAPE '\')
+        AND ($2::timestamptz IS NULL OR (subscribed_at, id) > ($2, $3::uuid))
+        ORDER BY subscribed_at, id
+        LIMIT $4
+        "#,
+        pattern,
+        cursor.as_ref().map(|cursor| cursor.subscribed_at),
+        cursor.as_ref().map(|cursor| cursor.id),
+        limit + 1
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::admin::subscriptions::{like_prefix, Cursor};
+    use chrono::DateTime;
+    use claims::assert_err;
+    use uuid::Uuid;
+
+    #[test]
+    fn a_cursor_survives_a_round_trip() {
+        let cursor = Cursor {
+            subscribed_at: DateTime::from_timestamp_micros(1_733_000_000_123_456).unwrap(),
+            id: Uuid::new_v4(),
+        };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.subscribed_at, cursor.subscribed_at);
+        assert_eq!(decoded.id, cursor.id);
+    }
+
+    #[test]
+    fn a_garbage_cursor_is_rejected() {
+        assert_err!(Cursor::decode("not a cursor"));
+        assert_err!(Cursor::decode("MTIzNDU"));
+    }
+
+    #[test]
+    fn wildcards_in_a_search_term_are_escaped() {
+        assert_eq!(like_prefix("Ursula"), "ursula%");
+        assert_eq!(like_prefix("100%_off"), r"100\%\_off%");
+        assert_eq!(like_prefix(r"a\b"), r"a\\b%");
+    }
+}
@@ -0,0 +1,170 @@
+use crate::authentication::AdminUser;
+use crate::domain::SubscriberEmail;
+use crate::routes::error_chain_fmt;
+use crate::subscription_events::{record_subscription_event, SubscriptionEvent};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+const MAX_REASON_LENGTH: usize = 1000;
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeBody {
+    email: String,
+    /// Free text on why the subscriber is leaving
+    reason: Option<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum AdminUnsubscribeError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There is no subscriber associated with the provided email")]
+    UnknownSubscriber,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for AdminUnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for AdminUnsubscribeError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::ValidationError(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            Self::UnknownSubscriber => HttpResponse::new(StatusCode::NOT_FOUND),
+            Self::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber on behalf of an admin",
+    skip(admin, body, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn admin_unsubscribe(
+    admin: AdminUser,
+    body: web::Json<UnsubscribeBody>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AdminUnsubscribeError> {
+    admin.record_on_current_span();
+    let user_id = admin.user_id;
+
+    let UnsubscribeBody { email, reason } = body.0;
+    let email = SubscriberEmail::parse(email).map_err(AdminUnsubscribeError::ValidationError)?;
+    let reason = parse_reason(reason).map_err(AdminUnsubscribeError::ValidationError)?;
+    let (subscriber_id, status) = get_subscriber_by_email(&pool, &email)
+        .await
+        .context("Failed to retrieve the subscriber associated with the provided email")?
+        .ok_or(AdminUnsubscribeError::UnknownSubscriber)?;
+
+    if status != "unsubscribed" {
+        let mut transaction = pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool")?;
+        let metadata = serde_json::json!({
+            "from": status,
+            "source": "admin",
+            "user_id": user_id,
+        });
+        mark_subscriber_as_unsubscribed(&mut transaction, subscriber_id, metadata)
+            .await
+            .context("Failed to update the subscriber status to `unsubscribed`.")?;
+        record_unsubscribe_event(&mut transaction, subscriber_id, reason.as_deref())
+            .await
+            .context("Failed to record the unsubscribe event")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to unsubscribe a subscriber")?;
+        tracing::info!(
+            target: "audit",
+            event = "subscriber_unsubscribed",
+            %subscriber_id,
+            previous_status = %status,
+            %user_id,
+            "An admin unsubscribed a subscriber"
+        );
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Blank reasons count as no reason at all
+pub fn parse_reason(reason: Option<String>) -> Result<Option<String>, String> {
+    let Some(reason) = reason
+        .map(|r| r.trim().to_owned())
+        .filter(|r| !r.is_empty())
+    else {
+        return Ok(None);
+    };
+    if reason.chars().count() > MAX_REASON_LENGTH {
+        return Err(format!(
+            "The unsubscribe reason must be at most {} characters long",
+            MAX_REASON_LENGTH
+        ));
+    }
+    Ok(Some(reason))
+}
+
+#[tracing::instrument(name = "Get subscriber by email", skip(email, pool))]
+async fn get_subscriber_by_email(
+    pool: &PgPool,
+    email: &SubscriberEmail,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT id, status FROM subscriptions WHERE lower(email) = lower($1)"#,
+        email.as_ref()
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(result.map(|r| (r.id, r.status)))
+}
+
+#[tracing::instrument(
+    name = "Mark subscriber as unsubscribed",
+    skip(subscriber_id, transaction, metadata)
+)]
+pub async fn mark_subscriber_as_unsubscribed(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    metadata: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1"#,
+        subscriber_id,
+    );
+    transaction.execute(query).await?;
+    record_subscription_event(
+        transaction,
+        subscriber_id,
+        SubscriptionEvent::Unsubscribed,
+        metadata,
+    )
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Record unsubscribe event", skip(transaction, reason))]
+pub async fn record_unsubscribe_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    reason: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"INSERT INTO unsubscribe_events (id, subscriber_id, reason, unsubscribed_at) VALUES ($1, $2, $3, $4)"#,
+        Uuid::new_v4(),
+        subscriber_id,
+        reason,
+        Utc::now()
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
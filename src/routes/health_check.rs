@@ -1,5 +1,101 @@
-use actix_web::HttpResponse;
+use crate::email_client::EmailProvider;
+use crate::startup::{EmailReadinessCheck, StartedAt};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
 
+/// Don't let a probe hang on the pool's (much longer) acquire timeout
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum DependencyStatus {
+    Up,
+    Down,
+    /// Not checked, see `email_client.readiness_check_enabled`
+    Skipped,
+}
+
+#[derive(serde::Serialize)]
+struct Readiness {
+    postgres: DependencyStatus,
+    email_provider: DependencyStatus,
+}
+
+#[derive(serde::Serialize)]
+struct BuildInfo {
+    version: &'static str,
+    /// Set with `GIT_COMMIT` at build time, if it was
+    git_commit: Option<&'static str>,
+    uptime_seconds: u64,
+}
+
+/// Liveness: the process is up and serving requests
 pub async fn health_check() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
+
+/// Which build is deployed, and for how long it has been running
+pub async fn build_info(started_at: web::Data<StartedAt>) -> HttpResponse {
+    HttpResponse::Ok().json(BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("GIT_COMMIT"),
+        uptime_seconds: started_at.0.elapsed().as_secs(),
+    })
+}
+
+/// Readiness: the dependencies we need to serve traffic are reachable
+#[tracing::instrument(name = "Readiness check", skip(pool, email_client, email_check))]
+pub async fn readiness_check(
+    pool: web::Data<PgPool>,
+    email_client: web::Data<Arc<dyn EmailProvider>>,
+    email_check: web::Data<EmailReadinessCheck>,
+) -> HttpResponse {
+    let email_provider = async {
+        if email_check.0 {
+            check_email_provider(email_client.get_ref().as_ref()).await
+        } else {
+            DependencyStatus::Skipped
+        }
+    };
+    let (postgres, email_provider) = tokio::join!(check_postgres(&pool), email_provider);
+    let readiness = Readiness {
+        postgres,
+        email_provider,
+    };
+    if postgres == DependencyStatus::Down || email_provider == DependencyStatus::Down {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    } else {
+        HttpResponse::Ok().json(readiness)
+    }
+}
+
+async fn check_postgres(pool: &PgPool) -> DependencyStatus {
+    let ping = sqlx::query("SELECT 1").execute(pool);
+    match tokio::time::timeout(READINESS_TIMEOUT, ping).await {
+        Ok(Ok(_)) => DependencyStatus::Up,
+        Ok(Err(e)) => {
+            tracing::warn!(error.cause_chain = ?e, "Postgres is not reachable");
+            DependencyStatus::Down
+        }
+        Err(_) => {
+            tracing::warn!("Timed out waiting for Postgres");
+            DependencyStatus::Down
+        }
+    }
+}
+
+async fn check_email_provider(email_client: &dyn EmailProvider) -> DependencyStatus {
+    match tokio::time::timeout(READINESS_TIMEOUT, email_client.probe()).await {
+        Ok(Ok(())) => DependencyStatus::Up,
+        Ok(Err(e)) => {
+            tracing::warn!(error.cause_chain = ?e, "The email provider is not reachable");
+            DependencyStatus::Down
+        }
+        Err(_) => {
+            tracing::warn!("Timed out waiting for the email provider");
+            DependencyStatus::Down
+        }
+    }
+}
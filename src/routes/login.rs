@@ -0,0 +1,116 @@
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::routes::{error_chain_fmt, see_other};
+use crate::session_state::TypedSession;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use secrecy::Secret;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: Secret<String>,
+    csrf_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum LoginError {
+    #[error("The CSRF token is missing or invalid")]
+    InvalidCsrfToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for LoginError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::InvalidCsrfToken => StatusCode::BAD_REQUEST,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+pub async fn login_form(session: TypedSession) -> Result<HttpResponse, LoginError> {
+    let csrf_token = session.csrf_token()?;
+    // Flash messages are set by us, never taken from the request
+    let flash = session
+        .take_flash()?
+        .map(|message| format!("<p><i>{}</i></p>", message))
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Login</title>
+</head>
+<body>
+    {flash}
+    <form action="/login" method="post">
+        <input type="hidden" name="csrf_token" value="{csrf_token}">
+        <label>Username
+            <input type="text" placeholder="Enter Username" name="username">
+        </label>
+        <label>Password
+            <input type="password" placeholder="Enter Password" name="password">
+        </label>
+        <button type="submit">Login</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+/// Start an admin session. Wrong credentials send the user back to the form.
+#[tracing::instrument(
+    name = "Log in an admin",
+    skip(form, pool, session),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn login(
+    form: web::Form<LoginForm>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+) -> Result<HttpResponse, LoginError> {
+    let form = form.0;
+    let csrf_token_is_valid = session
+        .is_valid_csrf_token(&form.csrf_token)
+        .context("Failed to read the CSRF token from the session")?;
+    if !csrf_token_is_valid {
+        return Err(LoginError::InvalidCsrfToken);
+    }
+
+    let credentials = Credentials {
+        username: form.username,
+        password: form.password,
+    };
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+            session.renew();
+            session.reset_csrf_token();
+            session
+                .insert_user_id(user_id)
+                .context("Failed to store the user id in the session")?;
+            Ok(see_other("/admin/newsletters"))
+        }
+        Err(AuthError::InvalidCredentials(_)) => {
+            session
+                .set_flash("Authentication failed")
+                .context("Failed to store the flash message in the session")?;
+            Ok(see_other("/login"))
+        }
+        Err(e @ AuthError::UnexpectedError(_)) => Err(LoginError::UnexpectedError(e.into())),
+    }
+}
@@ -0,0 +1,9 @@
+use crate::metrics::Metrics;
+use actix_web::{web, HttpResponse};
+use prometheus::TEXT_FORMAT;
+
+pub async fn export_metrics(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(TEXT_FORMAT)
+        .body(metrics.render())
+}
@@ -1,12 +1,28 @@
 //! src/routes/mod.rs
+mod admin;
 mod error_chain_fmt;
 mod health_check;
+mod login;
+mod metrics;
 mod newsletter;
+mod password_reset;
+mod redirect;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_resend;
+mod subscriptions_status;
+mod unsubscribe;
 
+pub use admin::*;
 pub use error_chain_fmt::*;
 pub use health_check::*;
+pub use login::*;
+pub use metrics::*;
 pub use newsletter::*;
+pub use password_reset::*;
+pub use redirect::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_resend::*;
+pub use subscriptions_status::*;
+pub use unsubscribe::*;
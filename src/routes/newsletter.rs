@@ -1,40 +1,116 @@
-use crate::domain::SubscriberEmail;
-use crate::email_client::EmailClient;
-use crate::routes::error_chain_fmt;
-use crate::telemetry::spawn_blocking_with_traits;
-use actix_web::http::header::{HeaderMap, HeaderValue};
-use actix_web::http::{header, StatusCode};
+use crate::authentication::{AdminUser, PublishRealm};
+use crate::configuration::SubscriptionSettings;
+use crate::domain::{NewNewsletterIssue, NewsletterBody, SubscriberEmail};
+use crate::email_client::EmailProvider;
+use crate::idempotency::{save_response, try_processing, IdempotencyKey, NextAction};
+use crate::routes::{error_chain_fmt, hash_token, unsubscribe_link, unsubscribe_token};
+use crate::startup::{NewsletterSendConcurrency, RequestBaseUrl};
+use actix_web::http::StatusCode;
 use actix_web::web;
-use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::ResponseError;
 use anyhow::Context;
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use base64::Engine;
-use secrecy::ExposeSecret;
+use chrono::Utc;
+use futures::StreamExt;
 use secrecy::Secret;
 use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
 pub struct BodyData {
     title: String,
     content: Content,
+    /// Lets clients retry a submission without the issue going out twice
+    idempotency_key: Option<String>,
+    /// Only report how many subscribers would get the issue
+    #[serde(default)]
+    dry_run: bool,
 }
 
 #[derive(serde::Deserialize)]
-pub struct Content {
+pub struct PreviewData {
+    title: String,
+    content: Content,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Html {
+        html: String,
+        text: String,
+    },
+    /// Rendered to sanitized HTML and plain text before sending
+    Markdown {
+        markdown: String,
+    },
+}
+
+impl Content {
+    fn render(self) -> NewsletterBody {
+        match self {
+            Content::Html { html, text } => NewsletterBody { html, text },
+            Content::Markdown { markdown } => NewsletterBody::from_markdown(&markdown),
+        }
+    }
+}
+
+/// Render the issue and check it is fit to be sent
+pub(crate) fn parse_issue(title: String, content: Content) -> Result<NewNewsletterIssue, String> {
+    NewNewsletterIssue::parse(title, content.render()).map_err(|e| e.to_string())
+}
+
+#[derive(serde::Serialize)]
+struct PublishResponse {
+    newsletter_issue_id: Uuid,
+    #[serde(flatten)]
+    summary: DeliverySummary,
+}
+
+/// How a delivery went. Failed subscribers get no delivery row, so
+/// resending the issue tries them again.
+#[derive(serde::Serialize, Default)]
+pub(crate) struct DeliverySummary {
+    pub(crate) sent: usize,
+    pub(crate) failed: usize,
+}
+
+/// An issue exactly as subscribers would get it
+#[derive(serde::Serialize)]
+struct PreviewResponse {
+    title: String,
     html: String,
     text: String,
 }
 
+/// How many subscribers a dry run would send the issue to
+#[derive(serde::Serialize)]
+struct RecipientsResponse {
+    recipients: usize,
+}
+
+pub(crate) struct NewsletterIssue {
+    newsletter_issue_id: Uuid,
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
 struct ConfirmedSubscriber {
+    subscriber_id: Uuid,
     email: SubscriberEmail,
+    /// Subscribers only get an unsubscribe link with their confirmation
+    /// email
+    has_unsubscribe_token: bool,
 }
 
 #[derive(thiserror::Error)]
 pub enum PublishError {
-    #[error("Authentication failed")]
-    AuthError(#[source] anyhow::Error),
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There is no newsletter issue with this id")]
+    UnknownIssue,
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -48,182 +124,378 @@ impl std::fmt::Debug for PublishError {
 impl ResponseError for PublishError {
     fn error_response(&self) -> HttpResponse {
         match self {
+            PublishError::ValidationError(_) => {
+                HttpResponse::build(StatusCode::BAD_REQUEST).body(self.to_string())
+            }
+            PublishError::UnknownIssue => HttpResponse::new(StatusCode::NOT_FOUND),
             PublishError::UnexpectedError(_) => {
                 HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR)
             }
-            PublishError::AuthError(_) => {
-                let mut response = HttpResponse::new(StatusCode::UNAUTHORIZED);
-                let header_value = HeaderValue::from_str(r#"Basic realm="publish""#).unwrap();
-                response
-                    .headers_mut()
-                    .insert(header::WWW_AUTHENTICATE, header_value);
-                response
-            }
         }
     }
 }
 
-struct Credentials {
-    username: String,
-    password: Secret<String>,
-}
-
 #[tracing::instrument(
     name = "Publish a newsletter issue",
-    skip(body, pool, email_client, request),
+    skip(admin, body, pool, email_client, base_url, concurrency, settings),
     fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
 )]
 pub async fn publish_newsletter(
+    admin: AdminUser<PublishRealm>,
     body: web::Json<BodyData>,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
-    request: HttpRequest,
+    email_client: web::Data<Arc<dyn EmailProvider>>,
+    base_url: RequestBaseUrl,
+    concurrency: web::Data<NewsletterSendConcurrency>,
+    settings: web::Data<SubscriptionSettings>,
 ) -> Result<HttpResponse, PublishError> {
-    let credentials = basic_authentification(request.headers()).map_err(PublishError::AuthError)?;
-    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
-    let user_id = validate_credentials(credentials, &pool).await?;
-    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
-    let subscribers = get_confirmed_subscriber(&pool).await?;
-    for subscriber in subscribers {
-        match subscriber {
-            Ok(subscriber) => {
-                email_client
-                    .send_email(
-                        &subscriber.email,
-                        &body.title,
-                        &body.content.html,
-                        &body.content.text,
-                    )
-                    .await
-                    .with_context(|| {
-                        format!("Failed to send newsletter issue to {}", subscriber.email)
-                    })?;
-            }
-            Err(error) => {
-                tracing::warn!(error.cause_chain = ?error,
-                    "Skipping a confirmed subscriber. \
-                    Their stored contact details are invalid");
-            }
-        }
+    admin.record_on_current_span();
+    let user_id = admin.user_id;
+    let body = body.0;
+    let new_issue = parse_issue(body.title, body.content).map_err(PublishError::ValidationError)?;
+
+    if body.dry_run {
+        let recipients = get_confirmed_subscriber(&pool, None)
+            .await?
+            .iter()
+            .filter(|subscriber| subscriber.is_ok())
+            .count();
+        return Ok(HttpResponse::Ok().json(RecipientsResponse { recipients }));
     }
-    Ok(HttpResponse::Ok().finish())
+
+    let Some(idempotency_key) = body.idempotency_key else {
+        let issue = insert_newsletter_issue(&pool, new_issue).await?;
+        let summary = deliver_issue(
+            &issue,
+            &pool,
+            email_client.get_ref().as_ref(),
+            &base_url.0,
+            &settings.unsubscribe_token_key,
+            concurrency.0,
+        )
+        .await?;
+        return Ok(HttpResponse::Ok().json(PublishResponse {
+            newsletter_issue_id: issue.newsletter_issue_id,
+            summary,
+        }));
+    };
+    let idempotency_key: IdempotencyKey = idempotency_key
+        .try_into()
+        .map_err(PublishError::ValidationError)?;
+    let transaction = match try_processing(&pool, &idempotency_key, user_id).await? {
+        NextAction::StartProcessing(transaction) => transaction,
+        NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+    };
+    let issue = insert_newsletter_issue(&pool, new_issue).await?;
+    let summary = deliver_issue(
+        &issue,
+        &pool,
+        email_client.get_ref().as_ref(),
+        &base_url.0,
+        &settings.unsubscribe_token_key,
+        concurrency.0,
+    )
+    .await?;
+    let response = save_response(
+        transaction,
+        &idempotency_key,
+        user_id,
+        HttpResponse::Ok().json(PublishResponse {
+            newsletter_issue_id: issue.newsletter_issue_id,
+            summary,
+        }),
+    )
+    .await?;
+    Ok(response)
 }
 
-#[tracing::instrument(name = "Get confirmed Subscribers", skip(pool))]
-async fn get_confirmed_subscriber(
+/// Render an issue the way publishing it would, without storing or sending it
+#[tracing::instrument(
+    name = "Preview a newsletter issue",
+    skip(admin, body),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn preview_newsletter(
+    admin: AdminUser<PublishRealm>,
+    body: web::Json<PreviewData>,
+) -> Result<HttpResponse, PublishError> {
+    admin.record_on_current_span();
+    let body = body.0;
+    let issue = parse_issue(body.title, body.content).map_err(PublishError::ValidationError)?;
+    Ok(HttpResponse::Ok().json(PreviewResponse {
+        title: issue.title,
+        html: issue.body.html,
+        text: issue.body.text,
+    }))
+}
+
+/// Send an already published issue to the confirmed subscribers who haven't
+/// received it yet, e.g. those who confirmed after it went out
+#[tracing::instrument(
+    name = "Resend a newsletter issue",
+    skip(admin, pool, email_client, base_url, concurrency, settings),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn resend_newsletter(
+    admin: AdminUser<PublishRealm>,
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<Arc<dyn EmailProvider>>,
+    base_url: RequestBaseUrl,
+    concurrency: web::Data<NewsletterSendConcurrency>,
+    settings: web::Data<SubscriptionSettings>,
+) -> Result<HttpResponse, PublishError> {
+    admin.record_on_current_span();
+
+    let issue = get_newsletter_issue(&pool, *newsletter_issue_id)
+        .await
+        .context("Failed to retrieve the newsletter issue")?
+        .ok_or(PublishError::UnknownIssue)?;
+    let summary = deliver_issue(
+        &issue,
+        &pool,
+        email_client.get_ref().as_ref(),
+        &base_url.0,
+        &settings.unsubscribe_token_key,
+        concurrency.0,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+#[tracing::instrument(name = "Store a newsletter issue", skip_all)]
+pub(crate) async fn insert_newsletter_issue(
     pool: &PgPool,
-) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
-    let confirmed_subscribers =
-        sqlx::query!(r#"SELECT email FROM subscriptions WHERE status = 'confirmed'"#,)
-            .fetch_all(pool)
-            .await?
-            .into_iter()
-            .map(|r| match SubscriberEmail::parse(r.email) {
-                Ok(email) => Ok(ConfirmedSubscriber { email }),
-                Err(error) => Err(anyhow::anyhow!(error)),
-            })
-            .collect();
-    Ok(confirmed_subscribers)
+    new_issue: NewNewsletterIssue,
+) -> Result<NewsletterIssue, anyhow::Error> {
+    let issue = NewsletterIssue {
+        newsletter_issue_id: Uuid::new_v4(),
+        title: new_issue.title,
+        text_content: new_issue.body.text,
+        html_content: new_issue.body.html,
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues
+            (newsletter_issue_id, title, text_content, html_content, published_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        issue.newsletter_issue_id,
+        issue.title,
+        issue.text_content,
+        issue.html_content,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the newsletter issue")?;
+    Ok(issue)
 }
 
-fn basic_authentification(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
-    let header_value = headers
-        .get("Authorization")
-        .context("The 'Authorization' Header was missing")?
-        .to_str()
-        .context("The 'Authorization' header was not a valid UTF-8 string.")?;
-    let base64encodedu_segment = header_value
-        .strip_prefix("Basic ")
-        .context("The Authorization schema was not 'Basic '")?;
-    let decoded_bytes = base64::engine::general_purpose::STANDARD
-        .decode(base64encodedu_segment)
-        .context("Failed to base64-decode 'Basis ' Credentials")?;
-    let decoded_credentials = String::from_utf8(decoded_bytes)
-        .context("The decoded Credentials string is not valid UTF-8.")?;
-
-    let mut credentials = decoded_credentials.splitn(2, ':');
-    let username = credentials
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basis ' auth."))?
-        .to_string();
-    let password = credentials
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basis ' auth."))?
-        .to_string();
-
-    Ok(Credentials {
-        username,
-        password: Secret::new(password),
-    })
+#[tracing::instrument(name = "Get a newsletter issue", skip(pool))]
+async fn get_newsletter_issue(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<Option<NewsletterIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+        SELECT newsletter_issue_id, title, text_content, html_content
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool)
+    .await
 }
 
-#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
-async fn validate_credentials(
-    credentials: Credentials,
+/// Send `issue` to every confirmed subscriber without a delivery row for it,
+/// recording one after each send. Up to `concurrency` emails are sent at
+/// once, and a failed one doesn't stop the others.
+pub(crate) async fn deliver_issue(
+    issue: &NewsletterIssue,
     pool: &PgPool,
-) -> Result<uuid::Uuid, PublishError> {
-    let mut user_id = None;
-    let mut expected_password_hash = Secret::new(
-        "$argon2id$v=19$m=15000,t=2,p=1$\
-        gZiV/M1gPc22ElAH/Jh1Hw$\
-        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
-            .to_string(),
-    );
-
-    if let Some((stored_user_id, stored_password_hash)) =
-        get_stored_credentials(&credentials.username, pool)
-            .await
-            .map_err(PublishError::UnexpectedError)?
-    {
-        user_id = Some(stored_user_id);
-        expected_password_hash = stored_password_hash;
+    email_client: &dyn EmailProvider,
+    base_url: &str,
+    unsubscribe_token_key: &Secret<String>,
+    concurrency: usize,
+) -> Result<DeliverySummary, PublishError> {
+    let subscribers: Vec<_> = get_confirmed_subscriber(pool, Some(issue.newsletter_issue_id))
+        .await?
+        .into_iter()
+        .filter_map(|subscriber| match subscriber {
+            Ok(subscriber) => Some(subscriber),
+            Err(error) => {
+                tracing::warn!(error.cause_chain = ?error,
+                    "Skipping a confirmed subscriber. \
+                    Their stored contact details are invalid");
+                None
+            }
+        })
+        .collect();
+    let with_unsubscribe_token: Vec<Uuid> = subscribers
+        .iter()
+        .filter(|subscriber| subscriber.has_unsubscribe_token)
+        .map(|subscriber| subscriber.subscriber_id)
+        .collect();
+    store_derived_unsubscribe_tokens(pool, unsubscribe_token_key, &with_unsubscribe_token)
+        .await
+        .context("Failed to store the unsubscribe tokens")?;
+    let summary = futures::stream::iter(subscribers)
+        .map(|subscriber| {
+            deliver_to_subscriber(
+                issue,
+                pool,
+                email_client,
+                base_url,
+                unsubscribe_token_key,
+                subscriber,
+            )
+        })
+        .buffer_unordered(concurrency)
+        .fold(
+            DeliverySummary::default(),
+            |mut summary, delivered| async move {
+                if delivered {
+                    summary.sent += 1;
+                } else {
+                    summary.failed += 1;
+                }
+                summary
+            },
+        )
+        .await;
+    if summary.failed > 0 {
+        tracing::warn!(
+            sent = summary.sent,
+            failed = summary.failed,
+            "Some subscribers didn't get the newsletter issue"
+        );
     }
+    Ok(summary)
+}
 
-    spawn_blocking_with_traits(move || {
-        verify_password_hash(expected_password_hash, credentials.password)
-    })
-    .await
-    .context("Failed to spawn blocking thread")
-    .map_err(PublishError::UnexpectedError)??;
+/// Whether the subscriber got the issue, failures are logged
+async fn deliver_to_subscriber(
+    issue: &NewsletterIssue,
+    pool: &PgPool,
+    email_client: &dyn EmailProvider,
+    base_url: &str,
+    unsubscribe_token_key: &Secret<String>,
+    subscriber: ConfirmedSubscriber,
+) -> bool {
+    let list_unsubscribe = subscriber.has_unsubscribe_token.then(|| {
+        let token = unsubscribe_token(unsubscribe_token_key, subscriber.subscriber_id);
+        unsubscribe_link(base_url, &token)
+    });
+    let outcome = async {
+        email_client
+            .send(
+                &subscriber.email,
+                &issue.title,
+                Some(&issue.html_content),
+                &issue.text_content,
+                list_unsubscribe.as_deref(),
+            )
+            .await
+            .context("Failed to send the newsletter issue")?;
+        record_delivery(pool, issue.newsletter_issue_id, subscriber.subscriber_id)
+            .await
+            .context("Failed to record a newsletter delivery")
+    }
+    .await;
+    match outcome {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                recipient = %subscriber.email.obfuscated(),
+                "Failed to deliver a newsletter issue"
+            );
+            false
+        }
+    }
+}
 
-    user_id.ok_or_else(|| PublishError::AuthError(anyhow::anyhow!("Unknown username")))
+/// Subscribers whose unsubscribe token was random, from before tokens were
+/// derived, only have the hash of that one stored
+async fn store_derived_unsubscribe_tokens(
+    pool: &PgPool,
+    unsubscribe_token_key: &Secret<String>,
+    subscriber_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    let hashes: Vec<String> = subscriber_ids
+        .iter()
+        .map(|id| hash_token(&unsubscribe_token(unsubscribe_token_key, *id)))
+        .collect();
+    sqlx::query!(
+        r#"
+        INSERT INTO unsubscribe_tokens (unsubscribe_token_hash, subscriber_id)
+        SELECT * FROM UNNEST($1::text[], $2::uuid[])
+        ON CONFLICT (unsubscribe_token_hash) DO NOTHING
+        "#,
+        &hashes,
+        subscriber_ids
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
 }
 
-#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
-async fn get_stored_credentials(
-    username: &str,
+async fn record_delivery(
     pool: &PgPool,
-) -> Result<Option<(uuid::Uuid, Secret<String>)>, anyhow::Error> {
-    let row = sqlx::query!(
-        r#"SELECT user_id, password_hash
-        FROM users
-        WHERE username = $1
+    newsletter_issue_id: Uuid,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_delivery (newsletter_issue_id, subscriber_id, delivered_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
         "#,
-        username
+        newsletter_issue_id,
+        subscriber_id,
+        Utc::now()
     )
-    .fetch_optional(pool)
-    .await
-    .context("Failed to perform a query to retrieve stored credentials.")?
-    .map(|row| (row.user_id, Secret::new(row.password_hash)));
-    Ok(row)
+    .execute(pool)
+    .await?;
+    Ok(())
 }
 
-#[tracing::instrument(
-    name = "Verify password hash",
-    skip(expected_password_hash, password_candidate)
-)]
-fn verify_password_hash(
-    expected_password_hash: Secret<String>,
-    password_candidate: Secret<String>,
-) -> Result<(), PublishError> {
-    let expected_password_hash = PasswordHash::new(&expected_password_hash.expose_secret())
-        .map_err(|e| PublishError::UnexpectedError(anyhow::anyhow!(e)))?;
-
-    Argon2::default()
-        .verify_password(
-            password_candidate.expose_secret().as_bytes(),
-            &expected_password_hash,
+/// All confirmed subscribers, or only those who haven't received
+/// `undelivered_issue_id` yet
+#[tracing::instrument(name = "Get confirmed Subscribers", skip(pool))]
+async fn get_confirmed_subscriber(
+    pool: &PgPool,
+    undelivered_issue_id: Option<Uuid>,
+) -> Result<Vec<Result<ConfirmedSubscriber, anyhow::Error>>, anyhow::Error> {
+    let confirmed_subscribers = sqlx::query!(
+        r#"
+        SELECT id, email, EXISTS (
+            SELECT 1 FROM unsubscribe_tokens WHERE subscriber_id = subscriptions.id
+        ) AS "has_unsubscribe_token!"
+        FROM subscriptions
+        WHERE status = 'confirmed'
+        AND NOT EXISTS (
+            SELECT 1 FROM newsletter_delivery
+            WHERE newsletter_issue_id = $1 AND subscriber_id = subscriptions.id
         )
-        .context("Invalid password")
-        .map_err(PublishError::AuthError)
+        "#,
+        undelivered_issue_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| match SubscriberEmail::parse(r.email) {
+        Ok(email) => Ok(ConfirmedSubscriber {
+            subscriber_id: r.id,
+            email,
+            has_unsubscribe_token: r.has_unsubscribe_token,
+        }),
+        Err(error) => Err(anyhow::anyhow!(error)),
+    })
+    .collect();
+    Ok(confirmed_subscribers)
 }
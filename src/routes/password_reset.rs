@@ -0,0 +1,335 @@
+use crate::authentication::{change_password, validate_new_password};
+use crate::domain::SubscriberEmail;
+use crate::email_outbox::{enqueue_email, EmailContent, EmailKind};
+use crate::routes::{error_chain_fmt, hash_token, see_other};
+use crate::session_state::TypedSession;
+use crate::startup::{PasswordResetTokenTtl, RequestBaseUrl};
+use crate::telemetry::RequestId;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use secrecy::Secret;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct ForgotPasswordForm {
+    email: String,
+    csrf_token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetPasswordParameters {
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetPasswordForm {
+    token: String,
+    new_password: Secret<String>,
+    new_password_check: Secret<String>,
+    csrf_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum PasswordResetError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("The CSRF token is missing or invalid")]
+    InvalidCsrfToken,
+    #[error("There is no password reset associated with the provided token")]
+    UnknownToken,
+    #[error("The provided token has expired")]
+    ExpiredToken,
+    #[error("The provided token has already been used")]
+    UsedToken,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PasswordResetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PasswordResetError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::ValidationError(_) | Self::InvalidCsrfToken => StatusCode::BAD_REQUEST,
+            Self::UnknownToken => StatusCode::UNAUTHORIZED,
+            Self::ExpiredToken | Self::UsedToken => StatusCode::GONE,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+fn check_csrf_token(session: &TypedSession, csrf_token: &str) -> Result<(), PasswordResetError> {
+    let csrf_token_is_valid = session
+        .is_valid_csrf_token(csrf_token)
+        .context("Failed to read the CSRF token from the session")?;
+    if !csrf_token_is_valid {
+        return Err(PasswordResetError::InvalidCsrfToken);
+    }
+    Ok(())
+}
+
+pub async fn forgot_password_form(
+    session: TypedSession,
+) -> Result<HttpResponse, PasswordResetError> {
+    let csrf_token = session.csrf_token()?;
+    // Flash messages are set by us, never taken from the request
+    let flash = session
+        .take_flash()?
+        .map(|message| format!("<p><i>{}</i></p>", message))
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Forgot Password</title>
+</head>
+<body>
+    {flash}
+    <form action="/password/forgot" method="post">
+        <input type="hidden" name="csrf_token" value="{csrf_token}">
+        <label>Email
+            <input type="email" placeholder="Enter your email address" name="email">
+        </label>
+        <button type="submit">Send a reset link</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+/// Email a one-time reset link to the admin with this address. The response
+/// is the same for unknown addresses, so it doesn't reveal who is an admin.
+#[tracing::instrument(
+    name = "Request a password reset",
+    skip(form, pool, base_url, ttl, session, request_id),
+    fields(user_id=tracing::field::Empty)
+)]
+pub async fn forgot_password(
+    form: web::Form<ForgotPasswordForm>,
+    pool: web::Data<PgPool>,
+    base_url: RequestBaseUrl,
+    ttl: web::Data<PasswordResetTokenTtl>,
+    session: TypedSession,
+    request_id: RequestId,
+) -> Result<HttpResponse, PasswordResetError> {
+    let form = form.0;
+    check_csrf_token(&session, &form.csrf_token)?;
+    let email = SubscriberEmail::parse(form.email).map_err(PasswordResetError::ValidationError)?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let user_id = get_user_id_by_email(&mut transaction, &email)
+        .await
+        .context("Failed to look up the user")?;
+    if let Some(user_id) = user_id {
+        tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+        let reset_token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        store_reset_token(&mut transaction, user_id, &reset_token, ttl.0)
+            .await
+            .context("Failed to store the password reset token")?;
+        let reset_link = format!("{}/password/reset?token={}", base_url.0, reset_token);
+        let content = EmailContent {
+            kind: EmailKind::PasswordReset,
+            subject: "Reset your password".into(),
+            html_body: None,
+            text_body: format!(
+                "Visit {} to choose a new password.\n\
+                The link works once, within {} minutes. \
+                If you didn't ask for it, you can ignore this email.",
+                reset_link,
+                ttl.0.num_minutes()
+            ),
+            list_unsubscribe: None,
+        };
+        enqueue_email(&mut transaction, &email, &content, Some(&request_id))
+            .await
+            .context("Failed to enqueue the password reset email")?;
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to request a password reset")?;
+
+    session
+        .set_flash("If the address belongs to an admin, a reset link is on its way")
+        .context("Failed to store the flash message in the session")?;
+    Ok(see_other("/password/forgot"))
+}
+
+pub async fn reset_password_form(
+    parameters: web::Query<ResetPasswordParameters>,
+    session: TypedSession,
+) -> Result<HttpResponse, PasswordResetError> {
+    let csrf_token = session.csrf_token()?;
+    // Only ever hex, anything else can't be a token we issued
+    if !parameters.token.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(PasswordResetError::UnknownToken);
+    }
+    let token = &parameters.token;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Reset Password</title>
+</head>
+<body>
+    <form action="/password/reset" method="post">
+        <input type="hidden" name="csrf_token" value="{csrf_token}">
+        <input type="hidden" name="token" value="{token}">
+        <label>New password
+            <input type="password" placeholder="Enter new password" name="new_password">
+        </label>
+        <label>Confirm new password
+            <input type="password" placeholder="Type the new password again" name="new_password_check">
+        </label>
+        <button type="submit">Reset password</button>
+    </form>
+</body>
+</html>"#,
+        )))
+}
+
+/// Set a new password with a token from a reset email. Every token works
+/// once, and using one also uses up any others the admin asked for.
+#[tracing::instrument(
+    name = "Reset a password",
+    skip(form, pool, session),
+    fields(user_id=tracing::field::Empty)
+)]
+pub async fn reset_password(
+    form: web::Form<ResetPasswordForm>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+) -> Result<HttpResponse, PasswordResetError> {
+    let form = form.0;
+    check_csrf_token(&session, &form.csrf_token)?;
+    validate_new_password(&form.new_password, &form.new_password_check)
+        .map_err(PasswordResetError::ValidationError)?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let token = get_reset_token(&mut transaction, &form.token)
+        .await
+        .context("Failed to retrieve the password reset token")?
+        .ok_or(PasswordResetError::UnknownToken)?;
+    tracing::Span::current().record("user_id", tracing::field::display(&token.user_id));
+    if token.used_at.is_some() {
+        return Err(PasswordResetError::UsedToken);
+    }
+    if token.expires_at <= Utc::now() {
+        return Err(PasswordResetError::ExpiredToken);
+    }
+    use_reset_tokens(&mut transaction, token.user_id)
+        .await
+        .context("Failed to mark the password reset tokens as used")?;
+    // The token is used up first, if changing the password fails after that
+    // the admin asks for a new one
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to reset a password")?;
+    change_password(token.user_id, form.new_password, &pool).await?;
+
+    session
+        .set_flash("Your password has been reset, log in with the new one")
+        .context("Failed to store the flash message in the session")?;
+    Ok(see_other("/login"))
+}
+
+struct ResetToken {
+    user_id: Uuid,
+    expires_at: DateTime<Utc>,
+    used_at: Option<DateTime<Utc>>,
+}
+
+#[tracing::instrument(name = "Get user id by email", skip(transaction, email))]
+async fn get_user_id_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id FROM users WHERE lower(email) = lower($1)"#,
+        email.as_ref()
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    Ok(row.map(|row| row.user_id))
+}
+
+#[tracing::instrument(name = "Store password reset token", skip(transaction, reset_token))]
+async fn store_reset_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    reset_token: &str,
+    ttl: chrono::Duration,
+) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO password_reset_tokens (token_hash, user_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        hash_token(reset_token),
+        user_id,
+        now,
+        now + ttl
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// Locked, so the same token can't be used by two concurrent requests
+#[tracing::instrument(name = "Get password reset token", skip(transaction, reset_token))]
+async fn get_reset_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    reset_token: &str,
+) -> Result<Option<ResetToken>, sqlx::Error> {
+    sqlx::query_as!(
+        ResetToken,
+        r#"
+        SELECT user_id, expires_at, used_at
+        FROM password_reset_tokens
+        WHERE token_hash = $1
+        FOR UPDATE
+        "#,
+        hash_token(reset_token)
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
+
+async fn use_reset_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        UPDATE password_reset_tokens
+        SET used_at = $2
+        WHERE user_id = $1 AND used_at IS NULL
+        "#,
+        user_id,
+        Utc::now()
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
@@ -0,0 +1,10 @@
+//! src/routes/redirect.rs
+use actix_web::http::header::LOCATION;
+use actix_web::HttpResponse;
+
+/// Redirect a form submission, the browser follows up with a GET
+pub fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
@@ -1,107 +1,413 @@
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
-use crate::email_client::EmailClient;
-use crate::routes::error_chain_fmt;
-use crate::startup::ApplicationBaseUrl;
+use crate::configuration::{SubscriberIdentifier, SubscriptionSettings};
+use crate::domain::{
+    NewSubscriber, SubscriberEmail, SubscriberName, SubscriberValidationError, SubscriptionToken,
+};
+use crate::email_outbox::{enqueue_email, EmailContent, EmailKind};
+use crate::locale::requested_locale;
+use crate::mail_domain::check_mail_domain;
+use crate::retry::{is_unique_violation, retry_transient, AttemptError};
+use crate::routes::{confirm_subscriber, error_chain_fmt};
+use crate::signed_token::SignedToken;
+use crate::startup::{ClientIp, ConfirmationEmailOptions, MailDomainCheck, RequestBaseUrl};
+use crate::subscription_events::{record_subscription_event, SubscriptionEvent};
+use crate::telemetry::RequestId;
+use actix_web::dev::Payload;
+use actix_web::http::header::REFERER;
 use actix_web::http::StatusCode;
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
 use anyhow::Context;
 use chrono::Utc;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use futures::future::{ready, LocalBoxFuture};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
 use sqlx::postgres::PgRow;
-use sqlx::{Executor, PgPool, Postgres, Row, Transaction};
+use sqlx::{Acquire, Executor, PgPool, Postgres, Row, Transaction};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Formatter;
+use std::net::IpAddr;
 use tera::Tera;
 use uuid::Uuid;
 
+/// The Tera template the confirmation email's HTML part is rendered from,
+/// unless there is a `hello_email.{locale}.html` for the subscriber's locale
+pub const CONFIRMATION_EMAIL_TEMPLATE: &str = "hello_email.html";
+
+/// Fresh tokens tried when a new one is already taken, before giving up
+const MAX_TOKEN_ATTEMPTS: usize = 3;
+
+/// Longer consent sources aren't stored
+const MAX_CONSENT_SOURCE_LENGTH: usize = 2048;
+
+/// The raw fields of a subscription, from a urlencoded form or a JSON object
+/// of strings. Any other content type is rejected with a 415.
+pub struct SubscriptionFields(Vec<(String, String)>);
+
+impl FromRequest for SubscriptionFields {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        match req.content_type() {
+            "application/x-www-form-urlencoded" => {
+                let form = web::Form::<Vec<(String, String)>>::from_request(req, payload);
+                Box::pin(async move { Ok(Self(form.await?.0)) })
+            }
+            "application/json" => {
+                let json = web::Json::<HashMap<String, String>>::from_request(req, payload);
+                Box::pin(async move { Ok(Self(json.await?.0.into_iter().collect())) })
+            }
+            content_type => {
+                let e = actix_web::error::ErrorUnsupportedMediaType(format!(
+                    "{:?} is not supported, send a form or JSON",
+                    content_type
+                ));
+                Box::pin(ready(Err(e)))
+            }
+        }
+    }
+}
+
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
     name: String,
+    /// Which language to send the confirmation email in, e.g. `fr-CA`
+    locale: Option<String>,
+    /// The URL of the page the form is on, the `Referer` if not given
+    consent_source: Option<String>,
+}
+
+impl FormData {
+    /// Build the form from its raw fields, renaming aliased field names
+    /// (e.g. `email_address`) to our canonical ones first.
+    pub fn from_fields(
+        fields: Vec<(String, String)>,
+        field_map: &HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let mut email = None;
+        let mut name = None;
+        let mut locale = None;
+        let mut consent_source = None;
+        for (key, value) in fields {
+            match field_map.get(&key).unwrap_or(&key).as_str() {
+                "email" => email = Some(value),
+                "name" => name = Some(value),
+                "locale" => locale = Some(value),
+                "consent_source" => consent_source = Some(value),
+                _ => {}
+            }
+        }
+        Ok(FormData {
+            email: email.ok_or("missing field `email`")?,
+            name: name.ok_or("missing field `name`")?,
+            locale,
+            consent_source,
+        })
+    }
 }
 
 impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+    type Error = SubscriberValidationError;
 
     fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(NewSubscriber { email, name })
+        let name =
+            SubscriberName::parse(value.name).map_err(SubscriberValidationError::InvalidName)?;
+        let email =
+            SubscriberEmail::parse(value.email).map_err(SubscriberValidationError::InvalidEmail)?;
+        Ok(NewSubscriber {
+            email,
+            name,
+            consent_source: value.consent_source.and_then(valid_consent_source),
+            consent_ip: None,
+        })
     }
 }
 
+/// An http(s) URL of reasonable length, anything else isn't worth keeping
+fn valid_consent_source(source: String) -> Option<String> {
+    let url = url::Url::parse(&source).ok()?;
+    (matches!(url.scheme(), "http" | "https") && source.len() <= MAX_CONSENT_SOURCE_LENGTH)
+        .then_some(source)
+}
+
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(
+        form,
+        pool,
+        base_url,
+        email_options,
+        mail_domain_check,
+        settings,
+        identifier,
+        client_ip,
+        request
+    ),
     fields(
-        subscriber_email = %form.email,
-        subscriber_name = %form.name
+        subscriber_id = tracing::field::Empty,
+        subscriber_email = tracing::field::Empty,
+        subscriber_name = tracing::field::Empty
     )
 )]
 pub async fn subscribe(
-    form: web::Form<FormData>,
+    form: SubscriptionFields,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
-    base_url: web::Data<ApplicationBaseUrl>,
+    base_url: RequestBaseUrl,
+    email_options: web::Data<ConfirmationEmailOptions>,
+    mail_domain_check: web::Data<MailDomainCheck>,
+    settings: web::Data<SubscriptionSettings>,
+    identifier: web::Data<SubscriberIdentifier>,
+    client_ip: ClientIp,
+    request: HttpRequest,
 ) -> Result<HttpResponse, SubscribeError> {
-    let new_subscriber = form.0.try_into().map_err(SubscribeError::ValidationError)?;
+    let form = FormData::from_fields(form.0, &settings.form_field_map)
+        .map_err(SubscribeError::ValidationError)?;
+    let locale = requested_locale(form.locale.as_deref(), request.headers());
+    let mut new_subscriber: NewSubscriber = form
+        .try_into()
+        .map_err(|e: SubscriberValidationError| SubscribeError::ValidationError(e.to_string()))?;
+    if new_subscriber.consent_source.is_none() {
+        new_subscriber.consent_source = request
+            .headers()
+            .get(REFERER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|referer| valid_consent_source(referer.to_string()));
+    }
+    new_subscriber.consent_ip = client_ip.0;
+
+    if let (Some(ip_cap), Some(client_ip)) = (&settings.ip_cap, client_ip.0) {
+        let recent = count_recent_subscriptions(&pool, client_ip, ip_cap.window())
+            .await
+            .context("Failed to count the recent subscriptions from the client")?;
+        if recent >= i64::from(ip_cap.max_subscriptions) {
+            tracing::warn!(client = %client_ip, "Subscription cap per IP reached");
+            return Err(SubscribeError::TooManySubscriptions);
+        }
+    }
+    let loggable_email = identifier.loggable_email(&new_subscriber.email);
+    if let Some(email) = &loggable_email {
+        tracing::Span::current().record("subscriber_email", tracing::field::display(email));
+    }
+    if identifier.includes_name() {
+        tracing::Span::current().record(
+            "subscriber_name",
+            tracing::field::display(new_subscriber.name.as_ref()),
+        );
+    }
 
-    let mut transaction = pool
-        .begin()
-        .await
-        .context("Failed to acquire a Postgres connection from the pool")?;
+    if let Some(resolver) = &mail_domain_check.0 {
+        check_mail_domain(resolver.as_ref(), &new_subscriber.email)
+            .await
+            .map_err(SubscribeError::ValidationError)?;
+    }
 
-    let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
-        .await
-        .context("Failed to insert new subscriber in the database.")?;
+    let correlation_id = RequestId::of(&request);
+    let subscription = retry_transient(settings.transaction_retries, || {
+        store_new_subscriber(
+            &pool,
+            &new_subscriber,
+            &base_url.0,
+            &email_options,
+            locale.as_deref(),
+            &settings,
+            correlation_id.as_ref(),
+        )
+    })
+    .await?;
+    let (Subscription::PendingConfirmation(subscriber_id)
+    | Subscription::Confirmed(subscriber_id)
+    | Subscription::AlreadyConfirmed(subscriber_id)) = subscription;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+
+    match subscription {
+        Subscription::PendingConfirmation(_) | Subscription::Confirmed(_) => {
+            Ok(HttpResponse::Ok().finish())
+        }
+        Subscription::AlreadyConfirmed(_) => Err(SubscribeError::AlreadyConfirmed),
+    }
+}
+
+/// New subscribers who signed up from `client_ip` within `window`
+async fn count_recent_subscriptions(
+    pool: &PgPool,
+    client_ip: IpAddr,
+    window: chrono::Duration,
+) -> Result<i64, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"SELECT count(*) AS "count!" FROM subscriptions WHERE consent_ip = $1 AND subscribed_at > $2"#,
+        client_ip.to_string(),
+        Utc::now() - window
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(record.count)
+}
+
+/// The subscriber a subscription ended up with
+pub enum Subscription {
+    /// A new or returning subscriber, sent a fresh confirmation email
+    PendingConfirmation(Uuid),
+    /// Confirmed right away, as confirmation isn't required
+    Confirmed(Uuid),
+    /// Nothing was changed and no email was sent
+    AlreadyConfirmed(Uuid),
+}
 
-    let subscription_token = generate_subscription_token();
+/// Store the subscriber, a fresh confirmation token, their unsubscribe token
+/// and the confirmation email for the outbox in one transaction.
+/// Subscribers who already confirmed are left alone. Without
+/// `require_confirmation` the subscriber is confirmed instead, with neither
+/// a confirmation token nor an email.
+async fn store_new_subscriber(
+    pool: &PgPool,
+    new_subscriber: &NewSubscriber,
+    base_url: &str,
+    email_options: &ConfirmationEmailOptions,
+    locale: Option<&str>,
+    settings: &SubscriptionSettings,
+    correlation_id: Option<&RequestId>,
+) -> Result<Subscription, AttemptError> {
+    let mut transaction = pool.begin().await.map_err(|e| {
+        AttemptError::from_sqlx(e, "Failed to acquire a Postgres connection from the pool")
+    })?;
 
-    store_token(&mut transaction, subscriber_id, &subscription_token)
+    // Two identical signups at once both find no subscriber, the one that
+    // inserts second sees the other's row when it tries again
+    let subscription = insert_subscriber(&mut transaction, new_subscriber)
         .await
-        .context("Failed to store the confirmation token for a new subscriber")?;
+        .map_err(|e| {
+            AttemptError::from_racing_sqlx(e, "Failed to insert new subscriber in the database.")
+        })?;
+    let subscriber_id = match subscription {
+        Subscription::PendingConfirmation(subscriber_id) => subscriber_id,
+        Subscription::Confirmed(_) | Subscription::AlreadyConfirmed(_) => return Ok(subscription),
+    };
 
-    transaction
-        .commit()
+    let unsubscribe_token = store_unsubscribe_token(
+        &mut transaction,
+        subscriber_id,
+        &settings.unsubscribe_token_key,
+    )
+    .await
+    .map_err(|e| {
+        AttemptError::from_sqlx(
+            e,
+            "Failed to store the unsubscribe token for a new subscriber",
+        )
+    })?;
+
+    if !settings.require_confirmation {
+        confirm_subscriber(&mut transaction, subscriber_id)
+            .await
+            .map_err(|e| AttemptError::from_sqlx(e, "Failed to confirm a new subscriber"))?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to store a new subscriber")
+            .map_err(AttemptError::Permanent)?;
+        return Ok(Subscription::Confirmed(subscriber_id));
+    }
+
+    let subscription_token = issue_confirmation_token(&mut transaction, subscriber_id, settings)
         .await
-        .context("Failed to commit SQL transaction to store a new subscriber")?;
+        .map_err(|e| {
+            AttemptError::from_sqlx(
+                e.0,
+                "Failed to store the confirmation token for a new subscriber",
+            )
+        })?;
 
-    send_confirmation_email(
-        &email_client,
-        new_subscriber,
-        &base_url.0,
+    let email = confirmation_email(
+        new_subscriber.name.as_ref(),
+        base_url,
         &subscription_token,
+        &unsubscribe_token,
+        &email_options.subject,
+        email_options.templates.as_ref(),
+        locale,
+    );
+    enqueue_email(
+        &mut transaction,
+        &new_subscriber.email,
+        &email,
+        correlation_id,
     )
     .await
-    .context("Failed to send a confirmation email")?;
+    .map_err(|e| AttemptError::from_sqlx(e, "Failed to enqueue the confirmation email"))?;
 
-    Ok(HttpResponse::Ok().finish())
+    // If the commit itself fails we can't know whether it went through,
+    // so it is never retried
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a new subscriber")
+        .map_err(AttemptError::Permanent)?;
+
+    Ok(subscription)
 }
 
 #[tracing::instrument(
-    name= "Send a confirmation email to a new subscriber"
-    skip(email_client, new_subscriber, base_url)
+    name = "Render the confirmation email for a new subscriber",
+    skip(subscriber_name, base_url, unsubscribe_token, subject, templates)
 )]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
+pub fn confirmation_email(
+    subscriber_name: &str,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+    unsubscribe_token: &str,
+    subject: &str,
+    // Plain text only without templates
+    templates: Option<&Tera>,
+    locale: Option<&str>,
+) -> EmailContent {
     // Email
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token,
     );
-    let plain_body = &format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
+    let unsubscribe_link = unsubscribe_link(base_url, unsubscribe_token);
+    let text_body = format!(
+        "Welcome to our newsletter!\nVisit {} to confirm your subscription.\n\
+        To unsubscribe, visit {}",
+        confirmation_link, unsubscribe_link
     );
-    let html_body = generate_html_form(new_subscriber.name.as_ref(), &confirmation_link);
+    // A template that fails to render, e.g. on a variable it doesn't get,
+    // shouldn't stop the link from going out
+    let html_body = templates.and_then(|templates| {
+        generate_html_form(
+            templates,
+            locale,
+            subscriber_name,
+            &confirmation_link,
+            &unsubscribe_link,
+        )
+        .map_err(|e| {
+            tracing::warn!(
+                error.cause_chain = ?e,
+                "Failed to render the confirmation email, sending it as plain text"
+            );
+        })
+        .ok()
+    });
+
+    EmailContent {
+        kind: EmailKind::Confirmation,
+        subject: subject.into(),
+        html_body,
+        text_body,
+        list_unsubscribe: Some(unsubscribe_link),
+    }
+}
 
-    email_client
-        .send_email(&new_subscriber.email, "Welcome!", &html_body, plain_body)
-        .await
+/// Unsubscribes the subscriber with `unsubscribe_token`, opened or in one
+/// click with a POST
+pub fn unsubscribe_link(base_url: &str, unsubscribe_token: &str) -> String {
+    format!(
+        "{}/unsubscribe?unsubscribe_token={}",
+        base_url, unsubscribe_token
+    )
 }
 
 #[tracing::instrument(
@@ -111,41 +417,75 @@ pub async fn send_confirmation_email(
 pub async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Subscription, sqlx::Error> {
     // Check for existing subscriber
     let existing_subscriber = check_for_existing_subscriber(transaction, new_subscriber).await?;
 
     // Act if subscriber exist already
     // If User already exists return the Uuid from this user
     if let Some(record) = existing_subscriber {
-        tracing::info!(
-            "Subscriber with email {} already exists",
-            new_subscriber.email.as_ref()
-        );
-        return Ok(record.get("id"));
+        let subscriber_id: Uuid = record.get("id");
+        let status: String = record.get("status");
+        tracing::info!(%subscriber_id, "Subscriber already exists");
+        if status == "confirmed" {
+            return Ok(Subscription::AlreadyConfirmed(subscriber_id));
+        }
+        // Someone who unsubscribed has to confirm again to come back, their
+        // earlier consent is replaced by this one
+        if status == "unsubscribed" {
+            let query = sqlx::query!(
+                r#"UPDATE subscriptions
+                SET status = 'pending_confirmation', consent_source = $2, consent_ip = $3
+                WHERE id = $1"#,
+                subscriber_id,
+                new_subscriber.consent_source,
+                new_subscriber.consent_ip.map(|ip| ip.to_string())
+            );
+            transaction.execute(query).await?;
+            record_subscription_event(
+                transaction,
+                subscriber_id,
+                SubscriptionEvent::Resubscribed,
+                serde_json::json!({ "from": status }),
+            )
+            .await?;
+        }
+        return Ok(Subscription::PendingConfirmation(subscriber_id));
     }
 
     // Else create new Uuid for subscriber an add the subscriber to the database
     let subscriber_id = Uuid::new_v4();
     let query = sqlx::query!(
-        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status) VALUES ($1, $2, $3, $4, 'pending_confirmation')"#,
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status, consent_source, consent_ip)
+        VALUES ($1, $2, $3, $4, 'pending_confirmation', $5, $6)"#,
         subscriber_id,
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
-        Utc::now()
+        Utc::now(),
+        new_subscriber.consent_source,
+        new_subscriber.consent_ip.map(|ip| ip.to_string())
     );
 
     transaction.execute(query).await?;
-    Ok(subscriber_id)
+    record_subscription_event(
+        transaction,
+        subscriber_id,
+        SubscriptionEvent::Subscribed,
+        serde_json::json!({}),
+    )
+    .await?;
+    Ok(Subscription::PendingConfirmation(subscriber_id))
 }
 
 async fn check_for_existing_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
 ) -> Result<Option<PgRow>, sqlx::Error> {
-    // Check if subscriber is already in the database
+    // Check if subscriber is already in the database. Local parts are
+    // compared case-insensitively too, hardly any provider treats them as
+    // case-sensitive.
     let query = sqlx::query!(
-        r#"Select id FROM subscriptions WHERE email = $1"#,
+        r#"Select id, status FROM subscriptions WHERE lower(email) = lower($1)"#,
         new_subscriber.email.as_ref()
     );
 
@@ -157,50 +497,159 @@ async fn check_for_existing_subscriber(
     Ok(record)
 }
 
+/// Store a token from `generate_token` as the subscriber's confirmation
+/// token, replacing any earlier one. Should a token already belong to
+/// someone else, another one is generated, up to `MAX_TOKEN_ATTEMPTS` times.
 #[tracing::instrument(
     name = "Store subscription token in the database",
-    skip(subscription_token, transaction)
+    skip(generate_token, transaction)
 )]
 pub async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
-    subscription_token: &str,
-) -> Result<(), StoreTokenError> {
+    mut generate_token: impl FnMut() -> SubscriptionToken,
+) -> Result<SubscriptionToken, StoreTokenError> {
     // Check if for the user a subscription_token already exist
     let existing_token = check_for_existing_token(transaction, subscriber_id).await?;
-
     if existing_token.is_some() {
         tracing::info!(
             "Subscription token for subscriber: {:?} already exists",
             subscriber_id
         );
-        let query = sqlx::query!(
-            r#"UPDATE subscription_tokens SET subscription_token = $1 WHERE subscriber_id = $2"#,
-            subscription_token,
-            subscriber_id
-        );
-        transaction.execute(query).await.map_err(StoreTokenError)?;
-    } else {
-        let query = sqlx::query!(
-            r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ($1, $2)"#,
-            subscription_token,
-            subscriber_id
-        );
-        transaction.execute(query).await.map_err(|e| {
-            tracing::error!("Failed to insert subscription_token: {:?}", e);
-            StoreTokenError(e)
-        })?;
     }
+
+    let mut attempt = 1;
+    loop {
+        let subscription_token = generate_token();
+        // A failed statement aborts the whole transaction, unless it ran
+        // inside a savepoint that is rolled back
+        let mut savepoint = transaction.begin().await.map_err(StoreTokenError)?;
+        let stored = write_token(
+            &mut savepoint,
+            subscriber_id,
+            &subscription_token,
+            existing_token.is_some(),
+        )
+        .await;
+        match stored {
+            Ok(()) => {
+                savepoint.commit().await.map_err(StoreTokenError)?;
+                return Ok(subscription_token);
+            }
+            Err(e) if is_unique_violation(&e) && attempt < MAX_TOKEN_ATTEMPTS => {
+                tracing::warn!(
+                    attempt,
+                    "The new subscription token is taken, trying another"
+                );
+                savepoint.rollback().await.map_err(StoreTokenError)?;
+                attempt += 1;
+            }
+            Err(e) => {
+                tracing::error!("Failed to store subscription_token: {:?}", e);
+                return Err(StoreTokenError(e));
+            }
+        }
+    }
+}
+
+async fn write_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    subscription_token: &SubscriptionToken,
+    replace_existing: bool,
+) -> Result<(), sqlx::Error> {
+    let query = if replace_existing {
+        sqlx::query!(
+            r#"UPDATE subscription_tokens SET subscription_token_hash = $1, created_at = $3 WHERE subscriber_id = $2"#,
+            hash_token(subscription_token.as_ref()),
+            subscriber_id,
+            Utc::now()
+        )
+    } else {
+        sqlx::query!(
+            r#"INSERT INTO subscription_tokens (subscription_token_hash, subscriber_id, created_at) VALUES ($1, $2, $3)"#,
+            hash_token(subscription_token.as_ref()),
+            subscriber_id,
+            Utc::now()
+        )
+    };
+    transaction.execute(query).await?;
     Ok(())
 }
 
+/// Subscribers keep their unsubscribe token for good, so the links in every
+/// email they were ever sent keep working. Only its hash is stored, the
+/// token itself is derived again whenever it is mailed.
+#[tracing::instrument(
+    name = "Store unsubscribe token in the database",
+    skip(transaction, unsubscribe_token_key)
+)]
+pub async fn store_unsubscribe_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    unsubscribe_token_key: &Secret<String>,
+) -> Result<String, sqlx::Error> {
+    let unsubscribe_token = unsubscribe_token(unsubscribe_token_key, subscriber_id);
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO unsubscribe_tokens (unsubscribe_token_hash, subscriber_id) VALUES ($1, $2)
+        ON CONFLICT (unsubscribe_token_hash) DO NOTHING
+        "#,
+        hash_token(&unsubscribe_token),
+        subscriber_id
+    );
+    transaction.execute(query).await?;
+    Ok(unsubscribe_token)
+}
+
+/// `store_unsubscribe_token` for many subscribers in one statement, returning
+/// their tokens in the same order
+#[tracing::instrument(
+    name = "Store unsubscribe tokens in the database",
+    skip_all,
+    fields(subscribers = subscriber_ids.len())
+)]
+pub async fn store_unsubscribe_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_ids: &[Uuid],
+    unsubscribe_token_key: &Secret<String>,
+) -> Result<Vec<String>, sqlx::Error> {
+    let unsubscribe_tokens: Vec<String> = subscriber_ids
+        .iter()
+        .map(|subscriber_id| unsubscribe_token(unsubscribe_token_key, *subscriber_id))
+        .collect();
+    let hashes: Vec<String> = unsubscribe_tokens
+        .iter()
+        .map(|token| hash_token(token))
+        .collect();
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO unsubscribe_tokens (unsubscribe_token_hash, subscriber_id)
+        SELECT * FROM UNNEST($1::text[], $2::uuid[])
+        ON CONFLICT (unsubscribe_token_hash) DO NOTHING
+        "#,
+        &hashes,
+        subscriber_ids
+    );
+    transaction.execute(query).await?;
+    Ok(unsubscribe_tokens)
+}
+
+/// The token in a subscriber's unsubscribe links, the same every time
+pub fn unsubscribe_token(unsubscribe_token_key: &Secret<String>, subscriber_id: Uuid) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(unsubscribe_token_key.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(subscriber_id.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
 async fn check_for_existing_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
 ) -> Result<Option<PgRow>, StoreTokenError> {
     // Check if subscriber is already in the database
     let query = sqlx::query!(
-        r#"SELECT subscription_token FROM subscription_tokens WHERE subscriber_id = $1"#,
+        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscriber_id = $1"#,
         subscriber_id
     );
 
@@ -212,20 +661,155 @@ async fn check_for_existing_token(
     Ok(record)
 }
 
-fn generate_subscription_token() -> String {
-    let mut rng = thread_rng();
-    std::iter::repeat_with(|| rng.sample(Alphanumeric))
-        .map(char::from)
-        .take(25)
-        .collect()
+/// A fresh confirmation token for the subscriber. Signed tokens carry the
+/// subscriber id and their expiry themselves, so nothing is stored for them.
+pub async fn issue_confirmation_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    settings: &SubscriptionSettings,
+) -> Result<String, StoreTokenError> {
+    if let Some(signing_key) = settings.token_signing_key() {
+        let token = SignedToken {
+            subscriber_id,
+            expires_at: Utc::now() + settings.confirmation_token_ttl(),
+        };
+        return Ok(token.sign(signing_key));
+    }
+    let token = store_token(transaction, subscriber_id, || {
+        SubscriptionToken::generate(settings.token_length)
+    })
+    .await?;
+    Ok(token.as_ref().to_string())
+}
+
+/// `issue_confirmation_token` for many new subscribers at once, returning
+/// their tokens in the same order. Nobody may have a token yet. Tokens that
+/// turn out to be taken are generated again, up to `MAX_TOKEN_ATTEMPTS` times.
+#[tracing::instrument(
+    name = "Issue confirmation tokens",
+    skip_all,
+    fields(subscribers = subscriber_ids.len())
+)]
+pub async fn issue_confirmation_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_ids: &[Uuid],
+    settings: &SubscriptionSettings,
+) -> Result<Vec<String>, StoreTokenError> {
+    if let Some(signing_key) = settings.token_signing_key() {
+        let expires_at = Utc::now() + settings.confirmation_token_ttl();
+        return Ok(subscriber_ids
+            .iter()
+            .map(|&subscriber_id| {
+                SignedToken {
+                    subscriber_id,
+                    expires_at,
+                }
+                .sign(signing_key)
+            })
+            .collect());
+    }
+    let mut tokens = HashMap::with_capacity(subscriber_ids.len());
+    let mut pending = subscriber_ids.to_vec();
+    let mut attempt = 1;
+    while !pending.is_empty() {
+        let generated: Vec<SubscriptionToken> = pending
+            .iter()
+            .map(|_| SubscriptionToken::generate(settings.token_length))
+            .collect();
+        let hashes: Vec<String> = generated
+            .iter()
+            .map(|token| hash_token(token.as_ref()))
+            .collect();
+        // The last attempt fails on a taken token instead of skipping it
+        let stored: HashSet<Uuid> = if attempt < MAX_TOKEN_ATTEMPTS {
+            sqlx::query_scalar!(
+                r#"
+                INSERT INTO subscription_tokens (subscription_token_hash, subscriber_id, created_at)
+                SELECT *, $3 FROM UNNEST($1::text[], $2::uuid[])
+                ON CONFLICT (subscription_token_hash) DO NOTHING
+                RETURNING subscriber_id
+                "#,
+                &hashes,
+                &pending,
+                Utc::now()
+            )
+            .fetch_all(&mut **transaction)
+            .await
+            .map_err(StoreTokenError)?
+            .into_iter()
+            .collect()
+        } else {
+            let query = sqlx::query!(
+                r#"
+                INSERT INTO subscription_tokens (subscription_token_hash, subscriber_id, created_at)
+                SELECT *, $3 FROM UNNEST($1::text[], $2::uuid[])
+                "#,
+                &hashes,
+                &pending,
+                Utc::now()
+            );
+            transaction.execute(query).await.map_err(|e| {
+                tracing::error!("Failed to store subscription tokens: {:?}", e);
+                StoreTokenError(e)
+            })?;
+            pending.iter().copied().collect()
+        };
+        let mut taken = Vec::new();
+        for (subscriber_id, token) in pending.into_iter().zip(generated) {
+            if stored.contains(&subscriber_id) {
+                tokens.insert(subscriber_id, token);
+            } else {
+                taken.push(subscriber_id);
+            }
+        }
+        if !taken.is_empty() {
+            tracing::warn!(
+                attempt,
+                taken = taken.len(),
+                "Some new subscription tokens are taken, trying others"
+            );
+        }
+        pending = taken;
+        attempt += 1;
+    }
+    Ok(subscriber_ids
+        .iter()
+        .map(|subscriber_id| tokens[subscriber_id].as_ref().to_string())
+        .collect())
+}
+
+/// Only this hash of a confirmation or unsubscribe token is stored, so a
+/// database leak doesn't let anyone confirm or unsubscribe subscribers
+pub fn hash_token(subscription_token: &str) -> String {
+    hex::encode(Sha256::digest(subscription_token.as_bytes()))
 }
 
-fn generate_html_form(subscriber_name: &str, confirmation_link: &str) -> String {
-    let tera = Tera::new("templates/**/*").unwrap();
+fn generate_html_form(
+    templates: &Tera,
+    locale: Option<&str>,
+    subscriber_name: &str,
+    confirmation_link: &str,
+    unsubscribe_link: &str,
+) -> Result<String, tera::Error> {
     let mut context = tera::Context::new();
     context.insert("confirmation_link", confirmation_link);
+    context.insert("unsubscribe_link", unsubscribe_link);
     context.insert("name", subscriber_name);
-    tera.render("hello_email.html", &context).unwrap()
+    templates.render(&confirmation_template(templates, locale), &context)
+}
+
+/// The most specific template there is for `locale`: `hello_email.fr-ca.html`,
+/// then `hello_email.fr.html`, then the default one
+fn confirmation_template(templates: &Tera, locale: Option<&str>) -> String {
+    let mut locale = locale;
+    while let Some(current) = locale {
+        let name = format!("hello_email.{}.html", current);
+        if templates.get_template(&name).is_ok() {
+            return name;
+        }
+        locale = current.rsplit_once('-').map(|(language, _)| language);
+    }
+    CONFIRMATION_EMAIL_TEMPLATE.into()
 }
 
 // A new error type, wrapping s sqlx::Error
@@ -255,6 +839,10 @@ impl std::error::Error for StoreTokenError {
 pub enum SubscribeError {
     #[error("{0}")]
     ValidationError(String),
+    #[error("This email address is already subscribed")]
+    AlreadyConfirmed,
+    #[error("Too many subscriptions from your address, please try again later")]
+    TooManySubscriptions,
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -269,7 +857,91 @@ impl ResponseError for SubscribeError {
     fn status_code(&self) -> StatusCode {
         match self {
             SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::AlreadyConfirmed => StatusCode::CONFLICT,
+            SubscribeError::TooManySubscriptions => StatusCode::TOO_MANY_REQUESTS,
             SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::{NewSubscriber, SubscriberNameError, SubscriberValidationError};
+    use crate::routes::subscriptions::{confirmation_template, valid_consent_source, FormData};
+    use tera::Tera;
+
+    fn form(name: &str, email: &str) -> FormData {
+        FormData {
+            name: name.to_string(),
+            email: email.to_string(),
+            locale: None,
+            consent_source: None,
+        }
+    }
+
+    fn templates() -> Tera {
+        let mut templates = Tera::default();
+        templates
+            .add_raw_templates([
+                ("hello_email.html", "Welcome, {{ name }}"),
+                ("hello_email.fr.html", "Bienvenue, {{ name }}"),
+            ])
+            .unwrap();
+        templates
+    }
+
+    #[test]
+    fn a_localized_template_is_used_when_there_is_one() {
+        let templates = templates();
+        assert_eq!(
+            confirmation_template(&templates, Some("fr")),
+            "hello_email.fr.html"
+        );
+        assert_eq!(
+            confirmation_template(&templates, Some("fr-ca")),
+            "hello_email.fr.html"
+        );
+    }
+
+    #[test]
+    fn the_default_template_is_used_without_a_localized_one() {
+        let templates = templates();
+        assert_eq!(
+            confirmation_template(&templates, Some("de-at")),
+            "hello_email.html"
+        );
+        assert_eq!(confirmation_template(&templates, None), "hello_email.html");
+    }
+
+    #[test]
+    fn an_empty_name_is_an_invalid_name() {
+        let result = NewSubscriber::try_from(form(" ", "ursula_le_guin@gmail.com"));
+        assert_eq!(
+            result.err(),
+            Some(SubscriberValidationError::InvalidName(
+                SubscriberNameError::Empty
+            ))
+        );
+    }
+
+    #[test]
+    fn a_malformed_email_is_an_invalid_email() {
+        let result = NewSubscriber::try_from(form("le guin", "ursula_le_guin.gmail.com"));
+        assert!(matches!(
+            result.err(),
+            Some(SubscriberValidationError::InvalidEmail(_))
+        ));
+    }
+
+    #[test]
+    fn only_http_urls_are_kept_as_consent_sources() {
+        for source in ["https://example.com/signup", "http://example.com/?ref=blog"] {
+            assert_eq!(valid_consent_source(source.into()).as_deref(), Some(source));
+        }
+        for source in ["javascript:alert(1)", "not a url", "/signup"] {
+            assert_eq!(valid_consent_source(source.into()), None, "{}", source);
+        }
+        let too_long = format!("https://example.com/{}", "a".repeat(2048));
+        assert_eq!(valid_consent_source(too_long), None);
+    }
+}
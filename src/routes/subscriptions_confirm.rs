@@ -1,12 +1,23 @@
-use crate::routes::error_chain_fmt;
+use crate::configuration::{SubscriberIdentifier, SubscriptionSettings};
+use crate::confirmation_webhook::{ConfirmationWebhook, ConfirmedSubscriber};
+use crate::domain::{SubscriberEmail, SubscriptionToken};
+use crate::routes::{error_chain_fmt, hash_token};
+use crate::signed_token::{SignedToken, SignedTokenError};
+use crate::subscription_events::{record_subscription_event, SubscriptionEvent};
+use actix_web::http::header::ContentType;
 use actix_web::http::StatusCode;
 use actix_web::web;
 use actix_web::HttpResponse;
 use actix_web::ResponseError;
 use anyhow::Context;
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use tracing::Instrument;
 use uuid::Uuid;
 
+/// The token is parsed into a `SubscriptionToken` by the handler, its
+/// valid length is only known from the settings
 #[derive(serde::Deserialize)]
 pub struct Parameters {
     subscription_token: String,
@@ -16,8 +27,16 @@ pub struct Parameters {
 pub enum ConfirmationError {
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
+    #[error("{0}")]
+    MalformedToken(String),
     #[error("There is no subscriber associated with the provider token")]
     UnknownToken,
+    #[error("The token's signature doesn't match")]
+    InvalidSignature,
+    #[error("The subscriber has unsubscribed")]
+    Unsubscribed,
+    #[error("The provided token has expired")]
+    ExpiredToken,
 }
 
 impl std::fmt::Debug for ConfirmationError {
@@ -29,59 +48,355 @@ impl std::fmt::Debug for ConfirmationError {
 impl ResponseError for ConfirmationError {
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::UnknownToken => StatusCode::UNAUTHORIZED,
+            Self::MalformedToken(_) | Self::UnknownToken | Self::InvalidSignature => {
+                StatusCode::UNAUTHORIZED
+            }
+            Self::Unsubscribed | Self::ExpiredToken => StatusCode::GONE,
             Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, pool))]
+/// The link in the confirmation email. With `confirm_by_post_only` it
+/// doesn't confirm, it shows a button that does with a POST.
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(parameters, pool, settings, confirmation_webhook, identifier),
+    fields(
+        subscriber_id = tracing::field::Empty,
+        subscriber_email = tracing::field::Empty,
+        subscriber_name = tracing::field::Empty
+    )
+)]
 pub async fn confirm(
     parameters: web::Query<Parameters>,
     pool: web::Data<PgPool>,
+    settings: web::Data<SubscriptionSettings>,
+    confirmation_webhook: web::Data<Option<ConfirmationWebhook>>,
+    identifier: web::Data<SubscriberIdentifier>,
 ) -> Result<HttpResponse, ConfirmationError> {
-    if let Err(response) = validate_token_format(&parameters.subscription_token) {
-        return Ok(response);
+    let subscription_token = parameters.0.subscription_token;
+    if settings.confirm_by_post_only {
+        // Checked first, so a bad link fails right away rather than on the
+        // button, and so only a token of ours ends up in the page
+        resolve_token(&pool, &settings, subscription_token.clone()).await?;
+        return Ok(confirmation_form(&subscription_token));
     }
-    let id = get_subscriber_id_from_token(&pool, &parameters.subscription_token)
+    confirm_token(
+        &pool,
+        &settings,
+        confirmation_webhook,
+        **identifier,
+        subscription_token,
+    )
+    .await
+}
+
+/// Confirm with the token in a form, as posted by the button `confirm`
+/// shows. Mail clients and link scanners prefetch links, they don't submit
+/// forms.
+#[tracing::instrument(
+    name = "Confirm a pending subscriber from a form",
+    skip(form, pool, settings, confirmation_webhook, identifier),
+    fields(
+        subscriber_id = tracing::field::Empty,
+        subscriber_email = tracing::field::Empty,
+        subscriber_name = tracing::field::Empty
+    )
+)]
+pub async fn confirm_form_submission(
+    form: web::Form<Parameters>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SubscriptionSettings>,
+    confirmation_webhook: web::Data<Option<ConfirmationWebhook>>,
+    identifier: web::Data<SubscriberIdentifier>,
+) -> Result<HttpResponse, ConfirmationError> {
+    confirm_token(
+        &pool,
+        &settings,
+        confirmation_webhook,
+        **identifier,
+        form.0.subscription_token,
+    )
+    .await
+}
+
+fn confirmation_form(subscription_token: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Confirm your subscription</title>
+</head>
+<body>
+    <form action="/subscriptions/confirm" method="post">
+        <input type="hidden" name="subscription_token" value="{subscription_token}">
+        <button type="submit">Confirm my subscription</button>
+    </form>
+</body>
+</html>"#,
+        ))
+}
+
+async fn confirm_token(
+    pool: &PgPool,
+    settings: &SubscriptionSettings,
+    confirmation_webhook: web::Data<Option<ConfirmationWebhook>>,
+    identifier: SubscriberIdentifier,
+    subscription_token: String,
+) -> Result<HttpResponse, ConfirmationError> {
+    let id = resolve_token(pool, settings, subscription_token).await?;
+    // A signed token outlives a subscriber who was deleted
+    let status = get_subscriber_status(pool, id)
         .await
-        .context("Failed to retrieve the subscriber id associated with the provider token")?
+        .context("Failed to retrieve the subscriber status")?
         .ok_or(ConfirmationError::UnknownToken)?;
-    confirm_subscriber(&pool, id)
+    record_subscriber_identifier(pool, id, identifier).await?;
+    if status == "unsubscribed" {
+        return Err(ConfirmationError::Unsubscribed);
+    }
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let confirmed = confirm_subscriber(&mut transaction, id)
         .await
         .context("Failed to update the subscriber status to `confirmed`.")?;
-    Ok(HttpResponse::Ok().finish())
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to confirm a subscriber")?;
+    // e.g. the link was clicked twice
+    if !confirmed {
+        return Ok(HttpResponse::Ok().body("Your subscription was already confirmed."));
+    }
+    if confirmation_webhook.is_some() {
+        dispatch_confirmation_webhook(
+            Arc::new(pool.clone()),
+            confirmation_webhook.into_inner(),
+            id,
+        );
+    }
+    Ok(HttpResponse::Ok().body("Your subscription has been confirmed."))
+}
+
+/// The subscriber a confirmation token was issued to. Signed tokens are
+/// verified without a lookup, random ones stored before signing was enabled
+/// keep working until they expire.
+async fn resolve_token(
+    pool: &PgPool,
+    settings: &SubscriptionSettings,
+    subscription_token: String,
+) -> Result<Uuid, ConfirmationError> {
+    if let Some(signing_key) = settings.token_signing_key() {
+        if subscription_token.contains('.') {
+            let token =
+                SignedToken::verify(&subscription_token, signing_key).map_err(|e| match e {
+                    SignedTokenError::Malformed => ConfirmationError::MalformedToken(e.to_string()),
+                    SignedTokenError::InvalidSignature => ConfirmationError::InvalidSignature,
+                })?;
+            if token.is_expired() {
+                return Err(ConfirmationError::ExpiredToken);
+            }
+            return Ok(token.subscriber_id);
+        }
+    }
+    let subscription_token = SubscriptionToken::parse(subscription_token, settings.token_length)
+        .map_err(ConfirmationError::MalformedToken)?;
+    let token = get_stored_token(pool, &subscription_token)
+        .await
+        .context("Failed to retrieve the subscriber id associated with the provider token")?
+        .ok_or(ConfirmationError::UnknownToken)?;
+    if token.is_expired(settings.confirmation_token_ttl()) {
+        return Err(ConfirmationError::ExpiredToken);
+    }
+    Ok(token.subscriber_id)
 }
 
-fn validate_token_format(token: &str) -> Result<(), HttpResponse> {
-    if token.len() != 25 && !token.chars().all(|c| c.is_ascii_alphanumeric()) {
-        tracing::warn!("Invalid subscription token: {}", token);
-        return Err(HttpResponse::Unauthorized().finish());
+async fn record_subscriber_identifier(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    identifier: SubscriberIdentifier,
+) -> Result<(), anyhow::Error> {
+    let span = tracing::Span::current();
+    span.record("subscriber_id", tracing::field::display(&subscriber_id));
+    if identifier == SubscriberIdentifier::Id {
+        return Ok(());
+    }
+    let subscriber = get_confirmed_subscriber(pool, subscriber_id)
+        .await
+        .context("Failed to retrieve the subscriber to identify them in logs")?;
+    if let Ok(email) = SubscriberEmail::parse(subscriber.email) {
+        if let Some(email) = identifier.loggable_email(&email) {
+            span.record("subscriber_email", tracing::field::display(email));
+        }
+    }
+    if identifier.includes_name() {
+        span.record("subscriber_name", tracing::field::display(subscriber.name));
     }
     Ok(())
 }
 
-#[tracing::instrument(name = "Mark subscriber as confirmed", skip(subscriber_id, pool))]
-pub async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
+/// Notify the configured integration in the background, so a slow or failing
+/// webhook never holds up (or fails) the confirmation itself.
+fn dispatch_confirmation_webhook(
+    pool: Arc<PgPool>,
+    confirmation_webhook: Arc<Option<ConfirmationWebhook>>,
+    subscriber_id: Uuid,
+) {
+    let task = async move {
+        let Some(webhook) = confirmation_webhook.as_ref() else {
+            return;
+        };
+        let outcome = match get_confirmed_subscriber(&pool, subscriber_id).await {
+            Ok(subscriber) => webhook.send(&subscriber).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = outcome {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to deliver the confirmation webhook"
+            );
+        }
+    };
+    tokio::spawn(task.instrument(tracing::Span::current()));
+}
+
+#[tracing::instrument(name = "Get confirmed subscriber", skip(pool))]
+async fn get_confirmed_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<ConfirmedSubscriber, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT email, name FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(ConfirmedSubscriber {
+        id: subscriber_id,
+        email: row.email,
+        name: row.name,
+    })
+}
+
+#[derive(serde::Deserialize)]
+pub struct ValidateTokenBody {
+    subscription_token: String,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+enum InvalidTokenReason {
+    Malformed,
+    Unknown,
+    Expired,
+}
+
+#[derive(serde::Serialize)]
+struct ValidateTokenResponse {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<InvalidTokenReason>,
+}
+
+/// Tell a client whether a confirmation token would be accepted, without
+/// confirming anything.
+#[tracing::instrument(name = "Validate a confirmation token", skip(body, pool, settings))]
+pub async fn validate_confirmation_token(
+    body: web::Json<ValidateTokenBody>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<SubscriptionSettings>,
+) -> Result<HttpResponse, ConfirmationError> {
+    let reason = match resolve_token(&pool, &settings, body.0.subscription_token).await {
+        Ok(_) => None,
+        Err(ConfirmationError::MalformedToken(_)) => Some(InvalidTokenReason::Malformed),
+        Err(ConfirmationError::UnknownToken | ConfirmationError::InvalidSignature) => {
+            Some(InvalidTokenReason::Unknown)
+        }
+        Err(ConfirmationError::ExpiredToken) => Some(InvalidTokenReason::Expired),
+        Err(e) => return Err(e),
+    };
+    Ok(HttpResponse::Ok().json(ValidateTokenResponse {
+        valid: reason.is_none(),
+        reason,
+    }))
+}
+
+/// Only a pending subscriber is confirmed. Returns whether this changed
+/// their status, so confirming twice records a single event.
+#[tracing::instrument(
+    name = "Mark subscriber as confirmed",
+    skip(subscriber_id, transaction)
+)]
+pub async fn confirm_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET status = 'confirmed'
+        WHERE id = $1 AND status = 'pending_confirmation'
+        "#,
+        subscriber_id,
+    );
+    let result = transaction.execute(query).await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    record_subscription_event(
+        transaction,
         subscriber_id,
+        SubscriptionEvent::Confirmed,
+        serde_json::json!({}),
     )
-    .execute(pool)
     .await?;
-    Ok(())
+    Ok(true)
+}
+
+#[tracing::instrument(name = "Get subscriber status", skip(pool))]
+async fn get_subscriber_status(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let record = sqlx::query!(
+        r#"SELECT status FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(record.map(|r| r.status))
+}
+
+pub struct StoredToken {
+    pub subscriber_id: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+impl StoredToken {
+    pub fn is_expired(&self, ttl: chrono::Duration) -> bool {
+        Utc::now() - self.created_at > ttl
+    }
 }
 
 #[tracing::instrument(name = "Get subscriber_id from token", skip(subscription_token, pool))]
-pub async fn get_subscriber_id_from_token(
+pub async fn get_stored_token(
     pool: &PgPool,
-    subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
+    subscription_token: &SubscriptionToken,
+) -> Result<Option<StoredToken>, sqlx::Error> {
     let result = sqlx::query!(
-        "SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1",
-        subscription_token
+        "SELECT subscriber_id, created_at FROM subscription_tokens \
+        WHERE subscription_token_hash = $1",
+        hash_token(subscription_token.as_ref())
     )
     .fetch_optional(pool)
     .await?;
-    Ok(result.map(|r| r.subscriber_id))
+    Ok(result.map(|r| StoredToken {
+        subscriber_id: r.subscriber_id,
+        created_at: r.created_at,
+    }))
 }
@@ -0,0 +1,137 @@
+use crate::configuration::SubscriptionSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_outbox::enqueue_email;
+use crate::routes::{
+    confirmation_email, error_chain_fmt, issue_confirmation_token, store_unsubscribe_token,
+};
+use crate::startup::{ConfirmationEmailOptions, RequestBaseUrl};
+use crate::telemetry::RequestId;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct ResendForm {
+    email: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ResendConfirmationError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There is no pending subscription for this email address")]
+    UnknownSubscriber,
+    #[error("This email address is already subscribed")]
+    AlreadyConfirmed,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for ResendConfirmationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ResendConfirmationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::ValidationError(_) => StatusCode::BAD_REQUEST,
+            Self::UnknownSubscriber => StatusCode::NOT_FOUND,
+            Self::AlreadyConfirmed => StatusCode::CONFLICT,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Send a pending subscriber a fresh confirmation email. With stored tokens
+/// the link of the previous one stops working, only one is kept per
+/// subscriber.
+#[tracing::instrument(
+    name = "Resend a confirmation email",
+    skip(form, pool, base_url, email_options, settings, request_id),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn resend_confirmation(
+    form: web::Form<ResendForm>,
+    pool: web::Data<PgPool>,
+    base_url: RequestBaseUrl,
+    email_options: web::Data<ConfirmationEmailOptions>,
+    settings: web::Data<SubscriptionSettings>,
+    request_id: RequestId,
+) -> Result<HttpResponse, ResendConfirmationError> {
+    let email =
+        SubscriberEmail::parse(form.0.email).map_err(ResendConfirmationError::ValidationError)?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let subscriber = get_subscriber_by_email(&mut transaction, &email)
+        .await
+        .context("Failed to look up the subscriber")?;
+    let (subscriber_id, name) = match subscriber {
+        Some(subscriber) if subscriber.status == "pending_confirmation" => {
+            (subscriber.id, subscriber.name)
+        }
+        Some(subscriber) if subscriber.status == "confirmed" => {
+            return Err(ResendConfirmationError::AlreadyConfirmed)
+        }
+        // Unknown, or unsubscribed and so has to subscribe again
+        _ if settings.conceal_unknown_emails => return Ok(HttpResponse::Ok().finish()),
+        _ => return Err(ResendConfirmationError::UnknownSubscriber),
+    };
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+
+    // Only the token's hash is stored, so a new one has to be issued
+    let subscription_token = issue_confirmation_token(&mut transaction, subscriber_id, &settings)
+        .await
+        .context("Failed to store the new confirmation token")?;
+    let unsubscribe_token = store_unsubscribe_token(
+        &mut transaction,
+        subscriber_id,
+        &settings.unsubscribe_token_key,
+    )
+    .await
+    .context("Failed to store the unsubscribe token")?;
+    let content = confirmation_email(
+        &name,
+        &base_url.0,
+        &subscription_token,
+        &unsubscribe_token,
+        &email_options.subject,
+        email_options.templates.as_ref(),
+        None,
+    );
+    enqueue_email(&mut transaction, &email, &content, Some(&request_id))
+        .await
+        .context("Failed to enqueue the confirmation email")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to resend a confirmation email")?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+struct Subscriber {
+    id: Uuid,
+    name: String,
+    status: String,
+}
+
+#[tracing::instrument(name = "Get subscriber by email", skip(transaction, email))]
+async fn get_subscriber_by_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"SELECT id, name, status FROM subscriptions WHERE lower(email) = lower($1) FOR UPDATE"#,
+        email.as_ref()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
@@ -0,0 +1,78 @@
+use crate::authentication::{AdminUser, PublishRealm};
+use crate::domain::SubscriberEmail;
+use crate::routes::error_chain_fmt;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct EmailQuery {
+    email: String,
+}
+
+#[derive(serde::Serialize)]
+struct SubscriptionStatus {
+    status: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscriptionStatusError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There is no subscriber with the provided email")]
+    UnknownSubscriber,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for SubscriptionStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscriptionStatusError {
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::ValidationError(_) => HttpResponse::new(StatusCode::BAD_REQUEST),
+            Self::UnknownSubscriber => HttpResponse::new(StatusCode::NOT_FOUND),
+            Self::UnexpectedError(_) => HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Look up a subscription status",
+    skip(admin, query, pool),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn subscription_status(
+    admin: AdminUser<PublishRealm>,
+    query: web::Query<EmailQuery>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, SubscriptionStatusError> {
+    admin.record_on_current_span();
+
+    let email =
+        SubscriberEmail::parse(query.0.email).map_err(SubscriptionStatusError::ValidationError)?;
+    let status = get_status_by_email(&pool, &email)
+        .await
+        .context("Failed to retrieve the subscription status")?
+        .ok_or(SubscriptionStatusError::UnknownSubscriber)?;
+    Ok(HttpResponse::Ok().json(SubscriptionStatus { status }))
+}
+
+#[tracing::instrument(name = "Get subscription status by email", skip(pool, email))]
+async fn get_status_by_email(
+    pool: &PgPool,
+    email: &SubscriberEmail,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT status FROM subscriptions WHERE lower(email) = lower($1)"#,
+        email.as_ref()
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| r.status))
+}
@@ -0,0 +1,113 @@
+use crate::routes::{
+    error_chain_fmt, hash_token, mark_subscriber_as_unsubscribed, parse_reason,
+    record_unsubscribe_event,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    unsubscribe_token: String,
+    /// Free text on why the subscriber is leaving
+    reason: Option<String>,
+}
+
+/// The body of a `POST`, a one-click unsubscribe or a form with a reason
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeForm {
+    reason: Option<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+    #[error("There is no subscriber associated with the provided token")]
+    UnknownToken,
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::ValidationError(_) => StatusCode::BAD_REQUEST,
+            Self::UnknownToken => StatusCode::UNAUTHORIZED,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber",
+    skip(parameters, form, pool),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    form: Option<web::Form<UnsubscribeForm>>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let UnsubscribeParameters {
+        unsubscribe_token,
+        reason,
+    } = parameters.0;
+    let reason = form.and_then(|form| form.0.reason).or(reason);
+    let reason = parse_reason(reason).map_err(UnsubscribeError::ValidationError)?;
+    let (subscriber_id, status) = get_subscriber_from_unsubscribe_token(&pool, &unsubscribe_token)
+        .await
+        .context("Failed to retrieve the subscriber associated with the provided token")?
+        .ok_or(UnsubscribeError::UnknownToken)?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+
+    // Unsubscribing twice is fine, e.g. a link clicked again
+    if status != "unsubscribed" {
+        let mut transaction = pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool")?;
+        let metadata = serde_json::json!({ "from": status, "source": "link" });
+        mark_subscriber_as_unsubscribed(&mut transaction, subscriber_id, metadata)
+            .await
+            .context("Failed to update the subscriber status to `unsubscribed`.")?;
+        record_unsubscribe_event(&mut transaction, subscriber_id, reason.as_deref())
+            .await
+            .context("Failed to record the unsubscribe event")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit SQL transaction to unsubscribe a subscriber")?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(
+    name = "Get subscriber from unsubscribe token",
+    skip(unsubscribe_token, pool)
+)]
+async fn get_subscriber_from_unsubscribe_token(
+    pool: &PgPool,
+    unsubscribe_token: &str,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT s.id, s.status
+        FROM unsubscribe_tokens t
+        JOIN subscriptions s ON s.id = t.subscriber_id
+        WHERE t.unsubscribe_token_hash = $1
+        "#,
+        hash_token(unsubscribe_token)
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(result.map(|r| (r.id, r.status)))
+}
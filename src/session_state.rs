@@ -0,0 +1,159 @@
+//! src/session_state.rs
+use actix_session::storage::{
+    CookieSessionStore, LoadError, RedisSessionStore, SaveError, SessionKey, SessionStore,
+    UpdateError,
+};
+use actix_session::{Session, SessionExt, SessionGetError, SessionInsertError};
+use actix_web::cookie::time::Duration;
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use uuid::Uuid;
+
+/// The admin session, see `SessionSettings` for where it is stored
+pub struct TypedSession(Session);
+
+impl TypedSession {
+    const USER_ID_KEY: &'static str = "user_id";
+    const CSRF_TOKEN_KEY: &'static str = "csrf_token";
+    const FLASH_KEY: &'static str = "flash";
+
+    /// Give the session a new key, e.g. after a login so a key planted
+    /// beforehand is useless
+    pub fn renew(&self) {
+        self.0.renew();
+    }
+
+    pub fn insert_user_id(&self, user_id: Uuid) -> Result<(), SessionInsertError> {
+        self.0.insert(Self::USER_ID_KEY, user_id)
+    }
+
+    pub fn get_user_id(&self) -> Result<Option<Uuid>, SessionGetError> {
+        self.0.get(Self::USER_ID_KEY)
+    }
+
+    /// The token every POST form of this session has to send along,
+    /// created on first use
+    pub fn csrf_token(&self) -> Result<String, anyhow::Error> {
+        if let Some(token) = self.0.get::<String>(Self::CSRF_TOKEN_KEY)? {
+            return Ok(token);
+        }
+        let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        self.0.insert(Self::CSRF_TOKEN_KEY, &token)?;
+        Ok(token)
+    }
+
+    /// Forget the CSRF token, the next form gets a new one
+    pub fn reset_csrf_token(&self) {
+        self.0.remove(Self::CSRF_TOKEN_KEY);
+    }
+
+    /// Whether `token` is this session's CSRF token. A session that never
+    /// showed a form has none, so nothing matches.
+    pub fn is_valid_csrf_token(&self, token: &str) -> Result<bool, SessionGetError> {
+        let Some(expected) = self.0.get::<String>(Self::CSRF_TOKEN_KEY)? else {
+            return Ok(false);
+        };
+        // Compare every byte, so the response time doesn't tell how much of
+        // a guess was right
+        Ok(expected.len() == token.len()
+            && expected
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0)
+    }
+
+    /// Show `message` on the next page that is rendered
+    pub fn set_flash(&self, message: &str) -> Result<(), SessionInsertError> {
+        self.0.insert(Self::FLASH_KEY, message)
+    }
+
+    /// The message left by the previous request, if any. It is only shown once.
+    pub fn take_flash(&self) -> Result<Option<String>, anyhow::Error> {
+        self.0
+            .remove_as(Self::FLASH_KEY)
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Failed to deserialize the flash message: {}", e))
+    }
+}
+
+impl FromRequest for TypedSession {
+    type Error = <Session as FromRequest>::Error;
+    type Future = Ready<Result<TypedSession, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(TypedSession(req.get_session())))
+    }
+}
+
+/// The store picked by `session.store`
+pub enum AppSessionStore {
+    Cookie(CookieSessionStore),
+    Redis(Box<RedisSessionStore>),
+}
+
+// Every worker gets its own copy
+impl Clone for AppSessionStore {
+    fn clone(&self) -> Self {
+        match self {
+            // Stateless, everything is in the cookie
+            Self::Cookie(_) => Self::Cookie(CookieSessionStore::default()),
+            Self::Redis(store) => Self::Redis(store.clone()),
+        }
+    }
+}
+
+type SessionState = HashMap<String, String>;
+
+impl SessionStore for AppSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        match self {
+            Self::Cookie(store) => store.load(session_key).await,
+            Self::Redis(store) => store.load(session_key).await,
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            Self::Cookie(store) => store.save(session_state, ttl).await,
+            Self::Redis(store) => store.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            Self::Cookie(store) => store.update(session_key, session_state, ttl).await,
+            Self::Redis(store) => store.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Cookie(store) => store.update_ttl(session_key, ttl).await,
+            Self::Redis(store) => store.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Cookie(store) => store.delete(session_key).await,
+            Self::Redis(store) => store.delete(session_key).await,
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! src/shutdown.rs
+use crate::metrics::Metrics;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::web;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Counts the requests currently being handled, so a shutdown can wait for
+/// them to finish.
+pub struct InFlightRequests {
+    count: watch::Sender<usize>,
+    /// Since startup
+    finished: AtomicU64,
+}
+
+impl Default for InFlightRequests {
+    fn default() -> Self {
+        Self {
+            count: watch::channel(0).0,
+            finished: AtomicU64::new(0),
+        }
+    }
+}
+
+impl InFlightRequests {
+    fn start(&self) -> InFlightGuard<'_> {
+        self.count.send_modify(|count| *count += 1);
+        InFlightGuard(self)
+    }
+
+    /// Resolve once no request is being handled
+    pub async fn drained(&self) {
+        let mut count = self.count.subscribe();
+        // The sender lives in `self`, so this cannot fail
+        let _ = count.wait_for(|count| *count == 0).await;
+    }
+
+    pub fn current(&self) -> usize {
+        *self.count.borrow()
+    }
+
+    pub fn finished(&self) -> u64 {
+        self.finished.load(Ordering::Relaxed)
+    }
+}
+
+/// Also dropped if the client goes away and the request is cancelled
+struct InFlightGuard<'a>(&'a InFlightRequests);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        // Counted as finished first, so a drain woken up by the count sees it
+        self.0.finished.fetch_add(1, Ordering::Relaxed);
+        self.0.count.send_modify(|count| *count -= 1);
+    }
+}
+
+pub async fn track_in_flight_requests(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let in_flight = request.app_data::<web::Data<InFlightRequests>>().cloned();
+    let _guard = in_flight.as_ref().map(|in_flight| in_flight.start());
+    next.call(request).await
+}
+
+/// A graceful shutdown from the signal on. Every step of it shares one
+/// deadline, `application.shutdown_timeout_seconds` after the signal.
+pub struct Drain<'a> {
+    in_flight: &'a InFlightRequests,
+    metrics: &'a Metrics,
+    started_at: Instant,
+    deadline: tokio::time::Instant,
+    requests_finished_before: u64,
+    emails_sent_before: u64,
+    requests_completed: u64,
+    /// Still in flight at the deadline, cancelled when the server stops
+    requests_abandoned: usize,
+    timeouts_hit: u32,
+}
+
+impl<'a> Drain<'a> {
+    pub fn start(in_flight: &'a InFlightRequests, metrics: &'a Metrics, timeout: Duration) -> Self {
+        tracing::info!(
+            in_flight = in_flight.current(),
+            timeout_seconds = timeout.as_secs(),
+            "Shutting down, draining in-flight work"
+        );
+        Self {
+            in_flight,
+            metrics,
+            started_at: Instant::now(),
+            deadline: tokio::time::Instant::now() + timeout,
+            requests_finished_before: in_flight.finished(),
+            emails_sent_before: metrics.emails_sent_total.get(),
+            requests_completed: 0,
+            requests_abandoned: 0,
+            timeouts_hit: 0,
+        }
+    }
+
+    /// Wait for the requests in flight, counting those that finish in time
+    pub async fn wait_for_requests(&mut self) {
+        let in_flight = self.in_flight;
+        self.wait_for("in-flight requests", in_flight.drained())
+            .await;
+        self.requests_completed = in_flight.finished() - self.requests_finished_before;
+        self.requests_abandoned = in_flight.current();
+    }
+
+    /// Wait for `step` until the deadline. `None` if it didn't finish in time.
+    pub async fn wait_for<T>(&mut self, step: &str, work: impl Future<Output = T>) -> Option<T> {
+        let started_at = Instant::now();
+        match tokio::time::timeout_at(self.deadline, work).await {
+            Ok(output) => {
+                tracing::info!(
+                    step,
+                    elapsed_ms = started_at.elapsed().as_millis() as u64,
+                    "Drained"
+                );
+                Some(output)
+            }
+            Err(_) => {
+                self.timeouts_hit += 1;
+                tracing::warn!(step, "Did not drain before the shutdown timeout");
+                None
+            }
+        }
+    }
+
+    /// Log what the shutdown got done. `port` tells instances apart.
+    pub fn finish(self, port: u16) {
+        tracing::info!(
+            port,
+            requests_completed = self.requests_completed,
+            requests_abandoned = self.requests_abandoned,
+            emails_flushed = self.metrics.emails_sent_total.get() - self.emails_sent_before,
+            timeouts_hit = self.timeouts_hit,
+            elapsed_ms = self.started_at.elapsed().as_millis() as u64,
+            "Shutdown drain finished"
+        );
+    }
+}
+
+/// Resolve once the process receives SIGTERM or SIGINT
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => tracing::info!("Received SIGINT"),
+        () = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
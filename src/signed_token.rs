@@ -0,0 +1,139 @@
+//! src/signed_token.rs
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// What a signed confirmation token vouches for. Nothing about it is stored,
+/// `{subscriber_id}.{expires_at}.{signature}` carries it all.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SignedToken {
+    pub subscriber_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SignedTokenError {
+    #[error("The token isn't a signed confirmation token")]
+    Malformed,
+    #[error("The token's signature doesn't match")]
+    InvalidSignature,
+}
+
+impl SignedToken {
+    pub fn sign(&self, signing_key: &Secret<String>) -> String {
+        let payload = format!(
+            "{}.{}",
+            self.subscriber_id.simple(),
+            self.expires_at.timestamp()
+        );
+        let signature = hex::encode(mac(signing_key, &payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// The signature is checked before anything else is read from the token
+    pub fn verify(token: &str, signing_key: &Secret<String>) -> Result<Self, SignedTokenError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(SignedTokenError::Malformed)?;
+        let signature = hex::decode(signature).map_err(|_| SignedTokenError::Malformed)?;
+        mac(signing_key, payload)
+            .verify_slice(&signature)
+            .map_err(|_| SignedTokenError::InvalidSignature)?;
+        let (subscriber_id, expires_at) =
+            payload.split_once('.').ok_or(SignedTokenError::Malformed)?;
+        let subscriber_id =
+            Uuid::try_parse(subscriber_id).map_err(|_| SignedTokenError::Malformed)?;
+        let expires_at = expires_at
+            .parse()
+            .ok()
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .ok_or(SignedTokenError::Malformed)?;
+        Ok(Self {
+            subscriber_id,
+            expires_at,
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+fn mac(signing_key: &Secret<String>, payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signed_token::{SignedToken, SignedTokenError};
+    use chrono::{DateTime, Duration, SubsecRound, Utc};
+    use claims::assert_err_eq;
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn key(key: &str) -> Secret<String> {
+        Secret::new(key.into())
+    }
+
+    fn token(expires_at: DateTime<Utc>) -> SignedToken {
+        SignedToken {
+            subscriber_id: Uuid::new_v4(),
+            expires_at: expires_at.trunc_subsecs(0),
+        }
+    }
+
+    #[test]
+    fn a_signed_token_verifies_with_the_same_key() {
+        let token = token(Utc::now() + Duration::hours(1));
+        let signed = token.sign(&key("signing-key-0451"));
+
+        let verified = SignedToken::verify(&signed, &key("signing-key-0451")).unwrap();
+
+        assert_eq!(verified, token);
+        assert!(!verified.is_expired());
+    }
+
+    #[test]
+    fn a_token_signed_with_another_key_is_rejected() {
+        let signed = token(Utc::now()).sign(&key("signing-key-0451"));
+
+        assert_err_eq!(
+            SignedToken::verify(&signed, &key("another-key")),
+            SignedTokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn a_token_with_a_changed_expiry_is_rejected() {
+        let expired = token(Utc::now() - Duration::hours(1));
+        let signed = expired.sign(&key("signing-key-0451"));
+        let (payload, signature) = signed.rsplit_once('.').unwrap();
+        let tampered = format!(
+            "{}.{}.{}",
+            payload.split_once('.').unwrap().0,
+            (Utc::now() + Duration::hours(1)).timestamp(),
+            signature
+        );
+
+        assert!(expired.is_expired());
+        assert_err_eq!(
+            SignedToken::verify(&tampered, &key("signing-key-0451")),
+            SignedTokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn anything_but_a_signed_token_is_malformed() {
+        for token in ["", "abc", "abc.not-hex"] {
+            assert_err_eq!(
+                SignedToken::verify(token, &key("signing-key-0451")),
+                SignedTokenError::Malformed,
+                "{}",
+                token
+            );
+        }
+    }
+}
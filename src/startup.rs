@@ -1,35 +1,209 @@
-use crate::configuration::{DatabaseSettings, Settings};
-use crate::email_client::EmailClient;
-use crate::routes::{confirm, health_check, publish_newsletter, subscribe};
+use crate::body_limit::{require_content_length, BodyLimit};
+use crate::configuration::{
+    CorsSettings, DatabaseSettings, PasswordProvider, SessionSettings, SessionStoreKind, Settings,
+};
+use crate::confirmation_webhook::ConfirmationWebhook;
+use crate::deprecation::{add_deprecation_headers, Deprecations};
+use crate::email_client::EmailProvider;
+use crate::email_outbox::run_worker_until_stopped;
+use crate::forwarded::{forwarded_base_url, forwarded_client_ip};
+use crate::idempotency::clean_up_expired_keys;
+use crate::mail_domain::{MailDomainResolver, SystemResolver};
+use crate::metrics::{record_request_metrics, sample_pool_metrics, Metrics, METRICS_PATH};
+use crate::problem_details::render_problem_details;
+use crate::rate_limit::{limit_requests, RateLimiter};
+use crate::request_timeout::{time_out_requests, RequestTimeout};
+use crate::routes::{
+    admin_unsubscribe, build_info, bulk_update_status, change_admin_password, change_password_form,
+    confirm, confirm_form_submission, edit_subscriber, erase_subscriber, export_metrics,
+    export_subscriber, forgot_password, forgot_password_form, get_subscriber_detail, health_check,
+    import_subscribers, list_subscriptions, login, login_form, newsletter_form, preview_newsletter,
+    publish_newsletter, publish_newsletter_form, readiness_check, reconfirm_subscriber,
+    resend_confirmation, resend_newsletter, reset_password, reset_password_form,
+    search_subscriptions, subscribe, subscription_counts, subscription_status, unsubscribe,
+    unsubscribe_reasons, validate_confirmation_token, CONFIRMATION_EMAIL_TEMPLATE,
+};
+use crate::session_state::AppSessionStore;
+use crate::shutdown::{shutdown_signal, track_in_flight_requests, Drain, InFlightRequests};
+use crate::telemetry::{propagate_request_id, SampledRootSpanBuilder, TraceSampleRate};
+use actix_cors::Cors;
+use actix_session::storage::{CookieSessionStore, RedisSessionStore};
+use actix_session::SessionMiddleware;
+use actix_web::cookie::Key;
+use actix_web::dev::Payload;
 use actix_web::dev::Server;
-use actix_web::{web, App, HttpServer};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::web::{FormConfig, JsonConfig, PayloadConfig};
+use actix_web::{guard, web, App, FromRequest, HttpRequest, HttpServer};
+use secrecy::ExposeSecret;
+use sqlx::migrate::{Migrate, MigrateError};
 use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
-use std::net::TcpListener;
+use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::collections::HashSet;
+use std::future::{ready, Future, Ready};
+use std::net::{IpAddr, TcpListener};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tera::Tera;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tracing_actix_web::TracingLogger;
 
 pub struct Application {
     port: u16,
     server: Server,
+    /// Closed at shutdown, which stops the background tasks using it
+    pool: PgPool,
+    metrics: Arc<Metrics>,
+    in_flight: Arc<InFlightRequests>,
+    shutdown_timeout: Duration,
+    stop_worker: watch::Sender<bool>,
+    worker: Option<JoinHandle<()>>,
 }
 
-pub struct ApplicationBaseUrl(pub String);
+/// What links in emails start with
+pub struct ApplicationBaseUrl {
+    pub configured: String,
+    /// Whether a reverse proxy in front of us may tell us the external URL
+    pub trust_proxy_headers: bool,
+}
+
+impl ApplicationBaseUrl {
+    /// The base URL the request came in on according to the proxy, if
+    /// trusted and it says so, the configured one otherwise
+    pub fn for_request(&self, request: &HttpRequest) -> String {
+        if self.trust_proxy_headers {
+            if let Some(base_url) = forwarded_base_url(request.headers()) {
+                return base_url;
+            }
+        }
+        self.configured.clone()
+    }
+}
+
+/// The base URL for links in emails sent while handling a request
+pub struct RequestBaseUrl(pub String);
+
+impl FromRequest for RequestBaseUrl {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<RequestBaseUrl, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let base_url = req
+            .app_data::<web::Data<ApplicationBaseUrl>>()
+            .map(|base_url| RequestBaseUrl(base_url.for_request(req)))
+            .ok_or_else(|| ErrorInternalServerError("The application base URL is not configured"));
+        ready(base_url)
+    }
+}
+
+/// Whether a reverse proxy in front of us may tell us who the client is,
+/// and which proxies before it to skip
+pub struct TrustProxyHeaders {
+    pub enabled: bool,
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// The address of the client that sent a request: the one a trusted proxy
+/// forwarded it for, or else the peer. `None` if neither is known.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    pub fn of(req: &HttpRequest) -> Self {
+        let forwarded = req
+            .app_data::<web::Data<TrustProxyHeaders>>()
+            .filter(|trust| trust.enabled)
+            .and_then(|trust| forwarded_client_ip(req.headers(), &trust.trusted_proxies));
+        let peer = || req.peer_addr().map(|addr| addr.ip());
+        ClientIp(forwarded.or_else(peer))
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<ClientIp, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(ClientIp::of(req)))
+    }
+}
+
+/// How long the link in a password reset email works
+pub struct PasswordResetTokenTtl(pub chrono::Duration);
+
+/// How many newsletter emails are sent at once
+pub struct NewsletterSendConcurrency(pub usize);
+
+/// Whether readiness depends on the email provider being reachable
+pub struct EmailReadinessCheck(pub bool);
+
+/// When the server started, for the uptime in `/info`
+pub struct StartedAt(pub Instant);
+
+/// How confirmation emails are rendered
+pub struct ConfirmationEmailOptions {
+    pub subject: String,
+    /// What their HTML part is rendered from, plain text only without
+    pub templates: Option<Tera>,
+}
+
+/// Checks the email domain of new subscribers, if `email_client.verify_mx`
+pub struct MailDomainCheck(pub Option<Arc<dyn MailDomainResolver>>);
 
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
+        let password_provider = Arc::new(configuration.database.static_password());
+        Self::build_with_password_provider(configuration, password_provider).await
+    }
+
+    /// Like `build`, but the database password comes from `password_provider`
+    /// instead of the configuration file.
+    pub async fn build_with_password_provider(
+        configuration: Settings,
+        password_provider: Arc<dyn PasswordProvider>,
+    ) -> Result<Self, std::io::Error> {
         // Panic if we cant read the configuration
-        let connection_pool = get_connection_pool(&configuration.database);
-        let sender_email = configuration
+        let connection_pool = get_connection_pool_using(&configuration.database, password_provider);
+        if let Some(max_wait) = configuration.database.startup_max_wait() {
+            wait_for_database(
+                &connection_pool,
+                configuration.database.startup_max_retries,
+                max_wait,
+            )
+            .await
+            .map_err(std::io::Error::other)?;
+        }
+        if configuration.application.run_migrations_on_start {
+            run_migrations(&connection_pool)
+                .await
+                .map_err(std::io::Error::other)?;
+        }
+        let metrics = Arc::new(Metrics::new());
+        tokio::spawn(sample_pool_metrics(
+            connection_pool.clone(),
+            metrics.clone(),
+            configuration.telemetry.pool_metrics_interval(),
+        ));
+        if configuration.idempotency.cleanup_enabled {
+            tokio::spawn(clean_up_expired_keys(
+                connection_pool.clone(),
+                configuration.idempotency.clone(),
+            ));
+        }
+        let email_client = configuration
             .email_client
-            .sender()
-            .expect("Invalid sender email address");
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout,
-        );
+            .provider(metrics.clone())
+            .map_err(std::io::Error::other)?;
+        let confirmation_webhook = configuration
+            .confirmation_webhook
+            .clone()
+            .map(ConfirmationWebhook::new)
+            .transpose()
+            .map_err(|e| {
+                std::io::Error::other(format!("Invalid confirmation webhook template: {}", e))
+            })?;
 
         let address = format!(
             "{}:{}",
@@ -37,49 +211,579 @@ impl Application {
         );
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr()?.port();
+        let email_outbox = configuration.email_outbox.clone();
+        let shutdown_timeout =
+            Duration::from_secs(configuration.application.shutdown_timeout_seconds);
+        let in_flight = Arc::new(InFlightRequests::default());
         let server = run(
             listener,
-            connection_pool,
-            email_client,
-            configuration.application.base_url,
-        )?;
-        Ok(Self { port, server })
+            connection_pool.clone(),
+            email_client.clone(),
+            metrics.clone(),
+            in_flight.clone(),
+            confirmation_webhook,
+            configuration,
+        )
+        .await?;
+
+        let (stop_worker, stop_signal) = watch::channel(false);
+        let worker = email_outbox.worker_enabled.then(|| {
+            tokio::spawn(run_worker_until_stopped(
+                connection_pool.clone(),
+                email_client,
+                metrics.clone(),
+                email_outbox,
+                stop_signal,
+            ))
+        });
+        Ok(Self {
+            port,
+            server,
+            pool: connection_pool,
+            metrics,
+            in_flight,
+            shutdown_timeout,
+            stop_worker,
+            worker,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// Serve requests until SIGTERM or SIGINT, then shut down gracefully
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Serve requests until `signal` resolves. The server then stops
+    /// accepting connections, in-flight requests finish and the outbox worker
+    /// finishes the email it is sending, all by
+    /// `application.shutdown_timeout_seconds` after the signal. Closing the
+    /// pool last stops the other background tasks. What got drained is
+    /// logged at the end.
+    pub async fn run_until(self, signal: impl Future<Output = ()>) -> Result<(), std::io::Error> {
+        let handle = self.server.handle();
+        let mut server = tokio::spawn(self.server);
+        let mut worker = self.worker;
+        let mut drain = None;
+        let outcome = tokio::select! {
+            outcome = &mut server => outcome,
+            () = signal => {
+                let drain = drain.insert(Drain::start(
+                    &self.in_flight,
+                    &self.metrics,
+                    self.shutdown_timeout,
+                ));
+                handle.pause().await;
+                drain.wait_for_requests().await;
+                // The worker may be using a connection opened by one of the
+                // server's workers, which is of no use once they stopped
+                stop_outbox_worker(&self.stop_worker, worker.take(), Some(drain)).await;
+                handle.stop(true).await;
+                server.await
+            }
+        };
+        stop_outbox_worker(&self.stop_worker, worker, None).await;
+        // Marks the pool closed, which stops the background tasks using it.
+        // Connections opened by the server's workers can't be closed
+        // gracefully once those have stopped, so they aren't waited for.
+        drop(self.pool.close());
+        if let Some(drain) = drain {
+            drain.finish(self.port);
+        }
+        outcome.map_err(std::io::Error::other)?
+    }
+}
+
+/// Stop the outbox worker once it has sent the email it is on, within the
+/// deadline of `drain` if there is one
+async fn stop_outbox_worker(
+    stop: &watch::Sender<bool>,
+    worker: Option<JoinHandle<()>>,
+    drain: Option<&mut Drain<'_>>,
+) {
+    // The worker may already be gone if it panicked
+    let _ = stop.send(true);
+    let Some(worker) = worker else {
+        return;
+    };
+    let abort = worker.abort_handle();
+    let stopped = match drain {
+        Some(drain) => drain.wait_for("email outbox worker", worker).await,
+        None => Some(worker.await),
+    };
+    match stopped {
+        Some(Ok(())) => {}
+        Some(Err(e)) => tracing::error!(error.cause_chain = ?e, "The email outbox worker failed"),
+        None => abort.abort(),
     }
 }
 
-pub fn run(
+pub async fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
+    email_client: Arc<dyn EmailProvider>,
+    metrics: Arc<Metrics>,
+    in_flight: Arc<InFlightRequests>,
+    confirmation_webhook: Option<ConfirmationWebhook>,
+    configuration: Settings,
 ) -> Result<Server, std::io::Error> {
-    let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let started_at = web::Data::new(StartedAt(Instant::now()));
+    let templates = load_email_templates(&configuration)?;
+    let session_key = Key::try_from(configuration.session.hmac_secret.expose_secret().as_bytes())
+        .map_err(std::io::Error::other)?;
+    let session_store = session_store(&configuration.session).await?;
+    let cookie_secure = configuration.session.cookie_secure;
+    let cors_settings = configuration.cors.clone();
+    let shutdown_timeout = configuration.application.shutdown_timeout_seconds;
+    let workers = configuration.application.workers;
+    let max_body_bytes = configuration.application.max_body_bytes;
+    let max_newsletter_body_bytes = configuration.application.max_newsletter_body_bytes;
+    let body_limit = web::Data::new(BodyLimit(max_body_bytes));
+    let newsletter_body_limit = web::Data::new(BodyLimit(max_newsletter_body_bytes));
+    let deprecations =
+        Deprecations::new(&configuration.deprecations).map_err(std::io::Error::other)?;
+    let password_reset_token_ttl = web::Data::new(PasswordResetTokenTtl(
+        configuration.application.password_reset_token_ttl(),
+    ));
+    let base_url = web::Data::new(ApplicationBaseUrl {
+        configured: configuration.application.base_url,
+        trust_proxy_headers: configuration.application.trust_proxy_headers,
+    });
+    let trust_proxy_headers = web::Data::new(TrustProxyHeaders {
+        enabled: configuration.application.trust_proxy_headers,
+        trusted_proxies: configuration.application.trusted_proxies.clone(),
+    });
+    let send_concurrency = web::Data::new(NewsletterSendConcurrency(
+        configuration.email_client.max_concurrent_sends,
+    ));
+    let email_readiness_check = web::Data::new(EmailReadinessCheck(
+        configuration.email_client.readiness_check_enabled,
+    ));
+    let mail_domain_check = web::Data::new(MailDomainCheck(
+        configuration
+            .email_client
+            .verify_mx
+            .then(|| Arc::new(SystemResolver) as Arc<dyn MailDomainResolver>),
+    ));
+    let confirmation_email_options = web::Data::new(ConfirmationEmailOptions {
+        subject: configuration.email_client.confirmation_subject,
+        templates,
+    });
+    let trace_sample_rate =
+        web::Data::new(TraceSampleRate(configuration.telemetry.trace_sample_rate));
+    let rate_limiter = web::Data::new(RateLimiter::new(&configuration.subscriptions.rate_limit));
+    let validate_rate_limiter = web::Data::new(RateLimiter::new(
+        &configuration.subscriptions.validate_rate_limit,
+    ));
+    let subscriber_identifier = web::Data::new(configuration.telemetry.log_subscriber_identifier);
+    let subscribe_timeout = web::Data::new(RequestTimeout(
+        configuration.subscriptions.request_timeout(),
+    ));
+    let subscription_settings = web::Data::new(configuration.subscriptions);
+    let confirmation_webhook = web::Data::new(confirmation_webhook);
+    let deprecations = web::Data::new(deprecations);
+    let metrics = web::Data::from(metrics);
+    let in_flight = web::Data::from(in_flight);
     let db_pool = web::Data::new(db_pool);
     let email_client = web::Data::new(email_client);
-    let server = HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
-            .wrap(TracingLogger::default())
+            .wrap(from_fn(render_problem_details))
+            .wrap(
+                SessionMiddleware::builder(session_store.clone(), session_key.clone())
+                    .cookie_secure(cookie_secure)
+                    .build(),
+            )
+            .wrap(from_fn(add_deprecation_headers))
+            .wrap(from_fn(record_request_metrics))
+            .wrap(from_fn(propagate_request_id))
+            .wrap(TracingLogger::<SampledRootSpanBuilder>::new())
+            .wrap(from_fn(track_in_flight_requests))
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
-            .route("/subscriptions/confirm", web::get().to(confirm))
-            .route("/newsletters", web::post().to(publish_newsletter))
+            .route("/health/ready", web::get().to(readiness_check))
+            .route("/info", web::get().to(build_info))
+            .route(METRICS_PATH, web::get().to(export_metrics))
+            // Browsers may sign up from other origins, but never read the
+            // admin listing that shares the path
+            .service(
+                web::resource("/subscriptions")
+                    .guard(guard::Any(guard::Post()).or(guard::Options()))
+                    .app_data(subscribe_timeout.clone())
+                    .wrap(cors(&cors_settings))
+                    .route(
+                        web::post()
+                            .to(subscribe)
+                            .wrap(from_fn(time_out_requests))
+                            .wrap(from_fn(limit_requests))
+                            .wrap(from_fn(require_content_length)),
+                    ),
+            )
+            .service(web::resource("/subscriptions").route(web::get().to(list_subscriptions)))
+            .route("/subscriptions/count", web::get().to(subscription_counts))
+            .service(
+                web::resource("/subscriptions/resend-confirmation")
+                    .wrap(from_fn(limit_requests))
+                    .wrap(cors(&cors_settings))
+                    .route(web::post().to(resend_confirmation)),
+            )
+            .service(
+                web::resource("/subscriptions/confirm")
+                    .wrap(cors(&cors_settings))
+                    .route(web::get().to(confirm))
+                    .route(web::post().to(confirm_form_submission)),
+            )
+            .service(
+                // Its own limiter shadows the shared one. The limit wraps the
+                // route, not the resource, to run after the resource's
+                // `app_data` is in place.
+                web::resource("/subscriptions/confirm/validate")
+                    .app_data(validate_rate_limiter.clone())
+                    .wrap(cors(&cors_settings))
+                    .route(
+                        web::post()
+                            .to(validate_confirmation_token)
+                            .wrap(from_fn(limit_requests)),
+                    ),
+            )
+            .route("/subscriptions/status", web::get().to(subscription_status))
+            .service(
+                web::resource("/unsubscribe")
+                    .route(web::get().to(unsubscribe))
+                    // One-click unsubscribes from mail clients, see RFC 8058
+                    .route(web::post().to(unsubscribe)),
+            )
+            .service(
+                web::resource("/newsletters")
+                    .app_data(JsonConfig::default().limit(max_newsletter_body_bytes))
+                    .app_data(newsletter_body_limit.clone())
+                    .route(
+                        web::post()
+                            .to(publish_newsletter)
+                            .wrap(from_fn(require_content_length)),
+                    ),
+            )
+            .service(
+                web::resource("/newsletters/preview")
+                    .app_data(JsonConfig::default().limit(max_newsletter_body_bytes))
+                    .app_data(newsletter_body_limit.clone())
+                    .route(
+                        web::post()
+                            .to(preview_newsletter)
+                            .wrap(from_fn(require_content_length)),
+                    ),
+            )
+            .route(
+                "/newsletters/{id}/resend",
+                web::post().to(resend_newsletter),
+            )
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            .route("/password/forgot", web::get().to(forgot_password_form))
+            .route("/password/forgot", web::post().to(forgot_password))
+            .route("/password/reset", web::get().to(reset_password_form))
+            .route("/password/reset", web::post().to(reset_password))
+            .route("/admin/password", web::get().to(change_password_form))
+            .route("/admin/password", web::post().to(change_admin_password))
+            .service(
+                web::resource("/admin/newsletters")
+                    .app_data(FormConfig::default().limit(max_newsletter_body_bytes))
+                    .app_data(newsletter_body_limit.clone())
+                    .route(web::get().to(newsletter_form))
+                    .route(
+                        web::post()
+                            .to(publish_newsletter_form)
+                            .wrap(from_fn(require_content_length)),
+                    ),
+            )
+            .route("/admin/unsubscribe", web::post().to(admin_unsubscribe))
+            .route(
+                "/admin/subscriptions/import",
+                web::post().to(import_subscribers),
+            )
+            .route(
+                "/admin/subscriptions/search",
+                web::get().to(search_subscriptions),
+            )
+            .route(
+                "/admin/subscribers/bulk-status",
+                web::post().to(bulk_update_status),
+            )
+            .route(
+                "/admin/subscribers/{id}",
+                web::get().to(get_subscriber_detail),
+            )
+            .route("/admin/subscribers/{id}", web::patch().to(edit_subscriber))
+            .route(
+                "/admin/subscribers/{id}",
+                web::delete().to(erase_subscriber),
+            )
+            .route(
+                "/admin/subscribers/{id}/export",
+                web::get().to(export_subscriber),
+            )
+            .route(
+                "/admin/subscribers/{id}/reconfirm",
+                web::post().to(reconfirm_subscriber),
+            )
+            .route(
+                "/admin/stats/unsubscribe-reasons",
+                web::get().to(unsubscribe_reasons),
+            )
+            .app_data(FormConfig::default().limit(max_body_bytes))
+            .app_data(JsonConfig::default().limit(max_body_bytes))
+            .app_data(PayloadConfig::default().limit(max_body_bytes))
+            .app_data(body_limit.clone())
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(trust_proxy_headers.clone())
+            .app_data(password_reset_token_ttl.clone())
+            .app_data(confirmation_email_options.clone())
+            .app_data(send_concurrency.clone())
+            .app_data(email_readiness_check.clone())
+            .app_data(started_at.clone())
+            .app_data(mail_domain_check.clone())
+            .app_data(trace_sample_rate.clone())
+            .app_data(subscription_settings.clone())
+            .app_data(subscriber_identifier.clone())
+            .app_data(confirmation_webhook.clone())
+            .app_data(deprecations.clone())
+            .app_data(metrics.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(in_flight.clone())
     })
-    .listen(listener)?
-    .run();
-    Ok(server)
+    // Shutdown signals are handled by `Application::run_until_stopped`, which
+    // drains in-flight requests before stopping the server
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout);
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    Ok(server.listen(listener)?.run())
+}
+
+/// Built per worker and route. With no allowed origins browsers keep
+/// blocking cross-origin calls, as before CORS support.
+fn cors(settings: &CorsSettings) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(["GET", "POST"])
+        .allowed_headers([header::CONTENT_TYPE, header::ACCEPT])
+        .max_age(settings.max_age_seconds);
+    for origin in &settings.allowed_origins {
+        cors = if origin == "*" {
+            cors.allow_any_origin()
+        } else {
+            cors.allowed_origin(origin)
+        };
+    }
+    if settings.allow_credentials {
+        cors = cors.supports_credentials();
+    }
+    cors
+}
+
+async fn session_store(settings: &SessionSettings) -> Result<AppSessionStore, std::io::Error> {
+    match settings.store {
+        SessionStoreKind::Cookie => Ok(AppSessionStore::Cookie(CookieSessionStore::default())),
+        SessionStoreKind::Redis => {
+            let redis_uri = settings
+                .redis_uri
+                .as_ref()
+                .ok_or_else(|| std::io::Error::other("The redis session store needs a URI"))?;
+            let store = RedisSessionStore::new(redis_uri.expose_secret().clone())
+                .await
+                .map_err(|e| std::io::Error::other(format!("Failed to connect to Redis: {}", e)))?;
+            Ok(AppSessionStore::Redis(Box::new(store)))
+        }
+    }
+}
+
+/// Compile the email templates once, so a broken template stops the
+/// application from starting instead of failing requests. With HTML emails
+/// disabled no templates are needed at all.
+fn load_email_templates(configuration: &Settings) -> Result<Option<Tera>, std::io::Error> {
+    if !configuration.email_client.html_enabled {
+        return Ok(None);
+    }
+    let glob = format!("{}/**/*", configuration.application.templates_directory);
+    let templates = Tera::new(&glob).map_err(|e| {
+        std::io::Error::other(format!(
+            "Failed to compile the email templates in {}: {:?}",
+            configuration.application.templates_directory, e
+        ))
+    })?;
+    if !templates
+        .get_template_names()
+        .any(|name| name == CONFIRMATION_EMAIL_TEMPLATE)
+    {
+        return Err(std::io::Error::other(format!(
+            "The email template {} is missing from {}",
+            CONFIRMATION_EMAIL_TEMPLATE, configuration.application.templates_directory
+        )));
+    }
+    Ok(Some(templates))
 }
 
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
-    PgPoolOptions::new().connect_lazy_with(configuration.with_db())
+    get_connection_pool_using(configuration, Arc::new(configuration.static_password()))
+}
+
+/// Build a pool whose password comes from `password_provider`. If the
+/// provider rotates its password, the pool's connect options are rebuilt in
+/// the background so new connections use the fresh one.
+pub fn get_connection_pool_using(
+    configuration: &DatabaseSettings,
+    password_provider: Arc<dyn PasswordProvider>,
+) -> PgPool {
+    let statement_timeout = configuration.statement_timeout();
+    let pool = PgPoolOptions::new()
+        .max_connections(configuration.max_connections)
+        .acquire_timeout(configuration.acquire_timeout())
+        .idle_timeout(configuration.idle_timeout())
+        .test_before_acquire(configuration.test_before_acquire)
+        .after_connect(move |connection, _| {
+            Box::pin(async move {
+                if let Some(statement_timeout) = statement_timeout {
+                    // `SET` doesn't take bind parameters
+                    let statement =
+                        format!("SET statement_timeout = {}", statement_timeout.as_millis());
+                    connection.execute(statement.as_str()).await?;
+                }
+                Ok(())
+            })
+        })
+        .connect_lazy_with(configuration.with_db_using(&*password_provider));
+    if let Some(refresh_interval) = password_provider.refresh_interval() {
+        let pool = pool.clone();
+        let configuration = configuration.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(refresh_interval);
+            // The first tick completes immediately
+            interval.tick().await;
+            let mut closed = pool.close_event();
+            while closed.do_until(interval.tick()).await.is_ok() {
+                tracing::debug!("Refreshing the database password");
+                pool.set_connect_options(configuration.with_db_using(&*password_provider));
+            }
+        });
+    }
+    if let Some(keepalive_interval) = configuration.keepalive_interval() {
+        tokio::spawn(keep_connections_warm(pool.clone(), keepalive_interval));
+    }
+    pool
+}
+
+/// Postgres still couldn't be reached when startup gave up on it
+#[derive(thiserror::Error, Debug)]
+#[error("Failed to connect to Postgres after {attempts} attempts")]
+pub struct DatabaseUnavailable {
+    pub attempts: u32,
+    #[source]
+    source: sqlx::Error,
+}
+
+/// Doubled after every failed attempt
+const INITIAL_CONNECT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Connect once with the options of `pool`, retrying with exponential
+/// backoff up to `max_retries` times or until `max_wait` has passed,
+/// whichever comes first.
+pub async fn wait_for_database(
+    pool: &PgPool,
+    max_retries: u32,
+    max_wait: Duration,
+) -> Result<(), DatabaseUnavailable> {
+    let deadline = tokio::time::Instant::now() + max_wait;
+    let mut backoff = INITIAL_CONNECT_BACKOFF;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let options = pool.connect_options();
+        let outcome = tokio::time::timeout_at(deadline, PgConnection::connect_with(&options))
+            .await
+            .unwrap_or_else(|_| Err(sqlx::Error::Io(std::io::ErrorKind::TimedOut.into())));
+        let e = match outcome {
+            Ok(connection) => {
+                if let Err(e) = connection.close().await {
+                    tracing::warn!(error.cause_chain = ?e, "Failed to close the startup connection");
+                }
+                tracing::info!(attempts, "Connected to Postgres");
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if attempts > max_retries || remaining.is_zero() {
+            tracing::error!(error.cause_chain = ?e, attempts, "Giving up connecting to Postgres");
+            return Err(DatabaseUnavailable {
+                attempts,
+                source: e,
+            });
+        }
+        tracing::warn!(
+            error.cause_chain = ?e,
+            attempt = attempts,
+            retry_in = ?backoff,
+            "Postgres is not reachable yet, retrying"
+        );
+        tokio::time::sleep(backoff.min(remaining)).await;
+        backoff *= 2;
+    }
+}
+
+/// Pending migrations couldn't be applied
+#[derive(thiserror::Error, Debug)]
+#[error("Failed to run the database migrations: {0}")]
+pub struct MigrationsFailed(#[from] MigrateError);
+
+/// Apply the migrations the database doesn't have yet, logging each one
+pub async fn run_migrations(pool: &PgPool) -> Result<(), MigrationsFailed> {
+    let migrator = sqlx::migrate!("./migrations");
+    let applied: HashSet<i64> = {
+        let mut connection = pool.acquire().await.map_err(MigrateError::Execute)?;
+        connection.ensure_migrations_table().await?;
+        connection
+            .list_applied_migrations()
+            .await?
+            .into_iter()
+            .map(|migration| migration.version)
+            .collect()
+    };
+    migrator.run(pool).await?;
+    for migration in migrator.iter() {
+        if !applied.contains(&migration.version) {
+            tracing::info!(
+                version = migration.version,
+                description = %migration.description,
+                "Applied a database migration"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Ping an idle connection every `interval`. A stale one is dropped by the
+/// ping, so the pool reconnects before a request needs it. Stops when the
+/// pool is closed.
+async fn keep_connections_warm(pool: PgPool, interval: std::time::Duration) {
+    let mut interval = tokio::time::interval(interval);
+    let mut closed = pool.close_event();
+    while closed.do_until(interval.tick()).await.is_ok() {
+        if pool.num_idle() == 0 {
+            continue;
+        }
+        match pool.acquire().await {
+            Ok(mut connection) => {
+                if let Err(e) = connection.ping().await {
+                    tracing::warn!(error.cause_chain = ?e, "Database keepalive ping failed");
+                    // Don't put the broken connection back
+                    drop(connection.detach());
+                }
+            }
+            Err(e) => tracing::warn!(error.cause_chain = ?e, "Database keepalive ping failed"),
+        }
+    }
 }
@@ -0,0 +1,109 @@
+//! src/subscription_events.rs
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// A change in a subscriber's lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionEvent {
+    Subscribed,
+    /// Someone who unsubscribed signed up again
+    Resubscribed,
+    Confirmed,
+    Unsubscribed,
+    /// Set directly by an admin
+    StatusChanged,
+    /// Their email and name were erased on request
+    Erased,
+}
+
+impl AsRef<str> for SubscriptionEvent {
+    fn as_ref(&self) -> &str {
+        match self {
+            Self::Subscribed => "subscribed",
+            Self::Resubscribed => "resubscribed",
+            Self::Confirmed => "confirmed",
+            Self::Unsubscribed => "unsubscribed",
+            Self::StatusChanged => "status_changed",
+            Self::Erased => "erased",
+        }
+    }
+}
+
+/// A row of a subscriber's audit trail
+#[derive(Debug, serde::Serialize)]
+pub struct RecordedSubscriptionEvent {
+    pub event: String,
+    pub occurred_at: DateTime<Utc>,
+    pub metadata: serde_json::Value,
+}
+
+/// Append to the audit trail. Call it in the transaction that changes the
+/// status, so the trail never disagrees with the subscriptions table.
+#[tracing::instrument(name = "Record a subscription event", skip(transaction, metadata))]
+pub async fn record_subscription_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    event: SubscriptionEvent,
+    metadata: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO subscription_events (subscriber_id, event, occurred_at, metadata)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        subscriber_id,
+        event.as_ref(),
+        Utc::now(),
+        metadata
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// `record_subscription_event` for many subscribers in one statement, all
+/// with the same metadata
+#[tracing::instrument(
+    name = "Record subscription events",
+    skip(transaction, subscriber_ids, metadata),
+    fields(subscribers = subscriber_ids.len())
+)]
+pub async fn record_subscription_events(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_ids: &[Uuid],
+    event: SubscriptionEvent,
+    metadata: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO subscription_events (subscriber_id, event, occurred_at, metadata)
+        SELECT subscriber_id, $2, $3, $4 FROM UNNEST($1::uuid[]) AS t(subscriber_id)
+        "#,
+        subscriber_ids,
+        event.as_ref(),
+        Utc::now(),
+        metadata
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// A subscriber's events, oldest first
+#[tracing::instrument(name = "Get subscription events", skip(pool))]
+pub async fn get_subscription_events(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Vec<RecordedSubscriptionEvent>, sqlx::Error> {
+    sqlx::query_as!(
+        RecordedSubscriptionEvent,
+        r#"
+        SELECT event, occurred_at, metadata
+        FROM subscription_events
+        WHERE subscriber_id = $1
+        ORDER BY id
+        "#,
+        subscriber_id
+    )
+    .fetch_all(pool)
+    .await
+}
@@ -1,27 +1,83 @@
 //! src/telemetry.rs
+use crate::configuration::LogFormat;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use rand::Rng;
+use std::future::{ready, Ready};
 use tokio::task::JoinHandle;
 use tracing::subscriber::set_global_default;
-use tracing::Subscriber;
+use tracing::{Span, Subscriber};
+use tracing_actix_web::{DefaultRootSpanBuilder, RootSpanBuilder};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
 use tracing_subscriber::fmt::MakeWriter;
-use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Layer, Registry};
+use uuid::Uuid;
 
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Logs are written to `sink` in `format`. Spans are also exported to an
+/// OpenTelemetry collector if `otlp_endpoint` is set, e.g.
+/// `http://localhost:4318/v1/traces`. Must be called from within a Tokio
+/// runtime in that case.
 pub fn get_subscriber<Sink>(
     name: String,
     env_filter: String,
     sink: Sink,
+    otlp_endpoint: Option<String>,
+    format: LogFormat,
 ) -> impl Subscriber + Send + Sync
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(env_filter));
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    let otlp_layer = otlp_endpoint.map(|endpoint| {
+        let tracer = otlp_tracer_provider(&name, endpoint).tracer(name.clone());
+        tracing_opentelemetry::layer().with_tracer(tracer)
+    });
+    let (json_layer, pretty_layer) = match format {
+        LogFormat::Json => (
+            Some(JsonStorageLayer.and_then(BunyanFormattingLayer::new(name, sink))),
+            None,
+        ),
+        LogFormat::Pretty => (
+            None,
+            Some(tracing_subscriber::fmt::layer().with_writer(sink)),
+        ),
+    };
     Registry::default()
         .with(env_filter)
-        .with(JsonStorageLayer)
-        .with(formatting_layer)
+        .with(json_layer)
+        .with(pretty_layer)
+        .with(otlp_layer)
+}
+
+/// Export spans over OTLP/HTTP in batches. The provider is registered
+/// globally, so `opentelemetry::global::shutdown_tracer_provider` flushes it.
+fn otlp_tracer_provider(service_name: &str, endpoint: String) -> TracerProvider {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .expect("Failed to build the OTLP span exporter");
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            service_name.to_string(),
+        )]))
+        .build();
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    provider
 }
 
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
@@ -37,3 +93,214 @@ where
     let current_span = tracing::Span::current();
     tokio::task::spawn_blocking(move || current_span.in_scope(f))
 }
+
+/// The correlation id of a request: the caller's `X-Request-Id` if it sent a
+/// usable one, a fresh UUID otherwise
+#[derive(Clone, Debug)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let usable = !value.is_empty()
+            && value.len() <= 128
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+        usable.then(|| Self(value.to_string()))
+    }
+
+    fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// `None` outside of requests seen by `SampledRootSpanBuilder`
+    pub fn of(request: &HttpRequest) -> Option<Self> {
+        request.extensions().get::<RequestId>().cloned()
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(RequestId::of(request).ok_or_else(|| {
+            actix_web::error::ErrorInternalServerError("The request id middleware is not set up")
+        }))
+    }
+}
+
+/// Echo the request's `RequestId` in the `X-Request-Id` response header.
+/// The id itself is attached by `SampledRootSpanBuilder`.
+pub async fn propagate_request_id(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = request.extensions().get::<RequestId>().cloned();
+    let mut response = next.call(request).await?;
+    if let Some(value) = request_id.and_then(|id| HeaderValue::from_str(&id.0).ok()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
+}
+
+/// Fraction of requests (between 0.0 and 1.0, see `Settings::validate`)
+/// that get a full root span.
+pub struct TraceSampleRate(pub f64);
+
+/// A `RootSpanBuilder` that only creates a root span for a sampled fraction
+/// of requests. Failed requests always get a root span, even if they were
+/// not sampled when they started.
+///
+/// Every request also gets a `RequestId`, recorded as the root span's
+/// `request_id` so all spans of the request carry it.
+pub struct SampledRootSpanBuilder;
+
+impl RootSpanBuilder for SampledRootSpanBuilder {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        request.extensions_mut().insert(request_id.clone());
+        let sample_rate = request
+            .app_data::<web::Data<TraceSampleRate>>()
+            .map(|rate| rate.0)
+            .unwrap_or(1.0);
+        if rand::thread_rng().gen_bool(sample_rate) {
+            let span = DefaultRootSpanBuilder::on_request_start(request);
+            span.record("request_id", tracing::field::display(&request_id));
+            span
+        } else {
+            Span::none()
+        }
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        if !span.is_disabled() {
+            return DefaultRootSpanBuilder::on_request_end(span, outcome);
+        }
+        let failed = match outcome {
+            Ok(response) => {
+                response.response().error().is_some() || response.status().is_server_error()
+            }
+            Err(_) => true,
+        };
+        if failed {
+            let span = match outcome {
+                Ok(response) => tracing::info_span!(
+                    "HTTP request",
+                    http.method = %response.request().method(),
+                    http.target = %response.request().uri(),
+                    request_id = response
+                        .request()
+                        .extensions()
+                        .get::<RequestId>()
+                        .map(tracing::field::display),
+                    http.status_code = tracing::field::Empty,
+                    otel.status_code = tracing::field::Empty,
+                    exception.message = tracing::field::Empty,
+                    exception.details = tracing::field::Empty,
+                ),
+                Err(_) => tracing::info_span!(
+                    "HTTP request",
+                    http.status_code = tracing::field::Empty,
+                    otel.status_code = tracing::field::Empty,
+                    exception.message = tracing::field::Empty,
+                    exception.details = tracing::field::Empty,
+                ),
+            };
+            DefaultRootSpanBuilder::on_request_end(span, outcome);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::LogFormat;
+    use crate::telemetry::{get_subscriber, SampledRootSpanBuilder, TraceSampleRate};
+    use actix_web::{test, web, App, HttpResponse};
+    use std::sync::{Arc, Mutex};
+    use tracing::span::{Attributes, Id};
+    use tracing::Subscriber;
+    use tracing_actix_web::TracingLogger;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::{Layer, Registry};
+
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(attrs.metadata().name().to_string());
+        }
+    }
+
+    impl SpanRecorder {
+        fn root_spans(&self) -> usize {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|name| *name == "HTTP request")
+                .count()
+        }
+    }
+
+    #[actix_web::test]
+    async fn with_a_sample_rate_of_zero_only_errors_get_a_root_span() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(Registry::default().with(recorder.clone()));
+        let app = test::init_service(
+            App::new()
+                .wrap(TracingLogger::<SampledRootSpanBuilder>::new())
+                .app_data(web::Data::new(TraceSampleRate(0.0)))
+                .route("/ok", web::get().to(HttpResponse::Ok))
+                .route("/error", web::get().to(HttpResponse::InternalServerError)),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/ok").to_request();
+        test::call_service(&app, request).await;
+        assert_eq!(recorder.root_spans(), 0);
+
+        let request = test::TestRequest::get().uri("/error").to_request();
+        test::call_service(&app, request).await;
+        assert_eq!(recorder.root_spans(), 1);
+    }
+
+    #[actix_web::test]
+    async fn a_subscriber_exporting_to_an_otlp_endpoint_can_be_built() {
+        let subscriber = get_subscriber(
+            "test".into(),
+            "info".into(),
+            std::io::sink,
+            Some("http://127.0.0.1:4318/v1/traces".into()),
+            LogFormat::Json,
+        );
+        let _guard = tracing::subscriber::set_default(subscriber);
+        tracing::info_span!("Exported span").in_scope(|| tracing::info!("Inside"));
+    }
+
+    #[actix_web::test]
+    async fn subscribers_can_be_built_for_both_log_formats() {
+        for format in [LogFormat::Json, LogFormat::Pretty] {
+            let subscriber =
+                get_subscriber("test".into(), "info".into(), std::io::sink, None, format);
+            tracing::subscriber::with_default(subscriber, || {
+                tracing::info_span!("A span", answer = 42).in_scope(|| tracing::info!("Inside"))
+            });
+        }
+    }
+}
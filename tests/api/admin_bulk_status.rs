@@ -0,0 +1,152 @@
+use crate::helpers::{spawn_app, TestApp};
+use chrono::Utc;
+use uuid::Uuid;
+
+async fn insert_subscriber(app: &TestApp, status: &str) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status) \
+        VALUES ($1, $2, $3, $4, $5)",
+        id,
+        format!("{}@example.com", id),
+        "le guin",
+        Utc::now(),
+        status
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert subscriber");
+    id
+}
+
+async fn status_of(app: &TestApp, id: Uuid) -> String {
+    sqlx::query!("SELECT status FROM subscriptions WHERE id = $1", id)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch subscriber status")
+        .status
+}
+
+#[tokio::test]
+async fn bulk_status_update_reports_and_persists_each_transition() {
+    let app = spawn_app().await;
+    let first_pending = insert_subscriber(&app, "pending_confirmation").await;
+    let second_pending = insert_subscriber(&app, "pending_confirmation").await;
+    let already_confirmed = insert_subscriber(&app, "confirmed").await;
+    let unsubscribed = insert_subscriber(&app, "unsubscribed").await;
+    let unknown = Uuid::new_v4();
+
+    let response = app
+        .post_bulk_status(serde_json::json!({
+            "ids": [first_pending, second_pending, already_confirmed, unsubscribed, unknown],
+            "status": "confirmed"
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let outcomes: Vec<_> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["outcome"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(
+        outcomes,
+        vec![
+            "updated",
+            "updated",
+            "unchanged",
+            "invalid_transition",
+            "not_found"
+        ]
+    );
+    assert_eq!(status_of(&app, first_pending).await, "confirmed");
+    assert_eq!(status_of(&app, second_pending).await, "confirmed");
+    assert_eq!(status_of(&app, already_confirmed).await, "confirmed");
+    assert_eq!(status_of(&app, unsubscribed).await, "unsubscribed");
+}
+
+#[tokio::test]
+async fn bulk_status_update_rejects_an_unknown_target_status() {
+    let app = spawn_app().await;
+    let id = insert_subscriber(&app, "pending_confirmation").await;
+
+    let response = app
+        .post_bulk_status(serde_json::json!({ "ids": [id], "status": "deleted" }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(status_of(&app, id).await, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn bulk_status_update_rejects_an_oversized_batch() {
+    let app = spawn_app().await;
+    let id = insert_subscriber(&app, "pending_confirmation").await;
+    let ids: Vec<_> = std::iter::once(id)
+        .chain((0..1000).map(|_| Uuid::new_v4()))
+        .collect();
+
+    let response = app
+        .post_bulk_status(serde_json::json!({ "ids": ids, "status": "confirmed" }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(status_of(&app, id).await, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn bulk_status_update_reports_a_repeated_id_once() {
+    let app = spawn_app().await;
+    let pending = insert_subscriber(&app, "pending_confirmation").await;
+    let confirmed = insert_subscriber(&app, "confirmed").await;
+
+    let response = app
+        .post_bulk_status(serde_json::json!({
+            "ids": [pending, confirmed, pending],
+            "status": "confirmed"
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let results: Vec<_> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| {
+            (
+                r["id"].as_str().unwrap().parse::<Uuid>().unwrap(),
+                r["outcome"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            (pending, "updated".to_owned()),
+            (confirmed, "unchanged".to_owned())
+        ]
+    );
+}
+
+#[tokio::test]
+async fn bulk_status_update_requires_admin_credentials() {
+    let app = spawn_app().await;
+    let id = insert_subscriber(&app, "pending_confirmation").await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/subscribers/bulk-status", &app.address))
+        .json(&serde_json::json!({ "ids": [id], "status": "confirmed" }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["WWW-Authenticate"],
+        r#"Basic realm="admin""#
+    );
+    assert_eq!(status_of(&app, id).await, "pending_confirmation");
+}
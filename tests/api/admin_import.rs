@@ -0,0 +1,200 @@
+use crate::helpers::{
+    captured_logs, create_confirmed_subscriber, spawn_app, spawn_app_with_configuration, TestApp,
+};
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn saved_statuses(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!("SELECT email, status FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions")
+        .into_iter()
+        .map(|r| (r.email, r.status))
+        .collect()
+}
+
+#[tokio::test]
+async fn a_mixed_batch_imports_the_valid_rows_and_reports_on_every_row() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_import(
+            serde_json::json!([
+                { "name": "Octavia Butler", "email": "octavia@example.com" },
+                { "name": "<script>", "email": "script@example.com" },
+                { "name": "No Email" },
+                { "name": "Ursula", "email": "Ursula_Le_Guin@gmail.com" },
+                { "name": "Octavia Again", "email": "octavia@example.com" },
+            ]),
+            false,
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 207);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let results: Vec<_> = body["results"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| {
+            (
+                r["row"].as_u64().unwrap(),
+                r["status"].as_str().unwrap().to_owned(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            (1, "imported".into()),
+            (2, "invalid".into()),
+            (3, "invalid".into()),
+            (4, "already_subscribed".into()),
+            (5, "already_subscribed".into()),
+        ]
+    );
+    assert_eq!(body["results"][2]["error"], "missing field `email`");
+    assert_eq!(
+        saved_statuses(&app).await,
+        vec![
+            ("octavia@example.com".into(), "pending_confirmation".into()),
+            ("ursula_le_guin@gmail.com".into(), "confirmed".into()),
+        ]
+    );
+    // Pending imports are asked to confirm
+    app.dispatch_all_pending_emails().await;
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let last_email = email_requests.last().unwrap();
+    let confirmation_links = app.get_confirmation_links(last_email);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    assert_eq!(saved_statuses(&app).await[0].1, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribers_can_be_imported_as_confirmed_without_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_import(
+            serde_json::json!([
+                { "name": "Octavia Butler", "email": "octavia@example.com" },
+                { "name": "N. K. Jemisin", "email": "nk@example.com" },
+            ]),
+            true,
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+    assert_eq!(
+        saved_statuses(&app).await,
+        vec![
+            ("nk@example.com".into(), "confirmed".into()),
+            ("octavia@example.com".into(), "confirmed".into()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn thousands_of_rows_are_imported_in_batches() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.application.max_body_bytes = 1 << 20;
+        c.subscriptions.import_batch_size = 1000;
+    })
+    .await;
+    let mut rows: Vec<_> = (0..2500)
+        .map(|i| serde_json::json!({ "name": format!("Reader {}", i), "email": format!("reader{}@example.com", i) }))
+        .collect();
+    // The same address as the first row, in another batch
+    rows[2000]["email"] = "READER0@example.com".into();
+    let request_id = format!("import-{}", Uuid::new_v4());
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/subscriptions/import", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .header("X-Request-Id", &request_id)
+        .json(&rows)
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 207);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2500);
+    for (index, result) in results.iter().enumerate() {
+        let expected = if index == 2000 {
+            "already_subscribed"
+        } else {
+            "imported"
+        };
+        assert_eq!(result["row"], index + 1);
+        assert_eq!(result["status"], expected);
+    }
+    let counts = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT count(*) FROM subscriptions WHERE status = 'pending_confirmation') AS "subscribers!",
+            (SELECT count(DISTINCT subscriber_id) FROM subscription_tokens) AS "confirmation_tokens!",
+            (SELECT count(DISTINCT subscriber_id) FROM unsubscribe_tokens) AS "unsubscribe_tokens!",
+            (SELECT count(DISTINCT recipient) FROM email_outbox) AS "emails!",
+            (SELECT count(*) FROM subscription_events WHERE event = 'subscribed') AS "events!"
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to count the imported rows");
+    assert_eq!(counts.subscribers, 2499);
+    assert_eq!(counts.confirmation_tokens, 2499);
+    assert_eq!(counts.unsubscribe_tokens, 2499);
+    assert_eq!(counts.emails, 2499);
+    assert_eq!(counts.events, 2499);
+    // Three batches of writes, not one per row
+    assert!(captured_logs().lines().any(|line| {
+        line.contains("Imported subscribers")
+            && line.contains(&format!(r#""request_id":"{}""#, request_id))
+            && line.contains(r#""batches":3"#)
+            && line.contains(r#""elapsed_ms":"#)
+    }));
+}
+
+#[tokio::test]
+async fn importing_requires_admin_credentials() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/subscriptions/import", &app.address))
+        .json(&serde_json::json!([{ "name": "Octavia", "email": "octavia@example.com" }]))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert!(saved_statuses(&app).await.is_empty());
+}
@@ -0,0 +1,99 @@
+use crate::helpers::{
+    assert_is_redirect_to, create_confirmed_subscriber, csrf_token, spawn_app,
+    spawn_app_with_configuration,
+};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn newsletter_form(csrf_token: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+        "csrf_token": csrf_token,
+    })
+}
+
+#[tokio::test]
+async fn the_newsletter_form_requires_a_login() {
+    let app = spawn_app().await;
+
+    let response = app.get_admin_newsletters().await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn publishing_from_the_form_requires_a_login() {
+    let app = spawn_app().await;
+
+    let response = app.post_admin_newsletters(&newsletter_form("")).await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn the_form_publishes_an_issue_to_confirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.login().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let html = app.get_admin_newsletters().await.text().await.unwrap();
+
+    // Act
+    let response = app
+        .post_admin_newsletters(&newsletter_form(&csrf_token(&html)))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    let html = app.get_admin_newsletters().await.text().await.unwrap();
+    assert!(html.contains("<p><i>The newsletter issue has been published!</i></p>"));
+}
+
+#[tokio::test]
+async fn publishing_without_a_valid_csrf_token_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.login().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_admin_newsletters(&newsletter_form("not-the-session-token"))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn an_oversized_form_is_rejected_with_a_413() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.application.max_body_bytes = 1024;
+        c.application.max_newsletter_body_bytes = 16 * 1024;
+    })
+    .await;
+    app.login().await;
+    let html = app.get_admin_newsletters().await.text().await.unwrap();
+    let mut form = newsletter_form(&csrf_token(&html));
+    form["text_content"] = "a".repeat(32 * 1024).into();
+
+    // Act
+    let response = app.post_admin_newsletters(&form).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 413);
+}
@@ -0,0 +1,407 @@
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, TestApp,
+};
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::routes::hash_token;
+
+async fn subscriber_id(app: &TestApp) -> String {
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .id
+        .to_string()
+}
+
+#[tokio::test]
+async fn admin_can_fetch_a_subscriber_detail() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+
+    let response = app.get_admin_subscriber(&id).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["id"], id);
+    assert_eq!(body["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(body["name"], "le guin");
+    assert_eq!(body["status"], "pending_confirmation");
+    assert_eq!(body["has_confirmation_token"], true);
+}
+
+#[tokio::test]
+async fn a_valid_edit_is_persisted() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+
+    let response = app
+        .patch_admin_subscriber(&id, serde_json::json!({"name": "Ursula K. Le Guin"}))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "Ursula K. Le Guin");
+}
+
+#[tokio::test]
+async fn an_invalid_edit_is_rejected_with_a_422() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+
+    let response = app
+        .patch_admin_subscriber(&id, serde_json::json!({"name": "   "}))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 422);
+    let saved = sqlx::query!("SELECT name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn fetching_an_unknown_subscriber_returns_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .get_admin_subscriber("5c8c0b2e-8e0c-4a7f-9c1e-1b1a4c2f3d4e")
+        .await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn reconfirming_sends_a_fresh_confirmation_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let first_link = create_unconfirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_reconfirm_subscriber(&id).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let fresh_link = app.get_confirmation_links(&email_request);
+    assert_ne!(fresh_link.html, first_link.html);
+    reqwest::get(fresh_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn reconfirming_an_unknown_subscriber_returns_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_reconfirm_subscriber(&Uuid::new_v4().to_string())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn reconfirming_a_confirmed_subscriber_returns_a_409() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_reconfirm_subscriber(&id).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+    app.dispatch_all_pending_emails().await;
+}
+
+/// Rows left that point at the subscriber, the subscription itself included
+async fn rows_about(app: &TestApp, id: Uuid) -> (i64, i64, i64, i64) {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT count(*) FROM subscriptions WHERE id = $1) AS "subscriptions!",
+            (SELECT count(*) FROM subscription_tokens WHERE subscriber_id = $1) AS "subscription_tokens!",
+            (SELECT count(*) FROM unsubscribe_tokens WHERE subscriber_id = $1) AS "unsubscribe_tokens!",
+            (SELECT count(*) FROM subscription_events WHERE subscriber_id = $1) AS "events!"
+        "#,
+        id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    (
+        row.subscriptions,
+        row.subscription_tokens,
+        row.unsubscribe_tokens,
+        row.events,
+    )
+}
+
+async fn emails_to_the_subscriber(app: &TestApp) -> i64 {
+    sqlx::query!(
+        r#"
+        SELECT
+            (SELECT count(*) FROM subscriptions WHERE email = 'ursula_le_guin@gmail.com')
+            + (SELECT count(*) FROM email_outbox WHERE recipient = 'ursula_le_guin@gmail.com')
+            AS "count!"
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .count
+}
+
+#[tokio::test]
+async fn an_erased_subscriber_is_anonymized_and_keeps_their_history() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+
+    // Act
+    let response = app.delete_admin_subscriber(&id, false).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(emails_to_the_subscriber(&app).await, 0);
+    let id: Uuid = id.parse().unwrap();
+    let (subscriptions, subscription_tokens, unsubscribe_tokens, events) =
+        rows_about(&app, id).await;
+    assert_eq!(subscriptions, 1);
+    assert_eq!((subscription_tokens, unsubscribe_tokens), (0, 0));
+    // Subscribed, confirmed and erased
+    assert_eq!(events, 3);
+    let body: serde_json::Value = app
+        .get_admin_subscriber(&id.to_string())
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(body["email"].as_str().unwrap().ends_with("@erased.invalid"));
+    assert_eq!(body["name"], "");
+    assert_eq!(body["status"], "unsubscribed");
+
+    // Erasing again changes nothing
+    let response = app.delete_admin_subscriber(&id.to_string(), false).await;
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(rows_about(&app, id).await.3, 3);
+}
+
+#[tokio::test]
+async fn an_anonymized_subscriber_keeps_nothing_derived_from_their_email_or_consent() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+    sqlx::query!(
+        "UPDATE subscriptions SET consent_source = 'landing-page', consent_ip = '203.0.113.7'"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    app.delete_admin_subscriber(&id, false)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let saved = sqlx::query!("SELECT email, consent_source, consent_ip FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_ne!(
+        saved.email,
+        format!("{}@erased.invalid", hash_token("ursula_le_guin@gmail.com"))
+    );
+    assert_eq!(saved.consent_source, None);
+    assert_eq!(saved.consent_ip, None);
+}
+
+#[tokio::test]
+async fn an_erased_subscriber_leaves_no_unsubscribe_reason_behind() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+    app.post_admin_unsubscribe(serde_json::json!({
+        "email": "ursula_le_guin@gmail.com",
+        "reason": "Too many emails"
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    // Act
+    app.delete_admin_subscriber(&id, false)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let events = sqlx::query!(r#"SELECT metadata::text AS "metadata!" FROM subscription_events"#)
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(!events.is_empty());
+    assert!(events
+        .iter()
+        .all(|event| !event.metadata.contains("Too many emails")));
+    let export = app
+        .get_admin_subscriber_export(&id)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(!export.contains("Too many emails"));
+}
+
+#[tokio::test]
+async fn an_address_that_only_looks_erased_is_still_anonymized() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+    sqlx::query!("UPDATE subscriptions SET email = 'ursula@fooerased.invalid'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    app.delete_admin_subscriber(&id, false)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let saved = sqlx::query!("SELECT email, name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_ne!(saved.email, "ursula@fooerased.invalid");
+    assert!(saved.email.ends_with("@erased.invalid"));
+    assert_eq!(saved.name, "");
+}
+
+#[tokio::test]
+async fn a_hard_erase_deletes_every_row_about_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+
+    // Act
+    let response = app.delete_admin_subscriber(&id, true).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(emails_to_the_subscriber(&app).await, 0);
+    assert_eq!(rows_about(&app, id.parse().unwrap()).await, (0, 0, 0, 0));
+    assert_eq!(app.get_admin_subscriber(&id).await.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn erasing_an_unknown_subscriber_returns_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .delete_admin_subscriber(&Uuid::new_v4().to_string(), false)
+        .await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn the_audit_trail_still_rejects_deletes_outside_an_erasure() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    let outcome = sqlx::query!("DELETE FROM subscription_events")
+        .execute(&app.db_pool)
+        .await;
+
+    assert!(outcome.is_err());
+}
+
+#[tokio::test]
+async fn an_export_is_an_attachment_with_everything_about_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    let id = subscriber_id(&app).await;
+
+    // Act
+    let response = app.get_admin_subscriber_export(&id).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Disposition"],
+        format!(r#"attachment; filename="subscriber-{}.json""#, id).as_str()
+    );
+    let body = response.text().await.unwrap();
+    let token = confirmation_links
+        .plain_text
+        .query_pairs()
+        .find(|(name, _)| name == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+    assert!(!body.contains(&token));
+    let export: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(export["subscription"]["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(export["subscription"]["status"], "pending_confirmation");
+    assert!(export["confirmation_token_created_at"].is_string());
+    assert_eq!(export["has_unsubscribe_token"], true);
+    assert_eq!(export["events"][0]["event"], "subscribed");
+    assert_eq!(export["emails"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn exporting_an_unknown_subscriber_returns_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .get_admin_subscriber_export(&Uuid::new_v4().to_string())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
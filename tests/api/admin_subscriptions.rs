@@ -0,0 +1,305 @@
+use crate::helpers::{spawn_app, TestApp};
+use chrono::{Duration, Utc};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// Insert `count` subscribers, three at a time sharing a timestamp
+async fn insert_subscribers(app: &TestApp, count: usize, status: &str) -> Vec<Uuid> {
+    let start = Utc::now() - Duration::days(1);
+    let mut ids = Vec::new();
+    for i in 0..count {
+        let id = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+            VALUES ($1, $2, $3, $4, $5)",
+            id,
+            format!("subscriber{}@example.com", id),
+            format!("Subscriber {}", i),
+            start + Duration::seconds((i / 3) as i64),
+            status
+        )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+        ids.push(id);
+    }
+    ids
+}
+
+/// Follow `next_cursor` until the last page, returning every id seen
+async fn walk_pages(app: &TestApp, limit: &str, status: Option<&str>) -> Vec<Uuid> {
+    let mut ids = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut query = vec![("limit", limit)];
+        if let Some(cursor) = &cursor {
+            query.push(("cursor", cursor));
+        }
+        if let Some(status) = status {
+            query.push(("status", status));
+        }
+        let response = app.get_subscriptions(&query).await;
+        assert_eq!(response.status().as_u16(), 200);
+        let page: serde_json::Value = response.json().await.unwrap();
+        for item in page["items"].as_array().unwrap() {
+            ids.push(item["id"].as_str().unwrap().parse().unwrap());
+        }
+        match page["next_cursor"].as_str() {
+            Some(next) => cursor = Some(next.to_string()),
+            None => return ids,
+        }
+    }
+}
+
+#[tokio::test]
+async fn walking_the_cursor_returns_every_subscriber_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let inserted = insert_subscribers(&app, 25, "confirmed").await;
+
+    // Act
+    let seen = walk_pages(&app, "7", None).await;
+
+    // Assert
+    assert_eq!(seen.len(), 25);
+    let unique: HashSet<_> = seen.iter().collect();
+    assert_eq!(unique.len(), 25);
+    assert_eq!(unique, inserted.iter().collect());
+}
+
+#[tokio::test]
+async fn the_last_page_has_no_cursor() {
+    let app = spawn_app().await;
+    insert_subscribers(&app, 3, "confirmed").await;
+
+    let response = app.get_subscriptions(&[("limit", "3")]).await;
+
+    let page: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(page["items"].as_array().unwrap().len(), 3);
+    assert!(page["next_cursor"].is_null());
+}
+
+#[tokio::test]
+async fn subscribers_can_be_filtered_by_status() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmed = insert_subscribers(&app, 8, "confirmed").await;
+    insert_subscribers(&app, 5, "pending_confirmation").await;
+
+    // Act
+    let seen = walk_pages(&app, "3", Some("confirmed")).await;
+
+    // Assert
+    assert_eq!(
+        seen.iter().collect::<HashSet<_>>(),
+        confirmed.iter().collect()
+    );
+    assert_eq!(seen.len(), 8);
+}
+
+#[tokio::test]
+async fn invalid_queries_are_rejected_with_a_400() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (vec![("limit", "0")], "a zero limit"),
+        (vec![("limit", "101")], "a limit above the cap"),
+        (vec![("cursor", "not-a-cursor")], "a garbage cursor"),
+        (vec![("status", "deleted")], "an unknown status"),
+    ];
+
+    for (query, description) in test_cases {
+        let response = app.get_subscriptions(&query).await;
+
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject {}",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn listing_subscribers_requires_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscriptions", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn subscriptions_are_counted_by_status() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_subscribers(&app, 3, "pending_confirmation").await;
+    insert_subscribers(&app, 5, "confirmed").await;
+
+    // Act
+    let response = app.get_subscription_counts().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "pending_confirmation": 3,
+            "confirmed": 5,
+            "unsubscribed": 0,
+        })
+    );
+}
+
+#[tokio::test]
+async fn counting_subscriptions_requires_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscriptions/count", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+async fn insert_named_subscriber(app: &TestApp, email: &str, name: &str) {
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, $2, $3, $4, 'confirmed')",
+        Uuid::new_v4(),
+        email,
+        name,
+        Utc::now()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+/// The emails of every subscriber matching `q`, on a single page
+async fn search_emails(app: &TestApp, q: &str) -> Vec<String> {
+    let response = app.search_subscriptions(&[("q", q)]).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let page: serde_json::Value = response.json().await.unwrap();
+    let mut emails: Vec<String> = page["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["email"].as_str().unwrap().to_string())
+        .collect();
+    emails.sort();
+    emails
+}
+
+#[tokio::test]
+async fn subscribers_are_found_by_the_start_of_their_email_or_name() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_named_subscriber(&app, "ursula@example.com", "Ursula Le Guin").await;
+    insert_named_subscriber(&app, "octavia@example.com", "Octavia Butler").await;
+    insert_named_subscriber(&app, "le.guin.fan@example.com", "A reader").await;
+
+    // Act & Assert
+    assert_eq!(search_emails(&app, "URS").await, vec!["ursula@example.com"]);
+    assert_eq!(
+        search_emails(&app, "octavia b").await,
+        vec!["octavia@example.com"]
+    );
+    assert_eq!(
+        search_emails(&app, "le").await,
+        vec!["le.guin.fan@example.com"]
+    );
+}
+
+#[tokio::test]
+async fn a_search_without_matches_returns_an_empty_page() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_named_subscriber(&app, "ursula@example.com", "Ursula Le Guin").await;
+
+    // Act
+    let response = app.search_subscriptions(&[("q", "nobody")]).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let page: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(page["items"], serde_json::json!([]));
+    assert!(page["next_cursor"].is_null());
+}
+
+#[tokio::test]
+async fn wildcards_and_quotes_in_a_search_are_taken_literally() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_named_subscriber(&app, "ursula@example.com", "Ursula Le Guin").await;
+    insert_named_subscriber(&app, "100%_off@example.com", "Bargain hunter").await;
+
+    // Act & Assert
+    assert!(search_emails(&app, "%").await.is_empty());
+    assert!(search_emails(&app, "_rsula").await.is_empty());
+    assert!(search_emails(&app, "' OR '1'='1").await.is_empty());
+    assert_eq!(
+        search_emails(&app, "100%_").await,
+        vec!["100%_off@example.com"]
+    );
+}
+
+#[tokio::test]
+async fn search_results_are_paged() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_subscribers(&app, 5, "confirmed").await;
+
+    // Act
+    let first = app
+        .search_subscriptions(&[("q", "subscriber"), ("limit", "3")])
+        .await;
+    let first: serde_json::Value = first.json().await.unwrap();
+    let cursor = first["next_cursor"].as_str().unwrap();
+    let second = app
+        .search_subscriptions(&[("q", "subscriber"), ("limit", "3"), ("cursor", cursor)])
+        .await;
+    let second: serde_json::Value = second.json().await.unwrap();
+
+    // Assert
+    assert_eq!(first["items"].as_array().unwrap().len(), 3);
+    assert_eq!(second["items"].as_array().unwrap().len(), 2);
+    assert!(second["next_cursor"].is_null());
+}
+
+#[tokio::test]
+async fn invalid_searches_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    for query in [vec![], vec![("q", "  ")], vec![("q", "a"), ("limit", "0")]] {
+        // Act
+        let response = app.search_subscriptions(&query).await;
+
+        // Assert
+        assert_eq!(response.status().as_u16(), 400, "{:?}", query);
+    }
+}
+
+#[tokio::test]
+async fn searching_subscribers_requires_authentication() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/subscriptions/search", &app.address))
+        .query(&[("q", "ursula")])
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
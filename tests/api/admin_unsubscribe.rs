@@ -0,0 +1,76 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
+
+#[tokio::test]
+async fn admin_unsubscribe_unsubscribes_a_confirmed_subscriber() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let response = app
+        .post_admin_unsubscribe(serde_json::json!({"email": "ursula_le_guin@gmail.com"}))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "unsubscribed");
+
+    let response = app
+        .post_admin_unsubscribe(serde_json::json!({"email": "ursula_le_guin@gmail.com"}))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn admin_unsubscribe_returns_a_404_for_an_unknown_email() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_admin_unsubscribe(serde_json::json!({"email": "ursula_le_guin@gmail.com"}))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn admin_unsubscribe_requires_authorization() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/unsubscribe", &app.address))
+        .json(&serde_json::json!({"email": "ursula_le_guin@gmail.com"}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn the_unsubscribe_reason_is_recorded_and_shows_up_in_the_breakdown() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let response = app
+        .post_admin_unsubscribe(serde_json::json!({
+            "email": "ursula_le_guin@gmail.com",
+            "reason": "  Too many emails "
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let saved = sqlx::query!("SELECT reason FROM unsubscribe_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the unsubscribe event.");
+    assert_eq!(saved.reason.as_deref(), Some("Too many emails"));
+
+    let response = app.get_unsubscribe_reasons().await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({"reasons": [{"reason": "Too many emails", "count": 1}]})
+    );
+}
@@ -0,0 +1,163 @@
+use crate::helpers::{spawn_app, spawn_app_with_configuration, TestApp};
+
+const ALLOWED_ORIGIN: &str = "https://newsletter.example.com";
+
+async fn spawn_app_allowing_origin() -> TestApp {
+    spawn_app_with_configuration(|c| {
+        c.cors.allowed_origins = vec![ALLOWED_ORIGIN.into()];
+    })
+    .await
+}
+
+async fn post_subscriptions_from(app: &TestApp, origin: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Origin", origin)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        // Fails validation, so no email has to be mocked
+        .body("name=&email=")
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn preflight_from(app: &TestApp, path: &str, origin: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("{}{}", &app.address, path),
+        )
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "POST")
+        .header("Access-Control-Request-Headers", "content-type")
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn an_allowed_origin_is_echoed() {
+    // Arrange
+    let app = spawn_app_allowing_origin().await;
+
+    // Act
+    let response = post_subscriptions_from(&app, ALLOWED_ORIGIN).await;
+
+    // Assert
+    assert_eq!(
+        response.headers()["Access-Control-Allow-Origin"],
+        ALLOWED_ORIGIN
+    );
+}
+
+#[tokio::test]
+async fn a_disallowed_origin_gets_no_cors_headers() {
+    // Arrange
+    let app = spawn_app_allowing_origin().await;
+
+    // Act
+    let response = post_subscriptions_from(&app, "https://evil.example.com").await;
+
+    // Assert
+    assert!(response
+        .headers()
+        .get("Access-Control-Allow-Origin")
+        .is_none());
+}
+
+#[tokio::test]
+async fn no_origin_is_allowed_by_default() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = post_subscriptions_from(&app, ALLOWED_ORIGIN).await;
+
+    // Assert
+    assert!(response
+        .headers()
+        .get("Access-Control-Allow-Origin")
+        .is_none());
+}
+
+#[tokio::test]
+async fn preflights_from_an_allowed_origin_succeed() {
+    // Arrange
+    let app = spawn_app_allowing_origin().await;
+
+    // Act
+    let response = preflight_from(&app, "/subscriptions", ALLOWED_ORIGIN).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Access-Control-Allow-Origin"],
+        ALLOWED_ORIGIN
+    );
+    let allowed_methods = response.headers()["Access-Control-Allow-Methods"]
+        .to_str()
+        .unwrap();
+    assert!(allowed_methods.contains("POST"));
+}
+
+#[tokio::test]
+async fn preflights_from_a_disallowed_origin_are_not_allowed() {
+    // Arrange
+    let app = spawn_app_allowing_origin().await;
+
+    // Act
+    let response = preflight_from(&app, "/subscriptions", "https://evil.example.com").await;
+
+    // Assert
+    assert!(response
+        .headers()
+        .get("Access-Control-Allow-Origin")
+        .is_none());
+}
+
+#[tokio::test]
+async fn admin_routes_get_no_cors_headers() {
+    // Arrange
+    let app = spawn_app_allowing_origin().await;
+
+    // Act
+    let response = preflight_from(&app, "/admin/unsubscribe", ALLOWED_ORIGIN).await;
+
+    // Assert
+    assert!(response
+        .headers()
+        .get("Access-Control-Allow-Origin")
+        .is_none());
+}
+
+#[tokio::test]
+async fn the_admin_subscription_listing_gets_no_cors_headers() {
+    // Arrange
+    let app = spawn_app_allowing_origin().await;
+
+    // Nobody has subscribed, so the status lookup finds no one
+    for (path, status) in [
+        ("/subscriptions", 200),
+        ("/subscriptions/status?email=ursula%40gmail.com", 404),
+    ] {
+        // Act
+        let response = reqwest::Client::new()
+            .get(format!("{}{}", &app.address, path))
+            .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+            .header("Origin", ALLOWED_ORIGIN)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(response.status().as_u16(), status, "{}", path);
+        assert!(
+            response
+                .headers()
+                .get("Access-Control-Allow-Origin")
+                .is_none(),
+            "{}",
+            path
+        );
+    }
+}
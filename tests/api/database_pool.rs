@@ -0,0 +1,107 @@
+use crate::helpers::spawn_app;
+use sqlx::{Connection, PgConnection};
+use std::time::Duration;
+use zero2prod::configuration::get_configuration;
+use zero2prod::startup::{get_connection_pool, wait_for_database, Application};
+
+#[tokio::test]
+async fn requests_recover_after_the_database_drops_all_connections() {
+    let app = spawn_app().await;
+    let response = app.post_validate_token(&"a".repeat(25)).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Simulate a Postgres restart by killing every connection to the test database
+    let database_name: String = sqlx::query_scalar("SELECT current_database()")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let configuration = get_configuration().expect("Failed to read configuration.");
+    let mut connection = PgConnection::connect_with(&configuration.database.without_db())
+        .await
+        .expect("Failed to connect to Postgres");
+    sqlx::query(
+        "SELECT pg_terminate_backend(pid) FROM pg_stat_activity \
+        WHERE datname = $1 AND pid <> pg_backend_pid()",
+    )
+    .bind(&database_name)
+    .execute(&mut connection)
+    .await
+    .expect("Failed to terminate the database connections");
+
+    let response = app.post_validate_token(&"a".repeat(25)).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn startup_retries_an_unreachable_database_before_giving_up() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    // Nothing listens there
+    configuration.database.port = 1;
+    let pool = get_connection_pool(&configuration.database);
+
+    // Act
+    let outcome = wait_for_database(&pool, 3, Duration::from_secs(30)).await;
+
+    // Assert
+    match outcome {
+        Ok(_) => panic!("Connected to a port nothing listens on"),
+        Err(e) => assert_eq!(e.attempts, 4),
+    }
+}
+
+#[tokio::test]
+async fn startup_gives_up_on_the_database_after_the_max_wait() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.database.port = 1;
+    let pool = get_connection_pool(&configuration.database);
+
+    // Act
+    let started = std::time::Instant::now();
+    let outcome = wait_for_database(&pool, 100, Duration::from_millis(500)).await;
+
+    // Assert
+    assert!(outcome.is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn the_build_fails_if_the_database_stays_unreachable() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.database.port = 1;
+    configuration.database.startup_max_retries = 1;
+
+    // Act
+    let outcome = Application::build(configuration).await;
+
+    // Assert
+    match outcome {
+        Ok(_) => panic!("The application was built without a database"),
+        Err(e) => assert!(e.to_string().contains("Failed to connect to Postgres")),
+    }
+}
+
+#[tokio::test]
+async fn queries_running_past_the_statement_timeout_are_cancelled() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.database.statement_timeout_milliseconds = Some(100);
+    let pool = get_connection_pool(&configuration.database);
+
+    // Act
+    let started = std::time::Instant::now();
+    let outcome = sqlx::query("SELECT pg_sleep(5)").execute(&pool).await;
+
+    // Assert
+    assert!(started.elapsed() < Duration::from_secs(2));
+    match outcome {
+        Ok(_) => panic!("The slow query wasn't cancelled"),
+        Err(sqlx::Error::Database(e)) => assert_eq!(e.code().as_deref(), Some("57014")),
+        Err(e) => panic!("Unexpected error: {:?}", e),
+    }
+    // The connection is still good for quick queries
+    sqlx::query("SELECT 1").execute(&pool).await.unwrap();
+}
@@ -1,4 +1,9 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with_configuration};
+use chrono::NaiveDate;
+use wiremock::matchers::method;
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::{get_configuration, DeprecatedEndpointSettings};
+use zero2prod::startup::Application;
 
 #[tokio::test]
 async fn health_check_works() {
@@ -7,7 +12,7 @@ async fn health_check_works() {
     let client = reqwest::Client::new();
 
     let response = client
-        .get(&format!("{}/health_check", &test_app.address))
+        .get(format!("{}/health_check", &test_app.address))
         .send()
         .await
         .expect("Failes to execute request.");
@@ -15,3 +20,252 @@ async fn health_check_works() {
     assert!(response.status().is_success());
     assert_eq!(Some(0), response.content_length());
 }
+
+#[tokio::test]
+async fn info_reports_the_crate_version_and_uptime() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/info", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 200);
+    let info: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert!(info["uptime_seconds"].is_u64());
+    assert!(info.get("git_commit").is_some());
+}
+
+#[tokio::test]
+async fn deprecated_routes_announce_their_deprecation_and_sunset() {
+    let test_app = spawn_app_with_configuration(|c| {
+        c.deprecations.push(DeprecatedEndpointSettings {
+            path: "/health_check".into(),
+            method: Some("get".into()),
+            deprecated_on: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            sunset_on: NaiveDate::from_ymd_opt(2024, 6, 30).unwrap(),
+            link: Some("https://example.com/migration".into()),
+        })
+    })
+    .await;
+
+    let response = reqwest::get(format!("{}/health_check", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(response.status().is_success());
+    let headers = response.headers();
+    assert_eq!(headers["Deprecation"], "@1704067200");
+    assert_eq!(headers["Sunset"], "Sun, 30 Jun 2024 00:00:00 GMT");
+    assert_eq!(
+        headers["Warning"],
+        r#"299 - "Deprecated API: /health_check will be removed on 2024-06-30""#
+    );
+    assert_eq!(
+        headers["Link"],
+        r#"<https://example.com/migration>; rel="deprecation""#
+    );
+}
+
+#[tokio::test]
+async fn routes_that_are_not_deprecated_carry_no_deprecation_headers() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/health_check", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    assert!(response.headers().get("Deprecation").is_none());
+    assert!(response.headers().get("Sunset").is_none());
+}
+
+#[tokio::test]
+async fn readiness_check_succeeds_when_postgres_is_reachable() {
+    let test_app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/health/ready", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["postgres"], "up");
+}
+
+#[tokio::test]
+async fn readiness_check_skips_the_email_provider_unless_enabled() {
+    let test_app = spawn_app().await;
+    Mock::given(method("HEAD"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(0)
+        .mount(&test_app.email_server)
+        .await;
+
+    let response = reqwest::get(format!("{}/health/ready", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["email_provider"], "skipped");
+}
+
+#[tokio::test]
+async fn readiness_check_succeeds_when_the_email_provider_is_reachable() {
+    // Arrange
+    let test_app =
+        spawn_app_with_configuration(|c| c.email_client.readiness_check_enabled = true).await;
+    Mock::given(method("HEAD"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = reqwest::get(format!("{}/health/ready", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["postgres"], "up");
+    assert_eq!(body["email_provider"], "up");
+}
+
+#[tokio::test]
+async fn readiness_check_fails_when_the_email_provider_returns_server_errors() {
+    // Arrange
+    let test_app =
+        spawn_app_with_configuration(|c| c.email_client.readiness_check_enabled = true).await;
+    Mock::given(method("HEAD"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&test_app.email_server)
+        .await;
+
+    // Act
+    let response = reqwest::get(format!("{}/health/ready", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["postgres"], "up");
+    assert_eq!(body["email_provider"], "down");
+}
+
+#[tokio::test]
+async fn readiness_check_fails_when_the_email_provider_is_unreachable() {
+    let test_app = spawn_app_with_configuration(|c| {
+        c.email_client.readiness_check_enabled = true;
+        // Nothing listens there
+        c.email_client.base_url = "http://127.0.0.1:1".into();
+    })
+    .await;
+
+    let response = reqwest::get(format!("{}/health/ready", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["email_provider"], "down");
+}
+
+#[tokio::test]
+async fn readiness_check_fails_when_postgres_is_unreachable() {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    // Nothing listens there
+    configuration.database.port = 1;
+    configuration.database.startup_max_wait_seconds = None;
+    let application = Application::build(configuration)
+        .await
+        .expect("Failed to build application");
+    let address = format!("http://127.0.0.1:{}", application.port());
+    tokio::spawn(application.run_until_stopped());
+
+    let response = reqwest::get(format!("{}/health/ready", address))
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 503);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["postgres"], "down");
+}
+
+fn health_check_requests(metrics: &str) -> u64 {
+    metrics
+        .lines()
+        .find(|line| {
+            line.starts_with("http_requests_total")
+                && line.contains(r#"route="/health_check""#)
+                && line.contains(r#"status="200""#)
+        })
+        .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+        .unwrap_or(0)
+}
+
+#[tokio::test]
+async fn requests_are_counted_in_the_metrics() {
+    let test_app = spawn_app().await;
+    let metrics_url = format!("{}/metrics", &test_app.address);
+    let before = reqwest::get(&metrics_url)
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    reqwest::get(format!("{}/health_check", &test_app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    let response = reqwest::get(&metrics_url).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let after = response.text().await.unwrap();
+    assert_eq!(health_check_requests(&before), 0);
+    assert_eq!(health_check_requests(&after), 1);
+    assert!(after.contains("http_request_duration_seconds_bucket"));
+    assert!(!after.contains(r#"route="/metrics""#));
+}
+
+fn gauge(metrics: &str, name: &str) -> i64 {
+    metrics
+        .lines()
+        .find(|line| line.split(' ').next() == Some(name))
+        .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+        .unwrap_or_else(|| panic!("{} is not registered", name))
+}
+
+#[tokio::test]
+async fn the_connection_pool_is_sampled_into_the_metrics() {
+    // Arrange
+    let test_app =
+        spawn_app_with_configuration(|c| c.telemetry.pool_metrics_interval_milliseconds = 20).await;
+    let metrics_url = format!("{}/metrics", &test_app.address);
+
+    // Act
+    for _ in 0..3 {
+        reqwest::get(format!("{}/health/ready", &test_app.address))
+            .await
+            .expect("Failed to execute request.");
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    // Assert
+    let metrics = reqwest::get(&metrics_url)
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let connections = gauge(&metrics, "db_pool_connections");
+    assert!(connections >= 1);
+    assert_eq!(
+        gauge(&metrics, "db_pool_idle_connections") + gauge(&metrics, "db_pool_active_connections"),
+        connections
+    );
+}
@@ -3,28 +3,62 @@ use argon2::password_hash::SaltString;
 use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
 use uuid::Uuid;
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::configuration::{
+    get_configuration, DatabaseSettings, EmailOutboxSettings, LogFormat, Settings,
+};
+use zero2prod::email_client::{EmailProvider, SentEmail};
+use zero2prod::email_outbox::{try_execute_task, ExecutionOutcome};
+use zero2prod::metrics::Metrics;
 use zero2prod::startup::{get_connection_pool, Application};
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
+// Everything logged during the test run, so tests can assert on log output.
+// Logs are also echoed to stdout if `TEST_LOG` is set.
+static CAPTURED_LOGS: Lazy<Mutex<Vec<u8>>> = Lazy::new(Default::default);
+
+struct LogCapture;
+
+impl std::io::Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        CAPTURED_LOGS.lock().unwrap().extend_from_slice(buf);
+        if std::env::var("TEST_LOG").is_ok() {
+            std::io::stdout().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
     let subscriber_name = "test".to_string();
-    if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::stdout);
-        init_subscriber(subscriber);
-    } else {
-        let subscriber = get_subscriber(subscriber_name, default_filter_level, std::io::sink);
-        init_subscriber(subscriber);
-    }
+    let subscriber = get_subscriber(
+        subscriber_name,
+        default_filter_level,
+        || LogCapture,
+        None,
+        LogFormat::Json,
+    );
+    init_subscriber(subscriber);
 });
 
+pub fn captured_logs() -> String {
+    String::from_utf8_lossy(&CAPTURED_LOGS.lock().unwrap()).into_owned()
+}
+
 pub struct TestUser {
     pub user_id: Uuid,
     pub username: String,
     pub password: String,
+    pub email: String,
 }
 
 impl TestUser {
@@ -33,6 +67,7 @@ impl TestUser {
             user_id: Uuid::new_v4(),
             username: Uuid::new_v4().to_string(),
             password: Uuid::new_v4().to_string(),
+            email: format!("{}@example.com", Uuid::new_v4()),
         }
     }
 
@@ -47,11 +82,12 @@ impl TestUser {
         .unwrap()
         .to_string();
         sqlx::query!(
-            "INSERT INTO users (user_id, username, password_hash)
-            VALUES ($1, $2, $3)",
+            "INSERT INTO users (user_id, username, password_hash, email)
+            VALUES ($1, $2, $3, $4)",
             self.user_id,
             self.username,
             password_hash,
+            self.email,
         )
         .execute(pool)
         .await
@@ -65,9 +101,45 @@ pub struct TestApp {
     pub email_server: MockServer,
     pub port: u16,
     pub test_user: TestUser,
+    pub email_client: Arc<dyn EmailProvider>,
+    pub email_outbox: EmailOutboxSettings,
+    /// Keeps the session cookie and doesn't follow redirects
+    pub api_client: reqwest::Client,
+    /// Notify to shut the application down, like a SIGTERM would
+    pub shutdown: Arc<Notify>,
 }
 
 impl TestApp {
+    /// Deliver every email in the outbox that is due. The outbox worker
+    /// doesn't run in tests, so they control when emails go out.
+    pub async fn dispatch_all_pending_emails(&self) {
+        self.dispatch_all_pending_emails_to(&*self.email_client)
+            .await
+    }
+
+    /// Like `dispatch_all_pending_emails`, through another provider, e.g. a
+    /// `MemoryEmailClient` to assert on the emails without the mock server
+    pub async fn dispatch_all_pending_emails_to(&self, email_client: &dyn EmailProvider) {
+        self.dispatch(email_client, &Default::default()).await
+    }
+
+    /// Like `dispatch_all_pending_emails`, counting what is sent in `metrics`
+    pub async fn dispatch_all_pending_emails_counted_in(&self, metrics: &Metrics) {
+        self.dispatch(&*self.email_client, metrics).await
+    }
+
+    async fn dispatch(&self, email_client: &dyn EmailProvider, metrics: &Metrics) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_task(&self.db_pool, email_client, metrics, &self.email_outbox)
+                    .await
+                    .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
             .post(format!("{}/subscriptions", &self.address))
@@ -78,13 +150,98 @@ impl TestApp {
             .expect("Failed to execute request.")
     }
 
+    /// POST `body` in a single chunk, without a `Content-Length`. Returns the
+    /// response status, reqwest can only stream bodies with a feature we
+    /// don't build it with.
+    pub async fn post_chunked(&self, path: &str, content_type: &str, body: String) -> u16 {
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\n\
+            Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+            path,
+            content_type,
+            body.len(),
+            body
+        );
+        let port = self.port;
+        tokio::task::spawn_blocking(move || {
+            use std::io::{Read, Write};
+            let mut stream = std::net::TcpStream::connect(("127.0.0.1", port))
+                .expect("Failed to connect to the application");
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            // The server may close before reading the whole body
+            let _ = stream.read_to_string(&mut response);
+            response
+                .split_whitespace()
+                .nth(1)
+                .and_then(|status| status.parse().ok())
+                .expect("Failed to read the response status")
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn post_subscriptions_with_content_type(
+        &self,
+        body: String,
+        content_type: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
+            .header("Content-Type", content_type)
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_resend_confirmation(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/subscriptions/resend-confirmation",
+                &self.address
+            ))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationsLinks {
+        self.get_links(email_request, "/subscriptions/confirm")
+    }
+
+    pub fn get_unsubscribe_links(&self, email_request: &wiremock::Request) -> ConfirmationsLinks {
+        self.get_links(email_request, "/unsubscribe")
+    }
+
+    /// The confirmation links of an email kept by a `MemoryEmailClient`
+    pub fn get_sent_confirmation_links(&self, email: &SentEmail) -> ConfirmationsLinks {
+        self.get_links_in(
+            email.html_content.as_deref().unwrap(),
+            &email.text_content,
+            "/subscriptions/confirm",
+        )
+    }
+
+    /// Extract the link to `path` from both the HTML and the plain text body
+    fn get_links(&self, email_request: &wiremock::Request, path: &str) -> ConfirmationsLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
         dbg!(&body);
+        self.get_links_in(
+            body["HtmlBody"].as_str().unwrap(),
+            body["TextBody"].as_str().unwrap(),
+            path,
+        )
+    }
+
+    fn get_links_in(&self, html_body: &str, text_body: &str, path: &str) -> ConfirmationsLinks {
         let get_link = |s: &str| {
             let links: Vec<_> = linkify::LinkFinder::new()
                 .links(s)
                 .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .filter(|l| l.as_str().contains(&format!("{}?", path)))
                 .collect();
             assert_eq!(links.len(), 1);
             let raw_link = links[0].as_str().to_owned();
@@ -94,11 +251,20 @@ impl TestApp {
             confirmation_link
         };
 
-        let html = get_link(body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(body["TextBody"].as_str().unwrap());
+        let html = get_link(html_body);
+        let plain_text = get_link(text_body);
         ConfirmationsLinks { html, plain_text }
     }
 
+    pub async fn post_validate_token(&self, subscription_token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/confirm/validate", &self.address))
+            .json(&serde_json::json!({ "subscription_token": subscription_token }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
     pub async fn post_newsletter(&self, body: serde_json::Value) -> reqwest::Response {
         reqwest::Client::new()
             .post(format!("{}/newsletters", &self.address))
@@ -108,6 +274,260 @@ impl TestApp {
             .await
             .expect("Failed to execute request")
     }
+
+    pub async fn post_newsletter_preview(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters/preview", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_resend_newsletter(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/newsletters/{}/resend",
+                &self.address, newsletter_issue_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_bulk_status(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/subscribers/bulk-status", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_import(&self, rows: serde_json::Value, confirmed: bool) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/subscriptions/import", &self.address))
+            .query(&[("confirmed", confirmed)])
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&rows)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_subscription_status(&self, email: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/subscriptions/status", &self.address))
+            .query(&[("email", email)])
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_subscriptions(&self, query: &[(&str, &str)]) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/subscriptions", &self.address))
+            .query(query)
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn search_subscriptions(&self, query: &[(&str, &str)]) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/subscriptions/search", &self.address))
+            .query(query)
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_subscriber(&self, subscriber_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/admin/subscribers/{}",
+                &self.address, subscriber_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin_subscriber_export(&self, subscriber_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/admin/subscribers/{}/export",
+                &self.address, subscriber_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_reconfirm_subscriber(&self, subscriber_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!(
+                "{}/admin/subscribers/{}/reconfirm",
+                &self.address, subscriber_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn delete_admin_subscriber(
+        &self,
+        subscriber_id: &str,
+        hard: bool,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .delete(format!(
+                "{}/admin/subscribers/{}",
+                &self.address, subscriber_id
+            ))
+            .query(&[("hard", hard)])
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn patch_admin_subscriber(
+        &self,
+        subscriber_id: &str,
+        body: serde_json::Value,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .patch(format!(
+                "{}/admin/subscribers/{}",
+                &self.address, subscriber_id
+            ))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_subscription_counts(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/subscriptions/count", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_unsubscribe_reasons(&self) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/admin/stats/unsubscribe-reasons", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn post_login(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Log in as the test user through the login form
+    pub async fn login(&self) {
+        let csrf_token = csrf_token(&self.get_login_html().await);
+        let response = self
+            .post_login(&serde_json::json!({
+                "username": &self.test_user.username,
+                "password": &self.test_user.password,
+                "csrf_token": csrf_token,
+            }))
+            .await;
+        assert_is_redirect_to(&response, "/admin/newsletters");
+    }
+
+    /// GET a form page and return the body
+    pub async fn get_html(&self, path: &str) -> String {
+        self.api_client
+            .get(format!("{}{}", &self.address, path))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    /// POST a form, the session cookie goes along
+    pub async fn post_form(&self, path: &str, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}{}", &self.address, path))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// The token of the link in a password reset email
+    pub fn get_password_reset_token(&self, email_request: &wiremock::Request) -> String {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        let (_, rest) = body["TextBody"]
+            .as_str()
+            .unwrap()
+            .split_once("/password/reset?token=")
+            .expect("No password reset link in the email");
+        rest.split_whitespace().next().unwrap().to_string()
+    }
+
+    pub async fn get_admin_newsletters(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/newsletters", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_newsletters(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/newsletters", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_admin_unsubscribe(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/unsubscribe", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
 }
 
 pub struct ConfirmationsLinks {
@@ -117,7 +537,26 @@ pub struct ConfirmationsLinks {
 
 /// Spin up the instanceof our application
 ///and returns its address (i.e. http://127.0.0.1:XXXX)
+/// The hidden CSRF token field of a form page
+pub fn csrf_token(html: &str) -> String {
+    let (_, rest) = html
+        .split_once(r#"name="csrf_token" value=""#)
+        .expect("No CSRF token in the page");
+    rest.split('"').next().unwrap().to_string()
+}
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers()["Location"], location);
+}
+
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with_configuration(|_| {}).await
+}
+
+/// Like `spawn_app`, but lets the caller tweak the configuration
+/// before the application is built
+pub async fn spawn_app_with_configuration(customise: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
     let email_server = MockServer::start().await;
@@ -127,6 +566,9 @@ pub async fn spawn_app() -> TestApp {
         c.database.database_name = Uuid::new_v4().to_string();
         c.application.port = 0;
         c.email_client.base_url = email_server.uri();
+        c.email_outbox.worker_enabled = false;
+        c.idempotency.cleanup_enabled = false;
+        customise(&mut c);
         c
     };
 
@@ -136,20 +578,33 @@ pub async fn spawn_app() -> TestApp {
         .await
         .expect("Failed to build application");
     let application_port = application.port();
-    let _ = tokio::spawn(application.run_until_stopped());
+    let shutdown = Arc::new(Notify::new());
+    let shutdown_signal = shutdown.clone();
+    tokio::spawn(application.run_until(async move { shutdown_signal.notified().await }));
     let test_app = TestApp {
         address: format!("http://localhost:{}", application_port),
         db_pool: get_connection_pool(&configuration.database),
         email_server,
         port: application_port,
         test_user: TestUser::generate(),
+        email_client: configuration
+            .email_client
+            .provider(Default::default())
+            .expect("Failed to build the email provider"),
+        shutdown,
+        email_outbox: configuration.email_outbox.clone(),
+        api_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .cookie_store(true)
+            .build()
+            .unwrap(),
     };
     test_app.test_user.store(&test_app.db_pool).await;
     test_app
 }
 
-async fn configure_database(config: &DatabaseSettings) -> PgPool {
-    // Create Database
+/// Create an empty database, without running the migrations
+pub async fn create_database(config: &DatabaseSettings) {
     let mut connection = PgConnection::connect_with(&config.without_db())
         .await
         .expect("Failed to connect to Postgres");
@@ -157,6 +612,10 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
         .execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
         .await
         .expect("Failed to create DAtabase");
+}
+
+async fn configure_database(config: &DatabaseSettings) -> PgPool {
+    create_database(config).await;
 
     // Migrate Database
     let connection_pool = PgPool::connect_with(config.with_db())
@@ -169,3 +628,38 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
 
     connection_pool
 }
+
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationsLinks {
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed Subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    let confirmation_link = create_unconfirmed_subscriber(app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
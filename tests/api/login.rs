@@ -0,0 +1,85 @@
+use crate::helpers::{assert_is_redirect_to, csrf_token, spawn_app};
+
+#[tokio::test]
+async fn logging_in_sets_a_session_cookie() {
+    // Arrange
+    let app = spawn_app().await;
+    let csrf_token = csrf_token(&app.get_login_html().await);
+
+    // Act
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &app.test_user.password,
+            "csrf_token": csrf_token,
+        }))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    let session_cookie = response
+        .cookies()
+        .find(|cookie| cookie.name() == "id")
+        .expect("No session cookie was set");
+    assert!(session_cookie.http_only());
+    let response = app.get_admin_newsletters().await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn a_login_without_a_valid_csrf_token_is_rejected() {
+    let app = spawn_app().await;
+    // The session has a token, the form sends another one
+    app.get_login_html().await;
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &app.test_user.password,
+            "csrf_token": "not-the-session-token",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let response = app.get_admin_newsletters().await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn a_login_without_a_session_is_rejected() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": &app.test_user.password,
+            "csrf_token": "",
+        }))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn a_failed_login_shows_a_flash_message_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let csrf_token = csrf_token(&app.get_login_html().await);
+
+    // Act
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": "wrong-password",
+            "csrf_token": csrf_token,
+        }))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+    assert!(app
+        .get_login_html()
+        .await
+        .contains("<p><i>Authentication failed</i></p>"));
+    assert!(!app.get_login_html().await.contains("Authentication failed"));
+}
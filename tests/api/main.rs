@@ -1,5 +1,22 @@
+mod admin_bulk_status;
+mod admin_import;
+mod admin_newsletters;
+mod admin_subscriber;
+mod admin_subscriptions;
+mod admin_unsubscribe;
+mod cors;
+mod database_pool;
 mod health_check;
 mod helpers;
+mod login;
 mod newsletter;
+mod password;
+mod password_provider;
+mod request_id;
+mod shutdown;
+mod startup;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_resend;
+mod subscriptions_status;
+mod unsubscribe;
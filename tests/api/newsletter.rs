@@ -1,9 +1,14 @@
-use crate::helpers::{spawn_app, ConfirmationsLinks, TestApp};
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app,
+    spawn_app_with_configuration, TestApp,
+};
+use chrono::{Duration, Utc};
 use uuid::Uuid;
 use wiremock::{
-    matchers::{any, method, path},
+    matchers::{any, body_partial_json, method, path},
     Mock, ResponseTemplate,
 };
+use zero2prod::idempotency::delete_expired_keys;
 
 #[tokio::test]
 async fn newsletter_are_not_delivered_to_unconfirmed_subscribers() {
@@ -81,45 +86,55 @@ async fn newsletter_return_400_for_invalid_data() {
     }
 }
 
-async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationsLinks {
-    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
-
-    let _mock_guard = Mock::given(path("/email"))
-        .and(method("POST"))
+#[tokio::test]
+async fn blank_newsletters_are_rejected_with_a_400_and_not_delivered() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(any())
         .respond_with(ResponseTemplate::new(200))
-        .named("Create unconfirmed Subscriber")
-        .expect(1)
-        .mount_as_scoped(&app.email_server)
+        .expect(0)
+        .mount(&app.email_server)
         .await;
-    app.post_subscriptions(body.into())
-        .await
-        .error_for_status()
-        .unwrap();
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "title": "  ",
+                "content": {"text": "Plain text", "html": "<p>HTML</p>"}
+            }),
+            "The title must not be empty",
+        ),
+        (
+            serde_json::json!({
+                "title": "Newsletter title",
+                "content": {"text": "", "html": ""}
+            }),
+            "The content must not be empty",
+        ),
+        (
+            serde_json::json!({
+                "title": "Newsletter title",
+                "content": {"markdown": "\n\n"}
+            }),
+            "The content must not be empty",
+        ),
+    ];
 
-    let email_request = &app
-        .email_server
-        .received_requests()
-        .await
-        .unwrap()
-        .pop()
-        .unwrap();
-    app.get_confirmation_links(&email_request)
-}
+    for (invalid_body, error_message) in test_cases {
+        // Act
+        let response = app.post_newsletter(invalid_body).await;
 
-async fn create_confirmed_subscriber(app: &TestApp) {
-    let confirmation_link = create_unconfirmed_subscriber(app).await;
-    reqwest::get(confirmation_link.html)
-        .await
-        .unwrap()
-        .error_for_status()
-        .unwrap();
+        // Assert
+        assert_eq!(response.status().as_u16(), 400);
+        assert_eq!(response.text().await.unwrap(), error_message);
+    }
 }
 
 #[tokio::test]
 async fn requests_missing_authorization_are_rejected() {
     let app = spawn_app().await;
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .json(&serde_json::json!({
             "title": "Newsletter title",
             "content": {
@@ -145,7 +160,7 @@ async fn non_existing_user_is_rejected() {
     let password = Uuid::new_v4().to_string();
 
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .basic_auth(username, Some(password))
         .json(&serde_json::json!({
             "title": "Newsletter title",
@@ -172,7 +187,7 @@ async fn invalid_password_is_rejected() {
     let password = Uuid::new_v4().to_string();
 
     let response = reqwest::Client::new()
-        .post(&format!("{}/newsletters", &app.address))
+        .post(format!("{}/newsletters", &app.address))
         .basic_auth(username, Some(password))
         .json(&serde_json::json!({
             "title": "Newsletter title",
@@ -191,3 +206,485 @@ async fn invalid_password_is_rejected() {
         response.headers()["WWW-AUTHENTICATE"]
     );
 }
+
+fn newsletter_request_body_with_key(idempotency_key: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML<p>",
+        },
+        "idempotency_key": idempotency_key
+    })
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let body = newsletter_request_body_with_key(&Uuid::new_v4().to_string());
+
+    let response = app.post_newsletter(body.clone()).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Submitting the same issue again replays the saved response
+    let response = app.post_newsletter(body).await;
+    assert_eq!(response.status().as_u16(), 200);
+    // Mock verifies on Drop that we have sent the newsletter email **once**
+}
+
+#[tokio::test]
+async fn concurrent_form_submission_is_handled_gracefully() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        // Keep the first request in flight while the second one arrives
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(2)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let body = newsletter_request_body_with_key(&Uuid::new_v4().to_string());
+
+    let response1 = app.post_newsletter(body.clone());
+    let response2 = app.post_newsletter(body);
+    let (response1, response2) = tokio::join!(response1, response2);
+
+    assert_eq!(response1.status(), response2.status());
+    assert_eq!(
+        response1.text().await.unwrap(),
+        response2.text().await.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn an_invalid_idempotency_key_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_newsletter(newsletter_request_body_with_key(""))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn expired_idempotency_keys_are_deleted_and_fresh_ones_kept() {
+    // Arrange
+    let app = spawn_app().await;
+    for (key, age) in [
+        ("expired", Duration::hours(49)),
+        ("fresh", Duration::hours(1)),
+    ] {
+        sqlx::query!(
+            "INSERT INTO idempotency (user_id, idempotency_key, created_at) VALUES ($1, $2, $3)",
+            app.test_user.user_id,
+            key,
+            Utc::now() - age
+        )
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    }
+
+    // Act
+    let purged = delete_expired_keys(&app.db_pool, Duration::hours(48))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(purged, 1);
+    let keys: Vec<_> = sqlx::query!("SELECT idempotency_key FROM idempotency")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.idempotency_key)
+        .collect();
+    assert_eq!(keys, vec!["fresh"]);
+}
+
+async fn insert_subscriber(app: &TestApp, status: &str) {
+    insert_subscriber_with_email(app, &format!("{}@example.com", Uuid::new_v4()), status).await;
+}
+
+async fn insert_subscriber_with_email(app: &TestApp, email: &str, status: &str) {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status) \
+        VALUES ($1, $2, $3, $4, $5)",
+        id,
+        email,
+        "le guin",
+        Utc::now(),
+        status
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert subscriber");
+}
+
+#[tokio::test]
+async fn a_dry_run_counts_confirmed_subscribers_without_sending_emails() {
+    // Arrange
+    let app = spawn_app().await;
+    insert_subscriber(&app, "confirmed").await;
+    insert_subscriber(&app, "confirmed").await;
+    insert_subscriber(&app, "pending_confirmation").await;
+    insert_subscriber(&app, "unsubscribed").await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML<p>",
+            },
+            "dry_run": true,
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, serde_json::json!({ "recipients": 2 }));
+}
+
+fn newsletter_request_body_of_size(bytes: usize) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "a".repeat(bytes),
+            "html": "<p>Newsletter body as HTML<p>",
+        }
+    })
+}
+
+#[tokio::test]
+async fn newsletters_may_be_larger_than_other_bodies() {
+    let app = spawn_app_with_configuration(|c| {
+        c.application.max_body_bytes = 1024;
+        c.application.max_newsletter_body_bytes = 16 * 1024;
+    })
+    .await;
+
+    let response = app
+        .post_newsletter(newsletter_request_body_of_size(8 * 1024))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn newsletter_returns_a_413_for_an_oversized_body() {
+    let app = spawn_app_with_configuration(|c| {
+        c.application.max_body_bytes = 1024;
+        c.application.max_newsletter_body_bytes = 16 * 1024;
+    })
+    .await;
+
+    let response = app
+        .post_newsletter(newsletter_request_body_of_size(32 * 1024))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 413);
+}
+
+#[tokio::test]
+async fn newsletter_returns_a_411_for_a_body_without_a_declared_length() {
+    let app = spawn_app_with_configuration(|c| {
+        c.application.max_body_bytes = 1024;
+        c.application.max_newsletter_body_bytes = 16 * 1024;
+    })
+    .await;
+
+    let status = app
+        .post_chunked(
+            "/newsletters",
+            "application/json",
+            newsletter_request_body_of_size(32 * 1024).to_string(),
+        )
+        .await;
+
+    assert_eq!(status, 411);
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML<p>",
+        }
+    })
+}
+
+async fn publish_issue(app: &TestApp) -> String {
+    let response = app.post_newsletter(newsletter_request_body()).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["newsletter_issue_id"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn publishing_records_the_issue_and_its_deliveries() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_issue_id = publish_issue(&app).await;
+
+    // Assert
+    let issue = sqlx::query!("SELECT newsletter_issue_id, title FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the newsletter issue");
+    assert_eq!(issue.newsletter_issue_id.to_string(), newsletter_issue_id);
+    assert_eq!(issue.title, "Newsletter title");
+    let deliveries = sqlx::query!("SELECT subscriber_id FROM newsletter_delivery")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch the newsletter deliveries");
+    assert_eq!(deliveries.len(), 1);
+}
+
+#[tokio::test]
+async fn resending_delivers_the_issue_to_new_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let first_send = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let newsletter_issue_id = publish_issue(&app).await;
+    drop(first_send);
+    insert_subscriber(&app, "confirmed").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_resend_newsletter(&newsletter_issue_id).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, serde_json::json!({ "sent": 1, "failed": 0 }));
+}
+
+#[tokio::test]
+async fn resending_skips_subscribers_who_already_received_the_issue() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let first_send = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let newsletter_issue_id = publish_issue(&app).await;
+    drop(first_send);
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_resend_newsletter(&newsletter_issue_id).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, serde_json::json!({ "sent": 0, "failed": 0 }));
+}
+
+#[tokio::test]
+async fn resending_an_unknown_issue_returns_a_404() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_resend_newsletter(&Uuid::new_v4().to_string())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn markdown_newsletters_are_sent_as_sanitized_html_and_plain_text() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletter(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "markdown": "# Hello\n\nRead [more](https://example.com)<script>alert(1)</script>",
+            }
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.contains("<h1>Hello</h1>"));
+    assert!(!html.contains("script"));
+    assert_eq!(
+        body["TextBody"].as_str().unwrap(),
+        "Hello\n\nRead more (https://example.com)"
+    );
+}
+
+#[tokio::test]
+async fn a_preview_is_what_subscribers_would_get_without_sending_anything() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let newsletter = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "markdown": "# Hello\n\nRead [more](https://example.com)<script>alert(1)</script>",
+        }
+    });
+    let _mock_guard = Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletter_preview(newsletter.clone()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let preview: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(preview["title"], "Newsletter title");
+    let issues = sqlx::query!("SELECT count(*) AS \"count!\" FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(issues, 0);
+    drop(_mock_guard);
+
+    // The same issue, published
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletter(newsletter)
+        .await
+        .error_for_status()
+        .unwrap();
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(preview["html"], email["HtmlBody"]);
+    assert_eq!(preview["text"], email["TextBody"]);
+}
+
+#[tokio::test]
+async fn previews_require_authentication() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters/preview", &app.address))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_failed_email_is_counted_without_aborting_the_delivery() {
+    // Arrange
+    let app = spawn_app().await;
+    for _ in 0..3 {
+        insert_subscriber(&app, "confirmed").await;
+    }
+    insert_subscriber_with_email(&app, "unreachable@example.com", "confirmed").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .and(body_partial_json(
+            serde_json::json!({ "To": "unreachable@example.com" }),
+        ))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletter(newsletter_request_body()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["sent"], 3);
+    assert_eq!(body["failed"], 1);
+    // Only the failed subscriber is left for a resend
+    let deliveries = sqlx::query!("SELECT subscriber_id FROM newsletter_delivery")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch the newsletter deliveries");
+    assert_eq!(deliveries.len(), 3);
+}
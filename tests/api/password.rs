@@ -0,0 +1,259 @@
+use crate::helpers::{assert_is_redirect_to, csrf_token, spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::metrics::Metrics;
+
+/// Ask for a reset email for the test user and return the token it links to
+async fn request_password_reset(app: &TestApp) -> String {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let csrf_token = csrf_token(&app.get_html("/password/forgot").await);
+    let response = app
+        .post_form(
+            "/password/forgot",
+            &serde_json::json!({ "email": &app.test_user.email, "csrf_token": csrf_token }),
+        )
+        .await;
+    assert_is_redirect_to(&response, "/password/forgot");
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    app.get_password_reset_token(email_request)
+}
+
+async fn reset_password(app: &TestApp, token: &str, new_password: &str) -> reqwest::Response {
+    let html = app
+        .get_html(&format!("/password/reset?token={}", token))
+        .await;
+    app.post_form(
+        "/password/reset",
+        &serde_json::json!({
+            "token": token,
+            "new_password": new_password,
+            "new_password_check": new_password,
+            "csrf_token": csrf_token(&html),
+        }),
+    )
+    .await
+}
+
+async fn login_with(app: &TestApp, password: &str) -> reqwest::Response {
+    let csrf_token = csrf_token(&app.get_html("/login").await);
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": password,
+        "csrf_token": csrf_token,
+    }))
+    .await
+}
+
+#[tokio::test]
+async fn a_reset_token_sets_a_new_password() {
+    // Arrange
+    let app = spawn_app().await;
+    let token = request_password_reset(&app).await;
+    let new_password = Uuid::new_v4().to_string();
+
+    // Act
+    let response = reset_password(&app, &token, &new_password).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+    assert!(app
+        .get_html("/login")
+        .await
+        .contains("<p><i>Your password has been reset, log in with the new one</i></p>"));
+    assert_is_redirect_to(&login_with(&app, &new_password).await, "/admin/newsletters");
+}
+
+#[tokio::test]
+async fn reset_tokens_are_stored_hashed() {
+    let app = spawn_app().await;
+    let token = request_password_reset(&app).await;
+
+    let saved = sqlx::query!("SELECT token_hash FROM password_reset_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the saved reset token.");
+
+    assert_ne!(saved.token_hash, token);
+}
+
+#[tokio::test]
+async fn an_expired_reset_token_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let token = request_password_reset(&app).await;
+    sqlx::query!("UPDATE password_reset_tokens SET expires_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = reset_password(&app, &token, &Uuid::new_v4().to_string()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+    assert_is_redirect_to(
+        &login_with(&app, &app.test_user.password).await,
+        "/admin/newsletters",
+    );
+}
+
+#[tokio::test]
+async fn a_reset_token_only_works_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let token = request_password_reset(&app).await;
+    let new_password = Uuid::new_v4().to_string();
+    assert_is_redirect_to(&reset_password(&app, &token, &new_password).await, "/login");
+
+    // Act
+    let response = reset_password(&app, &token, &Uuid::new_v4().to_string()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+    assert_is_redirect_to(&login_with(&app, &new_password).await, "/admin/newsletters");
+}
+
+#[tokio::test]
+async fn an_unknown_reset_token_is_rejected() {
+    let app = spawn_app().await;
+
+    let response = reset_password(&app, "abcdef", &Uuid::new_v4().to_string()).await;
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn no_reset_email_is_sent_for_an_unknown_address() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let csrf_token = csrf_token(&app.get_html("/password/forgot").await);
+
+    // Act
+    let response = app
+        .post_form(
+            "/password/forgot",
+            &serde_json::json!({ "email": "nobody@example.com", "csrf_token": csrf_token }),
+        )
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/password/forgot");
+}
+
+#[tokio::test]
+async fn reset_emails_are_not_counted_as_confirmation_emails() {
+    // Arrange
+    let app = spawn_app().await;
+    let metrics = Metrics::default();
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    let csrf_token = csrf_token(&app.get_html("/password/forgot").await);
+
+    // Act
+    app.post_form(
+        "/password/forgot",
+        &serde_json::json!({ "email": &app.test_user.email, "csrf_token": csrf_token }),
+    )
+    .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails_counted_in(&metrics).await;
+
+    // Assert
+    assert_eq!(metrics.confirmation_emails_sent_total.get(), 1);
+}
+
+#[tokio::test]
+async fn a_logged_in_admin_can_change_their_password() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login().await;
+    let csrf_token = csrf_token(&app.get_html("/admin/password").await);
+    let new_password = Uuid::new_v4().to_string();
+
+    // Act
+    let response = app
+        .post_form(
+            "/admin/password",
+            &serde_json::json!({
+                "current_password": &app.test_user.password,
+                "new_password": &new_password,
+                "new_password_check": &new_password,
+                "csrf_token": csrf_token,
+            }),
+        )
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/password");
+    assert!(app
+        .get_html("/admin/password")
+        .await
+        .contains("<p><i>Your password has been changed</i></p>"));
+    assert_is_redirect_to(&login_with(&app, &new_password).await, "/admin/newsletters");
+}
+
+#[tokio::test]
+async fn changing_the_password_needs_the_current_one() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login().await;
+    let csrf_token = csrf_token(&app.get_html("/admin/password").await);
+    let new_password = Uuid::new_v4().to_string();
+
+    // Act
+    let response = app
+        .post_form(
+            "/admin/password",
+            &serde_json::json!({
+                "current_password": "wrong-password",
+                "new_password": &new_password,
+                "new_password_check": &new_password,
+                "csrf_token": csrf_token,
+            }),
+        )
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/password");
+    assert!(app
+        .get_html("/admin/password")
+        .await
+        .contains("<p><i>The current password is incorrect</i></p>"));
+}
+
+#[tokio::test]
+async fn changing_the_password_requires_a_login() {
+    let app = spawn_app().await;
+    let new_password = Uuid::new_v4().to_string();
+
+    let response = app
+        .post_form(
+            "/admin/password",
+            &serde_json::json!({
+                "current_password": &app.test_user.password,
+                "new_password": &new_password,
+                "new_password_check": &new_password,
+                "csrf_token": "",
+            }),
+        )
+        .await;
+
+    assert_is_redirect_to(&response, "/login");
+}
@@ -0,0 +1,59 @@
+use secrecy::Secret;
+use sqlx::{Connection, PgConnection};
+use std::sync::Mutex;
+use std::time::Duration;
+use zero2prod::configuration::{get_configuration, PasswordProvider};
+use zero2prod::startup::get_connection_pool_using;
+
+/// Hands out whatever password the test last stored
+struct StubPasswordProvider {
+    password: Mutex<Secret<String>>,
+    refresh_interval: Option<Duration>,
+}
+
+impl PasswordProvider for StubPasswordProvider {
+    fn password(&self) -> Secret<String> {
+        self.password.lock().unwrap().clone()
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        self.refresh_interval
+    }
+}
+
+#[tokio::test]
+async fn connection_options_use_the_provider_supplied_password() {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    let provider = StubPasswordProvider {
+        password: Mutex::new(configuration.database.password.clone()),
+        refresh_interval: None,
+    };
+    configuration.database.password = Secret::new("not-the-password".into());
+
+    PgConnection::connect_with(&configuration.database.with_db_using(&provider))
+        .await
+        .expect("Failed to connect with the provider-supplied password");
+    assert!(
+        PgConnection::connect_with(&configuration.database.with_db())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn the_pool_picks_up_a_rotated_password() {
+    let configuration = get_configuration().expect("Failed to read configuration.");
+    let provider = std::sync::Arc::new(StubPasswordProvider {
+        password: Mutex::new(Secret::new("expired-token".into())),
+        refresh_interval: Some(Duration::from_millis(50)),
+    });
+    let pool = get_connection_pool_using(&configuration.database, provider.clone());
+    assert!(pool.acquire().await.is_err());
+
+    *provider.password.lock().unwrap() = configuration.database.password.clone();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    pool.acquire()
+        .await
+        .expect("The pool did not pick up the rotated password");
+}
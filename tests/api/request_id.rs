@@ -0,0 +1,71 @@
+use crate::helpers::{captured_logs, spawn_app};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn responses_carry_a_generated_request_id() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!("{}/health_check", &app.address))
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    let request_id = response
+        .headers()
+        .get("X-Request-Id")
+        .expect("Missing X-Request-Id header");
+    assert!(Uuid::parse_str(request_id.to_str().unwrap()).is_ok());
+}
+
+#[tokio::test]
+async fn a_supplied_request_id_is_echoed_and_logged() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let request_id = format!("support-{}", Uuid::new_v4());
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Request-Id", &request_id)
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["X-Request-Id"], request_id.as_str());
+    let logs = captured_logs();
+    assert!(logs.lines().any(|line| {
+        line.contains("ADDING A NEW SUBSCRIBER")
+            && line.contains(&format!(r#""request_id":"{}""#, request_id))
+    }));
+}
+
+#[tokio::test]
+async fn an_unusable_request_id_is_replaced() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/health_check", &app.address))
+        .header("X-Request-Id", "no spaces allowed")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    let request_id = response.headers()["X-Request-Id"].to_str().unwrap();
+    assert!(Uuid::parse_str(request_id).is_ok());
+}
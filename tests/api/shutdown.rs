@@ -0,0 +1,173 @@
+use crate::helpers::{
+    captured_logs, create_confirmed_subscriber, spawn_app, spawn_app_with_configuration, TestApp,
+};
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn a_request_in_flight_during_shutdown_completes() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let emails_sent_before = app.email_server.received_requests().await.unwrap().len();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(1)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let in_flight = app.post_newsletter(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }));
+
+    // Act
+    let shutdown = async {
+        // Wait until the handler is busy sending the newsletter
+        while app.email_server.received_requests().await.unwrap().len() == emails_sent_before {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        app.shutdown.notify_one();
+    };
+    let (response, ()) = tokio::join!(in_flight, shutdown);
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+/// The drain summary of `app`, once its shutdown is over
+async fn drain_summary(app: &TestApp) -> serde_json::Value {
+    for _ in 0..500 {
+        let summary = captured_logs()
+            .lines()
+            .filter(|line| line.contains("Shutdown drain finished"))
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|summary| summary["port"] == app.port);
+        if let Some(summary) = summary {
+            return summary;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("No drain summary was logged");
+}
+
+async fn emails_received(app: &TestApp) -> usize {
+    app.email_server.received_requests().await.unwrap().len()
+}
+
+#[tokio::test]
+async fn the_drain_summary_counts_the_requests_and_emails_finished_during_shutdown() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.email_outbox.worker_enabled = true;
+        c.email_outbox.poll_interval_milliseconds = 10;
+    })
+    .await;
+    // A confirmed subscriber for the newsletter, confirmed through the worker
+    {
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount_as_scoped(&app.email_server)
+            .await;
+        app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+            .await
+            .error_for_status()
+            .unwrap();
+        while emails_received(&app).await == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let email_request = &app.email_server.received_requests().await.unwrap()[0];
+        reqwest::get(app.get_confirmation_links(email_request).html)
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3)))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    let emails_sent_before = emails_received(&app).await;
+    let in_flight = app.post_newsletter(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }));
+
+    // Act
+    let shutdown = async {
+        while emails_received(&app).await == emails_sent_before {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // A confirmation email for the outbox worker, while the handler is
+        // busy sending the newsletter
+        app.post_subscriptions("name=octavia&email=octavia%40example.com".into())
+            .await
+            .error_for_status()
+            .unwrap();
+        while emails_received(&app).await < emails_sent_before + 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        app.shutdown.notify_one();
+    };
+    let (response, ()) = tokio::join!(in_flight, shutdown);
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let summary = drain_summary(&app).await;
+    assert_eq!(summary["requests_completed"], 1);
+    assert_eq!(summary["requests_abandoned"], 0);
+    assert_eq!(summary["emails_flushed"], 1);
+    assert_eq!(summary["timeouts_hit"], 0);
+}
+
+#[tokio::test]
+async fn a_request_outlasting_the_shutdown_timeout_is_counted_as_abandoned() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.application.shutdown_timeout_seconds = 1).await;
+    create_confirmed_subscriber(&app).await;
+    let emails_sent_before = app.email_server.received_requests().await.unwrap().len();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+        .mount(&app.email_server)
+        .await;
+    // Cut off when the server stops, so it gets no response
+    let in_flight = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(&app.test_user.username, Some(&app.test_user.password))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send();
+
+    // Act
+    let shutdown = async {
+        while app.email_server.received_requests().await.unwrap().len() == emails_sent_before {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        app.shutdown.notify_one();
+    };
+    let (response, ()) = tokio::join!(in_flight, shutdown);
+
+    // Assert
+    assert!(response.is_err());
+    let summary = drain_summary(&app).await;
+    assert_eq!(summary["requests_completed"], 0);
+    assert_eq!(summary["requests_abandoned"], 1);
+    assert_eq!(summary["timeouts_hit"], 1);
+}
@@ -0,0 +1,105 @@
+use crate::helpers::{create_database, spawn_app_with_configuration};
+use uuid::Uuid;
+use zero2prod::configuration::{get_configuration, EmailProviderKind};
+use zero2prod::startup::{get_connection_pool, Application};
+
+#[tokio::test]
+async fn a_malformed_email_template_fails_the_build() {
+    let templates_directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&templates_directory).unwrap();
+    std::fs::write(templates_directory.join("hello_email.html"), "{{ name ").unwrap();
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.application.templates_directory = templates_directory.display().to_string();
+
+    let outcome = Application::build(configuration).await;
+
+    std::fs::remove_dir_all(&templates_directory).unwrap();
+    match outcome {
+        Ok(_) => panic!("The application was built despite a malformed template"),
+        Err(e) => assert!(e
+            .to_string()
+            .contains("Failed to compile the email templates")),
+    }
+}
+
+#[tokio::test]
+async fn the_smtp_provider_fails_the_build_without_smtp_settings() {
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.email_client.provider = EmailProviderKind::Smtp;
+    configuration.email_client.smtp = None;
+
+    let outcome = Application::build(configuration).await;
+
+    match outcome {
+        Ok(_) => panic!("The application was built without SMTP settings"),
+        Err(e) => assert!(e.to_string().contains("email_client.smtp")),
+    }
+}
+
+#[tokio::test]
+async fn migrations_are_applied_on_start_if_enabled() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.application.run_migrations_on_start = true;
+    configuration.database.database_name = Uuid::new_v4().to_string();
+    create_database(&configuration.database).await;
+
+    // Act
+    Application::build(configuration.clone())
+        .await
+        .expect("Failed to build application");
+
+    // Assert
+    let pool = get_connection_pool(&configuration.database);
+    let table =
+        sqlx::query_scalar::<_, Option<String>>("SELECT to_regclass('public.subscriptions')::text")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(table.as_deref(), Some("subscriptions"));
+}
+
+#[tokio::test]
+async fn a_failing_migration_aborts_the_start() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.application.run_migrations_on_start = true;
+    configuration.database.database_name = Uuid::new_v4().to_string();
+    create_database(&configuration.database).await;
+    let pool = get_connection_pool(&configuration.database);
+    // Pretend a migration this build doesn't know about was already applied
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+        VALUES (99990101000000, 'from the future', true, '\\x00', 0)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    // Act
+    let outcome = Application::build(configuration).await;
+
+    // Assert
+    match outcome {
+        Ok(_) => panic!("The application was built despite a failing migration"),
+        Err(e) => assert!(e
+            .to_string()
+            .contains("Failed to run the database migrations")),
+    }
+}
+
+#[tokio::test]
+async fn the_server_serves_requests_with_a_single_worker() {
+    let app = spawn_app_with_configuration(|c| c.application.workers = Some(1)).await;
+
+    let response = reqwest::get(format!("{}/health_check", app.address))
+        .await
+        .unwrap();
+
+    assert!(response.status().is_success());
+}
@@ -1,6 +1,16 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{
+    captured_logs, create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app,
+    spawn_app_with_configuration, TestApp,
+};
+use sqlx::Executor;
+use std::collections::HashMap;
+use uuid::Uuid;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::{EmailProviderKind, IpCapSettings, SubscriberIdentifier};
+use zero2prod::domain::SubscriptionToken;
+use zero2prod::email_client::MemoryEmailClient;
+use zero2prod::routes::{hash_token, store_token};
 
 #[tokio::test]
 async fn subscribe_returns_a_200_for_valid_form_data() {
@@ -27,6 +37,179 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
     assert_eq!(saved.name, "le guin");
 }
 
+#[tokio::test]
+async fn subscribe_returns_a_200_for_valid_json() {
+    // Arrange
+    let test_app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&test_app.email_server)
+        .await;
+    let body = serde_json::json!({
+        "name": "le guin",
+        "email": "ursula_le_guin@gmail.com"
+    });
+
+    // Act
+    let response = test_app
+        .post_subscriptions_with_content_type(body.to_string(), "application/json")
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, name from subscriptions")
+        .fetch_one(&test_app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_for_json_missing_a_field() {
+    // Arrange
+    let test_app = spawn_app().await;
+    let body = serde_json::json!({ "name": "le guin" });
+
+    // Act
+    let response = test_app
+        .post_subscriptions_with_content_type(body.to_string(), "application/json")
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_415_for_an_unsupported_content_type() {
+    // Arrange
+    let test_app = spawn_app().await;
+
+    // Act
+    let response = test_app
+        .post_subscriptions_with_content_type(
+            "<subscriber><name>le guin</name></subscriber>".into(),
+            "application/xml",
+        )
+        .await;
+
+    // Assert
+    assert_eq!(415, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_413_for_an_oversized_form() {
+    // Arrange
+    let test_app = spawn_app_with_configuration(|c| c.application.max_body_bytes = 1024).await;
+    let body = format!("name={}&email=ursula_le_guin%40gmail.com", "a".repeat(2048));
+
+    // Act
+    let response = test_app.post_subscriptions(body).await;
+
+    // Assert
+    assert_eq!(413, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_411_for_a_body_without_a_declared_length() {
+    // Arrange
+    let test_app = spawn_app_with_configuration(|c| c.application.max_body_bytes = 1024).await;
+    let body = format!("name={}&email=ursula_le_guin%40gmail.com", "a".repeat(2048));
+
+    // Act
+    let status = test_app
+        .post_chunked("/subscriptions", "application/x-www-form-urlencoded", body)
+        .await;
+
+    // Assert
+    assert_eq!(status, 411);
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&test_app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_413_for_an_oversized_json_body() {
+    // Arrange
+    let test_app = spawn_app_with_configuration(|c| c.application.max_body_bytes = 1024).await;
+    let body = serde_json::json!({
+        "name": "a".repeat(2048),
+        "email": "ursula_le_guin@gmail.com"
+    });
+
+    // Act
+    let response = test_app
+        .post_subscriptions_with_content_type(body.to_string(), "application/json")
+        .await;
+
+    // Assert
+    assert_eq!(413, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_errors_are_problem_details_for_clients_accepting_them() {
+    // Arrange
+    let test_app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "application/problem+json")
+        .body("name=&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/problem+json"
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["type"], "about:blank");
+    assert_eq!(body["title"], "Bad Request");
+    assert_eq!(body["status"], 400);
+    assert!(body["detail"].as_str().unwrap().contains("name"));
+}
+
+#[tokio::test]
+async fn subscribe_errors_keep_their_plain_body_for_browsers() {
+    // Arrange
+    let test_app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &test_app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+        .body("name=&email=ursula_le_guin%40gmail.com")
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_ne!(
+        response.headers().get("Content-Type").map(|v| v.as_bytes()),
+        Some("application/problem+json".as_bytes())
+    );
+}
+
 #[tokio::test]
 async fn subscribe_persist_the_new_subscriber() {
     let app = spawn_app().await;
@@ -92,6 +275,57 @@ async fn subscriber_returns_a_400_when_fields_are_present_but_invalid() {
     }
 }
 
+async fn subscribe_with_required_confirmation(require_confirmation: bool) -> (TestApp, String) {
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.require_confirmation = require_confirmation;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(u64::from(require_confirmation))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    (app, saved.status)
+}
+
+#[tokio::test]
+async fn subscribers_are_pending_and_sent_an_email_when_confirmation_is_required() {
+    let (app, status) = subscribe_with_required_confirmation(true).await;
+
+    assert_eq!(status, "pending_confirmation");
+    let tokens = sqlx::query!("SELECT subscriber_id FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens.len(), 1);
+    // Mock asserts on drop
+}
+
+#[tokio::test]
+async fn subscribers_are_confirmed_without_an_email_when_confirmation_isnt_required() {
+    let (app, status) = subscribe_with_required_confirmation(false).await;
+
+    assert_eq!(status, "confirmed");
+    let tokens = sqlx::query!("SELECT subscriber_id FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(tokens.is_empty());
+    // Mock asserts on drop
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_confirmation_email_for_valid_data() {
     // Arrange
@@ -107,6 +341,7 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data() {
 
     // Act
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     // Mock asserts on drop
@@ -126,14 +361,293 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     // Act
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_link = app.get_confirmation_links(&email_request);
+    let confirmation_link = app.get_confirmation_links(email_request);
 
     assert_eq!(confirmation_link.html, confirmation_link.plain_text);
 }
 
+#[tokio::test]
+async fn the_confirmation_email_goes_to_the_subscriber_with_a_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let email_client = MemoryEmailClient::default();
+
+    // Act
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails_to(&email_client).await;
+
+    // Assert
+    let sent = email_client.sent_emails();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].recipient, "ursula_le_guin@gmail.com");
+    let confirmation_links = app.get_sent_confirmation_links(&sent[0]);
+    assert_eq!(confirmation_links.html, confirmation_links.plain_text);
+    assert_eq!(confirmation_links.html.path(), "/subscriptions/confirm");
+    assert!(app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+/// The confirmation link in the plain text body of the first email sent
+async fn sent_confirmation_link(app: &TestApp) -> String {
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    linkify::LinkFinder::new()
+        .links(body["TextBody"].as_str().unwrap())
+        .map(|l| l.as_str().to_owned())
+        .find(|l| l.contains("/subscriptions/confirm?"))
+        .unwrap()
+}
+
+async fn subscribe_through_a_proxy(app: &TestApp, headers: &[(&str, &str)]) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("name=le%20guin&email=ursula_le_guin%40gmail.com");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request.send().await.expect("Failed to execute request.");
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn trusted_proxy_headers_set_the_base_of_the_confirmation_link() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.application.trust_proxy_headers = true).await;
+
+    // Act
+    subscribe_through_a_proxy(
+        &app,
+        &[
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "news.example.com"),
+        ],
+    )
+    .await;
+
+    // Assert
+    let link = sent_confirmation_link(&app).await;
+    assert!(
+        link.starts_with("https://news.example.com/subscriptions/confirm?"),
+        "{}",
+        link
+    );
+}
+
+#[tokio::test]
+async fn the_forwarded_header_sets_the_base_of_the_confirmation_link() {
+    let app = spawn_app_with_configuration(|c| c.application.trust_proxy_headers = true).await;
+
+    subscribe_through_a_proxy(
+        &app,
+        &[(
+            "Forwarded",
+            r#"for=192.0.2.60;proto=https;host="news.example.com""#,
+        )],
+    )
+    .await;
+
+    let link = sent_confirmation_link(&app).await;
+    assert!(
+        link.starts_with("https://news.example.com/subscriptions/confirm?"),
+        "{}",
+        link
+    );
+}
+
+#[tokio::test]
+async fn proxy_headers_are_ignored_unless_trusted() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    subscribe_through_a_proxy(
+        &app,
+        &[
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "evil.example.com"),
+        ],
+    )
+    .await;
+
+    // Assert
+    let link = sent_confirmation_link(&app).await;
+    assert!(
+        link.starts_with("http://127.0.0.1/subscriptions/confirm?"),
+        "{}",
+        link
+    );
+}
+
+#[tokio::test]
+async fn invalid_proxy_headers_fall_back_to_the_configured_base_url() {
+    let app = spawn_app_with_configuration(|c| c.application.trust_proxy_headers = true).await;
+
+    subscribe_through_a_proxy(
+        &app,
+        &[
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "evil.example.com/phishing"),
+        ],
+    )
+    .await;
+
+    let link = sent_confirmation_link(&app).await;
+    assert!(
+        link.starts_with("http://127.0.0.1/subscriptions/confirm?"),
+        "{}",
+        link
+    );
+}
+
+#[tokio::test]
+async fn confirmation_emails_have_an_html_part_by_default() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body["HtmlBody"].is_string());
+    assert!(body["TextBody"].is_string());
+}
+
+async fn confirmation_email_html(app: &TestApp, accept_language: &str, body: &str) -> String {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept-Language", accept_language)
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    body["HtmlBody"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn confirmation_emails_use_the_template_for_the_accepted_language() {
+    let app = spawn_app().await;
+
+    let html = confirmation_email_html(
+        &app,
+        "fr-CH, en;q=0.8",
+        "name=le%20guin&email=ursula_le_guin%40gmail.com",
+    )
+    .await;
+
+    assert!(html.contains("Bienvenue dans notre newsletter, le guin"));
+}
+
+#[tokio::test]
+async fn the_form_locale_takes_precedence_over_the_accepted_language() {
+    let app = spawn_app().await;
+
+    let html = confirmation_email_html(
+        &app,
+        "de",
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&locale=fr",
+    )
+    .await;
+
+    assert!(html.contains("Bienvenue dans notre newsletter"));
+}
+
+#[tokio::test]
+async fn confirmation_emails_fall_back_to_the_default_template() {
+    let app = spawn_app().await;
+
+    let html = confirmation_email_html(
+        &app,
+        "de-AT",
+        "name=le%20guin&email=ursula_le_guin%40gmail.com",
+    )
+    .await;
+
+    assert!(html.contains("Welcome to Our Newsletter, le guin"));
+}
+
+#[tokio::test]
+async fn confirmation_emails_are_plain_text_only_if_html_is_disabled() {
+    let app = spawn_app_with_configuration(|c| c.email_client.html_enabled = false).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body.get("HtmlBody").is_none());
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("/subscriptions/confirm?subscription_token="));
+}
+
+#[tokio::test]
+async fn confirmation_emails_use_the_configured_subject_and_sender_name() {
+    let app = spawn_app_with_configuration(|c| {
+        c.email_client.confirmation_subject = "Confirm your Zero2Prod subscription".into();
+        c.email_client.sender_name = Some("Zero2Prod Newsletter".into());
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Confirm your Zero2Prod subscription");
+    assert_eq!(body["From"], "Zero2Prod Newsletter <test@gmail.com>");
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_second_confirmation() {
     // Arrange
@@ -150,8 +664,10 @@ async fn subscribe_sends_a_second_confirmation() {
 
     // First Subscription
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     // Second Subscription
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 }
 
 #[tokio::test]
@@ -179,7 +695,7 @@ async fn after_second_subscription_there_is_only_one_subscription_token() {
 
     // Get record for subscriber_id in subscription_tokens db
     let record = sqlx::query!(
-        "SELECT subscription_token FROM subscription_tokens WHERE subscriber_id=$1",
+        "SELECT subscription_token_hash FROM subscription_tokens WHERE subscriber_id=$1",
         subscriber_id
     )
     .fetch_all(&app.db_pool)
@@ -209,25 +725,25 @@ async fn update_subscription_token_after_second_subscription() {
     let subscription_id = record.id;
 
     let first_subscription_token = sqlx::query!(
-        "SELECT subscription_token FROM subscription_tokens WHERE subscriber_id = $1",
+        "SELECT subscription_token_hash FROM subscription_tokens WHERE subscriber_id = $1",
         subscription_id
     )
     .fetch_one(&app.db_pool)
     .await
     .expect("Failed to fetch first subscription token")
-    .subscription_token;
+    .subscription_token_hash;
 
     // Second Subscription
     app.post_subscriptions(body.into()).await;
 
     let second_subscription_token = sqlx::query!(
-        "SELECT subscription_token FROM subscription_tokens WHERE subscriber_id = $1",
+        "SELECT subscription_token_hash FROM subscription_tokens WHERE subscriber_id = $1",
         subscription_id
     )
     .fetch_one(&app.db_pool)
     .await
     .expect("Failed to fetch second subscription token")
-    .subscription_token;
+    .subscription_token_hash;
 
     assert_ne!(first_subscription_token, second_subscription_token);
 }
@@ -246,17 +762,19 @@ async fn second_subscribe_sends_a_confirmation_email_with_a_new_link() {
 
     // Act First subscription
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     let first_email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let first_confirmation_link = app.get_confirmation_links(&first_email_request);
+    let first_confirmation_link = app.get_confirmation_links(first_email_request);
 
     // Act second subscription
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     let second_email_request = &app.email_server.received_requests().await.unwrap()[1];
-    let second_confirmation_link = app.get_confirmation_links(&second_email_request);
+    let second_confirmation_link = app.get_confirmation_links(second_email_request);
     assert_ne!(
         first_confirmation_link.plain_text,
         second_confirmation_link.plain_text
@@ -265,10 +783,37 @@ async fn second_subscribe_sends_a_confirmation_email_with_a_new_link() {
 }
 
 #[tokio::test]
-async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+async fn subscribe_returns_a_409_and_sends_no_email_for_a_confirmed_subscriber() {
+    // Arrange
     let app = spawn_app().await;
-    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
-
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
     // Sabotage database
     sqlx::query!("ALTER TABLE subscriptions DROP COLUMN email;",)
         .execute(&app.db_pool)
@@ -279,3 +824,781 @@ async fn subscribe_fails_if_there_is_a_fatal_database_error() {
 
     assert_eq!(response.status().as_u16(), 500);
 }
+
+#[tokio::test]
+async fn subscribe_accepts_aliased_form_field_names() {
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.form_field_map = HashMap::from([
+            ("email_address".to_string(), "email".to_string()),
+            ("full_name".to_string(), "name".to_string()),
+        ]);
+    })
+    .await;
+    let body = "full_name=le%20guin&email_address=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved.name, "le guin");
+}
+
+#[tokio::test]
+async fn a_failed_confirmation_email_is_kept_for_a_retry_and_logged_without_the_raw_email() {
+    let app = spawn_app_with_configuration(|c| {
+        c.telemetry.log_subscriber_identifier = SubscriberIdentifier::EmailObfuscated;
+    })
+    .await;
+    let domain = format!("{}.com", Uuid::new_v4());
+    let email = format!("ursula@{}", domain);
+    let body = format!("name=le%20guin&email=ursula%40{}", domain);
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let outbox =
+        sqlx::query!("SELECT attempts, last_error, delivered_at, failed_at FROM email_outbox")
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("Failed to fetch the queued email.");
+    assert_eq!(outbox.attempts, 1);
+    assert!(outbox.last_error.is_some());
+    assert!(outbox.delivered_at.is_none());
+    assert!(outbox.failed_at.is_none());
+    let logs = captured_logs();
+    assert!(logs
+        .lines()
+        .any(|line| line.contains("Failed to deliver an email, retrying later")));
+    assert!(!logs.contains(&email));
+}
+
+#[tokio::test]
+async fn a_failed_confirmation_email_is_logged_with_context_but_without_the_raw_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let domain = format!("{}.com", Uuid::new_v4());
+    let email = format!("ursula@{}", domain);
+    let request_id = format!("support-{}", Uuid::new_v4());
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Request-Id", &request_id)
+        .body(format!("name=le%20guin&email=ursula%40{}", domain))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let logs = captured_logs();
+    let failure = logs
+        .lines()
+        .find(|line| {
+            line.contains("Failed to deliver an email")
+                && line.contains(&format!(r#""correlation_id":"{}""#, request_id))
+        })
+        .expect("The failed send was not logged with its correlation id");
+    assert!(failure.contains(&format!("u***@{}", domain)));
+    assert!(!logs.contains(&email));
+}
+
+#[tokio::test]
+async fn a_rejected_email_is_logged_with_the_provider_status_and_a_masked_recipient() {
+    let app = spawn_app().await;
+    let domain = format!("{}.com", Uuid::new_v4());
+    let email = format!("ursula@{}", domain);
+    let body = format!("name=le%20guin&email=ursula%40{}", domain);
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(422)
+                .set_body_string(format!("Invalid 'To' address: '{}'", email)),
+        )
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let logs = captured_logs();
+    let rejection = logs
+        .lines()
+        .find(|line| {
+            line.contains("The email provider rejected the email") && line.contains(&domain)
+        })
+        .expect("The rejection wasn't logged");
+    assert!(rejection.contains(r#""email_provider.status":422"#));
+    assert!(rejection.contains(&format!("u***@{}", domain)));
+    assert!(!logs.contains(&email));
+}
+
+#[tokio::test]
+async fn subscribers_are_only_identified_by_id_in_logs_by_default() {
+    let app = spawn_app().await;
+    let domain = format!("{}.com", Uuid::new_v4());
+    let email = format!("ursula@{}", domain);
+    let body = format!("name=le%20guin&email=ursula%40{}", domain);
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .id;
+    let logs = captured_logs();
+    assert!(logs.contains(&subscriber_id.to_string()));
+    assert!(!logs.contains(&domain));
+}
+
+#[tokio::test]
+async fn subscribing_twice_with_differently_cased_emails_keeps_one_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first = app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40Gmail.com".into())
+        .await;
+    let second = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.COM".into())
+        .await;
+
+    // Assert
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "Ursula_Le_Guin@gmail.com");
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_429_once_a_client_exceeds_the_rate_limit() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.rate_limit.max_requests = 3;
+        c.subscriptions.rate_limit.window_seconds = 60;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    // Act
+    for _ in 0..3 {
+        let response = app.post_subscriptions(body.into()).await;
+        assert_eq!(200, response.status().as_u16());
+    }
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(429, response.status().as_u16());
+    let retry_after: u64 = response
+        .headers()
+        .get("Retry-After")
+        .expect("Missing Retry-After header")
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(retry_after, 20);
+}
+
+#[tokio::test]
+async fn a_trusted_forwarded_for_header_identifies_the_client() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.rate_limit.max_requests = 1;
+        c.application.trust_proxy_headers = true;
+        c.application.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let post_from = |client: &'static str| {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("X-Forwarded-For", format!("{}, 10.0.0.1", client))
+            .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+            .send()
+    };
+
+    // Act
+    let first = post_from("203.0.113.7").await.unwrap();
+    let second = post_from("198.51.100.23").await.unwrap();
+    let repeated = post_from("203.0.113.7").await.unwrap();
+
+    // Assert
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+    assert_eq!(429, repeated.status().as_u16());
+}
+
+#[tokio::test]
+async fn rotating_the_forwarded_hops_a_client_sends_does_not_dodge_the_rate_limit() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.rate_limit.max_requests = 1;
+        c.application.trust_proxy_headers = true;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    // Our proxy appends the address it was connected from to whatever the
+    // client sent
+    let post_spoofing = |spoofed: &'static str| {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &app.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("X-Forwarded-For", format!("{}, 203.0.113.7", spoofed))
+            .body("name=le%20guin&email=ursula_le_guin%40gmail.com")
+            .send()
+    };
+
+    // Act
+    let first = post_spoofing("198.51.100.1").await.unwrap();
+    let second = post_spoofing("198.51.100.2").await.unwrap();
+
+    // Assert
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(429, second.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribe_queues_the_confirmation_email_even_if_the_email_api_is_down() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscriber = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(subscriber.status, "pending_confirmation");
+    let queued = sqlx::query!("SELECT recipient, delivered_at FROM email_outbox")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch the queued emails.");
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].recipient, subscriber.email);
+    assert!(queued[0].delivered_at.is_none());
+    assert!(app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn no_subscriber_is_stored_if_the_confirmation_email_cannot_be_queued() {
+    // Arrange
+    let app = spawn_app().await;
+    sqlx::query!("ALTER TABLE email_outbox DROP COLUMN text_body;")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+    let subscribers = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert!(subscribers.is_empty());
+}
+
+#[tokio::test]
+async fn the_outbox_worker_delivers_queued_confirmation_emails() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.email_outbox.worker_enabled = true;
+        c.email_outbox.poll_interval_milliseconds = 50;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let delivered = async {
+        loop {
+            let delivered = sqlx::query!("SELECT delivered_at FROM email_outbox")
+                .fetch_one(&app.db_pool)
+                .await
+                .unwrap()
+                .delivered_at;
+            if delivered.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(10), delivered)
+        .await
+        .expect("The queued email was not delivered");
+}
+
+#[tokio::test]
+async fn an_invalid_name_is_rejected_with_the_broken_rule() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20%3Cguin%3E&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "Invalid name: The subscriber name must not contain '<'"
+    );
+}
+
+#[tokio::test]
+async fn the_null_email_provider_delivers_nothing() {
+    // Arrange
+    let app =
+        spawn_app_with_configuration(|c| c.email_client.provider = EmailProviderKind::Null).await;
+
+    // Act
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let queued = sqlx::query!("SELECT delivered_at FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the queued email.");
+    assert!(queued.delivered_at.is_some());
+    assert!(app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn a_confirmation_token_that_is_already_taken_is_replaced_by_a_fresh_one() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    let taken_token = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(name, _)| name == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+    let subscriber_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status) VALUES ($1, 'other@example.com', 'other', now(), 'pending_confirmation')"#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    // The generator comes up with the taken token first
+    let mut tokens = vec![
+        SubscriptionToken::parse("b".repeat(25), 25).unwrap(),
+        SubscriptionToken::parse(taken_token.clone(), 25).unwrap(),
+    ];
+
+    // Act
+    let mut transaction = app.db_pool.begin().await.unwrap();
+    let stored = store_token(&mut transaction, subscriber_id, || tokens.pop().unwrap())
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    // Assert
+    assert_eq!(stored.as_ref(), "b".repeat(25));
+    let hash = sqlx::query!(
+        "SELECT subscription_token_hash FROM subscription_tokens WHERE subscriber_id = $1",
+        subscriber_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .subscription_token_hash;
+    assert_eq!(hash, hash_token(&"b".repeat(25)));
+    // The first subscriber's token still works
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn an_email_domain_that_cant_receive_email_is_rejected_if_verified() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.email_client.verify_mx = true).await;
+
+    // Act
+    // `.invalid` is reserved and never resolves, see RFC 2606
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmailc.invalid".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "The email domain gmailc.invalid can't receive email"
+    );
+    let saved = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[tokio::test]
+async fn email_domains_are_not_looked_up_by_default() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula%40gmailc.invalid".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn concurrent_identical_subscriptions_store_a_single_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    // Act
+    let (first, second) = tokio::join!(
+        app.post_subscriptions(body.into()),
+        app.post_subscriptions(body.into())
+    );
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    let subscribers = sqlx::query!(r#"SELECT count(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(subscribers, 1);
+}
+
+/// Make the next `failures` inserts into `subscriptions` fail with a
+/// serialization failure, what a concurrent transaction may cause. The
+/// counter is a sequence, so it survives the rollback of the failed attempt.
+async fn fail_the_next_subscriber_inserts(app: &TestApp, failures: u32) {
+    app.db_pool
+        .execute(
+            format!(
+                r#"
+                CREATE SEQUENCE failed_subscriber_inserts;
+                CREATE FUNCTION fail_subscriber_insert() RETURNS trigger AS $$
+                BEGIN
+                    IF nextval('failed_subscriber_inserts') <= {failures} THEN
+                        RAISE EXCEPTION 'could not serialize access'
+                            USING ERRCODE = 'serialization_failure';
+                    END IF;
+                    RETURN NEW;
+                END
+                $$ LANGUAGE plpgsql;
+                CREATE TRIGGER fail_subscriber_insert BEFORE INSERT ON subscriptions
+                    FOR EACH ROW EXECUTE FUNCTION fail_subscriber_insert();
+                "#
+            )
+            .as_str(),
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn a_transient_database_failure_while_subscribing_is_retried() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.subscriptions.transaction_retries = 2).await;
+    fail_the_next_subscriber_inserts(&app, 1).await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].email, "ursula_le_guin@gmail.com");
+    assert_eq!(saved[0].status, "pending_confirmation");
+    let queued = sqlx::query!(r#"SELECT count(*) AS "count!" FROM email_outbox"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(queued, 1);
+}
+
+#[tokio::test]
+async fn a_transient_database_failure_outlasting_the_retries_returns_a_500() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.subscriptions.transaction_retries = 2).await;
+    fail_the_next_subscriber_inserts(&app, 3).await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+}
+
+#[tokio::test]
+async fn a_subscribe_request_that_takes_too_long_returns_a_504_and_stores_nothing() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.request_timeout_milliseconds = Some(500);
+    })
+    .await;
+    // Hold the outbox so the subscribe transaction gets stuck queueing the
+    // confirmation email, after inserting the subscriber
+    let mut lock = app.db_pool.begin().await.unwrap();
+    sqlx::query!("LOCK TABLE email_outbox IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut *lock)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    lock.rollback().await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 504);
+    let subscribers = sqlx::query!(r#"SELECT count(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(subscribers, 0);
+}
+
+#[tokio::test]
+async fn the_consent_source_and_ip_are_stored_with_a_new_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    subscribe_through_a_proxy(
+        &app,
+        &[
+            ("Referer", "https://news.example.com/signup"),
+            ("X-Forwarded-For", "203.0.113.7"),
+        ],
+    )
+    .await;
+
+    // Assert
+    let saved = sqlx::query!("SELECT consent_source, consent_ip FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(
+        saved.consent_source.as_deref(),
+        Some("https://news.example.com/signup")
+    );
+    // The proxy header isn't trusted by default
+    assert_eq!(saved.consent_ip.as_deref(), Some("127.0.0.1"));
+}
+
+#[tokio::test]
+async fn a_trusted_proxy_sets_the_consent_ip_and_the_form_sets_the_source() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.application.trust_proxy_headers = true;
+        c.application.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+    })
+    .await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("X-Forwarded-For", "203.0.113.7, 10.0.0.1")
+        .header("Referer", "https://news.example.com/elsewhere")
+        .form(&[
+            ("name", "le guin"),
+            ("email", "ursula_le_guin@gmail.com"),
+            ("consent_source", "https://news.example.com/signup"),
+        ])
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT consent_source, consent_ip FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(
+        saved.consent_source.as_deref(),
+        Some("https://news.example.com/signup")
+    );
+    assert_eq!(saved.consent_ip.as_deref(), Some("203.0.113.7"));
+}
+
+#[tokio::test]
+async fn subscribing_beyond_the_cap_per_ip_returns_a_429_and_stores_nothing() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.ip_cap = Some(IpCapSettings {
+            max_subscriptions: 2,
+            window_hours: 24,
+        });
+    })
+    .await;
+
+    // Act
+    let mut statuses = Vec::new();
+    for name in ["ursula", "octavia", "nnedi"] {
+        let response = app
+            .post_subscriptions(format!("name={}&email={}%40gmail.com", name, name))
+            .await;
+        statuses.push(response.status().as_u16());
+    }
+
+    // Assert
+    assert_eq!(statuses, vec![200, 200, 429]);
+    let subscribers = sqlx::query!(r#"SELECT count(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(subscribers, 2);
+    let queued = sqlx::query!("SELECT recipient, delivered_at FROM email_outbox")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.len(), 2);
+}
+
+#[tokio::test]
+async fn a_confirmation_email_that_fails_to_render_is_sent_as_plain_text() {
+    // Arrange
+    let templates_directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&templates_directory).unwrap();
+    std::fs::write(
+        templates_directory.join("hello_email.html"),
+        "Welcome, {{ no_such_variable }}",
+    )
+    .unwrap();
+    let app = spawn_app_with_configuration(|c| {
+        c.application.templates_directory = templates_directory.display().to_string();
+    })
+    .await;
+    let email_client = MemoryEmailClient::default();
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails_to(&email_client).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let sent = email_client.sent_emails();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].html_content, None);
+    assert!(sent[0]
+        .text_content
+        .contains("/subscriptions/confirm?subscription_token="));
+    std::fs::remove_dir_all(&templates_directory).unwrap();
+}
@@ -1,6 +1,16 @@
-use crate::helpers::spawn_app;
-use wiremock::matchers::{method, path};
-use wiremock::{Mock, ResponseTemplate};
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app,
+    spawn_app_with_configuration, TestApp,
+};
+use secrecy::Secret;
+use std::collections::HashMap;
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::configuration::{ConfirmationWebhookSettings, TokenMode};
+use zero2prod::confirmation_webhook::sign;
+use zero2prod::routes::hash_token;
+use zero2prod::signed_token::SignedToken;
+use zero2prod::subscription_events::get_subscription_events;
 
 #[tokio::test]
 async fn confirmations_without_token_are_rejected_with_a_400() {
@@ -25,8 +35,9 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_link = app.get_confirmation_links(&email_request);
+    let confirmation_link = app.get_confirmation_links(email_request);
 
     let response = reqwest::get(confirmation_link.html).await.unwrap();
     assert_eq!(response.status().as_u16(), 200);
@@ -44,8 +55,9 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_link = app.get_confirmation_links(&email_request);
+    let confirmation_link = app.get_confirmation_links(email_request);
 
     reqwest::get(confirmation_link.html)
         .await
@@ -63,6 +75,103 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     assert_eq!(saved.status, "confirmed");
 }
 
+#[tokio::test]
+async fn clicking_the_confirmation_link_twice_returns_a_200_both_times() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+
+    // Act
+    let first = reqwest::get(confirmation_link.html.clone()).await.unwrap();
+    let first_status = first.status().as_u16();
+    let first_body = first.text().await.unwrap();
+    let second = reqwest::get(confirmation_link.html).await.unwrap();
+
+    // Assert
+    assert_eq!(first_status, 200);
+    assert_eq!(first_body, "Your subscription has been confirmed.");
+    assert_eq!(second.status().as_u16(), 200);
+    assert_eq!(
+        second.text().await.unwrap(),
+        "Your subscription was already confirmed."
+    );
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn subscribing_and_confirming_records_two_ordered_events() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+
+    // Act
+    reqwest::get(confirmation_link.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    // A second click changes nothing
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    let events = get_subscription_events(&app.db_pool, subscriber_id)
+        .await
+        .unwrap();
+    let names: Vec<_> = events.iter().map(|e| e.event.as_str()).collect();
+    assert_eq!(names, ["subscribed", "confirmed"]);
+    assert!(events[0].occurred_at <= events[1].occurred_at);
+}
+
+#[tokio::test]
+async fn confirmation_tokens_are_stored_hashed_and_still_confirm() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    let token = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+
+    // Assert
+    let stored = sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the stored token")
+        .subscription_token_hash;
+    assert_ne!(stored, token);
+    assert_eq!(stored, hash_token(&token));
+
+    // Act
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
 #[tokio::test]
 async fn clicking_on_the_old_confirmation_link_gives_a_400() {
     let app = spawn_app().await;
@@ -75,10 +184,12 @@ async fn clicking_on_the_old_confirmation_link_gives_a_400() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_link = app.get_confirmation_links(&email_request);
+    let confirmation_link = app.get_confirmation_links(email_request);
 
     let response = reqwest::get(confirmation_link.html).await.unwrap();
 
@@ -97,10 +208,12 @@ async fn clicking_on_the_new_confirmation_link_gives_a_200() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[1];
-    let confirmation_link = app.get_confirmation_links(&email_request);
+    let confirmation_link = app.get_confirmation_links(email_request);
 
     let response = reqwest::get(confirmation_link.html).await.unwrap();
 
@@ -119,9 +232,10 @@ async fn clicking_on_the_confirmation_link_twice_gives_a_200() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_link = app.get_confirmation_links(&email_request);
+    let confirmation_link = app.get_confirmation_links(email_request);
 
     reqwest::get(confirmation_link.html.clone()).await.unwrap();
     let response = reqwest::get(confirmation_link.html).await.unwrap();
@@ -141,6 +255,7 @@ async fn clicking_on_a_invalid_confirmation_link_gives_a_401() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token=test",
@@ -151,3 +266,450 @@ async fn clicking_on_a_invalid_confirmation_link_gives_a_401() {
 
     assert_eq!(response.status().as_u16(), 401);
 }
+
+#[tokio::test]
+async fn tokens_of_a_configured_length_confirm_and_others_are_rejected() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.subscriptions.token_length = 40).await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    let token: String = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+    assert_eq!(token.len(), 40);
+    let mut truncated_link = confirmation_link.html.clone();
+    truncated_link.set_query(Some(&format!("subscription_token={}", &token[..25])));
+
+    // Act
+    let truncated = reqwest::get(truncated_link).await.unwrap();
+    let confirmed = reqwest::get(confirmation_link.html).await.unwrap();
+
+    // Assert
+    assert_eq!(truncated.status().as_u16(), 401);
+    assert_eq!(confirmed.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn an_unknown_token_is_a_401_problem_for_clients_accepting_them() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token={}",
+            app.address,
+            "a".repeat(25)
+        ))
+        .header("Accept", "application/problem+json")
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/problem+json"
+    );
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "type": "about:blank",
+            "title": "Unauthorized",
+            "status": 401,
+            "detail": "There is no subscriber associated with the provider token"
+        })
+    );
+}
+
+#[tokio::test]
+async fn confirming_a_subscriber_delivers_the_confirmation_webhook() {
+    let webhook_server = MockServer::start().await;
+    let signing_key = "webhook-secret".to_string();
+    let app = spawn_app_with_configuration(|c| {
+        c.confirmation_webhook = Some(ConfirmationWebhookSettings {
+            url: format!("{}/hooks/confirmed", webhook_server.uri()),
+            template: r#"{"event": "confirmed", "contact": {{ email | json_encode() | safe }}}"#
+                .to_string(),
+            headers: HashMap::from([("X-Integration".to_string(), "crm".to_string())]),
+            signing_key: Some(Secret::new(signing_key.clone())),
+            timeout_milliseconds: 1000,
+        });
+    })
+    .await;
+    let expected_payload = r#"{"event": "confirmed", "contact": "ursula_le_guin@gmail.com"}"#;
+    let expected_signature = sign(&Secret::new(signing_key), expected_payload);
+    Mock::given(path("/hooks/confirmed"))
+        .and(method("POST"))
+        .and(header("X-Integration", "crm"))
+        .and(header("X-Webhook-Signature", expected_signature.as_str()))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&webhook_server)
+        .await;
+
+    create_confirmed_subscriber(&app).await;
+
+    // The webhook is dispatched in the background
+    let mut webhook_requests = vec![];
+    for _ in 0..50 {
+        webhook_requests = webhook_server.received_requests().await.unwrap();
+        if !webhook_requests.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let request = webhook_requests
+        .first()
+        .expect("The confirmation webhook was not delivered");
+    assert_eq!(
+        std::str::from_utf8(&request.body).unwrap(),
+        expected_payload
+    );
+}
+
+#[tokio::test]
+async fn validating_a_freshly_issued_token_reports_it_as_valid_without_confirming() {
+    let app = spawn_app().await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    let token = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+
+    let response = app.post_validate_token(&token).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, serde_json::json!({ "valid": true }));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn validating_an_unknown_or_malformed_token_reports_why_it_is_invalid() {
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("a".repeat(25), "unknown"),
+        ("not-a-token!".to_string(), "malformed"),
+    ];
+
+    for (token, reason) in test_cases {
+        let response = app.post_validate_token(&token).await;
+
+        assert_eq!(response.status().as_u16(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "valid": false, "reason": reason }),
+            "Unexpected result for token {}",
+            token
+        );
+    }
+}
+
+#[tokio::test]
+async fn validating_tokens_is_rate_limited_per_client() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.validate_rate_limit.max_requests = 2;
+        c.subscriptions.validate_rate_limit.window_seconds = 60;
+    })
+    .await;
+
+    let token = "a".repeat(25);
+
+    // Act
+    let mut statuses = Vec::new();
+    for _ in 0..3 {
+        statuses.push(app.post_validate_token(&token).await.status().as_u16());
+    }
+
+    // Assert
+    assert_eq!(statuses, vec![200, 200, 429]);
+}
+
+#[tokio::test]
+async fn validating_tokens_and_subscribing_are_limited_separately() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| {
+        c.subscriptions.rate_limit.max_requests = 1;
+        c.subscriptions.validate_rate_limit.max_requests = 1;
+    })
+    .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    let token = "a".repeat(25);
+
+    // Act
+    let validated = app.post_validate_token(&token).await.status().as_u16();
+    let subscribed = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .status()
+        .as_u16();
+    let validated_again = app.post_validate_token(&token).await.status().as_u16();
+
+    // Assert
+    assert_eq!(validated, 200);
+    assert_eq!(subscribed, 200);
+    assert_eq!(validated_again, 429);
+}
+
+async fn backdate_confirmation_tokens(app: &TestApp, age: chrono::Duration) {
+    sqlx::query!(
+        "UPDATE subscription_tokens SET created_at = $1",
+        chrono::Utc::now() - age
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to backdate the confirmation token.");
+}
+
+#[tokio::test]
+async fn confirming_with_an_expired_token_is_rejected_with_a_410() {
+    let app =
+        spawn_app_with_configuration(|c| c.subscriptions.confirmation_token_ttl_hours = 24).await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    backdate_confirmation_tokens(&app, chrono::Duration::hours(25)).await;
+
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn tokens_within_their_ttl_still_confirm() {
+    let app =
+        spawn_app_with_configuration(|c| c.subscriptions.confirmation_token_ttl_hours = 24).await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    backdate_confirmation_tokens(&app, chrono::Duration::hours(23)).await;
+
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn validating_an_expired_token_reports_it_as_expired() {
+    let app =
+        spawn_app_with_configuration(|c| c.subscriptions.confirmation_token_ttl_hours = 24).await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    backdate_confirmation_tokens(&app, chrono::Duration::hours(25)).await;
+    let token = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+
+    let response = app.post_validate_token(&token).await;
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({ "valid": false, "reason": "expired" })
+    );
+}
+
+const SIGNING_KEY: &str = "confirmation-token-signing-key-0451";
+
+async fn spawn_app_with_signed_tokens() -> TestApp {
+    spawn_app_with_configuration(|c| {
+        c.subscriptions.token_mode = TokenMode::Signed;
+        c.subscriptions.token_signing_key = Some(Secret::new(SIGNING_KEY.into()));
+    })
+    .await
+}
+
+fn confirmation_link_with_token(app: &TestApp, subscription_token: &str) -> String {
+    format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        app.address, subscription_token
+    )
+}
+
+#[tokio::test]
+async fn a_signed_token_confirms_without_being_stored() {
+    // Arrange
+    let app = spawn_app_with_signed_tokens().await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+
+    // Act
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+    let stored_tokens = sqlx::query!(r#"SELECT count(*) AS "count!" FROM subscription_tokens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(stored_tokens, 0);
+}
+
+#[tokio::test]
+async fn an_expired_signed_token_is_rejected_with_a_410() {
+    // Arrange
+    let app = spawn_app_with_signed_tokens().await;
+    create_unconfirmed_subscriber(&app).await;
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    let token = SignedToken {
+        subscriber_id,
+        expires_at: chrono::Utc::now() - chrono::Duration::hours(1),
+    }
+    .sign(&Secret::new(SIGNING_KEY.into()));
+
+    // Act
+    let response = reqwest::get(confirmation_link_with_token(&app, &token))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+}
+
+#[tokio::test]
+async fn a_tampered_signed_token_is_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app_with_signed_tokens().await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    let token = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+    // Push the expiry back by a day, keeping the signature
+    let mut parts: Vec<String> = token.split('.').map(String::from).collect();
+    let expires_at: i64 = parts[1].parse().unwrap();
+    parts[1] = (expires_at + 86_400).to_string();
+    let tampered = parts.join(".");
+
+    // Act
+    let response = reqwest::get(confirmation_link_with_token(&app, &tampered))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .status
+}
+
+#[tokio::test]
+async fn posting_the_token_confirms_a_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    let token: String = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/confirm", app.address))
+        .form(&[("subscription_token", token)])
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn with_confirm_by_post_only_the_link_shows_a_form_that_confirms() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.subscriptions.confirm_by_post_only = true).await;
+    let confirmation_link = create_unconfirmed_subscriber(&app).await;
+    let token: String = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+
+    // Act - Part 1 - Open the link
+    let page = reqwest::get(confirmation_link.html).await.unwrap();
+
+    // Assert - Part 1
+    assert_eq!(page.status().as_u16(), 200);
+    let html = page.text().await.unwrap();
+    assert!(html.contains(r#"method="post""#));
+    assert!(html.contains(&format!(r#"value="{token}""#)));
+    assert_eq!(subscriber_status(&app).await, "pending_confirmation");
+
+    // Act - Part 2 - Submit the form
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions/confirm", app.address))
+        .form(&[("subscription_token", token)])
+        .send()
+        .await
+        .unwrap();
+
+    // Assert - Part 2
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn with_confirm_by_post_only_an_unknown_link_is_still_a_401() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.subscriptions.confirm_by_post_only = true).await;
+
+    // Act
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=%22%3E%3Cscript%3E",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
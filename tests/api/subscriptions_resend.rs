@@ -0,0 +1,104 @@
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app,
+    spawn_app_with_configuration,
+};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const EMAIL: &str = "email=ursula_le_guin%40gmail.com";
+
+#[tokio::test]
+async fn a_pending_subscriber_gets_a_new_working_confirmation_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let first_link = create_unconfirmed_subscriber(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_resend_confirmation(EMAIL.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let second_link = app.get_confirmation_links(&email_request);
+    assert_ne!(first_link.html, second_link.html);
+    reqwest::get(second_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn a_confirmed_subscriber_gets_a_409_and_no_email() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_resend_confirmation(EMAIL.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn an_unknown_email_gets_a_404() {
+    let app = spawn_app().await;
+
+    let response = app.post_resend_confirmation(EMAIL.into()).await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn an_unknown_email_gets_a_200_and_no_email_if_concealed() {
+    // Arrange
+    let app = spawn_app_with_configuration(|c| c.subscriptions.conceal_unknown_emails = true).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_resend_confirmation(EMAIL.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn an_invalid_email_gets_a_400() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_resend_confirmation("email=not-an-email".into())
+        .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
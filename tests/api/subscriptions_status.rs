@@ -0,0 +1,66 @@
+use crate::helpers::{create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app};
+
+#[tokio::test]
+async fn a_pending_subscriber_is_reported_as_pending_confirmation() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    let response = app
+        .get_subscription_status("ursula_le_guin@gmail.com")
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "pending_confirmation");
+}
+
+#[tokio::test]
+async fn a_confirmed_subscriber_is_reported_as_confirmed() {
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    let response = app
+        .get_subscription_status("ursula_le_guin@GMAIL.com")
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "confirmed");
+}
+
+#[tokio::test]
+async fn an_unknown_email_is_rejected_with_a_404() {
+    let app = spawn_app().await;
+
+    let response = app.get_subscription_status("nobody@gmail.com").await;
+
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn a_malformed_email_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = app.get_subscription_status("not-an-email").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn requests_without_credentials_are_rejected() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/subscriptions/status", &app.address))
+        .query(&[("email", "ursula_le_guin@gmail.com")])
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["WWW-Authenticate"],
+        r#"Basic realm="publish""#
+    );
+}
@@ -0,0 +1,324 @@
+use crate::helpers::{spawn_app, ConfirmationsLinks, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::routes::hash_token;
+
+/// Subscribe and return the confirmation and unsubscribe links from the email
+async fn subscribe(app: &TestApp) -> (ConfirmationsLinks, ConfirmationsLinks) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    (
+        app.get_confirmation_links(email_request),
+        app.get_unsubscribe_links(email_request),
+    )
+}
+
+/// The link in the `List-Unsubscribe` header of an email, checked for the
+/// one-click `List-Unsubscribe-Post` header next to it
+fn list_unsubscribe_link(app: &TestApp, email_request: &wiremock::Request) -> reqwest::Url {
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let header = |name: &str| {
+        body["Headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|header| header["Name"] == name)
+            .map(|header| header["Value"].as_str().unwrap().to_owned())
+            .unwrap()
+    };
+    assert_eq!(
+        header("List-Unsubscribe-Post"),
+        "List-Unsubscribe=One-Click"
+    );
+    let value = header("List-Unsubscribe");
+    let link = value.strip_prefix('<').unwrap().strip_suffix('>').unwrap();
+    let mut link = reqwest::Url::parse(link).unwrap();
+    link.set_port(Some(app.port)).unwrap();
+    link
+}
+
+async fn saved_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .status
+}
+
+#[tokio::test]
+async fn the_unsubscribe_link_in_the_confirmation_email_unsubscribes_a_confirmed_subscriber() {
+    let app = spawn_app().await;
+    let (confirmation_link, unsubscribe_link) = subscribe(&app).await;
+    assert_eq!(confirmation_link.html, confirmation_link.plain_text);
+    assert_eq!(unsubscribe_link.html, unsubscribe_link.plain_text);
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::get(unsubscribe_link.html.clone()).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_status(&app).await, "unsubscribed");
+
+    // A second click is harmless
+    let response = reqwest::get(unsubscribe_link.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn confirming_after_unsubscribing_returns_a_410() {
+    let app = spawn_app().await;
+    let (confirmation_link, unsubscribe_link) = subscribe(&app).await;
+    reqwest::get(unsubscribe_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    assert_eq!(saved_status(&app).await, "unsubscribed");
+}
+
+#[tokio::test]
+async fn unsubscribing_with_an_unknown_token_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!(
+        "{}/unsubscribe?unsubscribe_token={}",
+        app.address,
+        "a".repeat(25)
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn the_confirmation_email_has_a_list_unsubscribe_header() {
+    let app = spawn_app().await;
+    let (_, unsubscribe_link) = subscribe(&app).await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+
+    assert_eq!(
+        list_unsubscribe_link(&app, email_request),
+        unsubscribe_link.html
+    );
+}
+
+#[tokio::test]
+async fn newsletter_issues_have_a_list_unsubscribe_header() {
+    // Arrange
+    let app = spawn_app().await;
+    let (confirmation_link, unsubscribe_link) = subscribe(&app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    app.post_newsletter(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML<p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    // Assert
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    assert_eq!(
+        list_unsubscribe_link(&app, &email_requests[1]),
+        unsubscribe_link.html
+    );
+}
+
+#[tokio::test]
+async fn a_one_click_post_to_the_unsubscribe_link_unsubscribes() {
+    let app = spawn_app().await;
+    let (confirmation_link, unsubscribe_link) = subscribe(&app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::Client::new()
+        .post(unsubscribe_link.html)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_status(&app).await, "unsubscribed");
+}
+
+/// The token in an unsubscribe link
+fn token_in(link: &reqwest::Url) -> String {
+    link.query_pairs()
+        .find(|(key, _)| key == "unsubscribe_token")
+        .map(|(_, value)| value.into_owned())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn only_a_hash_of_the_mailed_unsubscribe_token_is_stored() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let (_, unsubscribe_link) = subscribe(&app).await;
+
+    // Assert
+    let token = token_in(&unsubscribe_link.html);
+    let stored = sqlx::query!("SELECT unsubscribe_token_hash FROM unsubscribe_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .unsubscribe_token_hash;
+    assert_ne!(stored, token);
+    assert_eq!(stored, hash_token(&token));
+}
+
+#[tokio::test]
+async fn subscribers_with_an_old_random_token_keep_it_and_get_a_working_one_in_newsletters() {
+    // Arrange
+    let app = spawn_app().await;
+    let (confirmation_link, _) = subscribe(&app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    // What a token stored before they were derived looks like after the
+    // migration hashed it
+    let old_token = "a".repeat(25);
+    sqlx::query!(
+        "UPDATE unsubscribe_tokens SET unsubscribe_token_hash = $1",
+        hash_token(&old_token)
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    app.post_newsletter(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML<p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    // Assert
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let newsletter_link = list_unsubscribe_link(&app, &email_requests[1]);
+    assert_ne!(token_in(&newsletter_link), old_token);
+    let response = reqwest::get(newsletter_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_status(&app).await, "unsubscribed");
+    let response = reqwest::get(format!(
+        "{}/unsubscribe?unsubscribe_token={}",
+        app.address, old_token
+    ))
+    .await
+    .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn a_reason_given_when_unsubscribing_shows_up_in_the_breakdown() {
+    // Arrange
+    let app = spawn_app().await;
+    let (confirmation_link, mut unsubscribe_link) = subscribe(&app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    unsubscribe_link
+        .html
+        .query_pairs_mut()
+        .append_pair("reason", "  Too many emails ");
+
+    // Act
+    let response = reqwest::get(unsubscribe_link.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = app.get_unsubscribe_reasons().await.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({"reasons": [{"reason": "Too many emails", "count": 1}]})
+    );
+}
+
+#[tokio::test]
+async fn a_reason_can_be_posted_with_the_unsubscribe_form() {
+    // Arrange
+    let app = spawn_app().await;
+    let (confirmation_link, unsubscribe_link) = subscribe(&app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(unsubscribe_link.html)
+        .form(&[("reason", "Not relevant to me")])
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT reason FROM unsubscribe_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the unsubscribe event.");
+    assert_eq!(saved.reason.as_deref(), Some("Not relevant to me"));
+}
+
+#[tokio::test]
+async fn an_overlong_unsubscribe_reason_is_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let (_, mut unsubscribe_link) = subscribe(&app).await;
+    unsubscribe_link
+        .html
+        .query_pairs_mut()
+        .append_pair("reason", &"a".repeat(1001));
+
+    // Act
+    let response = reqwest::get(unsubscribe_link.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(saved_status(&app).await, "pending_confirmation");
+}